@@ -0,0 +1,137 @@
+// Reads src/core.clrs into rulsp::embed::Datum constants, so starting an interpreter doesn't
+// parse the prelude. The reader knows the syntax the prelude is written in, a test in core
+// checks it reads the same forms the parser does.
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+struct Reader<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || c.is_control() || ",()[]{}\"".contains(c)
+}
+
+impl<'a> Reader<'a> {
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c == ';' {
+                while self.chars.peek().map_or(false, |&c| c != '\n') {
+                    self.chars.next();
+                }
+            } else if c.is_whitespace() || c == ',' {
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // The Datum expression of the next form, None at the end
+    fn form(&mut self) -> Option<String> {
+        self.skip_blank();
+        let c = self.chars.next()?;
+        let quote = match c {
+            '\'' => Some("quote"),
+            '`' => Some("backquote"),
+            '~' if self.chars.peek() == Some(&'@') => {
+                self.chars.next();
+                Some("unquote-splicing")
+            }
+            '~' => Some("unquote"),
+            _ => None,
+        };
+        if let Some(name) = quote {
+            let quoted = self.form().expect("a quote needs a form after it");
+            return Some(format!("Datum::List(&[Datum::Symbol({:?}), {}])", name, quoted));
+        }
+
+        Some(match c {
+            '(' => format!("Datum::List(&[{}])", self.forms_until(')').join(", ")),
+            '[' => format!("Datum::Vector(&[{}])", self.forms_until(']').join(", ")),
+            '"' => format!("Datum::Str({:?})", self.string()),
+            c if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while self.chars.peek().map_or(false, |c| c.is_ascii_digit()) {
+                    digits.push(self.chars.next().unwrap());
+                }
+                format!("Datum::Int({})", digits)
+            }
+            c if !is_delimiter(c) && c != '#' && c != '\\' => {
+                let mut name = c.to_string();
+                while self.chars.peek().map_or(false, |&c| !is_delimiter(c)) {
+                    name.push(self.chars.next().unwrap());
+                }
+                identifier(&name)
+            }
+            c => panic!("the prelude reader in build.rs doesn't read {:?}", c),
+        })
+    }
+
+    fn forms_until(&mut self, close: char) -> Vec<String> {
+        let mut forms = vec![];
+        loop {
+            self.skip_blank();
+            if self.chars.peek() == Some(&close) {
+                self.chars.next();
+                return forms;
+            }
+            forms.push(self.form().expect("unbalanced brackets in the prelude"));
+        }
+    }
+
+    fn string(&mut self) -> String {
+        let mut string = String::new();
+        loop {
+            match self.chars.next().expect("unterminated string in the prelude") {
+                '"' => return string,
+                '\\' => {
+                    match self.chars.next() {
+                        Some('n') => string.push('\n'),
+                        Some('t') => string.push('\t'),
+                        Some('r') => string.push('\r'),
+                        Some('0') => string.push('\0'),
+                        Some(other) => string.push(other),
+                        None => {}
+                    }
+                }
+                c => string.push(c),
+            }
+        }
+    }
+}
+
+fn identifier(name: &str) -> String {
+    if name.to_uppercase() == "NIL" {
+        "Datum::Nil".to_string()
+    } else if name == "true" || name == "false" {
+        format!("Datum::Bool({})", name)
+    } else if name.len() > 1 && name.starts_with(':') {
+        format!("Datum::Keyword({:?})", &name[1..])
+    } else {
+        format!("Datum::Symbol({:?})", name)
+    }
+}
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let path = Path::new(&dir).join("src").join("core.clrs");
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    let mut source = String::new();
+    File::open(&path).and_then(|mut file| file.read_to_string(&mut source)).unwrap();
+    let mut reader = Reader { chars: source.chars().peekable() };
+    let mut forms = vec![];
+    while let Some(form) = reader.form() {
+        forms.push(form);
+    }
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("prelude.rs");
+    File::create(out)
+        .and_then(|mut file| write!(file, "&[{}]", forms.join(",\n")))
+        .unwrap();
+}
@@ -0,0 +1,27 @@
+/* Embedding rulsp from C, link against the cdylib built by `cargo build --lib` */
+#ifndef RULSP_H
+#define RULSP_H
+
+#include <stdint.h>
+
+typedef struct Rulsp Rulsp;
+
+/* An interpreter with the core library loaded, freed with rulsp_free */
+Rulsp *rulsp_new(void);
+
+/* Evaluates source, returns a handle to the value of the last form, or 0 on error; a panic in
+   the interpreter is an error too */
+uint64_t rulsp_eval(Rulsp *rulsp, const char *source);
+
+/* The value printed, or NULL for an unknown handle; valid until the next call */
+const char *rulsp_get_string(Rulsp *rulsp, uint64_t handle);
+
+/* The report of the last failed rulsp_eval; valid until the next one fails */
+const char *rulsp_error(Rulsp *rulsp);
+
+/* Drops the value behind handle */
+void rulsp_release(Rulsp *rulsp, uint64_t handle);
+
+void rulsp_free(Rulsp *rulsp);
+
+#endif
@@ -0,0 +1,60 @@
+// rulsp! { (defn f (x) (+ x 1)) } reads the forms while the host compiles, so they're checked
+// then, and expands to a rulsp::embed::Embedded constant holding them
+extern crate proc_macro;
+extern crate rulsp;
+
+use proc_macro::{Span, TokenStream};
+use rulsp::embed::datum_expr;
+use rulsp::lexer::Lexer;
+use rulsp::parser::Parser;
+use rulsp::report::Report;
+
+// The text of the forms, laid out at the lines and columns the tokens are at in the file so
+// reported positions are the file's; tokens inside brackets come out exactly as written
+fn source(input: TokenStream) -> String {
+    let mut source = String::new();
+    let (mut line, mut column) = (1, 1);
+    for tree in input {
+        let (start, end) = (tree.span().start(), tree.span().end());
+        while line < start.line() {
+            source.push('\n');
+            line += 1;
+            column = 1;
+        }
+        while column < start.column() {
+            source.push(' ');
+            column += 1;
+        }
+        source.push_str(&tree.span().source_text().unwrap_or_else(|| tree.to_string()));
+        line = end.line();
+        column = end.column();
+    }
+    source
+}
+
+fn compile_error(message: &str) -> TokenStream {
+    format!("compile_error!({:?})", message).parse().unwrap()
+}
+
+#[proc_macro]
+pub fn rulsp(input: TokenStream) -> TokenStream {
+    let file = Span::call_site().file();
+    let source = source(input);
+    let forms = match Parser::new(Lexer::new(&source)).parse_program() {
+        Ok(forms) => forms,
+        Err(err) => {
+            return compile_error(&Report::from_parse_error(&file, &source, &err).render(false))
+        }
+    };
+    let mut datums = vec![];
+    for form in &forms {
+        match datum_expr(form) {
+            Ok(datum) => datums.push(datum),
+            Err(err) => return compile_error(&format!("{}: {}", file, err)),
+        }
+    }
+    format!("::rulsp::embed::Embedded {{ file: file!(), line: line!(), forms: &[{}] }}",
+            datums.join(", "))
+        .parse()
+        .unwrap()
+}
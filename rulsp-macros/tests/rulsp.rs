@@ -0,0 +1,24 @@
+#[macro_use]
+extern crate rulsp_macros;
+extern crate rulsp;
+
+use rulsp::data::{c_int, c_string};
+use rulsp::embed::Embedded;
+use rulsp::interpreter::Interpreter;
+
+const LIBRARY: Embedded = rulsp! {
+    (defn add-one (x) (+ x 1))
+    (def greeting {:text "hi"})
+    'done
+};
+
+#[test]
+fn test_rulsp() {
+    let interpreter = Interpreter::new();
+    LIBRARY.load(&interpreter).unwrap();
+    assert_eq!(interpreter.eval("(add-one 41)").unwrap(), c_int(42));
+    assert_eq!(interpreter.eval("(:text greeting)").unwrap(), c_string("hi"));
+    assert!(LIBRARY.file.ends_with("rulsp.rs"));
+    assert_eq!(LIBRARY.line, 9);
+    assert_eq!(LIBRARY.forms.len(), 3);
+}
@@ -0,0 +1,580 @@
+use env::{Env, env_names, params_arity};
+use lexer::Token;
+use parser::{Cst, Span};
+
+// Names the evaluator handles itself, they're never looked up in an environment
+pub const SPECIAL_FORMS: &'static [&'static str] = &["binding", "def", "defn", "defmacro",
+                                                     "defmethod", "defmulti", "defprotocol",
+                                                     "defrecord", "defstruct", "do", "eval",
+                                                     "extend-type", "fn*", "handler-bind",
+                                                     "handler-case", "if", "let", "loop",
+                                                     "macroexpand", "match", "quote", "recur",
+                                                     "restart-case", "signal", "unwind-protect",
+                                                     "with-open", "with-out-str", "yield", "break",
+                                                     "assert", "call/cc", "p_env", "current-env",
+                                                     "&", "&opt", "&key"];
+
+// Names bound in env and special forms starting with prefix, sorted, for completing at a prompt
+pub fn completions(env: &Env, prefix: &str) -> Vec<String> {
+    let mut names = env_names(env);
+    names.extend(SPECIAL_FORMS.iter().filter(|name| !name.starts_with('&')).map(|name| {
+        name.to_string()
+    }));
+    names.retain(|name| name.starts_with(prefix));
+    names.sort();
+    names.dedup();
+    names
+}
+
+// A name defined at the top level of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub name: String,
+    // of the name in the defining form
+    pub span: Span,
+    pub doc: Option<String>,
+    // source of the parameter list of defn and defmacro (fn* ...)
+    pub params: Option<String>,
+    // fewest and most (None if variadic) args it takes when params are known
+    pub arity: Option<(usize, Option<usize>)>,
+    pub is_macro: bool,
+}
+
+// A symbol evaluated somewhere in a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub name: String,
+    pub span: Span,
+    // where the local binding or top-level definition it refers to is named
+    pub target: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BindingKind {
+    // of a function, method or clause
+    Param,
+    // of let and loop
+    Let,
+    // of match patterns and with-open
+    Pattern,
+}
+
+// A local name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    pub name: String,
+    pub span: Span,
+    pub kind: BindingKind,
+    // the enclosing local binding of the same name it hides
+    pub shadows: Option<Span>,
+}
+
+// A list starting with a symbol, called unless it's a special form
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub name: String,
+    // of the whole list
+    pub span: Span,
+    pub args: usize,
+    // like for references, None if the name isn't bound in the file
+    pub target: Option<Span>,
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub definitions: Vec<Definition>,
+    pub references: Vec<Reference>,
+    pub calls: Vec<Call>,
+    pub bindings: Vec<Binding>,
+    // patterns of match clauses following one that matches anything
+    pub unreachable: Vec<Span>,
+}
+
+impl Analysis {
+    // References to names that are neither bound in the file nor known to is_known
+    pub fn unresolved<F>(&self, is_known: F) -> Vec<&Reference>
+        where F: Fn(&str) -> bool
+    {
+        self.references
+            .iter()
+            .filter(|reference| {
+                reference.target.is_none() && !SPECIAL_FORMS.contains(&reference.name.as_str()) &&
+                !is_known(&reference.name)
+            })
+            .collect()
+    }
+}
+
+// Forms of a node list, trivia left out
+fn forms(nodes: &[Cst]) -> Vec<&Cst> {
+    nodes.iter().filter(|node| !node.is_trivia()).collect()
+}
+
+fn symbol(node: &Cst) -> Option<&str> {
+    match *node {
+        Cst::Atom(_, Token::Identifier(ref name), _) => {
+            if name.starts_with(':') && name.len() > 1 || name.to_uppercase() == "NIL" ||
+               name == "true" || name == "false" {
+                None
+            } else {
+                Some(name)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn string(node: Option<&&Cst>) -> Option<String> {
+    match node {
+        Some(&&Cst::Atom(_, Token::Str(ref s), _)) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+// Forms of a list, None for anything else
+fn list(node: &Cst) -> Option<Vec<&Cst>> {
+    match *node {
+        Cst::Seq(_, Token::Oparen, ref children) => Some(forms(children)),
+        _ => None,
+    }
+}
+
+// The (name value) pairs of a let in the form eval::let_pairs tells apart
+fn let_pairs<'a>(head: &str, bindings: Option<&&'a Cst>) -> Option<Vec<Vec<&'a Cst>>> {
+    let pairs = match (head, bindings) {
+        ("let", Some(&&Cst::Seq(_, Token::Oparen, ref children))) => {
+            forms(children).into_iter().map(list).collect::<Option<Vec<_>>>()?
+        }
+        _ => return None,
+    };
+    if !pairs.is_empty() && pairs.iter().all(|pair| pair.len() == 2) {
+        Some(pairs)
+    } else {
+        None
+    }
+}
+
+fn seq<'a>(node: Option<&&'a Cst>) -> Vec<&'a Cst> {
+    match node {
+        Some(&&Cst::Seq(_, _, ref children)) => forms(children),
+        _ => vec![],
+    }
+}
+
+fn arity(params: Option<&&Cst>) -> Option<(usize, Option<usize>)> {
+    let params = params?.ast().ok()??;
+    let params = params.get_seq().ok()?;
+    Some(params_arity(params))
+}
+
+fn define(definitions: &mut Vec<Definition>, name: &str, span: Span) -> usize {
+    definitions.push(Definition {
+        name: name.to_string(),
+        span: span,
+        doc: None,
+        params: None,
+        arity: None,
+        is_macro: false,
+    });
+    definitions.len() - 1
+}
+
+// Top-level definitions, also those inside top-level do
+fn collect_definitions(nodes: &[Cst], definitions: &mut Vec<Definition>) {
+    for node in nodes {
+        let items = match list(node) {
+            Some(items) => items,
+            None => continue,
+        };
+        let (head, name) = match (items.get(0).and_then(|n| symbol(n)), items.get(1)) {
+            (Some(head), Some(name)) => (head, name),
+            _ => continue,
+        };
+        if head == "do" {
+            if let Cst::Seq(_, _, ref children) = *node {
+                collect_definitions(children, definitions);
+            }
+            continue;
+        }
+        let name_str = match symbol(name) {
+            Some(name) => name,
+            None => continue,
+        };
+        let span = name.span();
+
+        match head {
+            "def" | "defmacro" | "defmulti" => {
+                let index = define(definitions, name_str, span);
+                // (def name (fn* (params) body)) documents its params
+                if let Some(value) = items.get(2).and_then(|value| list(value)) {
+                    if value.get(0).and_then(|n| symbol(n)) == Some("fn*") {
+                        definitions[index].params = value.get(1).map(|p| p.to_string());
+                        definitions[index].arity = arity(value.get(1));
+                    }
+                }
+                definitions[index].is_macro = head == "defmacro";
+                if head == "defmulti" {
+                    definitions[index].doc = string(items.get(2));
+                }
+            }
+            "defn" => {
+                let index = define(definitions, name_str, span);
+                let doc = string(items.get(2));
+                let params_index = if doc.is_some() { 3 } else { 2 };
+                definitions[index].doc = doc;
+                definitions[index].params = items.get(params_index).map(|p| p.to_string());
+                definitions[index].arity = arity(items.get(params_index));
+            }
+            "defrecord" | "defstruct" => {
+                define(definitions, name_str, span);
+                define(definitions, &format!("->{}", name_str), span);
+                define(definitions, &format!("map->{}", name_str), span);
+                for field in seq(items.get(2)) {
+                    if let Some(field_name) = symbol(field) {
+                        define(definitions, &format!("{}-{}", name_str, field_name), field.span());
+                    }
+                }
+            }
+            "defprotocol" => {
+                define(definitions, name_str, span);
+                for sig in items.iter().skip(2) {
+                    let sig = match list(sig) {
+                        Some(sig) => sig,
+                        None => continue,
+                    };
+                    if let Some(method) = sig.get(0).and_then(|n| symbol(n)) {
+                        let index = define(definitions, method, sig[0].span());
+                        definitions[index].params = sig.get(1).map(|p| p.to_string());
+                        definitions[index].doc = string(sig.get(2));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+struct Walker<'a> {
+    definitions: &'a [Definition],
+    scopes: Vec<(String, Span)>,
+    references: Vec<Reference>,
+    calls: Vec<Call>,
+    bindings: Vec<Binding>,
+    unreachable: Vec<Span>,
+}
+
+impl<'a> Walker<'a> {
+    fn is_macro(&self, name: &str, is_macro: &dyn Fn(&str) -> bool) -> bool {
+        match self.definitions.iter().find(|definition| definition.name == name) {
+            Some(definition) => definition.is_macro,
+            None => is_macro(name),
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Option<Span> {
+        self.scopes
+            .iter()
+            .rev()
+            .find(|binding| binding.0 == name)
+            .map(|binding| binding.1)
+            .or_else(|| self.definitions.iter().find(|d| d.name == name).map(|d| d.span))
+    }
+
+    fn reference(&mut self, name: &str, span: Span) {
+        let target = self.resolve(name);
+        self.references.push(Reference {
+            name: name.to_string(),
+            span: span,
+            target: target,
+        });
+    }
+
+    // Every symbol of a parameter list or destructuring pattern is bound
+    fn bind(&mut self, pattern: &Cst, kind: BindingKind) {
+        match *pattern {
+            Cst::Atom(span, _, _) => {
+                match symbol(pattern) {
+                    Some(name) if !name.starts_with('&') => {
+                        let shadows = self.scopes
+                            .iter()
+                            .rev()
+                            .find(|binding| binding.0 == name)
+                            .map(|binding| binding.1);
+                        self.bindings.push(Binding {
+                            name: name.to_string(),
+                            span: span,
+                            kind: kind,
+                            shadows: shadows,
+                        });
+                        self.scopes.push((name.to_string(), span));
+                    }
+                    _ => {}
+                }
+            }
+            Cst::Seq(_, _, ref children) => {
+                for child in children {
+                    self.bind(child, kind);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn walk_all(&mut self, nodes: &[&Cst], is_macro: &dyn Fn(&str) -> bool) {
+        for node in nodes {
+            self.walk(node, is_macro);
+        }
+    }
+
+    // Params at index, then the body in their scope
+    fn walk_fn(&mut self, items: &[&Cst], index: usize, is_macro: &dyn Fn(&str) -> bool) {
+        let depth = self.scopes.len();
+        if let Some(params) = items.get(index) {
+            self.bind(params, BindingKind::Param);
+        }
+        if items.len() > index + 1 {
+            self.walk_all(&items[index + 1..], is_macro);
+        }
+        self.scopes.truncate(depth);
+    }
+
+    // (name (params) body...) clauses
+    fn walk_clauses(&mut self, clauses: &[&Cst], is_macro: &dyn Fn(&str) -> bool) {
+        for clause in clauses {
+            match list(clause) {
+                Some(items) => self.walk_fn(&items, 1, is_macro),
+                None => self.walk(clause, is_macro),
+            }
+        }
+    }
+
+    fn walk_backquoted(&mut self, node: &Cst, is_macro: &dyn Fn(&str) -> bool) {
+        match *node {
+            Cst::Prefix(_, Token::Unquote, ref children) |
+            Cst::Prefix(_, Token::UnquoteSplicing, ref children) => {
+                self.walk_all(&forms(children), is_macro)
+            }
+            Cst::Prefix(_, _, ref children) |
+            Cst::Seq(_, _, ref children) => {
+                for child in children {
+                    self.walk_backquoted(child, is_macro);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn walk(&mut self, node: &Cst, is_macro: &dyn Fn(&str) -> bool) {
+        match *node {
+            Cst::Atom(span, _, _) => {
+                if let Some(name) = symbol(node) {
+                    self.reference(name, span);
+                }
+            }
+            Cst::Prefix(_, Token::Backquote, ref children) => {
+                for child in children {
+                    self.walk_backquoted(child, is_macro);
+                }
+            }
+            Cst::Prefix(_, Token::Unquote, ref children) |
+            Cst::Prefix(_, Token::UnquoteSplicing, ref children) => {
+                self.walk_all(&forms(children), is_macro)
+            }
+            Cst::Seq(span, Token::Oparen, ref children) => {
+                self.walk_list(span, &forms(children), is_macro)
+            }
+            Cst::Seq(_, _, ref children) => self.walk_all(&forms(children), is_macro),
+            // quoted and tagged forms are data, reader conditionals may not be read at all
+            _ => {}
+        }
+    }
+
+    fn walk_list(&mut self, span: Span, items: &[&Cst], is_macro: &dyn Fn(&str) -> bool) {
+        let head = match items.get(0).and_then(|n| symbol(n)) {
+            Some(head) if !self.scopes.iter().any(|binding| binding.0 == head) => head,
+            _ => return self.walk_all(items, is_macro),
+        };
+        if !SPECIAL_FORMS.contains(&head) {
+            let target = self.resolve(head);
+            self.calls.push(Call {
+                name: head.to_string(),
+                span: span,
+                args: items.len() - 1,
+                target: target,
+            });
+        }
+        let rest = |from: usize| &items[from.min(items.len())..];
+
+        match head {
+            "quote" => {}
+            "def" | "defmacro" | "defmulti" => self.walk_all(rest(2), is_macro),
+            "defn" => {
+                let index = if string(items.get(2)).is_some() { 3 } else { 2 };
+                self.walk_fn(items, index, is_macro)
+            }
+            "fn*" => self.walk_fn(items, 1, is_macro),
+            "defmethod" => {
+                self.walk_all(&items[1..items.len().min(3)], is_macro);
+                self.walk_fn(items, 3, is_macro)
+            }
+            "let" | "loop" | "with-open" => {
+                let depth = self.scopes.len();
+                let kind = if head == "with-open" {
+                    BindingKind::Pattern
+                } else {
+                    BindingKind::Let
+                };
+                let bindings = seq(items.get(1));
+                match let_pairs(head, items.get(1)) {
+                    Some(pairs) => {
+                        // the names are bound once all values are evaluated, see let_pairs
+                        for pair in &pairs {
+                            self.walk(pair[1], is_macro);
+                        }
+                        for pair in &pairs {
+                            self.bind(pair[0], kind);
+                        }
+                    }
+                    None => {
+                        for pair in bindings.chunks(2) {
+                            if let Some(value) = pair.get(1) {
+                                self.walk(value, is_macro);
+                            }
+                            self.bind(pair[0], kind);
+                        }
+                    }
+                }
+                self.walk_all(rest(2), is_macro);
+                self.scopes.truncate(depth);
+            }
+            "handler-bind" => {
+                for pair in seq(items.get(1)).chunks(2) {
+                    self.walk_all(&pair[1..], is_macro);
+                }
+                self.walk_all(rest(2), is_macro)
+            }
+            "handler-case" | "restart-case" => {
+                self.walk_all(&items[1..items.len().min(2)], is_macro);
+                self.walk_clauses(rest(2), is_macro)
+            }
+            "extend-type" => {
+                self.walk_all(&items[1..items.len().min(3)], is_macro);
+                self.walk_clauses(rest(3), is_macro)
+            }
+            "match" => {
+                self.walk_all(&items[1..items.len().min(2)], is_macro);
+                let mut clauses = rest(2).iter();
+                let mut matched_all = false;
+                while let Some(pattern) = clauses.next() {
+                    let depth = self.scopes.len();
+                    if matched_all {
+                        self.unreachable.push(pattern.span());
+                    }
+                    match **pattern {
+                        Cst::Prefix(_, Token::Apostrophe, _) => {}
+                        _ => self.bind(pattern, BindingKind::Pattern),
+                    }
+                    let mut body = clauses.next();
+                    if body.map_or(false, |node| node.to_string() == ":when") {
+                        if let Some(guard) = clauses.next() {
+                            self.walk(guard, is_macro);
+                        }
+                        body = clauses.next();
+                    } else if symbol(pattern).is_some() {
+                        matched_all = true;
+                    }
+                    if let Some(body) = body {
+                        self.walk(body, is_macro);
+                    }
+                    self.scopes.truncate(depth);
+                }
+            }
+            "defrecord" | "defstruct" | "defprotocol" => {}
+            // macros take their arguments unevaluated, whatever they bind is unknown
+            _ if self.is_macro(head, is_macro) => self.walk(items[0], is_macro),
+            _ => self.walk_all(items, is_macro),
+        }
+    }
+}
+
+// Definitions and symbol references of a file, is_macro tells if a name not defined in the file
+// is a macro
+pub fn analyze<F>(nodes: &[Cst], is_macro: F) -> Analysis
+    where F: Fn(&str) -> bool
+{
+    let mut definitions = vec![];
+    collect_definitions(nodes, &mut definitions);
+
+    let (references, calls, bindings, unreachable) = {
+        let mut walker = Walker {
+            definitions: &definitions,
+            scopes: vec![],
+            references: vec![],
+            calls: vec![],
+            bindings: vec![],
+            unreachable: vec![],
+        };
+        for node in nodes {
+            walker.walk(node, &is_macro);
+        }
+        (walker.references, walker.calls, walker.bindings, walker.unreachable)
+    };
+
+    Analysis {
+        definitions: definitions,
+        references: references,
+        calls: calls,
+        bindings: bindings,
+        unreachable: unreachable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::analyze;
+    use parser::parse_cst;
+
+    fn unresolved(source: &str) -> Vec<String> {
+        let analysis = analyze(&parse_cst(source).unwrap(), |name| name == "when");
+        analysis.unresolved(|name| name == "+" || name == "when")
+            .iter()
+            .map(|reference| reference.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_definitions() {
+        let source = "(defn add \"Adds\" (a b) (+ a b))\n(do (def x (fn* (y) y)))\n\
+                      (defrecord Point [x y])";
+        let analysis = analyze(&parse_cst(source).unwrap(), |_| false);
+        let names = analysis.definitions.iter().map(|d| d.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["add", "x", "Point", "->Point", "map->Point", "Point-x", "Point-y"]);
+
+        let add = &analysis.definitions[0];
+        assert_eq!(add.doc, Some("Adds".to_string()));
+        assert_eq!(add.params, Some("(a b)".to_string()));
+        assert_eq!(add.span.start.column, 7);
+        assert_eq!(add.arity, Some((2, Some(2))));
+        assert_eq!(analysis.definitions[1].params, Some("(y)".to_string()));
+        let calls = analysis.calls.iter().map(|c| (c.name.as_str(), c.args)).collect::<Vec<_>>();
+        assert_eq!(calls, [("+", 2)]);
+
+        let b = analysis.references.iter().find(|r| r.name == "b").unwrap();
+        assert_eq!(b.target.unwrap().start.column, 21);
+    }
+
+    #[test]
+    fn test_scopes() {
+        assert_eq!(unresolved("(defn f (a & more) (+ a more z))"), ["z"]);
+        assert_eq!(unresolved("(let [a 1 [b c] (list a)] (+ a b c)) a"), ["list", "a"]);
+        assert_eq!(unresolved("(let ((a 1) (b a)) b)"), ["a"]);
+        assert_eq!(unresolved("(fn* (x) (loop (i x) (if i (recur (+ i 1)) y)))"), ["y"]);
+        assert_eq!(unresolved("'(a b) `(a ~b ~@(c))"), ["b", "c"]);
+        assert_eq!(unresolved("(handler-case (f) (error (e) e z))"), ["f", "z"]);
+        assert_eq!(unresolved("(match v [a & rest] rest {:k k} :when (+ k) k 'q q)"),
+                   ["v", "q"]);
+        assert_eq!(unresolved("(when x (anything goes)) (defmacro m (fn* (x) x)) (m y)"),
+                   Vec::<String>::new());
+        assert_eq!(unresolved(":kw nil true \"s\" 1 (def later 1) later"), Vec::<String>::new());
+    }
+}
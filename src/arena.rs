@@ -0,0 +1,249 @@
+// Where atoms are allocated: slots of one size carved out of 64k chunks. Allocating takes the
+// next slot of the chunk in use, and once every atom in that chunk has died, which is the case
+// for the temporaries of a form after it was evaluated, the chunk starts over from its first
+// slot. Atoms which escape, like values stored in an env, keep their chunk; it's put aside when
+// it's full and the slots freed in it are reused later. There's no moving atoms out of a chunk,
+// other atoms point right at them.
+//
+// A chunk finds its header by the address of a slot, so a plugin's copy of this crate can free
+// atoms the interpreter allocated and the other way around.
+use std::alloc::{self, AllocError, Allocator, Global, Layout};
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::ptr::{self, NonNull};
+use data::AtomType;
+
+const CHUNK: usize = 64 * 1024;
+const ALIGN: usize = 16;
+// an atom with the counts of its Rc in front
+const SLOT: usize = (mem::size_of::<AtomType>() + 2 * mem::size_of::<usize>() + ALIGN - 1) &
+                    !(ALIGN - 1);
+const FIRST: usize = (mem::size_of::<Chunk>() + ALIGN - 1) & !(ALIGN - 1);
+
+// The allocator of AtomVal
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Arena;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // slots are taken from it
+    Current,
+    // in the chunks with free slots of its owner
+    Listed,
+    // full when it was last looked at, freed once it's empty
+    Full,
+}
+
+// At the start of every chunk
+struct Chunk {
+    // the Chunks of the thread it belongs to, null once that thread is shutting down
+    owner: Cell<*const Chunks>,
+    state: Cell<State>,
+    live: Cell<usize>,
+    // offset of the slot after the last one ever taken
+    top: Cell<usize>,
+    // freed slots below top, each holding the address of the next
+    free: Cell<*mut u8>,
+}
+
+fn chunk_layout() -> Layout {
+    Layout::from_size_align(CHUNK, CHUNK).unwrap()
+}
+
+impl Chunk {
+    fn new(owner: *const Chunks, state: State) -> &'static Chunk {
+        unsafe {
+            let chunk = alloc::alloc(chunk_layout()) as *mut Chunk;
+            if chunk.is_null() {
+                alloc::handle_alloc_error(chunk_layout());
+            }
+            ptr::write(chunk,
+                       Chunk {
+                           owner: Cell::new(owner),
+                           state: Cell::new(state),
+                           live: Cell::new(0),
+                           top: Cell::new(FIRST),
+                           free: Cell::new(ptr::null_mut()),
+                       });
+            &*chunk
+        }
+    }
+
+    unsafe fn of(slot: *mut u8) -> &'static Chunk {
+        &*((slot as usize & !(CHUNK - 1)) as *const Chunk)
+    }
+
+    fn base(&self) -> *mut u8 {
+        self as *const Chunk as *mut u8
+    }
+
+    fn take(&self) -> Option<*mut u8> {
+        let mut slot = self.free.get();
+        if !slot.is_null() {
+            self.free.set(unsafe { *(slot as *mut *mut u8) });
+        } else if self.top.get() + SLOT <= CHUNK {
+            slot = unsafe { self.base().add(self.top.get()) };
+            self.top.set(self.top.get() + SLOT);
+        } else {
+            return None;
+        }
+        self.live.set(self.live.get() + 1);
+        Some(slot)
+    }
+
+    unsafe fn release(&'static self, slot: *mut u8) {
+        let live = self.live.get() - 1;
+        self.live.set(live);
+        if live == 0 {
+            if self.state.get() == State::Full {
+                alloc::dealloc(self.base(), chunk_layout());
+            } else {
+                self.top.set(FIRST);
+                self.free.set(ptr::null_mut());
+            }
+            return;
+        }
+
+        *(slot as *mut *mut u8) = self.free.get();
+        self.free.set(slot);
+        if self.state.get() == State::Full {
+            // only the owner's list, the chunks of a plugin's copy aren't looked at by the
+            // interpreter
+            let _ = CHUNKS.try_with(|chunks| {
+                if ptr::eq(chunks, self.owner.get()) {
+                    self.state.set(State::Listed);
+                    chunks.listed.borrow_mut().push(self);
+                }
+            });
+        }
+    }
+}
+
+// The chunks of a thread
+struct Chunks {
+    current: Cell<Option<&'static Chunk>>,
+    // chunks slots were freed in since they were current
+    listed: RefCell<Vec<&'static Chunk>>,
+}
+
+thread_local! {
+    static CHUNKS: Chunks = Chunks {
+        current: Cell::new(None),
+        listed: RefCell::new(vec![]),
+    };
+}
+
+impl Chunks {
+    fn take(&self) -> *mut u8 {
+        if let Some(slot) = self.current.get().and_then(Chunk::take) {
+            return slot;
+        }
+        if let Some(full) = self.current.get() {
+            full.state.set(State::Full);
+        }
+        let listed = self.listed.borrow_mut().pop();
+        let chunk = listed.unwrap_or_else(|| Chunk::new(self, State::Current));
+        chunk.state.set(State::Current);
+        self.current.set(Some(chunk));
+        chunk.take().unwrap()
+    }
+
+    // Frees the listed chunks nothing lives in anymore
+    fn trim(&self) {
+        self.listed.borrow_mut().retain(|chunk| {
+            if chunk.live.get() > 0 {
+                return true;
+            }
+            unsafe { alloc::dealloc(chunk.base(), chunk_layout()) };
+            false
+        });
+    }
+}
+
+// Atoms freed after this are freed along with the last one in their chunk
+impl Drop for Chunks {
+    fn drop(&mut self) {
+        let listed = mem::take(self.listed.get_mut());
+        for chunk in self.current.take().into_iter().chain(listed) {
+            chunk.owner.set(ptr::null());
+            chunk.state.set(State::Full);
+            if chunk.live.get() == 0 {
+                unsafe { alloc::dealloc(chunk.base(), chunk_layout()) };
+            }
+        }
+    }
+}
+
+fn fits(layout: &Layout) -> bool {
+    layout.size() <= SLOT && layout.align() <= ALIGN
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !fits(&layout) {
+            return Global.allocate(layout);
+        }
+        // a thread shutting down gets a chunk for every atom
+        let slot = CHUNKS.try_with(Chunks::take)
+            .unwrap_or_else(|_| Chunk::new(ptr::null(), State::Full).take().unwrap());
+        Ok(NonNull::slice_from_raw_parts(unsafe { NonNull::new_unchecked(slot) }, SLOT))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if !fits(&layout) {
+            return Global.deallocate(ptr, layout);
+        }
+        Chunk::of(ptr.as_ptr()).release(ptr.as_ptr())
+    }
+}
+
+// Called between top-level forms, returns the chunks emptied since to the system
+pub fn trim() {
+    let _ = CHUNKS.try_with(Chunks::trim);
+}
+
+// Chunks of this thread in use, the current one included
+pub fn chunks() -> usize {
+    CHUNKS.with(|chunks| chunks.current.get().iter().count() + chunks.listed.borrow().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use test::Bencher;
+    use data::{c_int, c_list};
+    use super::{chunks, trim, CHUNK, SLOT};
+
+    #[test]
+    fn test_slots_reused() {
+        let first = c_int(1);
+        let address = &*first as *const _ as usize;
+        drop(first);
+        assert_eq!(&*c_int(2) as *const _ as usize, address);
+    }
+
+    #[test]
+    fn test_escaping_atoms_keep_their_chunk() {
+        let start = chunks();
+        let per_chunk = CHUNK / SLOT;
+        let kept = (0..per_chunk * 3).map(|i| c_list(vec![c_int(i as i64)])).collect::<Vec<_>>();
+        let escaped = kept.iter().step_by(7).cloned().collect::<Vec<_>>();
+        drop(kept);
+        for (i, value) in escaped.iter().enumerate() {
+            assert_eq!(**value, *c_list(vec![c_int(i as i64 * 7)]));
+        }
+
+        // the slots freed around them are taken before new chunks
+        let before = chunks();
+        let filling = (0..per_chunk * 2).map(|i| c_int(i as i64)).collect::<Vec<_>>();
+        assert!(chunks() <= before + 1);
+        drop(filling);
+        drop(escaped);
+        trim();
+        assert!(chunks() <= start + 1);
+    }
+
+    #[bench]
+    fn bench_temporaries(b: &mut Bencher) {
+        b.iter(|| c_list((0..16).map(c_int).collect()));
+    }
+}
@@ -0,0 +1,190 @@
+// Timing forms for rulsp bench: the form is wrapped in a function, called a number of times to
+// warm up and then timed call by call. Measurements read and write as maps, so a run can be
+// saved and later ones compared against it.
+use std::time::Instant;
+use data::{AtomKey, AtomVal, AtomType, c_int, c_keyword, c_map};
+use fnv::FnvHashMap;
+use interpreter::Interpreter;
+use stats::{self, Stats};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub runs: u64,
+    // nanoseconds per run
+    pub mean: u64,
+    pub median: u64,
+    pub stddev: u64,
+    pub allocations: u64,
+}
+
+fn allocated(stats: &Stats) -> u64 {
+    stats.allocated.iter().map(|&(_, count)| count).sum()
+}
+
+fn nanos(start: Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+}
+
+pub fn run(interpreter: &Interpreter,
+           form: &str,
+           warmup: u64,
+           runs: u64)
+           -> Result<Measurement, String> {
+    if runs == 0 {
+        return Err("bench needs at least one run".to_string());
+    }
+    let thunk = interpreter.eval(&format!("(fn* () {})", form)).map_err(|err| err.to_string())?;
+    for _ in 0..warmup {
+        thunk.apply(&[]).map_err(|err| err.to_string())?;
+    }
+
+    let before = allocated(&stats::snapshot());
+    let mut times = Vec::with_capacity(runs as usize);
+    for _ in 0..runs {
+        let start = Instant::now();
+        thunk.apply(&[]).map_err(|err| err.to_string())?;
+        times.push(nanos(start));
+    }
+    let allocations = allocated(&stats::snapshot()) - before;
+    Ok(Measurement::of(&mut times, allocations))
+}
+
+impl Measurement {
+    fn of(times: &mut [u64], allocations: u64) -> Measurement {
+        times.sort();
+        let runs = times.len() as u64;
+        let mean = times.iter().sum::<u64>() / runs;
+        let middle = times.len() / 2;
+        let median = if times.len().is_multiple_of(2) {
+            (times[middle - 1] + times[middle]) / 2
+        } else {
+            times[middle]
+        };
+        let variance = times.iter()
+            .map(|&time| {
+                let diff = time as f64 - mean as f64;
+                diff * diff
+            })
+            .sum::<f64>() / runs as f64;
+
+        Measurement {
+            runs,
+            mean,
+            median,
+            stddev: variance.sqrt() as u64,
+            allocations: allocations / runs,
+        }
+    }
+
+    pub fn to_value(&self) -> AtomVal {
+        let mut map = FnvHashMap::default();
+        for &(name, value) in &[("runs", self.runs),
+                                ("mean", self.mean),
+                                ("median", self.median),
+                                ("stddev", self.stddev),
+                                ("allocations", self.allocations)] {
+            map.insert(AtomKey::new(c_keyword(name)).unwrap(), c_int(value as i64));
+        }
+        c_map(map)
+    }
+
+    pub fn from_value(value: &AtomVal) -> Result<Measurement, String> {
+        let field = |name: &str| -> Result<u64, String> {
+            let key = AtomKey::new(c_keyword(name)).unwrap();
+            match value.get_map().ok().and_then(|map| map.get(&key)).map(|value| &**value) {
+                Some(&AtomType::Int(num)) if num >= 0 => Ok(num as u64),
+                _ => Err(format!("a measurement needs :{} as a count", name)),
+            }
+        };
+        Ok(Measurement {
+            runs: field("runs")?,
+            mean: field("mean")?,
+            median: field("median")?,
+            stddev: field("stddev")?,
+            allocations: field("allocations")?,
+        })
+    }
+
+    pub fn report(&self) -> String {
+        format!("runs: {}\nmean: {}\nmedian: {}\nstddev: {}\nallocations: {} per run\n",
+                self.runs,
+                duration(self.mean),
+                duration(self.median),
+                duration(self.stddev),
+                self.allocations)
+    }
+
+    // How this run differs from baseline, changes within a standard deviation of either are
+    // called noise
+    pub fn compare(&self, baseline: &Measurement) -> String {
+        let change = |now: u64, then: u64| if then == 0 {
+            "n/a".to_string()
+        } else {
+            format!("{:+.1}%", (now as f64 - then as f64) * 100.0 / then as f64)
+        };
+        let noise = self.stddev.max(baseline.stddev);
+        let verdict = if self.mean + noise < baseline.mean {
+            "faster"
+        } else if self.mean > baseline.mean + noise {
+            "slower"
+        } else {
+            "within noise"
+        };
+        format!("mean: {} -> {} ({}, {})\nmedian: {} -> {} ({})\nallocations: {} -> {} ({})\n",
+                duration(baseline.mean),
+                duration(self.mean),
+                change(self.mean, baseline.mean),
+                verdict,
+                duration(baseline.median),
+                duration(self.median),
+                change(self.median, baseline.median),
+                baseline.allocations,
+                self.allocations,
+                change(self.allocations, baseline.allocations))
+    }
+}
+
+fn duration(nanos: u64) -> String {
+    match nanos {
+        n if n >= 1_000_000_000 => format!("{:.2}s", n as f64 / 1e9),
+        n if n >= 1_000_000 => format!("{:.2}ms", n as f64 / 1e6),
+        n if n >= 1_000 => format!("{:.2}µs", n as f64 / 1e3),
+        n => format!("{}ns", n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use interpreter::Interpreter;
+    use super::{Measurement, duration, run};
+
+    #[test]
+    fn test_measurement() {
+        let measurement = Measurement::of(&mut [40, 10, 30, 20], 8);
+        assert_eq!(measurement,
+                   Measurement {
+                       runs: 4,
+                       mean: 25,
+                       median: 25,
+                       stddev: 11,
+                       allocations: 2,
+                   });
+        assert_eq!(Measurement::from_value(&measurement.to_value()), Ok(measurement.clone()));
+
+        let slower = Measurement { mean: 100, ..measurement.clone() };
+        assert!(slower.compare(&measurement).contains("(+300.0%, slower)"));
+        assert!(measurement.compare(&measurement).contains("within noise"));
+        assert_eq!(duration(1500), "1.50µs");
+    }
+
+    #[test]
+    fn test_run() {
+        let interpreter = Interpreter::new();
+        let measurement = run(&interpreter, "(list 1 2)", 2, 5).unwrap();
+        assert_eq!(measurement.runs, 5);
+        assert!(measurement.allocations >= 1);
+        assert!(run(&interpreter, "(undefined)", 0, 1).is_err());
+        assert!(run(&interpreter, "1", 0, 0).is_err());
+    }
+}
@@ -0,0 +1,82 @@
+// Standalone executables: a copy of the rulsp binary with a script appended, followed by a
+// trailer saying how long it is. Started, the binary finds the trailer at its own end and runs
+// the script instead of looking at its arguments.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use parser::{ParseError, parse_cst};
+
+const MAGIC: &'static [u8] = b"rulsp-bundle";
+const TRAILER: usize = 8 + 12;
+
+// The script named name, bundled with runtime; refused if it doesn't parse
+pub fn bundle(runtime: &[u8], name: &str, source: &str) -> Result<Vec<u8>, ParseError> {
+    parse_cst(source)?;
+    let payload = format!("{}\n{}", name, source);
+    let mut out = runtime.to_vec();
+    out.extend(payload.as_bytes());
+    let len = payload.len() as u64;
+    out.extend((0..8).map(|i| (len >> (i * 8)) as u8));
+    out.extend(MAGIC);
+    Ok(out)
+}
+
+// The length of the payload before the trailer at the end of tail
+fn payload_len(tail: &[u8]) -> Option<u64> {
+    if tail.len() < TRAILER || !tail.ends_with(MAGIC) {
+        return None;
+    }
+    let len = &tail[tail.len() - TRAILER..tail.len() - MAGIC.len()];
+    Some(len.iter().rev().fold(0, |len, &byte| len << 8 | byte as u64))
+}
+
+fn split(payload: String) -> Option<(String, String)> {
+    let newline = payload.find('\n')?;
+    Some((payload[..newline].to_string(), payload[newline + 1..].to_string()))
+}
+
+// The name and source of the script bundled into the executable at path, if there is one
+pub fn embedded(path: &str) -> io::Result<Option<(String, String)>> {
+    let mut file = File::open(path)?;
+    let size = file.seek(SeekFrom::End(0))?;
+    if size < TRAILER as u64 {
+        return Ok(None);
+    }
+    let mut tail = [0; TRAILER];
+    file.seek(SeekFrom::End(-(TRAILER as i64)))?;
+    file.read_exact(&mut tail)?;
+    let len = match payload_len(&tail) {
+        Some(len) if len <= size - TRAILER as u64 => len,
+        _ => return Ok(None),
+    };
+
+    file.seek(SeekFrom::Start(size - TRAILER as u64 - len))?;
+    let mut payload = String::new();
+    file.take(len).read_to_string(&mut payload)?;
+    Ok(split(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::env;
+    use super::{bundle, embedded};
+
+    #[test]
+    fn test_bundle() {
+        let runtime = b"\x7fELF not really a binary";
+        let bundled = bundle(runtime, "hello.clrs", "(println \"hi\")\n").unwrap();
+        assert!(bundled.starts_with(runtime));
+        assert!(bundle(runtime, "bad.clrs", "(println").is_err());
+
+        let path = env::temp_dir().join("rulsp-test-bundle");
+        File::create(&path).unwrap().write_all(&bundled).unwrap();
+        let path = path.to_str().unwrap();
+        assert_eq!(embedded(path).unwrap(),
+                   Some(("hello.clrs".to_string(), "(println \"hi\")\n".to_string())));
+
+        File::create(path).unwrap().write_all(runtime).unwrap();
+        assert_eq!(embedded(path).unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use analysis::{self, Analysis, BindingKind};
+use data::{AtomType, c_symbol};
+use env::{Env, env_get, params_arity};
+use lexer::Position;
+use parser::{Span, parse_cst};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// Something wrong with a file found without running it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub start: Position,
+    pub end: Position,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", self.start, severity, self.message)
+    }
+}
+
+// Fewest and most args of the macro (or function) name is bound to in env
+fn env_arity(env: &Env, name: &str, macros: bool) -> Option<(usize, Option<usize>)> {
+    match *env_get(env, &c_symbol(name))? {
+        AtomType::AFunc(ref fd) if fd.is_macro == macros => {
+            fd.params.get_seq().ok().map(params_arity)
+        }
+        _ => None,
+    }
+}
+
+pub fn is_macro(env: &Env, name: &str) -> bool {
+    match env_get(env, &c_symbol(name)).as_deref() {
+        Some(&AtomType::AFunc(ref fd)) => fd.is_macro,
+        _ => false,
+    }
+}
+
+fn arity_message(what: &str,
+                 name: &str,
+                 arity: (usize, Option<usize>),
+                 given: usize)
+                 -> Option<String> {
+    let (min, max) = arity;
+    if given >= min && max.map_or(true, |max| given <= max) {
+        return None;
+    }
+    let takes = match max {
+        None => format!("at least {}", min),
+        Some(max) if max == min => format!("{}", min),
+        Some(max) => format!("{} to {}", min, max),
+    };
+    Some(format!("{} {} takes {} args, given {}", what, name, takes, given))
+}
+
+// Problems of an analysed file, names not bound in it are looked up in env
+pub fn problems(analysis: &Analysis, env: &Env) -> Vec<Problem> {
+    let mut problems = vec![];
+
+    let unresolved = analysis.unresolved(|name| env_get(env, &c_symbol(name)).is_some());
+    for reference in unresolved {
+        problems.push(Problem {
+            start: reference.span.start,
+            end: reference.span.end,
+            severity: Severity::Warning,
+            message: format!("unresolved symbol {}", reference.name),
+        });
+    }
+
+    problems.extend(arity_problems(analysis, env, true));
+    problems.sort_by_key(|problem| problem.start.offset);
+    problems
+}
+
+// Calls of macros (or functions) with the wrong number of args, for macros it's an error
+fn arity_problems(analysis: &Analysis, env: &Env, macros: bool) -> Vec<Problem> {
+    let mut problems = vec![];
+    for call in &analysis.calls {
+        let arity = match call.target {
+            Some(target) => {
+                analysis.definitions
+                    .iter()
+                    .find(|definition| definition.span == target && definition.is_macro == macros)
+                    .and_then(|definition| definition.arity)
+            }
+            None => env_arity(env, &call.name, macros),
+        };
+        let what = if macros { "macro" } else { "function" };
+        if let Some(message) = arity.and_then(|a| arity_message(what, &call.name, a, call.args)) {
+            problems.push(Problem {
+                start: call.span.start,
+                end: call.span.end,
+                severity: if macros { Severity::Error } else { Severity::Warning },
+                message: message,
+            });
+        }
+    }
+    problems
+}
+
+// Likely mistakes: unused let bindings, parameters hiding a local, match clauses that can't
+// match and function calls with the wrong number of args
+pub fn lints(analysis: &Analysis, env: &Env) -> Vec<Problem> {
+    let mut problems = vec![];
+    let warning = |span: Span, message: String| {
+        Problem {
+            start: span.start,
+            end: span.end,
+            severity: Severity::Warning,
+            message: message,
+        }
+    };
+
+    for binding in &analysis.bindings {
+        if binding.name.starts_with('_') {
+            continue;
+        }
+        let used = analysis.references
+            .iter()
+            .any(|reference| reference.target == Some(binding.span));
+        match (binding.kind, binding.shadows) {
+            (BindingKind::Let, _) if !used => {
+                problems.push(warning(binding.span, format!("unused let binding {}", binding.name)))
+            }
+            (BindingKind::Param, Some(shadowed)) => {
+                problems.push(warning(binding.span,
+                                      format!("parameter {} shadows the binding at {}",
+                                              binding.name,
+                                              shadowed.start)))
+            }
+            _ => {}
+        }
+    }
+    for span in &analysis.unreachable {
+        problems.push(warning(*span, "unreachable match clause".to_string()));
+    }
+    problems.extend(arity_problems(analysis, env, false));
+
+    problems.sort_by_key(|problem| problem.start.offset);
+    problems
+}
+
+// Syntax errors or the problems found by analysing source, with lint also likely mistakes
+pub fn check(source: &str, env: &Env, lint: bool) -> Vec<Problem> {
+    match parse_cst(source) {
+        Ok(nodes) => {
+            let analysis = analysis::analyze(&nodes, |name| is_macro(env, name));
+            let mut problems = problems(&analysis, env);
+            if lint {
+                problems.extend(lints(&analysis, env));
+                problems.sort_by_key(|problem| problem.start.offset);
+            }
+            problems
+        }
+        Err(err) => {
+            let end = Position {
+                line: source.split('\n').count(),
+                column: source.rsplit('\n').next().map_or(0, |line| line.chars().count()) + 1,
+                offset: source.len(),
+            };
+            let start = err.position().unwrap_or(end);
+            vec![Problem {
+                     start: start,
+                     end: start,
+                     severity: Severity::Error,
+                     message: err.to_string(),
+                 }]
+        }
+    }
+}
+
+pub fn check_file(path: &str, env: &Env, lint: bool) -> Result<Vec<Problem>, String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    Ok(check(&source, env, lint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+    use core;
+
+    fn messages(source: &str) -> Vec<String> {
+        check(source, &core::build(), false).iter().map(|problem| problem.to_string()).collect()
+    }
+
+    fn lints(source: &str) -> Vec<String> {
+        check(source, &core::build(), true).iter().map(|problem| problem.to_string()).collect()
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(messages("(defn f (x) (+ x 1))\n(f 2)"), Vec::<String>::new());
+        assert_eq!(messages("(let [a 1]\n  (frob a b))"),
+                   ["2:4: warning: unresolved symbol frob", "2:11: warning: unresolved symbol b"]);
+        assert_eq!(messages("(defmacro unless (fn* (c body) c))\n(unless 1)\n(unless 1 2 3)"),
+                   ["2:1: error: macro unless takes 2 args, given 1",
+                    "3:1: error: macro unless takes 2 args, given 3"]);
+        assert_eq!(messages("(backquote)"),
+                   ["1:1: error: macro backquote takes 1 args, given 0"]);
+        assert_eq!(messages("(def x (+ 1 2)"), ["1:1: error: Unclosed `(` opened at 1:1"]);
+    }
+
+    #[test]
+    fn test_lint() {
+        assert_eq!(lints("(let [a 1 b 2 _c 3] b)"), ["1:7: warning: unused let binding a"]);
+        assert_eq!(lints("(fn* (x) (fn* (y x) (+ x y)))"),
+                   ["1:18: warning: parameter x shadows the binding at 1:7"]);
+        assert_eq!(lints("(match 1 2 :two n n x :x)"),
+                   ["1:21: warning: unreachable match clause"]);
+        assert_eq!(lints("(match 1 n :when (= n 2) n x :x)"), Vec::<String>::new());
+        assert_eq!(lints("(defn f (a b) a)\n(f 1)\n(second '(1 2) 3)"),
+                   ["2:1: warning: function f takes 2 args, given 1",
+                    "3:1: warning: function second takes 1 args, given 2"]);
+        assert_eq!(messages("(let [a 1] 2)"), Vec::<String>::new());
+    }
+}
@@ -1,16 +1,64 @@
+#[cfg(feature = "io")]
 use std::fs::File;
+#[cfg(feature = "io")]
 use std::io::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use std::collections::BTreeSet;
+#[cfg(feature = "io")]
+use std::time::{SystemTime, UNIX_EPOCH};
+use fnv::FnvHashMap;
+use log::LogLevel;
+use regex::{Captures, Regex};
 
-use env::{c_env, env_set, Env};
-use data::{AtomVal, AtomType, AtomRet, c_int, c_nil, c_list, c_symbol, c_func};
+use env::{c_env, env_set, params_arity, Env, EnvType};
+#[cfg(feature = "std")]
+use env::env_get;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomKey, c_bool, c_int, c_nil, c_string, c_list,
+           c_vector, c_map, c_symbol, c_keyword, c_doc_func, c_closure, c_generator, c_weak_ref,
+           c_regex, c_list_from_iter, ListBuilder, c_sorted_map, c_sorted_set, c_queue,
+           c_priority_queue, c_env_value, c_persistent_vector,
+           c_shared_string};
+#[cfg(feature = "prelude")]
 use eval::eval_str;
+#[cfg(feature = "prelude")]
+use embed::{self, Datum};
+use lexer::Lexer;
+use parser::Parser;
+use dump;
+#[cfg(feature = "std")]
+use data::c_handle;
+#[cfg(feature = "std")]
+use io::Handle;
+use output;
+use gc;
+use stats;
+use meta;
+use protocol;
+use queue;
+use printf;
+use term;
+use opts;
+#[cfg(feature = "io")]
+use path;
+use priority;
+use transient;
+use sorted::{self, SortKey};
+use seq;
+use testing;
+use debug;
+#[cfg(feature = "plugins")]
+use plugin;
+#[cfg(feature = "watch")]
+use watch;
+use diagnostics;
 
-fn safe_get(args: &[AtomVal], index: usize) -> AtomVal {
+pub(crate) fn safe_get(args: &[AtomVal], index: usize) -> AtomVal {
     args.get(index).cloned().unwrap_or_else(c_nil)
 }
 
 fn int_fold_op<F>(f: F, empty: i64, args: &[AtomVal]) -> AtomRet
-    where F: Fn(i64, i64) -> i64
+    where F: Fn(i64, i64) -> Result<i64, AtomError>
 {
     let mut it = args.iter();
     match it.next() {
@@ -19,33 +67,48 @@ fn int_fold_op<F>(f: F, empty: i64, args: &[AtomVal]) -> AtomRet
             let mut acc = acc.get_int()?;
             for arg in it {
                 let arg = arg.get_int()?;
-                acc = f(acc, arg);
+                acc = f(acc, arg)?;
             }
             Result::Ok(c_int(acc))
         }
     }
 }
 
+fn overflow(op: &str, acc: i64, v: i64) -> AtomError {
+    AtomError::InvalidOperation(format!("({} {} {}) overflows", op, acc, v))
+}
+
 fn add(args: &[AtomVal]) -> AtomRet {
-    int_fold_op(|acc, v| acc + v, 0, args)
+    int_fold_op(|acc, v| acc.checked_add(v).ok_or_else(|| overflow("+", acc, v)), 0, args)
 }
 
 fn sub(args: &[AtomVal]) -> AtomRet {
-    int_fold_op(|acc, v| acc - v, 0, args)
+    int_fold_op(|acc, v| acc.checked_sub(v).ok_or_else(|| overflow("-", acc, v)), 0, args)
 }
 
 fn mul(args: &[AtomVal]) -> AtomRet {
-    int_fold_op(|acc, v| acc * v, 1, args)
+    int_fold_op(|acc, v| acc.checked_mul(v).ok_or_else(|| overflow("*", acc, v)), 1, args)
 }
 
 fn div(args: &[AtomVal]) -> AtomRet {
-    int_fold_op(|acc, v| acc / v, 1, args)
+    int_fold_op(|acc, v| {
+                    if v == 0 {
+                        let message = format!("division by zero in (/ {} 0)", acc);
+                        return Err(AtomError::InvalidArgument(message));
+                    }
+                    acc.checked_div(v).ok_or_else(|| overflow("/", acc, v))
+                },
+                1,
+                args)
 }
 
 fn cons(args: &[AtomVal]) -> AtomRet {
-    let mut list = vec![safe_get(args, 0)];
-    list.extend(safe_get(args, 1).get_list()?.iter().cloned());
-    Ok(c_list(list))
+    let coll = safe_get(args, 1);
+    let tail = coll.get_list()?;
+    let mut list = ListBuilder::with_capacity(tail.len() + 1);
+    list.push(safe_get(args, 0));
+    list.extend(tail.iter().cloned());
+    Ok(list.finish())
 }
 
 fn list(args: &[AtomVal]) -> AtomRet {
@@ -54,118 +117,1720 @@ fn list(args: &[AtomVal]) -> AtomRet {
 
 fn is_list(args: &[AtomVal]) -> AtomRet {
     match *safe_get(args, 0) {
-        AtomType::List(_) => Ok(c_int(1)),
-        _ => Ok(c_nil()),
+        AtomType::List(..) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
     }
 }
 
 fn is_nil(args: &[AtomVal]) -> AtomRet {
     match *safe_get(args, 0) {
-        AtomType::Nil => Ok(c_int(1)),
-        _ => Ok(c_nil()),
+        AtomType::Nil => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+fn is_int(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Int(_) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
     }
 }
 
+fn is_string(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Str(_) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+fn is_symbol(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Symbol(..) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+fn is_keyword(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Keyword(_) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+fn is_vector(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Vector(_) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+// Sorted maps are maps too
+fn is_map(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Map(_) |
+        AtomType::SortedMap(_) => Ok(c_bool(true)),
+        _ => Ok(c_bool(false)),
+    }
+}
 
+// Builtins and functions, but not macros, as type-of tells them apart
+fn is_fn(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Func(_) => Ok(c_bool(true)),
+        AtomType::AFunc(ref fd) => Ok(c_bool(!fd.is_macro)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+
+// Keeps the first of equal elements, which have to be hashable
+fn distinct(args: &[AtomVal]) -> AtomRet {
+    let mut seen = FnvHashMap::default();
+    let mut output = vec![];
+    for value in safe_get(args, 0).get_seq()? {
+        if seen.insert(AtomKey::new(value.clone())?, ()).is_none() {
+            output.push(value.clone());
+        }
+    }
+
+    Ok(c_list(output))
+}
+
+// Strings count characters, not bytes
 fn count(args: &[AtomVal]) -> AtomRet {
-    Ok(c_int(safe_get(args, 0).get_list()?.len() as i64))
+    let coll = safe_get(args, 0);
+    match *coll {
+        AtomType::Str(ref s) => Ok(c_int(s.chars().count() as i64)),
+        AtomType::List(ref seq, _) => Ok(c_int(seq.len() as i64)),
+        AtomType::Vector(ref seq) => Ok(c_int(seq.len() as i64)),
+        AtomType::Map(ref map) => Ok(c_int(map.len() as i64)),
+        AtomType::Queue(ref queue) => Ok(c_int(queue.len() as i64)),
+        AtomType::SortedMap(ref map) => Ok(c_int(map.len() as i64)),
+        AtomType::SortedSet(ref set) => Ok(c_int(set.len() as i64)),
+        AtomType::PriorityQueue(ref queue) => Ok(c_int(queue.len() as i64)),
+        AtomType::Transient(ref transient) => Ok(c_int(transient.len() as i64)),
+        _ => Ok(c_int(seq::elements(&coll)?.len() as i64)),
+    }
+}
+
+
+// Position n in a sequence len long, negative n counting back from the end
+fn position(n: i64, len: usize) -> Option<usize> {
+    let position = if n < 0 { len as i64 + n } else { n };
+    if position >= 0 && position < len as i64 {
+        Some(position as usize)
+    } else {
+        None
+    }
 }
 
+// The element at n, default if n is out of range
+fn element(coll: &AtomVal, n: i64, default: Option<&AtomVal>) -> AtomRet {
+    let found = match **coll {
+        AtomType::Str(ref s) => {
+            position(n, s.chars().count())
+                .and_then(|n| s.chars().nth(n))
+                .map(|c| c_string(&c.to_string()))
+        }
+        AtomType::List(ref seq, _) => position(n, seq.len()).map(|n| seq[n].clone()),
+        AtomType::Vector(ref seq) => position(n, seq.len()).and_then(|n| seq.get(n).cloned()),
+        _ => {
+            let seq = seq::elements(coll)?;
+            position(n, seq.len()).map(|n| seq[n].clone())
+        }
+    };
+    Ok(found.unwrap_or_else(|| default.cloned().unwrap_or_else(c_nil)))
+}
 
 fn nth(args: &[AtomVal]) -> AtomRet {
     trace!("action=nth args={:?}", args);
-    let n = safe_get(args, 1).get_int().unwrap_or(0);
+    let n = safe_get(args, 1).get_int()?;
+    element(&safe_get(args, 0), n, args.get(2))
+}
+
+// (get coll key default?) looks key up in maps, sets and records and takes the element at key
+// from sequences, default if there's none
+fn get(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    let key = safe_get(args, 1);
+    let default = || Ok(args.get(2).cloned().unwrap_or_else(c_nil));
+    match *coll {
+        AtomType::Nil => default(),
+        AtomType::Map(_) |
+        AtomType::SortedMap(_) |
+        AtomType::SortedSet(_) => coll.apply(&args[1..]),
+        AtomType::Record(_) => {
+            match *key {
+                AtomType::Keyword(_) => key.apply(&[coll.clone(), default()?]),
+                _ => default(),
+            }
+        }
+        _ => {
+            match *key {
+                AtomType::Int(n) => element(&coll, n, args.get(2)),
+                _ => default(),
+            }
+        }
+    }
+}
 
-    Ok(safe_get(safe_get(args, 0).get_list()?, n as usize))
+// A [key value] vector or list, how map entries are conj'ed
+fn entry(value: &AtomVal) -> Result<(AtomVal, AtomVal), AtomError> {
+    match value.get_seq() {
+        Ok(pair) if pair.len() == 2 => Ok((pair[0].clone(), pair[1].clone())),
+        _ => Err(AtomError::InvalidType("[key value]".to_string(), value.format(true))),
+    }
 }
 
+// Each collection where adding is cheapest: lists at the front, vectors and queues at the back,
+// copied once for all of xs
+fn conj_all(coll: &AtomVal, xs: Vec<AtomVal>) -> AtomRet {
+    match **coll {
+        AtomType::Nil => Ok(c_list_from_iter(xs.into_iter().rev())),
+        AtomType::List(ref seq, _) => {
+            let mut list = ListBuilder::with_capacity(xs.len() + seq.len());
+            list.extend(xs.into_iter().rev());
+            list.extend(seq.iter().cloned());
+            Ok(list.finish())
+        }
+        AtomType::Vector(ref seq) => {
+            Ok(c_persistent_vector(xs.into_iter().fold(seq.clone(), |seq, x| seq.push(x))))
+        }
+        AtomType::Map(ref map) => {
+            let mut map = map.clone();
+            for x in &xs {
+                let (key, value) = entry(x)?;
+                map.insert(AtomKey::new(key)?, value);
+            }
+            Ok(c_map(map))
+        }
+        AtomType::SortedMap(ref map) => {
+            let mut map = map.clone();
+            for x in &xs {
+                let (key, value) = entry(x)?;
+                map.insert(SortKey::new(key)?, value);
+            }
+            Ok(c_sorted_map(map))
+        }
+        AtomType::SortedSet(ref set) => {
+            let mut set = set.clone();
+            for x in xs {
+                set.insert(SortKey::new(x)?);
+            }
+            Ok(c_sorted_set(set))
+        }
+        AtomType::Queue(ref queue) => {
+            Ok(c_queue(xs.into_iter().fold(queue.clone(), |queue, x| queue.push_back(x))))
+        }
+        AtomType::PriorityQueue(ref queue) => {
+            let mut queue = queue.clone();
+            for x in xs {
+                queue = queue.push(x.clone(), x)?;
+            }
+            Ok(c_priority_queue(queue))
+        }
+        _ => Err(AtomError::InvalidType("collection".to_string(), coll.format(true))),
+    }
+}
 
-fn rest(args: &[AtomVal]) -> AtomRet {
-    match safe_get(args, 0).get_list() {
-        Ok(seq) => {
-            if seq.len() > 0 {
-                Ok(c_list(seq[1..seq.len()].iter().cloned().collect()))
+fn conj(args: &[AtomVal]) -> AtomRet {
+    conj_all(&safe_get(args, 0), args.iter().skip(1).cloned().collect())
+}
+
+fn into(args: &[AtomVal]) -> AtomRet {
+    conj_all(&safe_get(args, 0), seq::elements(&safe_get(args, 1))?)
+}
+
+// Vectors take indices up to their length, one past the end appends
+fn assoc_one(coll: &AtomVal, key: AtomVal, value: AtomVal) -> AtomRet {
+    match **coll {
+        AtomType::Nil => {
+            let mut map = FnvHashMap::default();
+            map.insert(AtomKey::new(key)?, value);
+            Ok(c_map(map))
+        }
+        AtomType::Map(ref map) => {
+            let mut map = map.clone();
+            map.insert(AtomKey::new(key)?, value);
+            Ok(c_map(map))
+        }
+        AtomType::SortedMap(ref map) => {
+            let mut map = map.clone();
+            map.insert(SortKey::new(key)?, value);
+            Ok(c_sorted_map(map))
+        }
+        AtomType::Vector(ref seq) => {
+            let index = key.get_int()?;
+            if index < 0 || index as usize > seq.len() {
+                let message = format!("index {} out of range for a vector of {}", index, seq.len());
+                return Err(AtomError::InvalidArgument(message));
+            }
+            let seq = if index as usize == seq.len() {
+                seq.push(value)
             } else {
-                Ok(c_list(vec![]))
+                seq.set(index as usize, value).unwrap()
+            };
+            Ok(c_persistent_vector(seq))
+        }
+        _ => Err(AtomError::InvalidType("Map or Vector".to_string(), coll.format(true))),
+    }
+}
+
+fn assoc(args: &[AtomVal]) -> AtomRet {
+    if args.len() % 2 != 1 {
+        return Err(AtomError::InvalidArgument("assoc needs keys and values in pairs"
+            .to_string()));
+    }
+    let mut coll = safe_get(args, 0);
+    for pair in args[1..].chunks(2) {
+        coll = assoc_one(&coll, pair[0].clone(), pair[1].clone())?;
+    }
+    Ok(coll)
+}
+
+// (update coll k f & args) sets k to (f old & args), old being nil where k isn't set
+fn update(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    let key = safe_get(args, 1);
+    let old = match *coll {
+        AtomType::Vector(ref seq) => {
+            let index = key.get_int()?;
+            if index < 0 { c_nil() } else { seq.get(index as usize).cloned().unwrap_or_else(c_nil) }
+        }
+        AtomType::Nil => c_nil(),
+        _ => coll.apply(std::slice::from_ref(&key))?,
+    };
+    let mut f_args = vec![old];
+    f_args.extend(args.iter().skip(3).cloned());
+    let value = safe_get(args, 2).apply(&f_args)?;
+    assoc_one(&coll, key, value)
+}
+
+// start and end of (f coll start end?) within len, end defaulting to len
+fn bounds(name: &str, args: &[AtomVal], len: usize) -> Result<(usize, usize), AtomError> {
+    let start = safe_get(args, 1).get_int()?;
+    let end = match *safe_get(args, 2) {
+        AtomType::Nil => len as i64,
+        ref end => end.get_int()?,
+    };
+    if start < 0 || start > end || end > len as i64 {
+        return Err(AtomError::InvalidArgument(format!("{} range {} to {} out of 0 to {}",
+                                                      name,
+                                                      start,
+                                                      end,
+                                                      len)));
+    }
+    Ok((start as usize, end as usize))
+}
+
+// Vectors and strings share storage with what they're taken from, lists are copied
+fn subvec(args: &[AtomVal]) -> AtomRet {
+    let seq = safe_get(args, 0);
+    match *seq {
+        AtomType::Vector(ref vector) => {
+            let (start, end) = bounds("subvec", args, vector.len())?;
+            Ok(c_persistent_vector(vector.slice(start, end).unwrap()))
+        }
+        _ => {
+            let seq = seq.get_seq()?;
+            let (start, end) = bounds("subvec", args, seq.len())?;
+            Ok(c_vector(seq[start..end].to_vec()))
+        }
+    }
+}
+
+// Counts characters like count
+fn subs(args: &[AtomVal]) -> AtomRet {
+    let s = safe_get(args, 0);
+    let shared = match *s {
+        AtomType::Str(ref shared) => shared,
+        ref other => return Err(AtomError::InvalidType("String".to_string(), other.format(true))),
+    };
+    let (start, end) = bounds("subs", args, shared.chars().count())?;
+    let offset = |n| shared.char_indices().nth(n).map_or(shared.len(), |(offset, _)| offset);
+    Ok(c_shared_string(shared.slice(offset(start), offset(end)).unwrap()))
+}
+
+fn last(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    match *coll {
+        AtomType::Str(ref s) => {
+            Ok(s.chars().last().map(|c| c_string(&c.to_string())).unwrap_or_else(c_nil))
+        }
+        AtomType::SortedMap(ref map) => {
+            Ok(map.iter()
+                .next_back()
+                .map(|(k, v)| c_vector(vec![k.value().clone(), v.clone()]))
+                .unwrap_or_else(c_nil))
+        }
+        AtomType::SortedSet(ref set) => {
+            Ok(set.iter().next_back().map(|k| k.value().clone()).unwrap_or_else(c_nil))
+        }
+        AtomType::Vector(ref seq) => Ok(seq.last().cloned().unwrap_or_else(c_nil)),
+        _ => Ok(coll.get_seq()?.last().cloned().unwrap_or_else(c_nil)),
+    }
+}
+
+
+// A list of all but the first element of any sequence, nil for nil and other values
+fn rest(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    match *coll {
+        AtomType::Nil => Ok(c_nil()),
+        AtomType::List(ref seq, _) => Ok(c_list_from_iter(seq.iter().skip(1).cloned())),
+        AtomType::Vector(ref seq) => Ok(c_list_from_iter(seq.iter().skip(1).cloned())),
+        _ => {
+            match seq::elements(&coll) {
+                Ok(elements) => Ok(c_list_from_iter(elements.into_iter().skip(1))),
+                Err(_) => Ok(c_nil()),
             }
         }
-        _ => Ok(c_nil()),
     }
 }
 
+// (seq coll) is a list of the elements of coll, nil if there are none
+fn seq(args: &[AtomVal]) -> AtomRet {
+    let elements = seq::elements(&safe_get(args, 0))?;
+    if elements.is_empty() {
+        return Ok(c_nil());
+    }
+    Ok(c_list(elements))
+}
+
+fn reverse(args: &[AtomVal]) -> AtomRet {
+    Ok(c_list_from_iter(seq::elements(&safe_get(args, 0))?.into_iter().rev()))
+}
+
+// Characters are strings of one
+fn chars(args: &[AtomVal]) -> AtomRet {
+    let s = safe_get(args, 0);
+    Ok(c_list_from_iter(get_string(&s)?.chars().map(|c| c_string(&c.to_string()))))
+}
+
+fn string(args: &[AtomVal]) -> AtomRet {
+    let mut output = String::new();
+    for value in seq::elements(&safe_get(args, 0))? {
+        output.push_str(get_string(&value)?);
+    }
+    Ok(c_string(&output))
+}
+
+// Characters are strings of one character
+fn get_char(atom: &AtomVal) -> Result<char, AtomError> {
+    let s = get_string(atom)?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(AtomError::InvalidType("character".to_string(), atom.format(true))),
+    }
+}
+
+fn char_to_int(args: &[AtomVal]) -> AtomRet {
+    Ok(c_int(get_char(&safe_get(args, 0))? as i64))
+}
+
+fn int_to_char(args: &[AtomVal]) -> AtomRet {
+    let code = safe_get(args, 0).get_int()?;
+    let c = if code < 0 { None } else { ::std::char::from_u32(code as u32) };
+    match c {
+        Some(c) if code <= u32::MAX as i64 => Ok(c_string(&c.to_string())),
+        _ => Err(AtomError::InvalidArgument(format!("{} is not a character code", code))),
+    }
+}
+
+fn upper_case(args: &[AtomVal]) -> AtomRet {
+    Ok(c_string(&get_string(&safe_get(args, 0))?.to_uppercase()))
+}
+
+fn lower_case(args: &[AtomVal]) -> AtomRet {
+    Ok(c_string(&get_string(&safe_get(args, 0))?.to_lowercase()))
+}
+
+fn is_digit(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(get_char(&safe_get(args, 0))?.is_ascii_digit()))
+}
+
+fn is_whitespace(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(get_char(&safe_get(args, 0))?.is_whitespace()))
+}
+
+fn is_alpha(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(get_char(&safe_get(args, 0))?.is_alphabetic()))
+}
+
+fn vec(args: &[AtomVal]) -> AtomRet {
+    Ok(c_vector(seq::elements(&safe_get(args, 0))?))
+}
+
+// (list* x y coll) is x and y in front of the elements of coll
+fn list_star(args: &[AtomVal]) -> AtomRet {
+    match args.split_last() {
+        Some((coll, xs)) => {
+            let mut list = xs.to_vec();
+            list.extend(seq::elements(coll)?);
+            Ok(c_list(list))
+        }
+        None => Ok(c_nil()),
+    }
+}
+
+// The only sets are sorted ones
+fn set(args: &[AtomVal]) -> AtomRet {
+    let mut set = BTreeSet::new();
+    for value in seq::elements(&safe_get(args, 0))? {
+        set.insert(SortKey::new(value)?);
+    }
+    Ok(c_sorted_set(set))
+}
+
+fn hash_map(args: &[AtomVal]) -> AtomRet {
+    if !args.len().is_multiple_of(2) {
+        return Err(AtomError::InvalidArgument("hash-map needs keys and values in pairs"
+            .to_string()));
+    }
+    let mut map = FnvHashMap::default();
+    for pair in args.chunks(2) {
+        map.insert(AtomKey::new(pair[0].clone())?, pair[1].clone());
+    }
+    Ok(c_map(map))
+}
+
+// Stops at the shorter of keys and vals
+fn zipmap(args: &[AtomVal]) -> AtomRet {
+    let keys = seq::elements(&safe_get(args, 0))?;
+    let vals = seq::elements(&safe_get(args, 1))?;
+    let mut map = FnvHashMap::default();
+    for (key, val) in keys.into_iter().zip(vals) {
+        map.insert(AtomKey::new(key)?, val);
+    }
+    Ok(c_map(map))
+}
+
+fn is_seqable(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(seq::is_seqable(&safe_get(args, 0))))
+}
+
 fn partialeq(args: &[AtomVal]) -> AtomRet {
-    let mut output = c_int(1);
-    for (i, arg) in args.iter().enumerate() {
-        let next_arg = args.get(i + 1);
-        if next_arg.is_some() {
-            if next_arg.unwrap() != arg {
-                output = c_nil();
-            };
+    Ok(c_bool(args.windows(2).all(|pair| pair[0] == pair[1])))
+}
+
+fn not_eq(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(!args.windows(2).all(|pair| pair[0] == pair[1])))
+}
+
+fn is_eq(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(args.windows(2).all(|pair| Rc::ptr_eq(&pair[0], &pair[1]))))
+}
+
+fn not(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(!safe_get(args, 0).is_truthy()))
+}
+
+
+fn identity(args: &[AtomVal]) -> AtomRet {
+    Ok(safe_get(args, 0))
+}
+
+fn constantly(args: &[AtomVal]) -> AtomRet {
+    let value = safe_get(args, 0);
+    Ok(c_closure(move |_| Ok(value.clone())))
+}
+
+fn partial(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    let bound = args.iter().skip(1).cloned().collect::<Vec<_>>();
+
+    Ok(c_closure(move |args| {
+        let mut full_args = bound.clone();
+        full_args.extend(args.iter().cloned());
+        func.apply(&full_args)
+    }))
+}
+
+// (comp f g h) => (fn* (& xs) (f (g (apply h xs))))
+fn comp(args: &[AtomVal]) -> AtomRet {
+    let funcs = args.to_vec();
+
+    Ok(c_closure(move |args| {
+        let mut it = funcs.iter().rev();
+        match it.next() {
+            None => Ok(safe_get(args, 0)),
+            Some(func) => {
+                let mut result = func.apply(args)?;
+                for func in it {
+                    result = func.apply(&[result])?;
+                }
+                Ok(result)
+            }
         }
+    }))
+}
+
+// (juxt f g) => (fn* (& xs) [(apply f xs) (apply g xs)])
+fn juxt(args: &[AtomVal]) -> AtomRet {
+    let funcs = args.to_vec();
+
+    Ok(c_closure(move |args| {
+        let mut results = Vec::with_capacity(funcs.len());
+        for func in funcs.iter() {
+            results.push(func.apply(args)?);
+        }
+        Ok(c_vector(results))
+    }))
+}
+
+// (trampoline f & args) calls f with args, then what it returns without arguments for as long
+// as that's a function, so functions returning each other (see bounce) run in constant space
+fn trampoline(args: &[AtomVal]) -> AtomRet {
+    let mut result = safe_get(args, 0).apply(args.get(1..).unwrap_or(&[]))?;
+    loop {
+        match *result {
+            AtomType::Func(_) => {}
+            AtomType::AFunc(ref fd) if !fd.is_macro => {}
+            _ => break,
+        }
+        result = result.apply(&[])?;
     }
+    Ok(result)
+}
+
+// Calls with unhashable arguments bypass the cache
+fn memoize(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    let cache: RefCell<FnvHashMap<Vec<AtomKey>, AtomVal>> = RefCell::new(FnvHashMap::default());
 
-    Ok(output)
+    Ok(c_closure(move |args| {
+        let key = args.iter().cloned().map(AtomKey::new).collect::<Result<Vec<_>, _>>();
+        match key {
+            Ok(key) => {
+                if let Some(value) = cache.borrow().get(&key) {
+                    return Ok(value.clone());
+                }
+
+                let value = func.apply(args)?;
+                cache.borrow_mut().insert(key, value.clone());
+                Ok(value)
+            }
+            Err(_) => func.apply(args),
+        }
+    }))
 }
 
 
 fn format_args(args: &[AtomVal], format: bool) -> String {
     args.iter()
-        .map(|ref v| v.format(format))
+        .map(|v| v.format(format))
         .collect::<Vec<_>>()
         .join(" ")
 }
 
-fn println(args: &[AtomVal]) -> AtomRet {
-    println!("{}", format_args(&args, false));
-    Ok(safe_get(args, 0))
+// The value of a stream variable like *out* in env, where binding can rebind it. Builtins only
+// hold on to the env they're defined in weakly, once it's gone there's no stream
+#[cfg(feature = "std")]
+fn stream(env: &Weak<RefCell<EnvType>>, name: &str) -> Option<AtomVal> {
+    env.upgrade().and_then(|env| env_get(&env, &c_symbol(name)))
 }
 
-fn print(args: &[AtomVal]) -> AtomRet {
-    print!("{}", format_args(&args, false));
-    Ok(safe_get(args, 0))
+// print and friends write to *out*, stdout without it
+#[cfg(feature = "std")]
+fn write_out(env: &Weak<RefCell<EnvType>>, text: &str) -> Result<(), AtomError> {
+    match stream(env, "*out*") {
+        Some(out) => get_handle(&out)?.write(text),
+        None => {
+            output::write_out(text);
+            Ok(())
+        }
+    }
 }
 
-fn _println(args: &[AtomVal]) -> AtomRet {
-    println!("{}", format_args(&args, true));
-    Ok(safe_get(args, 0))
+// there are no handles without std
+#[cfg(not(feature = "std"))]
+fn write_out(_env: &Weak<RefCell<EnvType>>, text: &str) -> Result<(), AtomError> {
+    output::write_out(text);
+    Ok(())
 }
 
-fn _print(args: &[AtomVal]) -> AtomRet {
-    print!("{}", format_args(&args, true));
-    Ok(safe_get(args, 0))
+fn set_printer(env: &Env, name: &str, format: bool, newline: bool, doc: &'static str) {
+    let weak = Rc::downgrade(env);
+    env_set(env,
+            &c_symbol(name),
+            c_doc_func(move |args| {
+                let mut text = format_args(args, format);
+                if newline {
+                    text.push('\n');
+                }
+                write_out(&weak, &text)?;
+                Ok(safe_get(args, 0))
+            },
+                       doc));
 }
 
+// Script logs go through the log facade with target "script", so whatever logger the
+// embedder set up receives them
+fn script_log(level: LogLevel, args: &[AtomVal]) -> AtomRet {
+    if log_enabled!(target: "script", level) {
+        log!(target: "script", level, "{}", format_args(args, false));
+    }
+    Ok(c_nil())
+}
+
+fn log_debug(args: &[AtomVal]) -> AtomRet {
+    script_log(LogLevel::Debug, args)
+}
+
+fn log_info(args: &[AtomVal]) -> AtomRet {
+    script_log(LogLevel::Info, args)
+}
+
+fn log_warn(args: &[AtomVal]) -> AtomRet {
+    script_log(LogLevel::Warn, args)
+}
+
+fn log_error(args: &[AtomVal]) -> AtomRet {
+    script_log(LogLevel::Error, args)
+}
+
+
+// The docstring is the :doc metadata, which defaults to the one given to defn
+fn doc(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    let indent = match *func {
+        AtomType::Func(_) => "",
+        AtomType::AFunc(ref fd) => {
+            output::write_out(&format!("{}\n", fd.params));
+            "  "
+        }
+        _ => return Err(AtomError::InvalidType("function".to_string(), func.format(true))),
+    };
+
+    let doc = c_keyword("doc").apply(&[meta::meta(&func)])?;
+    if let AtomType::Str(ref doc) = *doc {
+        output::write_out(&format!("{}{}\n", indent, doc));
+    }
+    Ok(doc)
+}
+
+
+fn fn_name(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    match *func {
+        AtomType::AFunc(ref fd) => {
+            Ok(fd.name.as_ref().map(|name| c_symbol(name)).unwrap_or_else(c_nil))
+        }
+        AtomType::Func(_) => Ok(c_nil()),
+        _ => Err(AtomError::InvalidType("function".to_string(), func.format(true))),
+    }
+}
+
+fn fn_params(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    match *func {
+        AtomType::AFunc(ref fd) => Ok(fd.params.clone()),
+        AtomType::Func(_) => Ok(c_nil()),
+        _ => Err(AtomError::InvalidType("function".to_string(), func.format(true))),
+    }
+}
+
+// [min max], max is nil for variadic functions
+fn fn_arity(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    match *func {
+        AtomType::AFunc(ref fd) => {
+            let (min, max) = params_arity(fd.params.get_seq()?);
+            let max = max.map(|max| c_int(max as i64)).unwrap_or_else(c_nil);
+            Ok(c_vector(vec![c_int(min as i64), max]))
+        }
+        AtomType::Func(_) => Ok(c_nil()),
+        _ => Err(AtomError::InvalidType("function".to_string(), func.format(true))),
+    }
+}
+
+fn is_macro(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::AFunc(ref fd) => Ok(c_bool(fd.is_macro)),
+        _ => Ok(c_bool(false)),
+    }
+}
+
+
+fn generator(args: &[AtomVal]) -> AtomRet {
+    let func = safe_get(args, 0);
+    match *func {
+        AtomType::AFunc(_) | AtomType::Func(_) => {
+            Ok(c_generator(func.clone(), args.iter().skip(1).cloned().collect()))
+        }
+        _ => Err(AtomError::InvalidType("function".to_string(), func.format(true))),
+    }
+}
+
+fn next(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Generator(ref gen) => gen.resume(safe_get(args, 1)),
+        ref other => Err(AtomError::InvalidType("generator".to_string(), other.format(true))),
+    }
+}
+
+fn is_done(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Generator(ref gen) => Ok(c_bool(gen.is_done())),
+        ref other => Err(AtomError::InvalidType("generator".to_string(), other.format(true))),
+    }
+}
+
+
+// Strings are wrapped as {:type :error :message s}
+fn error(args: &[AtomVal]) -> AtomRet {
+    let condition = safe_get(args, 0);
+    match *condition {
+        AtomType::Str(_) => {
+            let mut map = FnvHashMap::default();
+            map.insert(AtomKey::new(c_keyword("type"))?, c_keyword("error"));
+            map.insert(AtomKey::new(c_keyword("message"))?, condition.clone());
+            Err(AtomError::Condition(c_map(map)))
+        }
+        _ => Err(AtomError::Condition(condition.clone())),
+    }
+}
+
+// (ex-info msg data) is the condition {:type :ex-info :message msg :data data}, to be thrown
+// with error
+fn ex_info(args: &[AtomVal]) -> AtomRet {
+    let message = safe_get(args, 0);
+    get_string(&message)?;
+    let data = match *safe_get(args, 1) {
+        AtomType::Nil => c_map(FnvHashMap::default()),
+        AtomType::Map(_) => safe_get(args, 1),
+        ref other => return Err(AtomError::InvalidType("Map".to_string(), other.format(true))),
+    };
+
+    let mut map = FnvHashMap::default();
+    map.insert(AtomKey::new(c_keyword("type"))?, c_keyword("ex-info"));
+    map.insert(AtomKey::new(c_keyword("message"))?, message);
+    map.insert(AtomKey::new(c_keyword("data"))?, data);
+    Ok(c_map(map))
+}
+
+// A field of a map condition, nil for other conditions
+fn condition_field(condition: &AtomVal, field: &str) -> AtomRet {
+    match **condition {
+        AtomType::Map(ref map) => {
+            Ok(map.get(&AtomKey::new(c_keyword(field))?).cloned().unwrap_or_else(c_nil))
+        }
+        _ => Ok(c_nil()),
+    }
+}
+
+fn ex_data(args: &[AtomVal]) -> AtomRet {
+    condition_field(&safe_get(args, 0), "data")
+}
+
+fn ex_message(args: &[AtomVal]) -> AtomRet {
+    condition_field(&safe_get(args, 0), "message")
+}
+
+fn invoke_restart(args: &[AtomVal]) -> AtomRet {
+    let name = safe_get(args, 0);
+    Err(AtomError::Restart(name.get_symbol()?.to_string(), args.iter().skip(1).cloned().collect()))
+}
+
+#[cfg(feature = "std")]
+fn get_handle(atom: &AtomVal) -> Result<&Handle, AtomError> {
+    match **atom {
+        AtomType::Handle(ref handle) => Ok(handle),
+        ref other => Err(AtomError::InvalidType("handle".to_string(), other.format(true))),
+    }
+}
+
+fn get_string(atom: &AtomVal) -> Result<&str, AtomError> {
+    match **atom {
+        AtomType::Str(ref s) => Ok(s),
+        ref other => Err(AtomError::InvalidType("String".to_string(), other.format(true))),
+    }
+}
+
+#[cfg(feature = "io")]
+fn open(args: &[AtomVal]) -> AtomRet {
+    let mode = match args.get(1) {
+        Some(mode) if mode.is_keyword("write") => "write",
+        Some(mode) if mode.is_keyword("append") => "append",
+        Some(mode) if !mode.is_keyword("read") => {
+            return Err(AtomError::InvalidArgument(format!("unknown file mode {}", mode)))
+        }
+        _ => "read",
+    };
+
+    Ok(c_handle(Handle::open(get_string(&safe_get(args, 0))?, mode)?))
+}
+
+#[cfg(feature = "io")]
+fn connect(args: &[AtomVal]) -> AtomRet {
+    Ok(c_handle(Handle::connect(get_string(&safe_get(args, 0))?)?))
+}
+
+// xorshift64*, good enough for games and shuffling, not for cryptography
+struct Rng(Cell<u64>);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // zero is a fixed point of xorshift
+        Rng(Cell::new(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed }))
+    }
+
+    fn next(&self) -> u64 {
+        let mut x = self.0.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+fn rand_int(rng: &Rng, args: &[AtomVal]) -> AtomRet {
+    let bound = safe_get(args, 0).get_int()?;
+    if bound <= 0 {
+        return Err(AtomError::InvalidArgument(format!("rand-int needs a positive bound, got {}",
+                                                      bound)));
+    }
+    Ok(c_int((rng.next() % bound as u64) as i64))
+}
+
+#[cfg(feature = "io")]
+fn now_millis() -> i64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() as i64 * 1000 + since_epoch.subsec_nanos() as i64 / 1_000_000
+}
+
+// Without IO there may be no clock, time stands still at the epoch
+#[cfg(not(feature = "io"))]
+fn now_millis() -> i64 {
+    0
+}
+
+// The handle given to read-line and read-all, *in* by default
+#[cfg(feature = "io")]
+fn input(env: &Weak<RefCell<EnvType>>, args: &[AtomVal]) -> AtomRet {
+    match args.get(0) {
+        Some(handle) => Ok(handle.clone()),
+        None => {
+            stream(env, "*in*")
+                .ok_or_else(|| AtomError::InvalidOperation("*in* is not bound".to_string()))
+        }
+    }
+}
+
+#[cfg(feature = "io")]
+fn read_line(env: &Weak<RefCell<EnvType>>, args: &[AtomVal]) -> AtomRet {
+    match get_handle(&input(env, args)?)?.read_line()? {
+        Some(line) => Ok(c_string(&line)),
+        None => Ok(c_nil()),
+    }
+}
+
+#[cfg(feature = "io")]
+fn read_all(env: &Weak<RefCell<EnvType>>, args: &[AtomVal]) -> AtomRet {
+    Ok(c_string(&get_handle(&input(env, args)?)?.read_all()?))
+}
+
+#[cfg(feature = "io")]
+fn string_buffer(_args: &[AtomVal]) -> AtomRet {
+    Ok(c_handle(Handle::buffer()))
+}
+
+#[cfg(feature = "io")]
+fn write(args: &[AtomVal]) -> AtomRet {
+    let handle = safe_get(args, 0);
+    let handle = get_handle(&handle)?;
+    for arg in args.iter().skip(1) {
+        handle.write(&arg.format(false))?;
+    }
+    Ok(c_nil())
+}
+
+#[cfg(feature = "io")]
+fn close(args: &[AtomVal]) -> AtomRet {
+    get_handle(&safe_get(args, 0))?.close()?;
+    Ok(c_nil())
+}
+
+#[cfg(feature = "io")]
+fn is_closed(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(get_handle(&safe_get(args, 0))?.is_closed()))
+}
+
+fn type_of(args: &[AtomVal]) -> AtomRet {
+    Ok(protocol::type_of(&safe_get(args, 0)))
+}
+
+fn satisfies(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::Protocol(ref protocol) => Ok(c_bool(protocol.satisfied_by(&safe_get(args, 1)))),
+        ref other => Err(AtomError::InvalidType("protocol".to_string(), other.format(true))),
+    }
+}
+
+fn is_instance(args: &[AtomVal]) -> AtomRet {
+    match (&*safe_get(args, 0), &*safe_get(args, 1)) {
+        (&AtomType::RecordType(ref rtype), &AtomType::Record(ref record)) => {
+            Ok(c_bool(Rc::ptr_eq(rtype, record.rtype())))
+        }
+        (&AtomType::RecordType(_), _) => Ok(c_bool(false)),
+        (other, _) => Err(AtomError::InvalidType("record type".to_string(), other.format(true))),
+    }
+}
+
+fn prefer_method(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::MultiFn(ref multi) => {
+            multi.prefer(safe_get(args, 1), safe_get(args, 2));
+            Ok(c_nil())
+        }
+        ref other => Err(AtomError::InvalidType("multimethod".to_string(), other.format(true))),
+    }
+}
+
+fn get_meta(args: &[AtomVal]) -> AtomRet {
+    Ok(meta::meta(&safe_get(args, 0)))
+}
+
+fn with_meta(args: &[AtomVal]) -> AtomRet {
+    meta::with_meta(&safe_get(args, 0), &safe_get(args, 1))
+}
+
+fn collect_garbage(_args: &[AtomVal]) -> AtomRet {
+    Ok(c_int(gc::collect() as i64))
+}
+
+fn weak_ref(args: &[AtomVal]) -> AtomRet {
+    Ok(c_weak_ref(&safe_get(args, 0)))
+}
+
+fn weak_deref(args: &[AtomVal]) -> AtomRet {
+    match *safe_get(args, 0) {
+        AtomType::WeakRef(ref weak) => Ok(weak.upgrade().unwrap_or_else(c_nil)),
+        ref other => Err(AtomError::InvalidType("weak reference".to_string(), other.format(true))),
+    }
+}
+
+fn get_regex(atom: &AtomVal) -> Result<&Regex, AtomError> {
+    match **atom {
+        AtomType::Regex(ref regex) => Ok(regex),
+        ref other => Err(AtomError::InvalidType("regex".to_string(), other.format(true))),
+    }
+}
+
+fn re_pattern(args: &[AtomVal]) -> AtomRet {
+    let pattern = safe_get(args, 0);
+    Regex::new(get_string(&pattern)?)
+        .map(c_regex)
+        .map_err(|err| AtomError::InvalidArgument(format!("invalid regex: {}", err)))
+}
+
+// The match, or a vector of it and its groups if the regex has any
+fn match_value(captures: Captures) -> AtomVal {
+    if captures.len() == 1 {
+        return c_string(captures.at(0).unwrap());
+    }
+    c_vector(captures.iter()
+        .map(|group| group.map(c_string).unwrap_or_else(c_nil))
+        .collect())
+}
+
+fn re_find(args: &[AtomVal]) -> AtomRet {
+    let (regex, s) = (safe_get(args, 0), safe_get(args, 1));
+    let (regex, s) = (get_regex(&regex)?, get_string(&s)?);
+    Ok(regex.captures(s).map(match_value).unwrap_or_else(c_nil))
+}
+
+// Anchoring keeps the groups, the leftmost match alone may be shorter than a full one
+fn re_matches(args: &[AtomVal]) -> AtomRet {
+    let (regex, s) = (safe_get(args, 0), safe_get(args, 1));
+    let (regex, s) = (get_regex(&regex)?, get_string(&s)?);
+    let anchored = Regex::new(&format!("^(?:{})$", regex.as_str())).unwrap();
+    Ok(anchored.captures(s).map(match_value).unwrap_or_else(c_nil))
+}
+
+thread_local! {
+    // how many symbols gensym has made
+    static GENSYMS: Cell<usize> = Cell::new(0);
+}
+
+fn fresh_symbol(prefix: &str) -> AtomVal {
+    let count = GENSYMS.with(|count| {
+        count.set(count.get() + 1);
+        count.get()
+    });
+    c_symbol(&format!("{}__{}", prefix, count))
+}
+
+// (gensym prefix?) is a symbol no other gensym call returns
+fn gensym(args: &[AtomVal]) -> AtomRet {
+    match args.get(0) {
+        Some(prefix) => Ok(fresh_symbol(get_string(prefix)?)),
+        None => Ok(fresh_symbol("G")),
+    }
+}
+
+// The symbols of a backquoted template ending in #, outside ~ and ~@, replaced by ~ of locals
+// bound to fresh gensyms, so each evaluation of the template, each expansion of a macro made
+// with it, names new symbols; nil if the template has none
+fn auto_gensym(args: &[AtomVal]) -> AtomRet {
+    let mut names = FnvHashMap::default();
+    let template = rename_auto(&safe_get(args, 0), &mut names);
+    if names.is_empty() {
+        return Ok(c_nil());
+    }
+
+    let mut bindings = Vec::with_capacity(names.len() * 2);
+    for (name, local) in names {
+        bindings.push(local);
+        bindings.push(c_list(vec![c_symbol("gensym"), c_string(&name[..name.len() - 1])]));
+    }
+    Ok(c_list(vec![c_symbol("let"),
+                   c_list(bindings),
+                   c_list(vec![c_symbol("backquote"), template])]))
+}
+
+// Hands back form itself if nothing in it was renamed
+fn rename_auto(form: &AtomVal, names: &mut FnvHashMap<String, AtomVal>) -> AtomVal {
+    let items = match **form {
+        AtomType::Symbol(ref name, _) if name.len() > 1 && name.ends_with('#') => {
+            let local = names.entry(name.to_string())
+                .or_insert_with(|| fresh_symbol(&name[..name.len() - 1]))
+                .clone();
+            return c_list(vec![c_symbol("unquote"), local]);
+        }
+        AtomType::List(ref items, _) => {
+            let head = safe_get(items, 0);
+            if head.is_symbol("unquote") || head.is_symbol("unquote-splicing") {
+                return form.clone();
+            }
+            items
+        }
+        AtomType::Vector(ref items) => items.as_slice(),
+        // keys have to stay hashable, so only the values are renamed
+        AtomType::Map(ref map) => {
+            let mut renamed = false;
+            let map = map.iter()
+                .map(|(key, value)| {
+                    let value_renamed = rename_auto(value, names);
+                    renamed |= !Rc::ptr_eq(&value_renamed, value);
+                    (key.clone(), value_renamed)
+                })
+                .collect();
+            return if renamed { c_map(map) } else { form.clone() };
+        }
+        _ => return form.clone(),
+    };
+
+    let renamed = items.iter().map(|item| rename_auto(item, names)).collect::<Vec<_>>();
+    if renamed.iter().zip(items).all(|(new, old)| Rc::ptr_eq(new, old)) {
+        return form.clone();
+    }
+    match **form {
+        AtomType::List(..) => c_list(renamed),
+        _ => c_vector(renamed),
+    }
+}
+
+// The first form of s as the reader reads it, nil if there is none
+fn read_string(args: &[AtomVal]) -> AtomRet {
+    let source = safe_get(args, 0);
+    Parser::new(Lexer::new(get_string(&source)?))
+        .next_form()
+        .map(|form| form.unwrap_or_else(c_nil))
+        .map_err(|err| AtomError::InvalidArgument(err.to_string()))
+}
+
+fn new_env(args: &[AtomVal]) -> AtomRet {
+    let env = build();
+    let bindings = safe_get(args, 0);
+    match *bindings {
+        AtomType::Nil => {}
+        _ => {
+            for (name, value) in bindings.get_map()? {
+                name.value().get_symbol()?;
+                env_set(&env, name.value(), value.clone());
+            }
+        }
+    }
+    Ok(c_env_value(env))
+}
+
+fn dump_tokens(args: &[AtomVal]) -> AtomRet {
+    let source = safe_get(args, 0);
+    dump::tokens(get_string(&source)?)
+        .map(|dump| c_string(&dump))
+        .map_err(|err| AtomError::InvalidArgument(err.to_string()))
+}
+
+fn dump_ast(args: &[AtomVal]) -> AtomRet {
+    let source = safe_get(args, 0);
+    dump::ast(get_string(&source)?)
+        .map(|dump| c_string(&dump))
+        .map_err(|err| AtomError::InvalidArgument(err.to_string()))
+}
+
+// {:atoms {:list n ...} :allocated {:list n ...} :envs n :max-env-depth n
+//  :interned-symbols n :eval-steps n :applications n :gc-runs n :gc-freed n}
+fn runtime_stats(_args: &[AtomVal]) -> AtomRet {
+    let stats = stats::snapshot();
+
+    let mut atoms = FnvHashMap::default();
+    for (name, count) in stats.atoms {
+        atoms.insert(AtomKey::new(c_keyword(name))?, c_int(count as i64));
+    }
+    let mut allocated = FnvHashMap::default();
+    for (name, count) in stats.allocated {
+        allocated.insert(AtomKey::new(c_keyword(name))?, c_int(count as i64));
+    }
+
+    let mut map = FnvHashMap::default();
+    map.insert(AtomKey::new(c_keyword("atoms"))?, c_map(atoms));
+    map.insert(AtomKey::new(c_keyword("allocated"))?, c_map(allocated));
+    map.insert(AtomKey::new(c_keyword("envs"))?, c_int(stats.envs as i64));
+    map.insert(AtomKey::new(c_keyword("max-env-depth"))?, c_int(stats.max_env_depth as i64));
+    map.insert(AtomKey::new(c_keyword("interned-symbols"))?,
+               c_int(stats.interned_symbols as i64));
+    map.insert(AtomKey::new(c_keyword("eval-steps"))?, c_int(stats.eval_steps as i64));
+    map.insert(AtomKey::new(c_keyword("applications"))?, c_int(stats.applications as i64));
+    map.insert(AtomKey::new(c_keyword("gc-runs"))?, c_int(stats.gc_runs as i64));
+    map.insert(AtomKey::new(c_keyword("gc-freed"))?, c_int(stats.gc_freed as i64));
+    Ok(c_map(map))
+}
+
+
+pub(crate) fn set_builtin(env: &Env,
+                          name: &str,
+                          func: fn(&[AtomVal]) -> AtomRet,
+                          doc: &'static str) {
+    env_set(env, &c_symbol(name), c_doc_func(func, doc));
+}
+
+// Fixed inputs for runs which have to be reproducible
+#[derive(Clone, Copy, Debug)]
+pub struct Deterministic {
+    pub seed: u64,
+    // what (now) returns, in milliseconds since the epoch
+    pub now: i64,
+}
+
+// Builtins whose results depend on the world outside the interpreter
+// Files and sockets, which targets like the browser don't have
+#[cfg(feature = "io")]
+fn register_io(env: &Env) {
+    set_builtin(env,
+                "open",
+                open,
+                "(open path mode?) opens the file at path, mode is :read (the default), \
+                 :write or :append");
+    set_builtin(env, "connect", connect, "(connect \"host:port\") opens a TCP connection");
+    set_builtin(env,
+                "string-buffer",
+                string_buffer,
+                "(string-buffer) returns a handle reading back what's written to it");
+    env_set(env, &c_symbol("*in*"), c_handle(Handle::stdin()));
+    path::register(env);
+    let weak = Rc::downgrade(env);
+    env_set(env,
+            &c_symbol("read-line"),
+            c_doc_func(move |args| read_line(&weak, args),
+                       "(read-line h?) reads the next line from h or *in* including its \
+                        newline, nil at the end"));
+    let weak = Rc::downgrade(env);
+    env_set(env,
+            &c_symbol("read-all"),
+            c_doc_func(move |args| read_all(&weak, args),
+                       "(read-all h?) reads the rest of h or *in* as a string"));
+    set_builtin(env, "write", write, "(write h & xs) writes the printed xs to h");
+    set_builtin(env, "close", close, "(close h) closes h, closing twice does nothing");
+    set_builtin(env,
+                "closed?",
+                is_closed,
+                "(closed? h) returns true if h is closed, false otherwise");
+}
+
+// The forms of core.clrs, read by build.rs
+#[cfg(feature = "prelude")]
+const PRELUDE: &'static [Datum] = include!(concat!(env!("OUT_DIR"), "/prelude.rs"));
+
+// Run from the source tree core.clrs can be changed without rebuilding, changed it's parsed at
+// startup instead of loading the forms read at build time
+#[cfg(all(feature = "prelude", feature = "io"))]
+fn changed_prelude() -> Option<String> {
+    let mut s = String::new();
+    match File::open("src/core.clrs").and_then(|mut f| f.read_to_string(&mut s)) {
+        Ok(_) if s != include_str!("core.clrs") => Some(s),
+        _ => None,
+    }
+}
+
+#[cfg(all(feature = "prelude", not(feature = "io")))]
+fn changed_prelude() -> Option<String> {
+    None
+}
+
+const NONDETERMINISTIC: &'static [&'static str] = &["connect", "runtime-stats", "load-plugin",
+                                                      "watch-path"];
 
-#[allow(unused_must_use)]
 pub fn build() -> Env {
+    build_with(None)
+}
+
+// The builtins and, with the prelude feature, the functions and macros of core.clrs
+pub fn build_with(deterministic: Option<Deterministic>) -> Env {
+    let env = build_bare(deterministic);
+    load_prelude(&env);
+    env
+}
+
+#[cfg(feature = "prelude")]
+fn load_prelude(env: &Env) {
+    diagnostics::muted(|| match changed_prelude() {
+            Some(source) => eval_str(&source, env),
+            None => embed::load_forms(PRELUDE, env),
+        })
+        .expect("Problem loading core.clrs into ENV");
+}
+
+#[cfg(not(feature = "prelude"))]
+fn load_prelude(_env: &Env) {}
+
+// Only the builtins written in Rust, for small targets and hosts bringing their own library
+#[allow(unused_must_use)]
+pub fn build_bare(deterministic: Option<Deterministic>) -> Env {
     let env = c_env(None);
 
-    env_set(&env, &c_symbol("print"), c_func(print));
-    env_set(&env, &c_symbol("println"), c_func(println));
-    env_set(&env, &c_symbol("_print"), c_func(_print));
-    env_set(&env, &c_symbol("_println"), c_func(_println));
-    env_set(&env, &c_symbol("+"), c_func(add));
-    env_set(&env, &c_symbol("-"), c_func(sub));
-    env_set(&env, &c_symbol("*"), c_func(mul));
-    env_set(&env, &c_symbol("/"), c_func(div));
-    env_set(&env, &c_symbol("cons"), c_func(cons));
-    env_set(&env, &c_symbol("list"), c_func(list));
-    env_set(&env, &c_symbol("list?"), c_func(is_list));
-    env_set(&env, &c_symbol("nil?"), c_func(is_nil));
-    env_set(&env, &c_symbol("nth"), c_func(nth));
-    env_set(&env, &c_symbol("rest"), c_func(rest));
-    env_set(&env, &c_symbol("count"), c_func(count));
+    // standard streams, rebound with binding
+    #[cfg(feature = "std")]
+    {
+        env_set(&env, &c_symbol("*out*"), c_handle(Handle::stdout()));
+        env_set(&env, &c_symbol("*err*"), c_handle(Handle::stderr()));
+    }
+    set_printer(&env,
+                "print",
+                false,
+                false,
+                "(print & xs) prints xs separated by spaces to *out*");
+    set_printer(&env,
+                "println",
+                false,
+                true,
+                "(println & xs) prints xs to *out* followed by a newline");
+    set_printer(&env,
+                "_print",
+                true,
+                false,
+                "(_print & xs) prints xs along with their types to *out*");
+    set_printer(&env,
+                "_println",
+                true,
+                true,
+                "(_println & xs) prints xs along with their types to *out*, followed by a \
+                 newline");
+    set_builtin(&env, "log/debug", log_debug, "(log/debug & xs) logs xs at debug level");
+    set_builtin(&env, "log/info", log_info, "(log/info & xs) logs xs at info level");
+    set_builtin(&env, "log/warn", log_warn, "(log/warn & xs) logs xs at warn level");
+    set_builtin(&env, "log/error", log_error, "(log/error & xs) logs xs at error level");
+    set_builtin(&env, "+", add, "(+ & xs) adds xs");
+    set_builtin(&env, "-", sub, "(- x & xs) subtracts xs from x");
+    set_builtin(&env, "*", mul, "(* & xs) multiplies xs");
+    set_builtin(&env, "/", div, "(/ x & xs) divides x by xs");
+    set_builtin(&env, "cons", cons, "(cons x coll) returns coll with x prepended");
+    set_builtin(&env, "list", list, "(list & xs) returns a list of xs");
+    set_builtin(&env, "list?", is_list, "(list? x) returns true if x is a list, false otherwise");
+    set_builtin(&env, "nil?", is_nil, "(nil? x) returns true if x is nil, false otherwise");
+    set_builtin(&env, "int?", is_int, "(int? x) returns true if x is an integer, false otherwise");
+    set_builtin(&env,
+                "string?",
+                is_string,
+                "(string? x) returns true if x is a string, false otherwise");
+    set_builtin(&env,
+                "symbol?",
+                is_symbol,
+                "(symbol? x) returns true if x is a symbol, false otherwise");
+    set_builtin(&env,
+                "keyword?",
+                is_keyword,
+                "(keyword? x) returns true if x is a keyword, false otherwise");
+    set_builtin(&env,
+                "fn?",
+                is_fn,
+                "(fn? x) returns true if x is a function or builtin, not a macro, false otherwise");
+    set_builtin(&env,
+                "map?",
+                is_map,
+                "(map? x) returns true if x is a map or sorted map, false otherwise");
+    set_builtin(&env,
+                "vector?",
+                is_vector,
+                "(vector? x) returns true if x is a vector, false otherwise");
+    set_builtin(&env,
+                "nth",
+                nth,
+                "(nth coll n default?) returns the nth element of coll, the nth character of a \
+                 string, counting back from the end for negative n; default or nil if there's \
+                 none");
+    set_builtin(&env,
+                "get",
+                get,
+                "(get coll key default?) returns the value for key in a map, set or record or \
+                 the element at index key of a sequence as nth does, default or nil if there's \
+                 none");
+    set_builtin(&env,
+                "distinct",
+                distinct,
+                "(distinct coll) returns the elements of coll without duplicates, in order");
+    set_builtin(&env,
+                "conj",
+                conj,
+                "(conj coll & xs) returns coll with xs added where it's natural for coll: at the \
+                 front of a list, the end of a vector or queue, [k v] entries into a map");
+    set_builtin(&env,
+                "into",
+                into,
+                "(into to from) returns to with the elements of from conj'ed, map entries as \
+                 [k v]");
+    set_builtin(&env,
+                "assoc",
+                assoc,
+                "(assoc coll & kvs) returns the map or vector coll with the keys or indices in kvs \
+                 set to their values, an index one past the end of a vector appends");
+    set_builtin(&env,
+                "update",
+                update,
+                "(update coll k f & args) returns the map or vector coll with k set to \
+                 (f old & args), old being the value at k or nil");
+    set_builtin(&env,
+                "subvec",
+                subvec,
+                "(subvec v start end?) returns the elements of v from start up to but not \
+                 including end, or to the end; a vector shares the storage of v");
+    set_builtin(&env,
+                "subs",
+                subs,
+                "(subs s start end?) returns the characters of s from start up to but not \
+                 including end, or to the end, sharing the storage of s");
+    set_builtin(&env,
+                "last",
+                last,
+                "(last coll) returns the last element of coll, nil if it's empty");
+    set_builtin(&env,
+                "seq",
+                seq,
+                "(seq coll) returns the elements of coll as a list, nil if it's empty; maps give \
+                 [k v] entries and strings their characters");
+    set_builtin(&env,
+                "reverse",
+                reverse,
+                "(reverse coll) returns the elements of coll as a list, last first");
+    set_builtin(&env,
+                "chars",
+                chars,
+                "(chars s) returns the characters of the string s as a list of strings");
+    set_builtin(&env,
+                "string",
+                string,
+                "(string coll) returns the strings in coll joined, the inverse of chars");
+    set_builtin(&env,
+                "char->int",
+                char_to_int,
+                "(char->int c) returns the code point of the character c");
+    set_builtin(&env,
+                "int->char",
+                int_to_char,
+                "(int->char n) returns the character with the code point n");
+    set_builtin(&env,
+                "upper-case",
+                upper_case,
+                "(upper-case s) returns the character or string s in upper case");
+    set_builtin(&env,
+                "lower-case",
+                lower_case,
+                "(lower-case s) returns the character or string s in lower case");
+    set_builtin(&env,
+                "digit?",
+                is_digit,
+                "(digit? c) returns true if c is a decimal digit, false otherwise");
+    set_builtin(&env,
+                "whitespace?",
+                is_whitespace,
+                "(whitespace? c) returns true if c is whitespace, false otherwise");
+    set_builtin(&env,
+                "alpha?",
+                is_alpha,
+                "(alpha? c) returns true if c is a letter, false otherwise");
+    set_builtin(&env, "vec", vec, "(vec coll) returns the elements of coll as a vector");
+    set_builtin(&env,
+                "list*",
+                list_star,
+                "(list* & xs coll) returns a list of xs followed by the elements of coll");
+    set_builtin(&env,
+                "set",
+                set,
+                "(set coll) returns a sorted set of the elements of coll");
+    set_builtin(&env,
+                "hash-map",
+                hash_map,
+                "(hash-map & kvs) returns a map of the key value pairs kvs");
+    set_builtin(&env,
+                "zipmap",
+                zipmap,
+                "(zipmap keys vals) returns a map of each of keys to the val at the same \
+                 position, as far as the shorter goes");
+    set_builtin(&env,
+                "seqable?",
+                is_seqable,
+                "(seqable? x) returns true if seq accepts x, false otherwise");
+    set_builtin(&env,
+                "rest",
+                rest,
+                "(rest coll) returns the elements of coll after the first as a list");
+    set_builtin(&env,
+                "count",
+                count,
+                "(count coll) returns the number of elements in coll, characters in a string");
+    set_builtin(&env, "doc", doc, "(doc f) prints and returns the docstring of f");
 
-    // predicates
-    env_set(&env, &c_symbol("="), c_func(partialeq));
-    // env_set(&env, &c_symbol("="), c_func(partialeq));
+    // introspection
+    set_builtin(&env, "fn-name", fn_name, "(fn-name f) returns the name f was defined with");
+    set_builtin(&env, "fn-params", fn_params, "(fn-params f) returns the parameter list of f");
+    set_builtin(&env,
+                "fn-arity",
+                fn_arity,
+                "(fn-arity f) returns [min max] number of arguments f accepts");
+    set_builtin(&env,
+                "macro?",
+                is_macro,
+                "(macro? x) returns true if x is a macro, false otherwise");
 
+    // combinators
+    set_builtin(&env, "identity", identity, "(identity x) returns x");
+    set_builtin(&env,
+                "constantly",
+                constantly,
+                "(constantly x) returns a function which always returns x");
+    set_builtin(&env,
+                "partial",
+                partial,
+                "(partial f & args) returns f with args bound as its first arguments");
+    set_builtin(&env,
+                "comp",
+                comp,
+                "(comp & fs) returns the composition of fs, applied right to left");
+    set_builtin(&env,
+                "juxt",
+                juxt,
+                "(juxt & fs) returns a function returning a vector with the result of each f");
+    set_builtin(&env,
+                "trampoline",
+                trampoline,
+                "(trampoline f & args) calls f with args and whatever function it returns \
+                 with no args until that returns something else, which it returns");
+    set_builtin(&env,
+                "memoize",
+                memoize,
+                "(memoize f) returns f caching its results by arguments");
 
-    let mut f = File::open("src/core.clrs").expect("core.clrs has to be openable");
-    let mut s = String::new();
-    f.read_to_string(&mut s).expect("Couldn't read core.clrs");
+    // generators
+    set_builtin(&env,
+                "generator",
+                generator,
+                "(generator f & args) returns a generator running f, suspended at each yield");
+    set_builtin(&env,
+                "next!",
+                next,
+                "(next! gen x?) resumes gen until the next yield, which evaluates to x; \
+                 returns the yielded value or nil once gen is done");
+    set_builtin(&env,
+                "done?",
+                is_done,
+                "(done? gen) returns true if gen has finished, false otherwise");
+
+    // conditions
+    set_builtin(&env,
+                "error",
+                error,
+                "(error condition) signals condition, failing if no handler takes over");
+    set_builtin(&env,
+                "ex-info",
+                ex_info,
+                "(ex-info msg data) returns a condition with the message msg and the map data, \
+                 handled as :ex-info");
+    set_builtin(&env,
+                "ex-data",
+                ex_data,
+                "(ex-data e) returns the data map of the ex-info condition e, nil for other \
+                 conditions");
+    set_builtin(&env,
+                "ex-message",
+                ex_message,
+                "(ex-message e) returns the message of the condition e, nil if it has none");
+    set_builtin(&env,
+                "deprecate!",
+                diagnostics::deprecate,
+                "(deprecate! 'f \"advice\") warns with the advice the first time f is called");
+    set_builtin(&env,
+                "invoke-restart",
+                invoke_restart,
+                "(invoke-restart 'name & args) unwinds to the innermost restart-case \
+                 offering name and runs it with args");
+
+    // files and sockets
+    #[cfg(feature = "io")]
+    register_io(&env);
+
+    // protocols
+    set_builtin(&env,
+                "type-of",
+                type_of,
+                "(type-of x) returns the keyword protocol methods dispatch on for x");
+    set_builtin(&env,
+                "satisfies?",
+                satisfies,
+                "(satisfies? protocol x) returns true if every method of protocol is \
+                 implemented for x, false otherwise");
+
+    set_builtin(&env,
+                "prefer-method",
+                prefer_method,
+                "(prefer-method multi a b) makes the method for dispatch value a win over \
+                 the one for b when both match");
+
+    // metadata
+    set_builtin(&env,
+                "meta",
+                get_meta,
+                "(meta x) returns the metadata map of x, functions include :name and :doc");
+    set_builtin(&env,
+                "with-meta",
+                with_meta,
+                "(with-meta x m) returns a copy of the symbol, collection or function x \
+                 with the metadata m, which doesn't affect equality");
+
+    // records
+    set_builtin(&env,
+                "instance?",
+                is_instance,
+                "(instance? Record x) returns true if x was created by the constructors of \
+                 Record, false otherwise");
 
-    eval_str(s.as_str(), &env).expect("Problem loading core.clrs into ENV");
+    set_builtin(&env,
+                "weak-ref",
+                weak_ref,
+                "(weak-ref x) returns a reference to x which doesn't keep it alive");
+    set_builtin(&env,
+                "weak-deref",
+                weak_deref,
+                "(weak-deref r) returns the value r refers to, nil once it has been freed");
+
+    set_builtin(&env,
+                "re-pattern",
+                re_pattern,
+                "(re-pattern s) compiles s to a regex, like the literal #\"s\"");
+    set_builtin(&env,
+                "re-find",
+                re_find,
+                "(re-find re s) returns the first match of re in s, a vector of the match and \
+                 its groups if re has any, nil if there is none");
+    set_builtin(&env,
+                "re-matches",
+                re_matches,
+                "(re-matches re s) returns like re-find if re matches all of s, nil otherwise");
+    set_builtin(&env,
+                "gensym",
+                gensym,
+                "(gensym prefix?) returns a new symbol starting with prefix, which defaults to G");
+    set_builtin(&env,
+                "auto-gensym",
+                auto_gensym,
+                "(auto-gensym template) returns the backquoted template in a let binding a fresh \
+                 gensym for each symbol ending in # outside ~ and ~@, nil if there are none; \
+                 so foo# names the same new symbol throughout one evaluation of `template");
+    set_builtin(&env,
+                "read-string",
+                read_string,
+                "(read-string s) returns the first form of s unevaluated, nil if there is none");
+    set_builtin(&env,
+                "new-env",
+                new_env,
+                "(new-env bindings?) returns a new env with the builtins and the prelude, and the \
+                 symbols in the map bindings bound to their values, for (eval form env)");
+    set_builtin(&env,
+                "dump-tokens",
+                dump_tokens,
+                "(dump-tokens s) returns the tokens of s with their positions, one per line");
+    set_builtin(&env,
+                "dump-ast",
+                dump_ast,
+                "(dump-ast s) returns the forms of s as an indented tree of nodes with their \
+                 spans");
+    set_builtin(&env,
+                "gc",
+                collect_garbage,
+                "(gc) frees environments only kept alive by closures referring to them, \
+                 returns how many were freed");
+
+    set_builtin(&env,
+                "runtime-stats",
+                runtime_stats,
+                "(runtime-stats) returns live and allocated atoms by type, live environments, \
+                 the deepest nesting of them, interned symbols, evaluation steps, function \
+                 applications and garbage collections so far");
+
+    let rng = Rc::new(Rng::new(match deterministic {
+        Some(ref fixed) => fixed.seed,
+        None => now_millis() as u64 ^ (&env as *const Env as u64),
+    }));
+    env_set(&env,
+            &c_symbol("rand-int"),
+            c_doc_func(move |args| rand_int(&rng, args),
+                       "(rand-int n) returns a pseudo-random integer from 0 below n"));
+    let frozen = deterministic.map(|fixed| fixed.now);
+    env_set(&env,
+            &c_symbol("now"),
+            c_doc_func(move |_| Ok(c_int(frozen.unwrap_or_else(now_millis))),
+                       "(now) returns the current time in milliseconds since the epoch"));
+
+    // predicates
+    set_builtin(&env, "not", not, "(not x) returns true if x is nil or false, false otherwise");
+    set_builtin(&env,
+                "=",
+                partialeq,
+                "(= & xs) returns true if all xs are structurally equal, false otherwise; \
+                 functions, generators and handles are only equal to themselves");
+    set_builtin(&env,
+                "eq?",
+                is_eq,
+                "(eq? & xs) returns true if all xs are the same object, false otherwise; \
+                 separately created equal values like two 1s are not eq?");
+    set_builtin(&env, "not=", not_eq, "(not= & xs) returns true if any xs differ, false otherwise");
+
+    queue::register(&env);
+    printf::register(&env);
+    term::register(&env);
+    opts::register(&env);
+    sorted::register(&env);
+    priority::register(&env);
+    transient::register(&env);
+    testing::register(&env);
+    debug::register(&env);
+    #[cfg(feature = "plugins")]
+    plugin::register(&env);
+    #[cfg(feature = "watch")]
+    watch::register(&env);
+
+    if deterministic.is_some() {
+        for name in NONDETERMINISTIC {
+            let forbidden = move |_: &[AtomVal]| {
+                Err(AtomError::InvalidOperation(format!("{} is not allowed in deterministic mode",
+                                                        name)))
+            };
+            env_set(&env,
+                    &c_symbol(name),
+                    c_doc_func(forbidden, "not allowed in deterministic mode"));
+        }
+    }
 
     env
 }
@@ -176,6 +1841,17 @@ mod tests {
     use data::c_int;
     use test::Bencher;
 
+    #[cfg(feature = "prelude")]
+    #[test]
+    fn test_prelude_read_at_build() {
+        use embed::Datum;
+        use lexer::Lexer;
+        use parser::Parser;
+
+        let parsed = Parser::new(Lexer::new(include_str!("core.clrs"))).parse_program().unwrap();
+        assert_eq!(super::PRELUDE.iter().map(Datum::value).collect::<Vec<_>>(), parsed);
+    }
+
     #[bench]
     fn bench_adding(b: &mut Bencher) {
         let args = vec![c_int(1), c_int(1)];
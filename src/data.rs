@@ -1,10 +1,40 @@
+use std::any::Any;
 use std::fmt::*;
-use std::rc::Rc;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::{Rc, Weak};
+use std::cell::{Cell, RefCell};
 use std::result;
-use eval::eval;
-use env::{c_env, env_bind, env_set, Env};
+use std::sync::Once;
+use std::cmp;
+use std::hash::{Hash, Hasher};
+use std::collections::{BTreeMap, BTreeSet};
+use fnv::{FnvHashMap, FnvHasher};
+use regex::Regex;
+use arena::Arena;
+use eval::{eval_body, Continuation, FormCache, Generator};
+#[cfg(feature = "std")]
+use io::Handle;
+use gc;
+use stats;
+use protocol::{Protocol, Method, MultiFn};
+use record::{Record, RecordType};
+use queue::Queue;
+use priority::PriorityQueue;
+use transient::Transient;
+use vector::PersistentVector;
+use shared::SharedStr;
+use sorted::SortKey;
+use env::{binds_nothing, c_env, env_bind, macros_bound, Env, SymbolCache};
 
-pub struct AtomFn(fn(&[AtomVal]) -> AtomRet);
+// Native function with an optional docstring
+#[derive(Clone)]
+pub struct AtomFn(Rc<dyn Fn(&[AtomVal]) -> AtomRet>, Option<&'static str>);
+
+impl AtomFn {
+    pub fn doc(&self) -> Option<&'static str> {
+        self.1
+    }
+}
 
 impl Debug for AtomFn {
     fn fmt(&self, f: &mut Formatter) -> Result {
@@ -12,53 +42,418 @@ impl Debug for AtomFn {
     }
 }
 
+// Natives are equal if they share the same code, copies made by with-meta included
 impl PartialEq for AtomFn {
-    fn eq(&self, _other: &AtomFn) -> bool {
-        false  // TODO
+    fn eq(&self, other: &AtomFn) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
     }
 }
 
-#[derive(Debug, PartialEq)]
 pub enum AtomType {
     Nil,
+    Bool(bool),
     Int(i64),
-    Symbol(Rc<String>),
-    List(Vec<AtomVal>),
+    Str(SharedStr),
+    // with where it resolved to last, see env_get
+    Symbol(Rc<String>, SymbolCache),
+    Keyword(Rc<String>),
+    // with what was worked out about it as code, see FormCache
+    List(Vec<AtomVal>, FormCache),
+    Vector(PersistentVector),
+    Map(FnvHashMap<AtomKey, AtomVal>),
+    Queue(Queue),
+    SortedMap(BTreeMap<SortKey, AtomVal>),
+    SortedSet(BTreeSet<SortKey>),
+    PriorityQueue(PriorityQueue),
+    Transient(Transient),
+    // an environment as a value, for (eval form env)
+    Env(Env),
     Func(AtomFn),
     AFunc(AFuncData), // user defined function
+    Generator(Generator),
+    Continuation(Continuation),
+    #[cfg(feature = "std")]
+    Handle(Handle),
+    Protocol(Protocol),
+    Method(Method),
+    MultiFn(MultiFn),
+    RecordType(Rc<RecordType>),
+    Record(Record),
+    WeakRef(WeakAtom),
+    Regex(Regex),
 }
 
 
-#[derive(Clone, Debug, PartialEq)]
+// Reference to a value which doesn't keep it alive, for caches in lisp code or embedders
+#[derive(Clone, Debug)]
+pub struct WeakAtom(Weak<AtomType, Arena>);
+
+impl WeakAtom {
+    pub fn new(value: &AtomVal) -> WeakAtom {
+        WeakAtom(Rc::downgrade(value))
+    }
+
+    // None once the value is gone
+    pub fn upgrade(&self) -> Option<AtomVal> {
+        self.0.upgrade()
+    }
+}
+
+// Weak references are equal while they refer to the same live value
+impl PartialEq for WeakAtom {
+    fn eq(&self, other: &WeakAtom) -> bool {
+        match (self.upgrade(), other.upgrade()) {
+            (Some(a), Some(b)) => Rc::ptr_eq(&a, &b),
+            _ => false,
+        }
+    }
+}
+
+// Shared structure compares equal without being walked; Rc's == ends up here with the
+// addresses of the shared values, so this short-circuits at every level
+impl PartialEq for AtomType {
+    fn eq(&self, other: &AtomType) -> bool {
+        if std::ptr::eq(self, other) {
+            return true;
+        }
+
+        match (self, other) {
+            (&AtomType::Nil, &AtomType::Nil) => true,
+            (&AtomType::Bool(a), &AtomType::Bool(b)) => a == b,
+            (&AtomType::Int(a), &AtomType::Int(b)) => a == b,
+            (&AtomType::Str(ref a), &AtomType::Str(ref b)) => a == b,
+            (&AtomType::Symbol(ref a, _), &AtomType::Symbol(ref b, _)) => a == b,
+            (&AtomType::Keyword(ref a), &AtomType::Keyword(ref b)) => a == b,
+            (&AtomType::List(ref a, _), &AtomType::List(ref b, _)) => a == b,
+            (&AtomType::Vector(ref a), &AtomType::Vector(ref b)) => a == b,
+            (&AtomType::Map(ref a), &AtomType::Map(ref b)) => a == b,
+            (&AtomType::Queue(ref a), &AtomType::Queue(ref b)) => a == b,
+            (&AtomType::SortedMap(ref a), &AtomType::SortedMap(ref b)) => a == b,
+            (&AtomType::SortedSet(ref a), &AtomType::SortedSet(ref b)) => a == b,
+            (&AtomType::PriorityQueue(ref a), &AtomType::PriorityQueue(ref b)) => a == b,
+            (&AtomType::Transient(ref a), &AtomType::Transient(ref b)) => a == b,
+            (&AtomType::Env(ref a), &AtomType::Env(ref b)) => Rc::ptr_eq(a, b),
+            (&AtomType::Func(ref a), &AtomType::Func(ref b)) => a == b,
+            (&AtomType::AFunc(ref a), &AtomType::AFunc(ref b)) => a == b,
+            (&AtomType::Generator(ref a), &AtomType::Generator(ref b)) => a == b,
+            (&AtomType::Continuation(ref a), &AtomType::Continuation(ref b)) => a == b,
+            #[cfg(feature = "std")]
+            (&AtomType::Handle(ref a), &AtomType::Handle(ref b)) => a == b,
+            (&AtomType::Protocol(ref a), &AtomType::Protocol(ref b)) => a == b,
+            (&AtomType::Method(ref a), &AtomType::Method(ref b)) => a == b,
+            (&AtomType::MultiFn(ref a), &AtomType::MultiFn(ref b)) => a == b,
+            (&AtomType::RecordType(ref a), &AtomType::RecordType(ref b)) => a == b,
+            (&AtomType::Record(ref a), &AtomType::Record(ref b)) => a == b,
+            (&AtomType::WeakRef(ref a), &AtomType::WeakRef(ref b)) => a == b,
+            (&AtomType::Regex(ref a), &AtomType::Regex(ref b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+// Map keys are restricted to atoms which can be hashed consistently
+#[derive(Clone, Debug)]
+pub struct AtomKey(AtomVal);
+
+impl AtomKey {
+    pub fn new(value: AtomVal) -> result::Result<AtomKey, AtomError> {
+        if value.is_hashable() {
+            Ok(AtomKey(value))
+        } else {
+            Err(AtomError::InvalidType("hashable value".to_string(), value.format(true)))
+        }
+    }
+
+    pub fn value(&self) -> &AtomVal {
+        &self.0
+    }
+}
+
+impl PartialEq for AtomKey {
+    fn eq(&self, other: &AtomKey) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for AtomKey {}
+
+impl Hash for AtomKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+// Consistent with ==, values which are only equal to themselves all hash alike
+impl Hash for AtomType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            AtomType::Int(num) => {
+                0.hash(state);
+                num.hash(state);
+            }
+            AtomType::Symbol(ref symbol, _) => {
+                1.hash(state);
+                symbol.hash(state);
+            }
+            AtomType::Keyword(ref keyword) => {
+                2.hash(state);
+                keyword.hash(state);
+            }
+            AtomType::Str(ref string) => {
+                3.hash(state);
+                string.hash(state);
+            }
+            AtomType::Bool(b) => {
+                4.hash(state);
+                b.hash(state);
+            }
+            AtomType::Nil => 5.hash(state),
+            AtomType::List(ref seq, _) => {
+                6.hash(state);
+                seq.hash(state);
+            }
+            AtomType::Vector(ref seq) => {
+                7.hash(state);
+                seq.len().hash(state);
+                for value in seq {
+                    value.hash(state);
+                }
+            }
+            // entries come in no particular order, so their hashes are combined commutatively
+            AtomType::Map(ref map) => {
+                8.hash(state);
+                let mut sum = 0u64;
+                for (k, v) in map {
+                    let mut hasher = FnvHasher::default();
+                    k.hash(&mut hasher);
+                    v.hash(&mut hasher);
+                    sum = sum.wrapping_add(hasher.finish());
+                }
+                sum.hash(state);
+            }
+            AtomType::Queue(ref queue) => {
+                11.hash(state);
+                queue.values().hash(state);
+            }
+            AtomType::SortedMap(ref map) => {
+                12.hash(state);
+                for (k, v) in map {
+                    k.value().hash(state);
+                    v.hash(state);
+                }
+            }
+            AtomType::SortedSet(ref set) => {
+                13.hash(state);
+                for k in set {
+                    k.value().hash(state);
+                }
+            }
+            AtomType::Record(ref record) => {
+                9.hash(state);
+                record.rtype().name().hash(state);
+                for (_, v) in record.fields() {
+                    v.hash(state);
+                }
+            }
+            _ => 10.hash(state),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct AFuncData {
     pub exp: AtomVal,
     pub env: Env,
     pub params: AtomVal,
-    pub is_macro: bool
+    pub name: Option<String>,
+    pub doc: Option<String>,
+    pub is_macro: bool,
+    // whether calls run the body in env itself and the macros_bound it was found at, see
+    // frameless
+    pub frameless: Cell<(bool, u64)>,
+}
+
+impl AFuncData {
+    // Whether a call can run the body in env itself, see env::binds_nothing; found again once a
+    // macro was bound since
+    pub fn frameless(&self) -> bool {
+        let macros = macros_bound(&self.env);
+        let (frameless, found_at) = self.frameless.get();
+        if found_at == macros {
+            return frameless;
+        }
+        let frameless = binds_nothing(&self.params, &self.exp, &self.env);
+        self.frameless.set((frameless, macros));
+        frameless
+    }
+}
+
+// User functions are equal if they were created from the same code in the same environment;
+// comparing environments structurally could recurse forever through the function itself
+impl PartialEq for AFuncData {
+    fn eq(&self, other: &AFuncData) -> bool {
+        Rc::ptr_eq(&self.exp, &other.exp) && Rc::ptr_eq(&self.env, &other.env) &&
+        Rc::ptr_eq(&self.params, &other.params) && self.is_macro == other.is_macro
+    }
+}
+
+fn map_lookup(map: &FnvHashMap<AtomKey, AtomVal>,
+              key: AtomVal,
+              default: Option<&AtomVal>)
+              -> AtomVal {
+    AtomKey::new(key)
+        .ok()
+        .and_then(|key| map.get(&key).cloned())
+        .unwrap_or_else(|| default.cloned().unwrap_or_else(c_nil))
+}
+
+fn sorted_lookup(map: &BTreeMap<SortKey, AtomVal>,
+                 key: AtomVal,
+                 default: Option<&AtomVal>)
+                 -> AtomVal {
+    SortKey::new(key)
+        .ok()
+        .and_then(|key| map.get(&key).cloned())
+        .unwrap_or_else(|| default.cloned().unwrap_or_else(c_nil))
 }
 
 impl Display for AtomType {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        write!(f, "{}", self.format(false))
+        self.write_to(f, false)
+    }
+}
+
+impl Debug for AtomType {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        self.write_to(f, true)
     }
 }
 
+thread_local! {
+    static PRINTING: RefCell<Vec<usize>> = RefCell::new(vec![]);
+}
+
+// Items separated by spaces between open and close
+fn write_seq<'a, W, I>(out: &mut W, open: &str, items: I, close: &str, with_type: bool) -> Result
+    where W: Write,
+          I: IntoIterator<Item = &'a AtomVal>
+{
+    out.write_str(open)?;
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.write_char(' ')?;
+        }
+        item.write_to(out, with_type)?;
+    }
+    out.write_str(close)
+}
+
+fn write_pairs<'a, W, I>(out: &mut W, open: &str, pairs: I, close: &str, with_type: bool) -> Result
+    where W: Write,
+          I: IntoIterator<Item = (&'a AtomVal, &'a AtomVal)>
+{
+    out.write_str(open)?;
+    for (i, (key, value)) in pairs.into_iter().enumerate() {
+        if i > 0 {
+            out.write_char(' ')?;
+        }
+        key.write_to(out, with_type)?;
+        out.write_char(' ')?;
+        value.write_to(out, with_type)?;
+    }
+    out.write_str(close)
+}
+
 impl AtomType {
     pub fn format(&self, with_type: bool) -> String {
+        let mut output = String::new();
+        // writing to a String can't fail
+        self.write_to(&mut output, with_type).unwrap();
+        output
+    }
+
+    // Writes the printed form straight into out, without a String per nested value.
+    // Collections currently being printed are tracked, so a collection containing itself
+    // prints as #cycle instead of recursing forever
+    pub fn write_to<W: Write>(&self, out: &mut W, with_type: bool) -> Result {
+        match *self {
+            AtomType::List(..) |
+            AtomType::Vector(_) |
+            AtomType::Map(_) |
+            AtomType::Queue(_) |
+            AtomType::SortedMap(_) |
+            AtomType::SortedSet(_) |
+            AtomType::PriorityQueue(_) |
+            AtomType::Record(_) => {}
+            _ => return self.write_value(out, with_type),
+        }
+
+        let address = self as *const AtomType as usize;
+        let is_cycle = PRINTING.with(|printing| {
+            let mut printing = printing.borrow_mut();
+            if printing.contains(&address) {
+                true
+            } else {
+                printing.push(address);
+                false
+            }
+        });
+        if is_cycle {
+            return out.write_str("#cycle");
+        }
+
+        let result = self.write_value(out, with_type);
+        PRINTING.with(|printing| printing.borrow_mut().pop());
+        result
+    }
+
+    fn write_value<W: Write>(&self, out: &mut W, with_type: bool) -> Result {
         if with_type {
             match self {
-                &AtomType::Int(num) => format!("Int({})", num),
-                &AtomType::List(ref seq) => {
-                    let list = seq.iter()
-                        .map(|ref v| v.format(true))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-
-                    format!("List({})", list)
+                &AtomType::Int(num) => write!(out, "Int({})", num),
+                &AtomType::Str(ref string) => write!(out, "String({:?})", string),
+                &AtomType::List(ref seq, _) => write_seq(out, "List(", seq, ")", true),
+                &AtomType::Vector(ref seq) => write_seq(out, "Vector(", seq, ")", true),
+                &AtomType::Map(ref map) => {
+                    write_pairs(out, "Map(", map.iter().map(|(k, v)| (k.value(), v)), ")", true)
+                }
+                &AtomType::Queue(ref queue) => write_seq(out, "Queue(", &queue.values(), ")", true),
+                &AtomType::SortedMap(ref map) => {
+                    let pairs = map.iter().map(|(k, v)| (k.value(), v));
+                    write_pairs(out, "SortedMap(", pairs, ")", true)
+                }
+                &AtomType::SortedSet(ref set) => {
+                    write_seq(out, "SortedSet(", set.iter().map(|k| k.value()), ")", true)
+                }
+                &AtomType::PriorityQueue(ref queue) => {
+                    write_seq(out, "PriorityQueue(", &queue.values(), ")", true)
+                }
+                &AtomType::Nil => out.write_str("Nil()"),
+                &AtomType::Bool(b) => write!(out, "Bool({})", b),
+                &AtomType::Symbol(ref symbol, _) => write!(out, "Symbol({})", symbol),
+                &AtomType::Keyword(ref keyword) => write!(out, "Keyword({})", keyword),
+                &AtomType::Func(_) => out.write_str("#func()"),
+                &AtomType::Generator(_) => out.write_str("#generator()"),
+                &AtomType::Transient(ref transient) => {
+                    write!(out, "Transient({})", transient.kind())
+                }
+                &AtomType::Env(_) => out.write_str("#env()"),
+                &AtomType::Continuation(_) => out.write_str("#continuation()"),
+                #[cfg(feature = "std")]
+                &AtomType::Handle(ref handle) => write!(out, "#handle({})", handle.name()),
+                &AtomType::Protocol(ref protocol) => write!(out, "#protocol({})", protocol.name()),
+                &AtomType::Method(ref method) => write!(out, "#method({})", method.name()),
+                &AtomType::MultiFn(ref multi) => write!(out, "#multimethod({})", multi.name()),
+                &AtomType::RecordType(ref rtype) => write!(out, "#record({})", rtype.name()),
+                &AtomType::WeakRef(_) => out.write_str("#weak()"),
+                &AtomType::Regex(ref regex) => write!(out, "Regex({:?})", regex.as_str()),
+                &AtomType::Record(ref record) => {
+                    write!(out, "Record({}", record.rtype().name())?;
+                    for (k, v) in record.fields() {
+                        write!(out, " Keyword({}) ", k)?;
+                        v.write_to(out, true)?;
+                    }
+                    out.write_char(')')
                 }
-                &AtomType::Nil => format!("Nil()"),
-                &AtomType::Symbol(ref symbol) => format!("Symbol({})", symbol),
-                &AtomType::Func(_) => format!("#func()"),
                 &AtomType::AFunc(ref data) => {
                     let _type = if data.is_macro {
                         "macro"
@@ -66,65 +461,149 @@ impl AtomType {
                         "builtin_func"
                     };
 
-                    format!("#{}(exp={} params={})",
-                            _type,
-                            data.exp,
-                            data.params.format(true))
+                    write!(out, "#{}(exp=", _type)?;
+                    data.exp.write_to(out, false)?;
+                    out.write_str(" params=")?;
+                    data.params.write_to(out, true)?;
+                    out.write_char(')')
                 }
             }
         } else {
             match self {
-                &AtomType::Int(num) => format!("{}", num),
-                &AtomType::List(ref seq) => {
-                    let list = seq.iter()
-                        .map(|ref v| v.format(false))
-                        .collect::<Vec<_>>()
-                        .join(" ");
-
-                    format!("({})", list)
+                &AtomType::Int(num) => write!(out, "{}", num),
+                &AtomType::Str(ref string) => out.write_str(string),
+                &AtomType::List(ref seq, _) => write_seq(out, "(", seq, ")", false),
+                &AtomType::Vector(ref seq) => write_seq(out, "[", seq, "]", false),
+                &AtomType::Map(ref map) => {
+                    write_pairs(out, "{", map.iter().map(|(k, v)| (k.value(), v)), "}", false)
+                }
+                &AtomType::Queue(ref queue) => {
+                    write_seq(out, "#queue(", &queue.values(), ")", false)
+                }
+                &AtomType::SortedMap(ref map) => {
+                    write_pairs(out, "{", map.iter().map(|(k, v)| (k.value(), v)), "}", false)
+                }
+                &AtomType::SortedSet(ref set) => {
+                    write_seq(out, "#{", set.iter().map(|k| k.value()), "}", false)
+                }
+                &AtomType::PriorityQueue(ref queue) => {
+                    write_seq(out, "#priority-queue(", &queue.values(), ")", false)
+                }
+                &AtomType::Nil => out.write_str("nil"),
+                &AtomType::Bool(b) => write!(out, "{}", b),
+                &AtomType::Symbol(ref symbol, _) => out.write_str(symbol),
+                &AtomType::Keyword(ref keyword) => write!(out, ":{}", keyword),
+                &AtomType::Func(_) => out.write_str("#func()"),
+                &AtomType::Generator(_) => out.write_str("#generator()"),
+                &AtomType::Transient(ref transient) => {
+                    write!(out, "#transient({})", transient.kind())
+                }
+                &AtomType::Env(_) => out.write_str("#env()"),
+                &AtomType::Continuation(_) => out.write_str("#continuation()"),
+                #[cfg(feature = "std")]
+                &AtomType::Handle(ref handle) => write!(out, "#handle({})", handle.name()),
+                &AtomType::Protocol(ref protocol) => write!(out, "#protocol({})", protocol.name()),
+                &AtomType::Method(ref method) => write!(out, "#method({})", method.name()),
+                &AtomType::MultiFn(ref multi) => write!(out, "#multimethod({})", multi.name()),
+                &AtomType::RecordType(ref rtype) => write!(out, "#record({})", rtype.name()),
+                &AtomType::WeakRef(_) => out.write_str("#weak()"),
+                &AtomType::Regex(ref regex) => write!(out, "#\"{}\"", regex.as_str()),
+                &AtomType::Record(ref record) => {
+                    write!(out, "#{}{{", record.rtype().name())?;
+                    for (i, (k, v)) in record.fields().enumerate() {
+                        if i > 0 {
+                            out.write_char(' ')?;
+                        }
+                        write!(out, ":{} ", k)?;
+                        v.write_to(out, false)?;
+                    }
+                    out.write_char('}')
                 }
-                &AtomType::Nil => format!("nil"),
-                &AtomType::Symbol(ref symbol) => format!("{}", symbol),
-                &AtomType::Func(_) => format!("#func()"),
                 &AtomType::AFunc(ref data) => {
                     if data.is_macro {
-                        format!("#macro()")
+                        out.write_str("#macro()")
                     } else {
-                        format!("#builtin_func()")
+                        out.write_str("#builtin_func()")
                     }
                 },
             }
         }
     }
 
-
     pub fn apply(&self, args: &[AtomVal]) -> AtomRet {
+        stats::function_applied();
         match *self {
-            AtomType::Func(ref f) => f.0(args),
+            // a bug in a builtin shouldn't take the host down with it
+            AtomType::Func(ref f) => {
+                catch_panic(|| f.0(args)).unwrap_or_else(|message| Err(AtomError::Panic(message)))
+            }
+            AtomType::AFunc(ref fd) if fd.frameless() => eval_body(fd, &fd.env),
             AtomType::AFunc(ref fd) => {
                 let func_env = c_env(Some(fd.env.clone()));
-                match *fd.params {
-                    AtomType::List(ref params) => {
-                        env_bind(&func_env, params, args);
-
-                        if let Some(args_count) = params.iter().position(|v| v.is_symbol("&")) {
-                            if let Some(restpar) = params.get(args_count + 1) {
-                                let rest = args.iter().skip(args_count).cloned().collect::<Vec<_>>();
-                                if !rest.is_empty() {
-                                    env_set(&func_env, restpar, c_list(rest));
-                                } else {
-                                    env_set(&func_env, restpar, c_nil());
-                                }
-                            }
-                        }
-
-                    },
-                    ref v => return Err(AtomError::InvalidType("list".to_string(), v.format(true)))
-                }
+                env_bind(&func_env, fd.params.get_seq()?, args)?;
 
                 trace!("action=AtomType#apply env={:?}", func_env);
-                eval(&fd.exp, &func_env)
+                eval_body(fd, &func_env)
             },
+            // (:key map default?), also looks up record fields
+            AtomType::Keyword(ref keyword) => {
+                match args.get(0).map(|map| &**map) {
+                    Some(&AtomType::Map(ref map)) => {
+                        let key = alloc(AtomType::Keyword(keyword.clone()));
+                        Ok(map_lookup(map, key, args.get(1)))
+                    }
+                    Some(&AtomType::SortedMap(ref map)) => {
+                        let key = alloc(AtomType::Keyword(keyword.clone()));
+                        Ok(sorted_lookup(map, key, args.get(1)))
+                    }
+                    Some(&AtomType::Record(ref record)) => {
+                        Ok(record.get(keyword)
+                            .or(args.get(1))
+                            .cloned()
+                            .unwrap_or_else(c_nil))
+                    }
+                    _ => Ok(args.get(1).cloned().unwrap_or_else(c_nil)),
+                }
+            }
+            // (map key default?)
+            AtomType::Map(ref map) => {
+                let key = args.get(0).cloned().unwrap_or_else(c_nil);
+                Ok(map_lookup(map, key, args.get(1)))
+            }
+            AtomType::SortedMap(ref map) => {
+                let key = args.get(0).cloned().unwrap_or_else(c_nil);
+                Ok(sorted_lookup(map, key, args.get(1)))
+            }
+            // (set x default?) is x if it's in set
+            AtomType::SortedSet(ref set) => {
+                let key = args.get(0).cloned().unwrap_or_else(c_nil);
+                Ok(SortKey::new(key)
+                    .ok()
+                    .and_then(|key| set.get(&key).map(|key| key.value().clone()))
+                    .unwrap_or_else(|| args.get(1).cloned().unwrap_or_else(c_nil)))
+            }
+            // (vector index)
+            AtomType::Vector(ref seq) => {
+                let index = match args.get(0) {
+                    Some(index) => index.get_int()?,
+                    None => return Err(AtomError::InvalidArgument("missing index".to_string())),
+                };
+
+                match seq.get(index as usize) {
+                    Some(value) if index >= 0 => Ok(value.clone()),
+                    _ => Err(AtomError::InvalidArgument(format!("index {} out of bounds", index))),
+                }
+            }
+            AtomType::Method(ref method) => method.dispatch(args)?.apply(args),
+            AtomType::MultiFn(ref multi) => {
+                let value = multi.dispatch_fn().apply(args)?;
+                multi.select(&value)?.apply(args)
+            }
+            AtomType::Continuation(_) => {
+                Err(AtomError::InvalidOperation("continuation invoked outside of the evaluation \
+                                                 which captured it"
+                    .to_string()))
+            }
             _ => Err(AtomError::InvalidType("function".to_string(), self.format(true)))
         }
     }
@@ -141,16 +620,34 @@ impl AtomType {
     pub fn get_list(&self) -> result::Result<&Vec<AtomVal>, AtomError>{
         trace!("action=AtomType#get_list self={}", self.format(true));
         match *self {
-            AtomType::List(ref list) => Ok(list),
+            AtomType::List(ref list, _) => Ok(list),
             _ => Err(AtomError::InvalidType("List".to_string(), self.format(true))),
         }
 
     }
 
+    // List or Vector, for places where both are accepted interchangeably
+    #[inline]
+    pub fn get_seq(&self) -> result::Result<&[AtomVal], AtomError> {
+        match *self {
+            AtomType::List(ref list, _) => Ok(list),
+            AtomType::Vector(ref vector) => Ok(vector.as_slice()),
+            _ => Err(AtomError::InvalidType("List or Vector".to_string(), self.format(true))),
+        }
+    }
+
+    #[inline]
+    pub fn get_map(&self) -> result::Result<&FnvHashMap<AtomKey, AtomVal>, AtomError> {
+        match *self {
+            AtomType::Map(ref map) => Ok(map),
+            _ => Err(AtomError::InvalidType("Map".to_string(), self.format(true))),
+        }
+    }
+
     #[inline]
     pub fn get_symbol(&self) -> result::Result<&str, AtomError> {
         match *self {
-            AtomType::Symbol(ref s) => Ok(s),
+            AtomType::Symbol(ref s, _) => Ok(s),
             _ => Err(AtomError::InvalidType("Symbol".to_string(), self.format(true))),
         }
     }
@@ -158,14 +655,85 @@ impl AtomType {
     #[inline]
     pub fn is_symbol(&self, sym: &str) -> bool {
         match *self {
-            AtomType::Symbol(ref s) => **s == sym,
+            AtomType::Symbol(ref s, _) => **s == sym,
+            _ => false
+        }
+    }
+
+    // Values made of atoms and collections of them, as opposed to functions and other
+    // values with identity only
+    pub fn is_hashable(&self) -> bool {
+        match *self {
+            AtomType::Nil |
+            AtomType::Bool(_) |
+            AtomType::Int(_) |
+            AtomType::Str(_) |
+            AtomType::Symbol(..) |
+            AtomType::Keyword(_) => true,
+            AtomType::List(ref seq, _) => seq.iter().all(|v| v.is_hashable()),
+            AtomType::Vector(ref seq) => seq.iter().all(|v| v.is_hashable()),
+            AtomType::Map(ref map) => map.values().all(|v| v.is_hashable()),
+            AtomType::Queue(ref queue) => queue.values().iter().all(|v| v.is_hashable()),
+            AtomType::SortedMap(ref map) => map.values().all(|v| v.is_hashable()),
+            AtomType::SortedSet(_) => true,
+            AtomType::Record(ref record) => record.fields().all(|(_, v)| v.is_hashable()),
+            _ => false,
+        }
+    }
+
+    fn type_index(&self) -> usize {
+        match *self {
+            AtomType::Nil => 0,
+            AtomType::Bool(_) => 1,
+            AtomType::Int(_) => 2,
+            AtomType::Str(_) => 3,
+            AtomType::Symbol(..) => 4,
+            AtomType::Keyword(_) => 5,
+            AtomType::List(..) => 6,
+            AtomType::Vector(_) => 7,
+            AtomType::Map(_) => 8,
+            AtomType::Func(_) => 9,
+            AtomType::AFunc(_) => 10,
+            AtomType::Generator(_) => 11,
+            AtomType::Continuation(_) => 12,
+            #[cfg(feature = "std")]
+            AtomType::Handle(_) => 13,
+            AtomType::Protocol(_) => 14,
+            AtomType::Method(_) => 15,
+            AtomType::MultiFn(_) => 16,
+            AtomType::RecordType(_) => 17,
+            AtomType::Record(_) => 18,
+            AtomType::WeakRef(_) => 19,
+            AtomType::Regex(_) => 20,
+            AtomType::Queue(_) => 21,
+            AtomType::SortedMap(_) => 22,
+            AtomType::SortedSet(_) => 23,
+            AtomType::PriorityQueue(_) => 24,
+            AtomType::Transient(_) => 25,
+            AtomType::Env(_) => 26,
+        }
+    }
+
+    // Only nil and false are false in conditions
+    #[inline]
+    pub fn is_truthy(&self) -> bool {
+        match *self {
+            AtomType::Nil | AtomType::Bool(false) => false,
+            _ => true,
+        }
+    }
+
+    #[inline]
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        match *self {
+            AtomType::Keyword(ref s) => **s == keyword,
             _ => false
         }
     }
 }
 
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum AtomError {
     // expected, received
     InvalidType(String, String),
@@ -174,6 +742,41 @@ pub enum AtomError {
     // message
     InvalidArgument(String),
     UndefinedSymbol(String),
+    // condition raised by `error`
+    Condition(AtomVal),
+    // restart name, args; unwinds to the matching restart-case
+    Restart(String, Vec<AtomVal>),
+    // message of a panic caught in a native function
+    Panic(String),
+}
+
+static QUIET_PANICS: Once = Once::new();
+
+// Runs f, a panic in it handed back as its message instead of printed by the panic hook
+pub fn catch_panic<R, F: FnOnce() -> R>(f: F) -> result::Result<R, String> {
+    QUIET_PANICS.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING_PANICS.with(Cell::get) == 0 {
+                hook(info);
+            }
+        }));
+    });
+    CATCHING_PANICS.with(|catching| catching.set(catching.get() + 1));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING_PANICS.with(|catching| catching.set(catching.get() - 1));
+    result.map_err(panic_message)
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => {
+            payload.downcast_ref::<&'static str>()
+                .map(|message| message.to_string())
+                .unwrap_or_else(|| "unknown panic".to_string())
+        }
+    }
 }
 
 
@@ -188,50 +791,299 @@ impl Display for AtomError {
             InvalidOperation(ref op) => format!("invalid operation: {}", op),
             InvalidArgument(ref op) => format!("invalid argument: {}", op),
             UndefinedSymbol(ref op) => format!("undefined symbol: {}", op),
+            Condition(ref condition) => format!("unhandled condition: {}", condition),
+            Restart(ref name, _) => format!("no restart named {}", name),
+            Panic(ref message) => format!("native function panicked: {}", message),
         };
 
         write!(f, "{}", output)
     }
 }
 
-pub type AtomVal = Rc<AtomType>;
+pub type AtomVal = Rc<AtomType, Arena>;
+
+pub const ATOM_TYPES: usize = 27;
+
+// Indexed by AtomType::type_index
+pub const ATOM_TYPE_NAMES: [&'static str; ATOM_TYPES] = ["nil",
+                                                        "bool",
+                                                        "int",
+                                                        "string",
+                                                        "symbol",
+                                                        "keyword",
+                                                        "list",
+                                                        "vector",
+                                                        "map",
+                                                        "native-fn",
+                                                        "fn",
+                                                        "generator",
+                                                        "continuation",
+                                                        "handle",
+                                                        "protocol",
+                                                        "method",
+                                                        "multimethod",
+                                                        "record-type",
+                                                        "record",
+                                                        "weak-ref",
+                                                        "regex",
+                                                        "queue",
+                                                        "sorted-map",
+                                                        "sorted-set",
+                                                        "priority-queue",
+                                                        "transient",
+                                                        "env"];
 pub type AtomRet = result::Result<AtomVal, AtomError>;
 
 
+// How symbol names are interned; see share_symbols
+pub type Intern = fn(&str) -> Rc<String>;
+
+// Names of the symbols alive, dead ones are dropped whenever the table has doubled, so
+// gensyms don't pile up
+struct Symbols {
+    names: FnvHashMap<String, Weak<String>>,
+    // size at which dead names are dropped next
+    limit: usize,
+}
+
+const SYMBOLS_KEPT: usize = 1024;
+
 thread_local! {
-    static NIL: AtomVal = Rc::new(AtomType::Nil);
+    static NIL: AtomVal = alloc(AtomType::Nil);
+    static SYMBOLS: RefCell<Symbols> = RefCell::new(Symbols {
+        names: FnvHashMap::default(),
+        limit: SYMBOLS_KEPT,
+    });
+    static INTERN: Cell<Intern> = Cell::new(intern_here);
+    // catch_panics running, panics in them aren't printed
+    static CATCHING_PANICS: Cell<usize> = Cell::new(0);
+}
+
+// Every atom is created here, so live atoms can be counted
+pub fn alloc(atom: AtomType) -> AtomVal {
+    stats::atom_allocated(atom.type_index());
+    Rc::new_in(atom, Arena)
+}
+
+impl Drop for AtomType {
+    fn drop(&mut self) {
+        stats::atom_freed(self.type_index());
+    }
+}
+
+// Symbols with the same name share their string, envs compare names by its address
+pub fn intern(name: &str) -> Rc<String> {
+    INTERN.with(|intern| intern.get())(name)
+}
+
+fn intern_here(name: &str) -> Rc<String> {
+    SYMBOLS.with(|symbols| {
+        let mut symbols = symbols.borrow_mut();
+        if let Some(interned) = symbols.names.get(name).and_then(Weak::upgrade) {
+            return interned;
+        }
+        if symbols.names.len() >= symbols.limit {
+            symbols.names.retain(|_, interned| interned.upgrade().is_some());
+            symbols.limit = cmp::max(SYMBOLS_KEPT, symbols.names.len() * 2);
+        }
+        let interned = Rc::new(name.to_string());
+        symbols.names.insert(name.to_string(), Rc::downgrade(&interned));
+        interned
+    })
+}
+
+// A plugin links its own copy of this crate, which interns symbols the interpreter's envs
+// wouldn't find; it's handed the interpreter's intern to use instead
+pub fn share_symbols(intern: Intern) {
+    INTERN.with(|current| current.set(intern));
+}
+
+// Those still in use
+pub fn interned_symbols() -> usize {
+    SYMBOLS.with(|symbols| {
+        symbols.borrow().names.values().filter(|interned| interned.upgrade().is_some()).count()
+    })
 }
 
 pub fn c_nil() -> AtomVal {
     NIL.with(|nil| nil.clone())
 }
 
+pub fn c_bool(b: bool) -> AtomVal {
+    alloc(AtomType::Bool(b))
+}
+
 pub fn c_int(num: i64) -> AtomVal {
-    Rc::new(AtomType::Int(num))
+    alloc(AtomType::Int(num))
+}
+
+pub fn c_string(string: &str) -> AtomVal {
+    alloc(AtomType::Str(SharedStr::new(string)))
+}
+
+pub fn c_shared_string(string: SharedStr) -> AtomVal {
+    alloc(AtomType::Str(string))
 }
 
 pub fn c_symbol(symbol: &str) -> AtomVal {
-    Rc::new(AtomType::Symbol(Rc::new(symbol.to_string())))
+    alloc(AtomType::Symbol(intern(symbol), SymbolCache::default()))
+}
+
+pub fn c_keyword(keyword: &str) -> AtomVal {
+    alloc(AtomType::Keyword(Rc::new(keyword.to_string())))
 }
 
 pub fn c_list(seq: Vec<AtomVal>) -> AtomVal {
-    Rc::new(AtomType::List(seq))
+    alloc(AtomType::List(seq, FormCache::default()))
+}
+
+pub fn c_list_from_iter<I: IntoIterator<Item = AtomVal>>(values: I) -> AtomVal {
+    let mut builder = ListBuilder::new();
+    builder.extend(values);
+    builder.finish()
+}
+
+// Gathers the elements of a list in the Vec the list ends up owning, sized up front where the
+// count is known, instead of collecting into one and copying
+pub struct ListBuilder(Vec<AtomVal>);
+
+impl Default for ListBuilder {
+    fn default() -> ListBuilder {
+        ListBuilder::new()
+    }
+}
+
+impl ListBuilder {
+    pub fn new() -> ListBuilder {
+        ListBuilder(Vec::new())
+    }
+
+    pub fn with_capacity(capacity: usize) -> ListBuilder {
+        ListBuilder(Vec::with_capacity(capacity))
+    }
+
+    pub fn push(&mut self, value: AtomVal) {
+        self.0.push(value);
+    }
+
+    pub fn extend<I: IntoIterator<Item = AtomVal>>(&mut self, values: I) {
+        self.0.extend(values);
+    }
+
+    pub fn finish(self) -> AtomVal {
+        c_list(self.0)
+    }
+}
+
+pub fn c_vector(seq: Vec<AtomVal>) -> AtomVal {
+    alloc(AtomType::Vector(PersistentVector::new(seq)))
+}
+
+pub fn c_persistent_vector(vector: PersistentVector) -> AtomVal {
+    alloc(AtomType::Vector(vector))
+}
+
+pub fn c_map(map: FnvHashMap<AtomKey, AtomVal>) -> AtomVal {
+    alloc(AtomType::Map(map))
+}
+
+pub fn c_doc_func<F>(f: F, doc: &'static str) -> AtomVal
+    where F: Fn(&[AtomVal]) -> AtomRet + 'static
+{
+    alloc(AtomType::Func(AtomFn(Rc::new(f), Some(doc))))
+}
+
+// Native function capturing its environment, e.g. the result of `partial`
+pub fn c_closure<F>(f: F) -> AtomVal
+    where F: Fn(&[AtomVal]) -> AtomRet + 'static
+{
+    alloc(AtomType::Func(AtomFn(Rc::new(f), None)))
+}
+
+
+pub fn c_afunc(env: Env,
+               params: AtomVal,
+               exp: AtomVal,
+               name: Option<String>,
+               doc: Option<String>)
+               -> AtomVal {
+    gc::track(&env);
+    let frameless = Cell::new((binds_nothing(&params, &exp, &env), macros_bound(&env)));
+    alloc(AtomType::AFunc(AFuncData { exp, env, params, name, doc, is_macro: false, frameless }))
+}
+
+pub fn c_generator(func: AtomVal, args: Vec<AtomVal>) -> AtomVal {
+    alloc(AtomType::Generator(Generator::new(func, args)))
+}
+
+pub fn c_continuation(k: Continuation) -> AtomVal {
+    alloc(AtomType::Continuation(k))
+}
+
+#[cfg(feature = "std")]
+pub fn c_handle(handle: Handle) -> AtomVal {
+    alloc(AtomType::Handle(handle))
+}
+
+pub fn c_protocol(name: &str, methods: Vec<AtomVal>) -> AtomVal {
+    alloc(AtomType::Protocol(Protocol::new(name, methods)))
+}
+
+pub fn c_method(name: &str, protocol: &str) -> AtomVal {
+    alloc(AtomType::Method(Method::new(name, protocol)))
 }
 
-pub fn c_func(f: fn(&[AtomVal]) -> AtomRet) -> AtomVal {
-    Rc::new(AtomType::Func(AtomFn(f)))
+pub fn c_multi_fn(name: &str, dispatch: AtomVal) -> AtomVal {
+    alloc(AtomType::MultiFn(MultiFn::new(name, dispatch)))
 }
 
+pub fn c_record_type(rtype: RecordType) -> AtomVal {
+    alloc(AtomType::RecordType(Rc::new(rtype)))
+}
+
+pub fn c_record(record: Record) -> AtomVal {
+    alloc(AtomType::Record(record))
+}
+
+pub fn c_queue(queue: Queue) -> AtomVal {
+    alloc(AtomType::Queue(queue))
+}
+
+pub fn c_sorted_map(map: BTreeMap<SortKey, AtomVal>) -> AtomVal {
+    alloc(AtomType::SortedMap(map))
+}
+
+pub fn c_sorted_set(set: BTreeSet<SortKey>) -> AtomVal {
+    alloc(AtomType::SortedSet(set))
+}
 
-pub fn c_afunc(env: Env, params: AtomVal, exp: AtomVal) -> AtomVal {
-    Rc::new(AtomType::AFunc(AFuncData { exp, env, params, is_macro: false }))
+pub fn c_priority_queue(queue: PriorityQueue) -> AtomVal {
+    alloc(AtomType::PriorityQueue(queue))
 }
 
-pub fn c_macro(fd: &AFuncData) -> AtomVal {
+pub fn c_transient(transient: Transient) -> AtomVal {
+    alloc(AtomType::Transient(transient))
+}
+
+pub fn c_env_value(env: Env) -> AtomVal {
+    gc::track(&env);
+    alloc(AtomType::Env(env))
+}
+
+pub fn c_weak_ref(value: &AtomVal) -> AtomVal {
+    alloc(AtomType::WeakRef(WeakAtom::new(value)))
+}
+
+pub fn c_regex(regex: Regex) -> AtomVal {
+    alloc(AtomType::Regex(regex))
+}
+
+pub fn c_macro(fd: &AFuncData, name: &str) -> AtomVal {
     let mut fd = fd.clone();
+    fd.name = Some(name.to_string());
     fd.is_macro = true;
 
-    Rc::new(AtomType::AFunc(fd))
+    alloc(AtomType::AFunc(fd))
 }
 
 
@@ -241,6 +1093,13 @@ mod tests {
     use super::c_int;
     use super::c_symbol;
     use super::c_list;
+    use super::c_vector;
+    use super::c_keyword;
+    use super::c_string;
+    use super::c_map;
+    use super::AtomKey;
+    use std::hash::{Hash, Hasher};
+    use fnv::{FnvHashMap, FnvHasher};
 
     #[test]
     fn test_nil() {
@@ -277,4 +1136,104 @@ mod tests {
 
         assert_eq!(format!("{}", list2), "((0 1) 2)");
     }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(format!("{}", c_string("test")), "test");
+        assert_eq!(c_string("a\"b").format(true), "String(\"a\\\"b\")");
+    }
+
+    #[test]
+    fn test_keyword() {
+        assert_eq!(format!("{}", c_keyword("test")), ":test");
+    }
+
+    #[test]
+    fn test_vector() {
+        let list = c_list(vec![c_int(1)]);
+        let vector = c_vector(vec![c_int(0), list]);
+
+        assert_eq!(format!("{}", vector), "[0 (1)]");
+    }
+
+    fn hash_of(key: &AtomKey) -> u64 {
+        let mut hasher = FnvHasher::default();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut forward = FnvHashMap::default();
+        let mut backward = FnvHashMap::default();
+        for i in 0..10 {
+            forward.insert(AtomKey::new(c_int(i)).unwrap(), c_list(vec![c_int(i)]));
+            backward.insert(AtomKey::new(c_int(9 - i)).unwrap(), c_list(vec![c_int(9 - i)]));
+        }
+
+        let forward = AtomKey::new(c_map(forward)).unwrap();
+        let backward = AtomKey::new(c_map(backward)).unwrap();
+        assert_eq!(forward, backward);
+        assert_eq!(hash_of(&forward), hash_of(&backward));
+
+        assert!(AtomKey::new(c_list(vec![c_int(1), c_vector(vec![c_nil()])])).is_ok());
+        assert!(AtomKey::new(c_list(vec![c_int(1), super::c_closure(|_| Ok(c_nil()))])).is_err());
+    }
+
+    #[test]
+    fn test_write_to() {
+        let value = c_list(vec![c_int(1), c_vector(vec![c_keyword("a"), c_nil()])]);
+        assert_eq!(format!("{}", value), "(1 [:a nil])");
+        assert_eq!(format!("{:?}", value), "List(Int(1) Vector(Keyword(a) Nil()))");
+        let mut out = String::from("> ");
+        value.write_to(&mut out, false).unwrap();
+        assert_eq!(out, "> (1 [:a nil])");
+    }
+
+    #[test]
+    fn test_list_builder() {
+        let mut builder = super::ListBuilder::with_capacity(2);
+        builder.push(c_int(1));
+        builder.extend(vec![c_int(2), c_int(3)]);
+        assert_eq!(builder.finish(), c_list(vec![c_int(1), c_int(2), c_int(3)]));
+        assert_eq!(super::c_list_from_iter((0..2).map(c_int)),
+                   c_list(vec![c_int(0), c_int(1)]));
+    }
+
+    #[test]
+    fn test_weak() {
+        let value = c_list(vec![c_int(1)]);
+        let weak = super::WeakAtom::new(&value);
+        assert_eq!(weak.upgrade(), Some(value.clone()));
+        assert_eq!(weak, super::WeakAtom::new(&value));
+
+        drop(value);
+        assert_eq!(weak.upgrade(), None);
+        assert!(weak != weak.clone());
+    }
+
+    #[test]
+    fn test_cycle() {
+        let shared = c_vector(vec![c_int(1)]);
+        let outer = c_list(vec![shared.clone(), shared.clone()]);
+        assert_eq!(format!("{}", outer), "([1] [1])");
+        assert_eq!(format!("{:?}", outer), "List(Vector(Int(1)) Vector(Int(1)))");
+
+        // as if outer contained itself, while it is being printed
+        super::PRINTING.with(|printing| printing.borrow_mut().push(&*outer as *const _ as usize));
+        assert_eq!(format!("{}", c_list(vec![c_int(0), outer.clone()])), "(0 #cycle)");
+        super::PRINTING.with(|printing| printing.borrow_mut().clear());
+        assert_eq!(format!("{}", outer), "([1] [1])");
+    }
+
+    use test::Bencher;
+
+    #[bench]
+    fn bench_shared_equality(b: &mut Bencher) {
+        let shared = c_list((0..10000).map(|i| c_vector(vec![c_int(i)])).collect());
+        let left = c_list(vec![c_int(0), shared.clone()]);
+        let right = c_list(vec![c_int(0), shared]);
+
+        b.iter(|| assert_eq!(left, right));
+    }
 }
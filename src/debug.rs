@@ -0,0 +1,484 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::rc::{Rc, Weak};
+use fnv::{FnvHashMap, FnvHashSet};
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_closure, c_list, c_nil, c_doc_func,
+           c_symbol};
+use env::{Env, EnvType, c_env, env_bindings, env_get, env_parent, env_set};
+use eval::{eval, error_condition};
+use lexer::Lexer;
+use parser::Parser;
+
+const HELP: &'static str = ":step (:s) stops at the next form, :continue (:c) runs on, \
+                            :locals (:l) prints the local bindings, :stack (:bt) the forms being \
+                            evaluated, :abort (:a) fails the evaluation, anything else is \
+                            evaluated here";
+
+// Where an error left the evaluator: the scope it happened in, the form that failed and the
+// forms being evaluated, innermost first
+struct Failure {
+    err: AtomError,
+    env: Env,
+    form: AtomVal,
+    forms: Vec<AtomVal>,
+}
+
+// Functions evaluation stops in, traced functions with the wrappers replacing them, the last
+// failure while post-mortem debugging and, for tests, lines read instead of stdin along with
+// what was printed
+#[derive(Default)]
+struct Debugger {
+    breakpoints: FnvHashSet<String>,
+    traced: FnvHashMap<String, (AtomVal, AtomVal)>,
+    failure: Option<Failure>,
+    script: Option<VecDeque<String>>,
+    transcript: Vec<String>,
+}
+
+thread_local! {
+    static DEBUGGER: RefCell<Debugger> = RefCell::new(Debugger::default());
+    // checked before every form, so kept apart from the rest
+    static STEPPING: Cell<bool> = Cell::new(false);
+    // forms evaluated in the debug REPL don't stop
+    static ACTIVE: Cell<bool> = Cell::new(false);
+    // traced calls currently running
+    static TRACE_DEPTH: Cell<usize> = Cell::new(0);
+    // the evaluator keeps track of where it is, to record failures
+    static POST_MORTEM: Cell<bool> = Cell::new(false);
+}
+
+fn set_stepping(stepping: bool) {
+    STEPPING.with(|cell| cell.set(stepping));
+}
+
+pub fn is_stepping() -> bool {
+    STEPPING.with(|stepping| stepping.get()) && !ACTIVE.with(|active| active.get())
+}
+
+pub fn has_breakpoints() -> bool {
+    DEBUGGER.with(|debugger| !debugger.borrow().breakpoints.is_empty())
+}
+
+pub fn is_breakpoint(name: &str) -> bool {
+    DEBUGGER.with(|debugger| debugger.borrow().breakpoints.contains(name))
+}
+
+pub fn set_post_mortem(enabled: bool) {
+    POST_MORTEM.with(|cell| cell.set(enabled));
+}
+
+pub fn is_post_mortem() -> bool {
+    POST_MORTEM.with(|post_mortem| post_mortem.get())
+}
+
+// Called by a machine giving up on err, nested machines abort before the ones waiting for them
+// and know better where it happened
+pub fn record_failure(err: &AtomError, env: Env, form: AtomVal, forms: Vec<AtomVal>) {
+    DEBUGGER.with(|debugger| {
+        let mut debugger = debugger.borrow_mut();
+        if debugger.failure.as_ref().map_or(false, |failure| failure.err == *err) {
+            return;
+        }
+        debugger.failure = Some(Failure {
+            err: err.clone(),
+            env: env,
+            form: form,
+            forms: forms,
+        });
+    });
+}
+
+pub fn has_failure() -> bool {
+    DEBUGGER.with(|debugger| debugger.borrow().failure.is_some())
+}
+
+pub fn clear_failure() {
+    DEBUGGER.with(|debugger| debugger.borrow_mut().failure = None);
+}
+
+// The printed form, cut short if it's long
+pub fn summary(form: &AtomVal) -> String {
+    let text = form.to_string();
+    if text.chars().count() <= 60 {
+        return text;
+    }
+    format!("{}...", text.chars().take(57).collect::<String>())
+}
+
+fn say(line: &str) {
+    println!("{}", line);
+    DEBUGGER.with(|debugger| {
+        let mut debugger = debugger.borrow_mut();
+        if debugger.script.is_some() {
+            debugger.transcript.push(line.to_string());
+        }
+    });
+}
+
+// None at the end of the input
+fn read_line() -> Option<String> {
+    let scripted = DEBUGGER.with(|debugger| {
+        debugger.borrow_mut().script.as_mut().map(|script| script.pop_front())
+    });
+    if let Some(line) = scripted {
+        return line;
+    }
+
+    print!("debug> ");
+    let _ = io::stdout().flush();
+    let mut line = String::new();
+    let stdin = io::stdin();
+    match stdin.lock().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
+}
+
+// Bindings of the scopes between env and the global environment, inner ones hiding outer ones
+fn locals(env: &Env) -> Vec<(String, AtomVal)> {
+    let mut locals = vec![];
+    let mut scope = env.clone();
+    while let Some(parent) = env_parent(&scope) {
+        for (name, value) in env_bindings(&scope) {
+            if !locals.iter().any(|local: &(String, AtomVal)| local.0 == name) {
+                locals.push((name, value));
+            }
+        }
+        scope = parent;
+    }
+    locals
+}
+
+fn evaluate(source: &str, env: &Env) -> AtomRet {
+    let forms = Parser::new(Lexer::new(source))
+        .parse_program()
+        .map_err(|err| AtomError::InvalidArgument(err.to_string()))?;
+    let mut result = c_nil();
+    for form in &forms {
+        result = eval(form, env)?;
+    }
+    Ok(result)
+}
+
+fn repl(env: &Env, forms: &[AtomVal]) -> Result<(), AtomError> {
+    loop {
+        let line = match read_line() {
+            Some(line) => line,
+            None => {
+                set_stepping(false);
+                return Ok(());
+            }
+        };
+        match line.trim() {
+            "" => {}
+            ":step" | ":s" => {
+                set_stepping(true);
+                return Ok(());
+            }
+            ":continue" | ":c" => {
+                set_stepping(false);
+                return Ok(());
+            }
+            ":locals" | ":l" => {
+                for (name, value) in locals(env) {
+                    say(&format!("{} = {}", name, value));
+                }
+            }
+            ":stack" | ":bt" => {
+                for (index, form) in forms.iter().enumerate() {
+                    say(&format!("#{} {}", index, summary(form)));
+                }
+            }
+            ":abort" | ":a" => {
+                set_stepping(false);
+                return Err(AtomError::InvalidOperation("aborted in the debugger".to_string()));
+            }
+            ":help" | ":h" => say(HELP),
+            source => {
+                match evaluate(source, env) {
+                    Ok(value) => say(&value.to_string()),
+                    Err(err) => say(&format!("error: {}", err)),
+                }
+            }
+        }
+    }
+}
+
+// Runs the debug REPL in env until it's told to go on, forms are the ones being evaluated,
+// innermost first
+pub fn enter(reason: &str, env: &Env, forms: &[AtomVal]) -> Result<(), AtomError> {
+    if ACTIVE.with(|active| active.get()) {
+        return Ok(());
+    }
+    ACTIVE.with(|active| active.set(true));
+    say(reason);
+    let result = repl(env, forms);
+    ACTIVE.with(|active| active.set(false));
+    result
+}
+
+// Runs the debug REPL where the last recorded failure happened, with the condition bound to
+// *error* and the form that failed to *form*; false if nothing failed
+pub fn post_mortem() -> bool {
+    let failure = match DEBUGGER.with(|debugger| debugger.borrow_mut().failure.take()) {
+        Some(failure) => failure,
+        None => return false,
+    };
+    let scope = c_env(Some(failure.env.clone()));
+    env_set(&scope, &c_symbol("*error*"), error_condition(&failure.err));
+    env_set(&scope, &c_symbol("*form*"), failure.form.clone());
+
+    let reason = format!("post-mortem: {} in {}", failure.err, summary(&failure.form));
+    let _ = enter(&reason, &scope, &failure.forms);
+    set_stepping(false);
+    clear_failure();
+    true
+}
+
+// (set-breakpoint! 'f), evaluation stops in the debug REPL whenever f is called
+fn set_breakpoint(args: &[AtomVal]) -> AtomRet {
+    let name = args.get(0).cloned().unwrap_or_else(c_nil);
+    let name = name.get_symbol()?;
+    DEBUGGER.with(|debugger| debugger.borrow_mut().breakpoints.insert(name.to_string()));
+    Ok(c_symbol(name))
+}
+
+// (clear-breakpoint! 'f), without a name every breakpoint is cleared
+fn clear_breakpoint(args: &[AtomVal]) -> AtomRet {
+    let name = match args.get(0) {
+        Some(name) => Some(name.get_symbol()?.to_string()),
+        None => None,
+    };
+    DEBUGGER.with(|debugger| {
+        let mut debugger = debugger.borrow_mut();
+        match name {
+            Some(ref name) => {
+                debugger.breakpoints.remove(name);
+            }
+            None => debugger.breakpoints.clear(),
+        }
+    });
+    Ok(c_nil())
+}
+
+// Prints the call and its value or error, indented by the traced calls it's nested in
+fn traced_call(name: &str, func: &AtomVal, args: &[AtomVal]) -> AtomRet {
+    let depth = TRACE_DEPTH.with(|depth| depth.get());
+    let indent = "| ".repeat(depth);
+    let mut call = vec![c_symbol(name)];
+    call.extend(args.iter().cloned());
+    say(&format!("{}{}", indent, c_list(call)));
+
+    TRACE_DEPTH.with(|cell| cell.set(depth + 1));
+    let result = func.apply(args);
+    TRACE_DEPTH.with(|cell| cell.set(depth));
+
+    match result {
+        Ok(ref value) => say(&format!("{}=> {}", indent, value)),
+        Err(ref err) => say(&format!("{}!! {}", indent, err)),
+    }
+    result
+}
+
+fn global_env(env: &Weak<RefCell<EnvType>>) -> Result<Env, AtomError> {
+    env.upgrade().ok_or_else(|| AtomError::InvalidOperation("interpreter is gone".to_string()))
+}
+
+// (trace 'f) replaces the global function f with a wrapper printing its calls
+fn trace(env: &Weak<RefCell<EnvType>>, args: &[AtomVal]) -> AtomRet {
+    let env = global_env(env)?;
+    let name_atom = args.get(0).cloned().unwrap_or_else(c_nil);
+    let name = name_atom.get_symbol()?.to_string();
+    if DEBUGGER.with(|debugger| debugger.borrow().traced.contains_key(&name)) {
+        return Ok(name_atom.clone());
+    }
+
+    let func = env_get(&env, &name_atom).ok_or_else(|| AtomError::UndefinedSymbol(name.clone()))?;
+    match *func {
+        AtomType::AFunc(ref fd) if fd.is_macro => {
+            return Err(AtomError::InvalidArgument(format!("macro {} can't be traced", name)))
+        }
+        AtomType::AFunc(_) | AtomType::Func(_) | AtomType::Method(_) | AtomType::MultiFn(_) => {}
+        ref other => return Err(AtomError::InvalidType("function".to_string(), other.format(true))),
+    }
+
+    let (wrapped_name, wrapped) = (name.clone(), func.clone());
+    let wrapper = c_closure(move |args| traced_call(&wrapped_name, &wrapped, args));
+    env_set(&env, &name_atom, wrapper.clone());
+    DEBUGGER.with(|debugger| debugger.borrow_mut().traced.insert(name, (func, wrapper)));
+    Ok(name_atom.clone())
+}
+
+// (untrace 'f) puts back the function trace replaced, unless f was defined again since;
+// without f every traced function
+fn untrace(env: &Weak<RefCell<EnvType>>, args: &[AtomVal]) -> AtomRet {
+    let env = global_env(env)?;
+    let names = match args.get(0) {
+        Some(name) => vec![name.get_symbol()?.to_string()],
+        None => DEBUGGER.with(|debugger| debugger.borrow().traced.keys().cloned().collect()),
+    };
+
+    for name in names {
+        let traced = DEBUGGER.with(|debugger| debugger.borrow_mut().traced.remove(&name));
+        if let Some((func, wrapper)) = traced {
+            let name = c_symbol(&name);
+            let current = env_get(&env, &name);
+            if current.map_or(false, |current| Rc::ptr_eq(&current, &wrapper)) {
+                env_set(&env, &name, func);
+            }
+        }
+    }
+    Ok(c_nil())
+}
+
+pub fn register(env: &Env) {
+    env_set(env,
+            &c_symbol("set-breakpoint!"),
+            c_doc_func(set_breakpoint,
+                       "(set-breakpoint! 'f) stops in the debug REPL whenever f is called, \
+                        (break) stops where it's evaluated"));
+    env_set(env,
+            &c_symbol("clear-breakpoint!"),
+            c_doc_func(clear_breakpoint,
+                       "(clear-breakpoint! 'f) removes the breakpoint of f, without f all \
+                        breakpoints"));
+
+    // weakly, env holds the builtins
+    let global = Rc::downgrade(env);
+    env_set(env,
+            &c_symbol("trace"),
+            c_doc_func(move |args| trace(&global, args),
+                       "(trace 'f) prints every call of the global function f with its args \
+                        and value, indented by the traced calls it's nested in"));
+    let global = Rc::downgrade(env);
+    env_set(env,
+            &c_symbol("untrace"),
+            c_doc_func(move |args| untrace(&global, args),
+                       "(untrace 'f) stops tracing f, without f every traced function"));
+}
+
+// Makes the debug REPL read lines instead of stdin, keeping what it and traces print
+#[cfg(test)]
+pub fn script(lines: &[&str]) {
+    DEBUGGER.with(|debugger| {
+        let mut debugger = debugger.borrow_mut();
+        debugger.script = Some(lines.iter().map(|line| line.to_string()).collect());
+        debugger.transcript.clear();
+    });
+}
+
+#[cfg(test)]
+pub fn transcript() -> Vec<String> {
+    DEBUGGER.with(|debugger| debugger.borrow().transcript.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{has_failure, post_mortem, script, set_post_mortem, transcript};
+    use core;
+    use data::{AtomError, c_int, c_symbol};
+    use eval::eval_str;
+
+    #[test]
+    fn test_breakpoints() {
+        let env = core::build();
+        eval_str("(defn add (a b) (let [c (* a 2)] (+ c b)))", &env).unwrap();
+        eval_str("(set-breakpoint! 'add)", &env).unwrap();
+
+        script(&[":locals", "(* a b)", "(frob)", ":stack", ":c"]);
+        assert_eq!(eval_str("(- 10 (add 2 3))", &env).unwrap(), c_int(3));
+        assert_eq!(transcript(),
+                   ["breakpoint in add",
+                    "a = 2",
+                    "b = 3",
+                    "6",
+                    "error: undefined symbol: frob",
+                    "#0 (- 10 (add 2 3))"]);
+
+        script(&[":abort"]);
+        assert_eq!(eval_str("(add 2 3)", &env).unwrap_err(),
+                   AtomError::InvalidOperation("aborted in the debugger".to_string()));
+
+        eval_str("(clear-breakpoint! 'add)", &env).unwrap();
+        script(&[]);
+        assert_eq!(eval_str("(add 2 3)", &env).unwrap(), c_int(7));
+        assert!(transcript().is_empty());
+    }
+
+    #[test]
+    fn test_trace() {
+        let env = core::build();
+        eval_str("(defn fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))", &env).unwrap();
+        eval_str("(defn fail (s) (error s))", &env).unwrap();
+        assert_eq!(eval_str("(trace 'fact)", &env).unwrap(), c_symbol("fact"));
+        eval_str("(trace 'fail)", &env).unwrap();
+
+        script(&[]);
+        assert_eq!(eval_str("(fact 2)", &env).unwrap(), c_int(2));
+        assert!(eval_str("(fail \"no\")", &env).is_err());
+        assert_eq!(transcript(),
+                   ["(fact 2)",
+                    "| (fact 1)",
+                    "| | (fact 0)",
+                    "| | => 1",
+                    "| => 1",
+                    "=> 2",
+                    "(fail no)",
+                    "!! unhandled condition: {:type :error :message no}"]);
+
+        eval_str("(untrace 'fact)", &env).unwrap();
+        script(&[]);
+        assert_eq!(eval_str("(fact 3)", &env).unwrap(), c_int(6));
+        eval_str("(defn fail (s) s)", &env).unwrap();
+        eval_str("(untrace)", &env).unwrap();
+        assert_eq!(eval_str("(fail 1)", &env).unwrap(), c_int(1));
+        assert!(transcript().is_empty());
+
+        assert!(eval_str("(trace 'is)", &env).is_err());
+        assert!(eval_str("(trace 'nothing)", &env).is_err());
+    }
+
+    #[test]
+    fn test_stepping() {
+        let env = core::build();
+        eval_str("(defn f (x) (let [y 2] (break) (if (= x 1) y x)))", &env).unwrap();
+
+        script(&[":l", ":s", ":bt", ":c"]);
+        assert_eq!(eval_str("(f 1)", &env).unwrap(), c_int(2));
+        assert_eq!(transcript(),
+                   ["break",
+                    "y = 2",
+                    "x = 1",
+                    "step: (if (= x 1) y x)",
+                    "#0 (if (= x 1) y x)"]);
+    }
+
+    #[test]
+    fn test_post_mortem() {
+        let env = core::build();
+        eval_str("(defn f (x) (let [y (* x 2)] (list (g y))))\n\
+                  (defn g (y) (list (nth y 0)))",
+                 &env)
+            .unwrap();
+
+        assert!(eval_str("(f 1)", &env).is_err());
+        assert!(!has_failure());
+
+        set_post_mortem(true);
+        assert!(eval_str("(f 1)", &env).is_err());
+        set_post_mortem(false);
+        script(&[":l", "*form*", "(:type *error*)", ":bt", ":c"]);
+        assert!(post_mortem());
+        assert_eq!(transcript(),
+                   ["post-mortem: expected: sequence, received: Int(2) in (list (nth y 0))",
+                    "*error* = {:type :type-error :message expected: sequence, received: Int(2)}",
+                    "*form* = (list (nth y 0))",
+                    "y = 2",
+                    "(list (nth y 0))",
+                    ":type-error",
+                    "#0 (list (nth y 0))",
+                    "#1 (list (g y))"]);
+        assert!(!post_mortem());
+    }
+}
@@ -0,0 +1,168 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use fnv::{FnvHashMap, FnvHashSet};
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_nil};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    // a builtin bound again by def
+    Redefinition,
+    // a function marked with deprecate! was called
+    Deprecated,
+}
+
+// A warning: evaluation goes on, but something is probably not as meant
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: Kind,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "warning: {}", self.message)
+    }
+}
+
+// Deprecated functions map to what to use instead, the ones already warned about aren't again
+#[derive(Default)]
+struct Diagnostics {
+    subscribers: Vec<Rc<dyn Fn(&Diagnostic)>>,
+    deprecated: FnvHashMap<String, String>,
+    warned: FnvHashSet<String>,
+    muted: bool,
+}
+
+thread_local! {
+    static DIAGNOSTICS: RefCell<Diagnostics> = RefCell::new(Diagnostics::default());
+}
+
+// f is called with every warning on the calling thread
+pub fn subscribe<F>(f: F)
+    where F: Fn(&Diagnostic) + 'static
+{
+    DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().subscribers.push(Rc::new(f)));
+}
+
+pub fn has_subscribers() -> bool {
+    DIAGNOSTICS.with(|diagnostics| {
+        let diagnostics = diagnostics.borrow();
+        !diagnostics.muted && !diagnostics.subscribers.is_empty()
+    })
+}
+
+// Runs f without warning, like while the prelude redefines builtins on purpose
+pub fn muted<T, F>(f: F) -> T
+    where F: FnOnce() -> T
+{
+    let was = DIAGNOSTICS.with(|diagnostics| {
+        let mut diagnostics = diagnostics.borrow_mut();
+        let was = diagnostics.muted;
+        diagnostics.muted = true;
+        was
+    });
+    let result = f();
+    DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().muted = was);
+    result
+}
+
+// Subscribers may evaluate code warning again
+pub fn warn(kind: Kind, message: String) {
+    let subscribers = DIAGNOSTICS.with(|diagnostics| {
+        let diagnostics = diagnostics.borrow();
+        if diagnostics.muted { vec![] } else { diagnostics.subscribers.clone() }
+    });
+    let diagnostic = Diagnostic {
+        kind: kind,
+        message: message,
+    };
+    for subscriber in subscribers {
+        subscriber(&diagnostic);
+    }
+}
+
+pub fn has_deprecations() -> bool {
+    DIAGNOSTICS.with(|diagnostics| !diagnostics.borrow().deprecated.is_empty())
+}
+
+// Warns the first time the deprecated function name is called
+pub fn called(name: &str) {
+    let advice = DIAGNOSTICS.with(|diagnostics| {
+        let mut diagnostics = diagnostics.borrow_mut();
+        match diagnostics.deprecated.get(name).cloned() {
+            Some(advice) => {
+                if diagnostics.warned.insert(name.to_string()) {
+                    Some(advice)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        }
+    });
+    if let Some(advice) = advice {
+        let message = if advice.is_empty() {
+            format!("{} is deprecated", name)
+        } else {
+            format!("{} is deprecated, {}", name, advice)
+        };
+        warn(Kind::Deprecated, message);
+    }
+}
+
+// (deprecate! 'f "use g instead") warns the first time f is called, by name
+pub fn deprecate(args: &[AtomVal]) -> AtomRet {
+    let name = args.get(0).cloned().unwrap_or_else(c_nil);
+    let advice = match args.get(1).map(|advice| &**advice) {
+        Some(&AtomType::Str(ref advice)) => advice.to_string(),
+        Some(other) => {
+            return Err(AtomError::InvalidType("String".to_string(), other.format(true)))
+        }
+        None => String::new(),
+    };
+    DIAGNOSTICS.with(|diagnostics| {
+        let mut diagnostics = diagnostics.borrow_mut();
+        diagnostics.warned.remove(name.get_symbol()?);
+        diagnostics.deprecated.insert(name.get_symbol()?.to_string(), advice);
+        Ok(name.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::{Diagnostic, Kind};
+    use interpreter::Interpreter;
+
+    #[test]
+    fn test_diagnostics() {
+        let interpreter = Interpreter::new();
+        let seen = Rc::new(RefCell::new(vec![]));
+        let sink = seen.clone();
+        interpreter.on_diagnostic(move |diagnostic| sink.borrow_mut().push(diagnostic.clone()));
+
+        interpreter.run("t", "(def now 1) (def now 2) (def mine 1) (def mine 2)").unwrap();
+        interpreter.run("t",
+                 "(defn old-add (a b) (+ a b))
+                  (deprecate! 'old-add \"use + instead\")
+                  (old-add 1 (old-add 2 3))")
+            .unwrap();
+
+        let warning = |kind, message: &str| {
+            Diagnostic {
+                kind: kind,
+                message: message.to_string(),
+            }
+        };
+        assert_eq!(*seen.borrow(),
+                   vec![warning(Kind::Redefinition, "def now replaces the builtin"),
+                        warning(Kind::Deprecated, "old-add is deprecated, use + instead")]);
+        assert_eq!(seen.borrow()[0].to_string(), "warning: def now replaces the builtin");
+
+        // loading the prelude of another interpreter redefines + without a warning
+        Interpreter::new();
+        assert_eq!(seen.borrow().len(), 2);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use analysis::{self, Definition};
+use parser::parse_cst;
+
+// Documented definitions of one file, named after its path like lib.strings for
+// lib/strings.clrs
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub definitions: Vec<Definition>,
+}
+
+// Definitions written out in source, not the functions defrecord derives from its name
+pub fn module(name: &str, source: &str) -> Result<Module, String> {
+    let nodes = parse_cst(source).map_err(|err| format!("{}: {}", name, err))?;
+    let definitions = analysis::analyze(&nodes, |_| false)
+        .definitions
+        .into_iter()
+        .filter(|d| source[d.span.start.offset..d.span.end.offset] == *d.name)
+        .collect();
+    Ok(Module {
+        name: name.to_string(),
+        definitions: definitions,
+    })
+}
+
+// The path if it's a file, the .clrs files under it if it's a directory
+pub fn collect_files(path: &Path, files: &mut Vec<String>) -> Result<(), String> {
+    let metadata = fs::metadata(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    if !metadata.is_dir() {
+        files.push(path.to_string_lossy().into_owned());
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("{}: {}", path.display(), err))?;
+        let path = entry.path();
+        if path.is_dir() || path.extension().map_or(false, |ext| ext == "clrs") {
+            collect_files(&path, files)?;
+        }
+    }
+    Ok(())
+}
+
+// Modules of the .clrs files under each path (or the file itself), sorted by name
+pub fn collect(paths: &[String]) -> Result<Vec<Module>, String> {
+    let mut modules = vec![];
+    for root in paths {
+        let mut files = vec![];
+        collect_files(Path::new(root), &mut files)?;
+        for file in files {
+            let path = Path::new(&file);
+            let relative = match path.strip_prefix(root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative.with_extension(""),
+                _ => PathBuf::from(path.file_stem().unwrap_or(path.as_os_str())),
+            };
+            let name = relative.components()
+                .map(|part| part.as_os_str().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+                .join(".");
+
+            let mut source = String::new();
+            File::open(&file)
+                .and_then(|mut f| f.read_to_string(&mut source))
+                .map_err(|err| format!("{}: {}", file, err))?;
+            modules.push(module(&name, &source)?);
+        }
+    }
+    modules.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(modules)
+}
+
+fn kind(definition: &Definition) -> &'static str {
+    if definition.is_macro {
+        "macro"
+    } else if definition.params.is_some() {
+        "function"
+    } else {
+        "value"
+    }
+}
+
+// (name params) as the definition is called
+fn usage(definition: &Definition) -> String {
+    match definition.params {
+        Some(ref params) => {
+            let params = params.trim_matches(|c| c == '(' || c == ')' || c == '[' || c == ']');
+            let space = if params.is_empty() { "" } else { " " };
+            format!("({}{}{})", definition.name, space, params)
+        }
+        None => definition.name.clone(),
+    }
+}
+
+pub fn markdown(modules: &[Module]) -> String {
+    let mut out = String::from("# API documentation\n");
+    for module in modules {
+        out.push_str(&format!("\n## {}\n", module.name));
+        for definition in &module.definitions {
+            out.push_str(&format!("\n### `{}`\n\n*{}*\n", usage(definition), kind(definition)));
+            if let Some(ref doc) = definition.doc {
+                out.push_str(&format!("\n{}\n", doc));
+            }
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn html(modules: &[Module]) -> String {
+    let mut out = String::from("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">\
+                                <title>API documentation</title></head>\n<body>\n\
+                                <h1>API documentation</h1>\n");
+    for module in modules {
+        out.push_str(&format!("<h2 id=\"{0}\">{0}</h2>\n", escape(&module.name)));
+        for definition in &module.definitions {
+            out.push_str(&format!("<h3 id=\"{}.{}\"><code>{}</code></h3>\n<p><em>{}</em></p>\n",
+                                  escape(&module.name),
+                                  escape(&definition.name),
+                                  escape(&usage(definition)),
+                                  kind(definition)));
+            if let Some(ref doc) = definition.doc {
+                out.push_str(&format!("<p>{}</p>\n", escape(doc)));
+            }
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{module, markdown, html};
+
+    #[test]
+    fn test_generate() {
+        let source = "(defn twice \"Doubles x\" (x) (* 2 x))\n\
+                      (defmacro unless (fn* (c & body) c))\n(def limit 10)\n\
+                      (defrecord Point [x y])";
+        let modules = vec![module("lib.math", source).unwrap()];
+        assert_eq!(markdown(&modules),
+                   "# API documentation\n\n## lib.math\n\n### `(twice x)`\n\n*function*\n\n\
+                    Doubles x\n\n### `(unless c & body)`\n\n*macro*\n\n### `limit`\n\n*value*\n\n\
+                    ### `Point`\n\n*value*\n");
+        assert!(html(&modules).contains("<h3 id=\"lib.math.twice\"><code>(twice x)</code></h3>\n\
+                                         <p><em>function</em></p>\n<p>Doubles x</p>\n"));
+        assert!(html(&modules).contains("<code>(unless c &amp; body)</code>"));
+        assert!(module("broken", "(defn").is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use lexer::{Lexer, Token};
+use parser::{Cst, ParseError, delimiter, parse_cst};
+
+// Every token of source, whitespace and comments included, one per line with its span
+pub fn tokens(source: &str) -> Result<String, ParseError> {
+    let mut lexer = Lexer::new(source);
+    let mut out = String::new();
+    while let Some(token) = lexer.next() {
+        let (start, token) = token.map_err(ParseError::Lex)?;
+        out.push_str(&format!("{}-{} {:?}\n", start, lexer.position(), token));
+    }
+    Ok(out)
+}
+
+fn describe(node: &Cst) -> String {
+    match *node {
+        Cst::Atom(_, ref token, _) => format!("{:?}", token),
+        Cst::Prefix(_, Token::Tag(ref tag), _) => format!("Tagged(#{})", tag),
+        Cst::Prefix(_, ref token, _) => format!("Prefix({})", delimiter(token)),
+        Cst::Seq(_, Token::Obracket, _) => "Vector".to_string(),
+        Cst::Seq(_, Token::Ocurly, _) => "Map".to_string(),
+        Cst::Seq(_, _, _) => "List".to_string(),
+        Cst::Trivia(_, _) => String::new(),
+    }
+}
+
+fn dump_node(node: &Cst, depth: usize, out: &mut String) {
+    if node.is_trivia() {
+        return;
+    }
+    let span = node.span();
+    out.push_str(&format!("{:indent$}{}-{} {}\n",
+                          "",
+                          span.start,
+                          span.end,
+                          describe(node),
+                          indent = depth * 2));
+    for child in node.children() {
+        dump_node(child, depth + 1, out);
+    }
+}
+
+// The forms of source as a tree, one node per line with its span
+pub fn ast(source: &str) -> Result<String, ParseError> {
+    let mut out = String::new();
+    for node in parse_cst(source)? {
+        dump_node(&node, 0, &mut out);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokens, ast};
+
+    #[test]
+    fn test_dumps() {
+        assert_eq!(tokens("(+ 1 \"é\") ; hi").unwrap(),
+                   "1:1-1:2 Oparen\n1:2-1:3 Identifier(\"+\")\n1:3-1:4 Whitespace\n\
+                    1:4-1:5 Int(1)\n1:5-1:6 Whitespace\n1:6-1:9 Str(\"é\")\n1:9-1:10 Cparen\n\
+                    1:10-1:11 Whitespace\n1:11-1:15 Comment\n");
+        assert_eq!(ast("(def x\n  '[1 #inst \"1970-01-01\"])").unwrap(),
+                   "1:1-2:27 List\n  1:2-1:5 Identifier(\"def\")\n  1:6-1:7 Identifier(\"x\")\n  \
+                    2:3-2:26 Prefix(')\n    2:4-2:26 Vector\n      2:5-2:6 Int(1)\n      \
+                    2:7-2:25 Tagged(#inst)\n        2:13-2:25 Str(\"1970-01-01\")\n");
+        assert!(tokens("\"open").is_err());
+        assert!(ast("(open").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+// Forms read while compiling the host, by the rulsp! macro of the rulsp-macros crate: they're
+// constants, turned into values and evaluated when loaded
+use std::fmt::Write;
+use regex::Regex;
+use data::{AtomKey, AtomRet, AtomType, AtomVal, c_bool, c_int, c_keyword, c_list_from_iter, c_map,
+           c_nil, c_regex, c_string, c_symbol, c_vector};
+use env::Env;
+use eval::eval;
+use fnv::FnvHashMap;
+use interpreter::Interpreter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Datum {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Str(&'static str),
+    Symbol(&'static str),
+    Keyword(&'static str),
+    Regex(&'static str),
+    List(&'static [Datum]),
+    Vector(&'static [Datum]),
+    Map(&'static [(Datum, Datum)]),
+}
+
+impl Datum {
+    pub fn value(&self) -> AtomVal {
+        match *self {
+            Datum::Nil => c_nil(),
+            Datum::Bool(b) => c_bool(b),
+            Datum::Int(num) => c_int(num),
+            Datum::Str(string) => c_string(string),
+            Datum::Symbol(name) => c_symbol(name),
+            Datum::Keyword(name) => c_keyword(name),
+            // the macro only embeds patterns which compiled
+            Datum::Regex(pattern) => c_regex(Regex::new(pattern).unwrap()),
+            Datum::List(items) => c_list_from_iter(items.iter().map(Datum::value)),
+            Datum::Vector(items) => c_vector(items.iter().map(Datum::value).collect()),
+            Datum::Map(pairs) => {
+                let mut map = FnvHashMap::default();
+                for &(ref key, ref value) in pairs {
+                    map.insert(AtomKey::new(key.value()).unwrap(), value.value());
+                }
+                c_map(map)
+            }
+        }
+    }
+}
+
+// What rulsp! expands to, file and line are where in the host the forms were written
+#[derive(Debug, Clone, Copy)]
+pub struct Embedded {
+    pub file: &'static str,
+    pub line: u32,
+    pub forms: &'static [Datum],
+}
+
+impl Embedded {
+    // Evaluates the forms in interpreter, returns the value of the last one
+    pub fn load(&self, interpreter: &Interpreter) -> AtomRet {
+        load_forms(self.forms, interpreter.env())
+    }
+}
+
+pub fn load_forms(forms: &[Datum], env: &Env) -> AtomRet {
+    let mut result = c_nil();
+    for form in forms {
+        result = eval(&form.value(), env)?;
+    }
+    Ok(result)
+}
+
+// The Rust expression constructing the Datum for a value the reader produced, how the macro
+// writes forms out; functions and other values the reader can't produce are refused
+pub fn datum_expr(value: &AtomVal) -> Result<String, String> {
+    let mut out = String::new();
+    write_datum(&mut out, value)?;
+    Ok(out)
+}
+
+fn write_datum(out: &mut String, value: &AtomVal) -> Result<(), String> {
+    out.push_str("::rulsp::embed::Datum::");
+    match **value {
+        AtomType::Nil => out.push_str("Nil"),
+        AtomType::Bool(b) => write!(out, "Bool({})", b).unwrap(),
+        AtomType::Int(num) => write!(out, "Int({})", num).unwrap(),
+        AtomType::Str(ref string) => write!(out, "Str({:?})", string).unwrap(),
+        AtomType::Symbol(ref name, _) => write!(out, "Symbol({:?})", name).unwrap(),
+        AtomType::Keyword(ref name) => write!(out, "Keyword({:?})", name).unwrap(),
+        AtomType::Regex(ref regex) => write!(out, "Regex({:?})", regex.as_str()).unwrap(),
+        AtomType::List(ref items, _) => write_items(out, "List", items)?,
+        AtomType::Vector(ref items) => write_items(out, "Vector", items.as_slice())?,
+        AtomType::Map(ref map) => {
+            out.push_str("Map(&[");
+            for (key, value) in map {
+                out.push('(');
+                write_datum(out, key.value())?;
+                out.push_str(", ");
+                write_datum(out, value)?;
+                out.push_str("), ");
+            }
+            out.push_str("])");
+        }
+        _ => return Err(format!("{} can't be embedded", value.format(true))),
+    }
+    Ok(())
+}
+
+fn write_items(out: &mut String, kind: &str, items: &[AtomVal]) -> Result<(), String> {
+    write!(out, "{}(&[", kind).unwrap();
+    for item in items {
+        write_datum(out, item)?;
+        out.push_str(", ");
+    }
+    out.push_str("])");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{c_int, c_string};
+    use interpreter::Interpreter;
+    use super::{Datum, Embedded, datum_expr};
+
+    const TWICE: Embedded = Embedded {
+        file: "host.rs",
+        line: 1,
+        forms: &[Datum::List(&[Datum::Symbol("defn"),
+                               Datum::Symbol("twice"),
+                               Datum::List(&[Datum::Symbol("x")]),
+                               Datum::List(&[Datum::Symbol("*"),
+                                             Datum::Int(2),
+                                             Datum::Symbol("x")])]),
+                 Datum::List(&[Datum::Keyword("a"),
+                               Datum::Map(&[(Datum::Keyword("a"), Datum::Str("b"))])])],
+    };
+
+    #[test]
+    fn test_embedded() {
+        let interpreter = Interpreter::new();
+        assert_eq!(TWICE.load(&interpreter).unwrap(), c_string("b"));
+        assert_eq!(interpreter.eval("(twice 21)").unwrap(), c_int(42));
+    }
+
+    #[test]
+    fn test_datum_expr() {
+        let form = Interpreter::new().eval("'(f [:k \"s\\n\"] nil)").unwrap();
+        assert_eq!(datum_expr(&form).unwrap(),
+                   "::rulsp::embed::Datum::List(&[::rulsp::embed::Datum::Symbol(\"f\"), \
+                    ::rulsp::embed::Datum::Vector(&[::rulsp::embed::Datum::Keyword(\"k\"), \
+                    ::rulsp::embed::Datum::Str(\"s\\n\"), ]), ::rulsp::embed::Datum::Nil, ])");
+        assert!(datum_expr(&Interpreter::new().eval("inc").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+// Rust source for a restricted subset of rulsp, for scripts graduating into compiled code. Each
+// defn becomes a Rust function taking the global env and its args, calls between them are
+// direct and everything else is looked up in the env at runtime. Forms needing the evaluator
+// at runtime (eval, fn*, macros, loop) and variadic functions are refused.
+use std::collections::HashSet;
+use fnv::FnvHashMap;
+use analysis::SPECIAL_FORMS;
+use check::is_macro;
+use data::{AtomVal, AtomType};
+use eval::let_pairs;
+use env::Env;
+use lexer::Lexer;
+use parser::Parser;
+
+// Rust keywords and the helpers of the generated module
+const RESERVED: &'static [&'static str] = &["as", "box", "break", "const", "continue", "crate",
+                                            "else", "enum", "extern", "false", "fn", "for", "if",
+                                            "impl", "in", "let", "loop", "match", "mod", "move",
+                                            "mut", "pub", "ref", "return", "self", "static",
+                                            "struct", "super", "trait", "true", "type", "unsafe",
+                                            "use", "where", "while", "global", "arity", "load"];
+
+// A name usable as a Rust identifier, `sum-to` as sum_to and `empty?` as empty_p
+fn mangle(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        match c {
+            c if c.is_ascii_alphanumeric() || c == '_' => out.push(c),
+            '-' => out.push('_'),
+            '?' => out.push_str("_p"),
+            '!' => out.push_str("_bang"),
+            _ => out.push_str(&format!("_{:x}", c as u32)),
+        }
+    }
+    if out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+fn function_name(name: &str) -> String {
+    let name = mangle(name);
+    if RESERVED.contains(&name.as_str()) {
+        name + "_"
+    } else {
+        name
+    }
+}
+
+fn variable(name: &str) -> String {
+    format!("v_{}", mangle(name))
+}
+
+fn bound_name(pattern: &AtomVal) -> Result<&str, String> {
+    pattern.get_symbol().map_err(|_| "let can only bind symbols".to_string())
+}
+
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: Vec<AtomVal>,
+}
+
+// (defn name "doc"? (params) body...)
+fn function(args: &[AtomVal]) -> Result<Function, String> {
+    let name = args.get(1).and_then(|name| name.get_symbol().ok()).ok_or("defn needs a name")?;
+    let rest = match args.get(2).map(|doc| &**doc) {
+        Some(&AtomType::Str(_)) => &args[3..],
+        _ => &args[2..],
+    };
+    let params = match rest.first().map(|params| params.get_seq()) {
+        Some(Ok(params)) => params,
+        _ => return Err(format!("defn {} needs a parameter list", name)),
+    };
+    let mut names = vec![];
+    for param in params {
+        match param.get_symbol() {
+            Ok(param) if !param.starts_with('&') => names.push(param.to_string()),
+            _ => return Err(format!("defn {} has to take a fixed number of symbols", name)),
+        }
+    }
+    Ok(Function {
+        name: name.to_string(),
+        params: names,
+        body: rest[1..].to_vec(),
+    })
+}
+
+struct Emitter<'a> {
+    env: &'a Env,
+    // the defns of the file and how many args they take
+    arities: FnvHashMap<String, usize>,
+}
+
+impl<'a> Emitter<'a> {
+    // A Rust expression building the quoted value
+    fn datum(&self, value: &AtomVal) -> Result<String, String> {
+        let items = |items: &[AtomVal]| -> Result<String, String> {
+            Ok(items.iter().map(|item| self.datum(item)).collect::<Result<Vec<_>, _>>()?.join(", "))
+        };
+        Ok(match **value {
+            AtomType::Symbol(ref name, _) => format!("c_symbol({:?})", name),
+            AtomType::List(ref items_, _) => format!("c_list(vec![{}])", items(items_)?),
+            AtomType::Vector(ref items_) => format!("c_vector(vec![{}])", items(items_.as_slice())?),
+            _ => return self.literal(value),
+        })
+    }
+
+    fn literal(&self, value: &AtomVal) -> Result<String, String> {
+        Ok(match **value {
+            AtomType::Nil => "c_nil()".to_string(),
+            AtomType::Bool(b) => format!("c_bool({})", b),
+            AtomType::Int(n) => format!("c_int({})", n),
+            AtomType::Str(ref s) => format!("c_string({:?})", s),
+            AtomType::Keyword(ref name) => format!("c_keyword({:?})", name),
+            _ => return Err(format!("{} can't be compiled", value.format(true))),
+        })
+    }
+
+    // The forms evaluated in order, the last one's value is the block's
+    fn statements(&self, forms: &[AtomVal], locals: &HashSet<String>) -> Result<String, String> {
+        let mut out = String::new();
+        for (i, form) in forms.iter().enumerate() {
+            let form = self.expr(form, locals)?;
+            if i + 1 < forms.len() {
+                out += &format!("let _ = {}; ", form);
+            } else {
+                out += &form;
+            }
+        }
+        if forms.is_empty() {
+            out += "c_nil()";
+        }
+        Ok(out)
+    }
+
+    fn body(&self, forms: &[AtomVal], locals: &HashSet<String>) -> Result<String, String> {
+        let statements = self.statements(forms, locals)?;
+        Ok(if forms.len() > 1 {
+            format!("{{ {} }}", statements)
+        } else {
+            statements
+        })
+    }
+
+    fn args(&self, args: &[AtomVal], locals: &HashSet<String>) -> Result<Vec<String>, String> {
+        args.iter().map(|arg| self.expr(arg, locals)).collect()
+    }
+
+    // A Rust expression of type AtomVal, in a function returning AtomRet
+    fn expr(&self, form: &AtomVal, locals: &HashSet<String>) -> Result<String, String> {
+        let items = match **form {
+            AtomType::Symbol(ref name, _) if locals.contains(&**name) => {
+                return Ok(format!("{}.clone()", variable(name)))
+            }
+            AtomType::Symbol(ref name, _) => return Ok(format!("global(env, {:?})?", name)),
+            AtomType::Vector(ref items) => {
+                return Ok(format!("c_vector(vec![{}])", self.args(items.as_slice(), locals)?.join(", ")))
+            }
+            AtomType::List(ref items, _) if !items.is_empty() => items,
+            _ => return self.literal(form),
+        };
+
+        let head = items[0].get_symbol().ok().map(|head| head.to_string());
+        let head = match head {
+            Some(ref head) if !locals.contains(head) => head.as_str(),
+            _ => {
+                let func = self.expr(&items[0], locals)?;
+                let args = self.args(&items[1..], locals)?;
+                return Ok(format!("{}.apply(&[{}])?", func, args.join(", ")));
+            }
+        };
+        match head {
+            "quote" => self.datum(items.get(1).ok_or("quote needs a form")?),
+            "do" => self.body(&items[1..], locals),
+            "if" => {
+                if items.len() < 3 || items.len() > 4 {
+                    return Err("if takes a condition, a then and maybe an else".to_string());
+                }
+                let otherwise = match items.get(3) {
+                    Some(otherwise) => self.expr(otherwise, locals)?,
+                    None => "c_nil()".to_string(),
+                };
+                Ok(format!("if {}.is_truthy() {{ {} }} else {{ {} }}",
+                           self.expr(&items[1], locals)?,
+                           self.expr(&items[2], locals)?,
+                           otherwise))
+            }
+            "let" => {
+                let mut inner = locals.clone();
+                let mut out = String::from("{ ");
+                match items.get(1).map(|bindings| &**bindings) {
+                    Some(&AtomType::Vector(ref bindings)) if bindings.len() % 2 == 0 => {
+                        for pair in bindings.as_slice().chunks(2) {
+                            let name = bound_name(&pair[0])?;
+                            out += &format!("let {} = {}; ",
+                                            variable(name),
+                                            self.expr(&pair[1], &inner)?);
+                            inner.insert(name.to_string());
+                        }
+                    }
+                    Some(_) if let_pairs(&items[1]).is_some() => {
+                        // all values first, in one let so none sees the names bound
+                        let mut names = vec![];
+                        let mut values = vec![];
+                        for pair in let_pairs(&items[1]).unwrap() {
+                            let pair = pair.get_list().map_err(|err| err.to_string())?;
+                            let name = bound_name(&pair[0])?;
+                            names.push(variable(name));
+                            values.push(self.expr(&pair[1], locals)?);
+                            inner.insert(name.to_string());
+                        }
+                        out += &format!("let ({},) = ({},); ", names.join(", "), values.join(", "));
+                    }
+                    _ => return Err("let needs a vector of names and values".to_string()),
+                }
+                Ok(out + &self.statements(&items[2..], &inner)? + " }")
+            }
+            _ if SPECIAL_FORMS.contains(&head) => Err(format!("{} can't be compiled", head)),
+            _ if self.arities.contains_key(head) => {
+                let arity = self.arities[head];
+                if items.len() - 1 != arity {
+                    return Err(format!("{} takes {} args, given {}", head, arity, items.len() - 1));
+                }
+                let mut args = vec!["env".to_string()];
+                args.extend(self.args(&items[1..], locals)?);
+                Ok(format!("{}({})?", function_name(head), args.join(", ")))
+            }
+            _ if is_macro(self.env, head) => {
+                Err(format!("macro {} can't be compiled, expand it first", head))
+            }
+            _ => {
+                let args = self.args(&items[1..], locals)?;
+                Ok(format!("global(env, {:?})?.apply(&[{}])?", head, args.join(", ")))
+            }
+        }
+    }
+}
+
+const HEADER: &'static str = "#![allow(unused_variables, unused_imports, unused_assignments)]
+use std::rc::Rc;
+use rulsp::data::{AtomVal, AtomRet, AtomError, c_bool, c_closure, c_int, c_keyword, c_list,
+                  c_nil, c_string, c_symbol, c_vector};
+use rulsp::env::{Env, env_get, env_set};
+
+fn global(env: &Env, name: &str) -> AtomRet {
+    env_get(env, &c_symbol(name)).ok_or_else(|| AtomError::UndefinedSymbol(name.to_string()))
+}
+
+fn arity(name: &str, args: &[AtomVal], arity: usize) -> Result<(), AtomError> {
+    if args.len() == arity {
+        Ok(())
+    } else {
+        let message = format!(\"{} takes {} args, given {}\", name, arity, args.len());
+        Err(AtomError::InvalidArgument(message))
+    }
+}
+";
+
+// The Rust module for the script in source, file names it; names it doesn't define are looked
+// up in env to tell macros from functions. Errors say where the form that can't be compiled is.
+pub fn emit_rust(file: &str, source: &str, env: &Env) -> Result<String, String> {
+    let mut parser = Parser::new(Lexer::new(source));
+    let mut forms = vec![];
+    loop {
+        match parser.next_form() {
+            Ok(Some(form)) => forms.push((parser.last_span().unwrap().start, form)),
+            Ok(None) => break,
+            Err(err) => return Err(format!("{}: {}", file, err)),
+        }
+    }
+
+    let mut emitter = Emitter {
+        env: env,
+        arities: FnvHashMap::default(),
+    };
+    let mut functions = vec![];
+    let mut names = HashSet::new();
+    for &(start, ref form) in &forms {
+        if let Ok(items) = form.get_seq() {
+            if items.first().map_or(false, |head| head.get_symbol().ok() == Some("defn")) {
+                let function = function(items)
+                    .map_err(|err| format!("{}:{}: {}", file, start, err))?;
+                if !names.insert(function_name(&function.name)) {
+                    return Err(format!("{}:{}: {} clashes with another function in Rust",
+                                       file,
+                                       start,
+                                       function.name));
+                }
+                emitter.arities.insert(function.name.clone(), function.params.len());
+                functions.push((start, function));
+            }
+        }
+    }
+
+    let mut out = format!("// Generated by rulsp emit-rust from {}\n{}", file, HEADER);
+    for &(start, ref function) in &functions {
+        let locals = function.params.iter().cloned().collect();
+        let body = emitter.body(&function.body, &locals)
+            .map_err(|err| format!("{}:{}: {}", file, start, err))?;
+        let mut params = vec!["env: &Env".to_string()];
+        params.extend(function.params.iter().map(|param| format!("{}: AtomVal", variable(param))));
+        out += &format!("\n// {}\npub fn {}({}) -> AtomRet {{\n    Ok({})\n}}\n",
+                        function.name,
+                        function_name(&function.name),
+                        params.join(", "),
+                        body);
+    }
+
+    out += &format!("\n// Defines in env what {} defines and runs its top-level forms,\n// returns \
+                     the value of the last one\n",
+                    file);
+    out += "pub fn load(env: &Env) -> AtomRet {\n    let mut result = c_nil();\n";
+    let locals = HashSet::new();
+    for &(start, ref form) in &forms {
+        let located = |err| format!("{}:{}: {}", file, start, err);
+        let items = form.get_seq().ok();
+        let head = items.and_then(|items| items.first()).and_then(|head| head.get_symbol().ok());
+        match head {
+            Some("defn") => {
+                let function = function(items.unwrap()).map_err(&located)?;
+                // the closure holds env weakly, env holds the closure
+                out += "    let weak = Rc::downgrade(env);\n";
+                out += &format!("    env_set(env, &c_symbol({:?}), c_closure(move |args| {{\n",
+                                function.name);
+                out += &format!("        arity({:?}, args, {})?;\n",
+                                function.name,
+                                function.params.len());
+                out += "        let env = weak.upgrade()\n            .ok_or_else(|| \
+                        AtomError::InvalidOperation(\"env is gone\".to_string()))?;\n";
+                let mut args = vec!["&env".to_string()];
+                args.extend((0..function.params.len()).map(|i| format!("args[{}].clone()", i)));
+                out += &format!("        {}({})\n    }}));\n",
+                                function_name(&function.name),
+                                args.join(", "));
+            }
+            Some("def") => {
+                let items = items.unwrap();
+                let name = items.get(1)
+                    .and_then(|name| name.get_symbol().ok())
+                    .ok_or_else(|| located("def needs a name".to_string()))?;
+                let value = match items.get(2) {
+                    Some(value) => emitter.expr(value, &locals).map_err(&located)?,
+                    None => "c_nil()".to_string(),
+                };
+                out += &format!("    result = {};\n", value);
+                out += &format!("    env_set(env, &c_symbol({:?}), result.clone());\n", name);
+            }
+            _ => {
+                out += &format!("    result = {};\n",
+                                emitter.expr(form, &locals).map_err(&located)?);
+            }
+        }
+    }
+    Ok(out + "    Ok(result)\n}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit_rust, mangle};
+    use core;
+
+    #[test]
+    fn test_emit_rust() {
+        assert_eq!(mangle("sum-to"), "sum_to");
+        assert_eq!(mangle("empty?"), "empty_p");
+        assert_eq!(mangle("1+"), "_1_2b");
+
+        let env = core::build();
+        let source = "(defn sum-to (n) (if (= n 0) 0 (+ n (sum-to (- n 1)))))\n\
+                      (def total (let [m 10] (sum-to m)))\n\
+                      (def swapped (let ((m 1) (n 2)) (let ((m n) (n m)) (- m n))))\n\
+                      (println total '(a [1 \"b\"]))";
+        let rust = emit_rust("sum.clrs", source, &env).unwrap();
+        assert!(rust.starts_with("// Generated by rulsp emit-rust from sum.clrs\n"));
+        assert!(rust.contains("pub fn sum_to(env: &Env, v_n: AtomVal) -> AtomRet {\n    Ok(if \
+                               global(env, \"=\")?.apply(&[v_n.clone(), c_int(0)])?.is_truthy() \
+                               { c_int(0) } else { global(env, \"+\")?.apply(&[v_n.clone(), \
+                               sum_to(env, global(env, \"-\")?.apply(&[v_n.clone(), \
+                               c_int(1)])?)?])? })\n}\n"));
+        assert!(rust.contains("    result = { let v_m = c_int(10); sum_to(env, v_m.clone())? \
+                               };\n    env_set(env, &c_symbol(\"total\"), result.clone());\n"));
+        assert!(rust.contains("{ let (v_m, v_n,) = (v_n.clone(), v_m.clone(),); "));
+        assert!(rust.contains("c_list(vec![c_symbol(\"a\"), c_vector(vec![c_int(1), \
+                               c_string(\"b\")])])"));
+
+        let error = |source| emit_rust("t.clrs", source, &env).unwrap_err();
+        assert_eq!(error("1\n(eval '(+ 1 2))"), "t.clrs:2:1: eval can't be compiled");
+        assert_eq!(error("(defn f (& xs) xs)"),
+                   "t.clrs:1:1: defn f has to take a fixed number of symbols");
+        assert_eq!(error("(defn f (x) x) (f 1 2)"), "t.clrs:1:16: f takes 1 args, given 2");
+        assert_eq!(error("(deftest t (is true))"),
+                   "t.clrs:1:1: macro deftest can't be compiled, expand it first");
+        assert_eq!(error("(defn a-b () 1) (defn a_b () 2)"),
+                   "t.clrs:1:17: a_b clashes with another function in Rust");
+    }
+}
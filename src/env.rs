@@ -1,17 +1,236 @@
-use super::data::{AtomVal, AtomType, c_nil};
-use std::rc::Rc;
-use std::cell::RefCell;
+use super::data::{AtomVal, AtomType, AtomKey, AtomError, c_nil, c_list, c_keyword};
+use super::eval::eval;
+use super::stats;
+use super::analysis::SPECIAL_FORMS;
+use std::rc::{Rc, Weak};
+use arena::Arena;
+use std::cmp;
+use std::cell::{Cell, RefCell};
 use std::fmt;
-use fnv::FnvHashMap;
+use std::hash::{Hash, Hasher};
+use fnv::{FnvHashMap, FnvHashSet};
 
+// Symbols are interned, so a name is told apart by its address rather than hashing and
+// comparing the string
+#[derive(Clone)]
+struct Name(Rc<String>);
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Name) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (&*self.0 as *const String as usize).hash(state);
+    }
+}
+
+// The two bits of name in the filter of the names a frame binds, so lookups can pass over
+// most frames without scanning them
+fn name_bits(name: &Rc<String>) -> u64 {
+    let hash = (&**name as *const String as usize as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    1 << (hash >> 58) | 1 << ((hash >> 52) & 63)
+}
+
+// The root env holds the globals, builtins and everything def'ed at the top, in a table.
+// Function calls and lets bind a handful of names each, which a short list scanned by address
+// finds faster than a table is built and hashed.
 #[derive(PartialEq)]
+enum Bindings {
+    Globals(FnvHashMap<Name, AtomVal>),
+    Frame(Vec<(Name, AtomVal)>),
+}
+
+impl Bindings {
+    fn get(&self, name: &Name) -> Option<&AtomVal> {
+        match *self {
+            Bindings::Globals(ref table) => table.get(name),
+            Bindings::Frame(ref frame) => {
+                frame.iter().find(|&&(ref bound, _)| bound == name).map(|&(_, ref value)| value)
+            }
+        }
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a Rc<String>, &'a AtomVal)> + 'a> {
+        match *self {
+            Bindings::Globals(ref table) => Box::new(table.iter().map(|(name, v)| (&name.0, v))),
+            Bindings::Frame(ref frame) => {
+                Box::new(frame.iter().map(|&(ref name, ref v)| (&name.0, v)))
+            }
+        }
+    }
+
+    fn drain(&mut self) -> Vec<AtomVal> {
+        match *self {
+            Bindings::Globals(ref mut table) => table.drain().map(|(_, value)| value).collect(),
+            Bindings::Frame(ref mut frame) => frame.drain(..).map(|(_, value)| value).collect(),
+        }
+    }
+}
+
+// Names bound in a frame somewhere. Any other name can only be a global, so it's looked up in
+// the root env right away instead of in each frame on the way there. Names no symbol uses
+// anymore are dropped whenever the set has doubled.
+struct FrameNames {
+    names: FnvHashSet<Name>,
+    // size at which unused names are dropped next
+    limit: usize,
+}
+
+const FRAME_NAMES_KEPT: usize = 1024;
+
+// What all envs of an interpreter share. It hangs off the envs rather than sitting in thread
+// locals, a plugin evaluates with its own copy of this crate but the envs of the host.
+struct Shared {
+    frame_names: RefCell<FrameNames>,
+    // bumped whenever a global is bound or a name is bound in a frame for the first time,
+    // which is all that changes where a symbol not bound in any frame resolves to
+    generation: Cell<u64>,
+    // bumped whenever a macro is bound, which can make code calling the name write to its env
+    macros: Cell<u64>,
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            frame_names: RefCell::new(FrameNames {
+                names: FnvHashSet::default(),
+                limit: FRAME_NAMES_KEPT,
+            }),
+            generation: Cell::new(0),
+            macros: Cell::new(0),
+        }
+    }
+
+    fn globals_changed(&self) {
+        self.generation.set(self.generation.get() + 1);
+    }
+
+    fn bound_in_frame(&self, name: &Name) {
+        let mut frame_names = self.frame_names.borrow_mut();
+        if frame_names.names.contains(name) {
+            return;
+        }
+        // symbols resolved to a global may be shadowed from now on
+        self.globals_changed();
+        if frame_names.names.len() >= frame_names.limit {
+            // the set holds the last reference to names that are gone everywhere else
+            frame_names.names.retain(|name| Rc::strong_count(&name.0) > 1);
+            frame_names.limit = cmp::max(FRAME_NAMES_KEPT, frame_names.names.len() * 2);
+        }
+        frame_names.names.insert(name.clone());
+    }
+
+    fn is_frame_name(&self, name: &Name) -> bool {
+        self.frame_names.borrow().names.contains(name)
+    }
+}
+
+// Where a symbol resolved to the last time
+enum Resolved {
+    // a global, with the envs it's from and their generation then. Both are held weakly, the
+    // value may well be a function whose body holds the symbol.
+    Global(Weak<Shared>, u64, Weak<AtomType, Arena>),
+    // a slot of the frame so many parents up, which is right as long as the frames below
+    // don't bind the name
+    Local(usize, usize),
+}
+
+// The slot of a symbol in code, remembering where it resolved to the last time it was
+// evaluated, so evaluating it again in a loop doesn't look it up
+#[derive(Default)]
+pub struct SymbolCache(RefCell<Option<Resolved>>);
+
+impl SymbolCache {
+    fn get(&self, env: &Env, name: &Rc<String>) -> Option<AtomVal> {
+        match *self.0.borrow() {
+            Some(Resolved::Global(ref shared, generation, ref value)) => {
+                let env = env.borrow();
+                if Weak::as_ptr(shared) == Rc::as_ptr(&env.shared) &&
+                   generation == env.shared.generation.get() {
+                    value.upgrade()
+                } else {
+                    None
+                }
+            }
+            Some(Resolved::Local(hops, slot)) => {
+                frame_slot(env, hops, slot, name, name_bits(name))
+            }
+            None => None,
+        }
+    }
+
+    fn set(&self, resolved: Resolved) {
+        *self.0.borrow_mut() = Some(resolved);
+    }
+}
+
+// The value in slot of the frame hops parents up, None unless that frame binds name there and
+// the ones below certainly don't
+fn frame_slot(env: &Env, hops: usize, slot: usize, name: &Rc<String>, bits: u64)
+              -> Option<AtomVal> {
+    let env = env.borrow();
+    if hops > 0 {
+        if env.names & bits == bits {
+            return None;
+        }
+        return frame_slot(env.parent.as_ref()?, hops - 1, slot, name, bits);
+    }
+    match env.data {
+        Bindings::Frame(ref frame) => {
+            frame.get(slot)
+                .and_then(|&(ref bound, ref value)| {
+                    if Rc::ptr_eq(&bound.0, name) { Some(value.clone()) } else { None }
+                })
+        }
+        Bindings::Globals(_) => None,
+    }
+}
+
 pub struct EnvType {
     parent: Option<Env>,
-    data: FnvHashMap<Rc<String>, AtomVal>,
+    data: Bindings,
+    // parents above it
+    depth: usize,
+    // the root env above a frame, weak so it's only kept alive through the parents
+    globals: Option<Weak<RefCell<EnvType>>>,
+    shared: Rc<Shared>,
+    // the bits of the names bound in a frame, see name_bits
+    names: u64,
 }
 
 pub type Env = Rc<RefCell<EnvType>>;
 
+impl EnvType {
+    fn insert(&mut self, name: Name, value: AtomVal) {
+        if let AtomType::AFunc(ref fd) = *value {
+            if fd.is_macro {
+                self.shared.macros.set(self.shared.macros.get() + 1);
+            }
+        }
+        match self.data {
+            Bindings::Globals(ref mut table) => {
+                self.shared.globals_changed();
+                table.insert(name, value);
+            }
+            Bindings::Frame(ref mut frame) => {
+                match frame.iter_mut().find(|&&mut (ref bound, _)| *bound == name) {
+                    Some(binding) => binding.1 = value,
+                    None => {
+                        self.shared.bound_in_frame(&name);
+                        self.names |= name_bits(&name.0);
+                        frame.push((name, value))
+                    }
+                }
+            }
+        }
+    }
+}
+
 
 impl fmt::Display for EnvType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -31,7 +250,7 @@ impl fmt::Debug for EnvType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut str: Vec<String> = vec![];
 
-        for (ref key, ref value) in self.data.iter() {
+        for (ref key, value) in self.data.iter() {
             str.push(format!("{} {}", key, value.format(true)));
         }
 
@@ -42,20 +261,103 @@ impl fmt::Debug for EnvType {
 }
 
 
+impl Drop for EnvType {
+    fn drop(&mut self) {
+        stats::env_freed();
+    }
+}
+
 pub fn c_env(env: Option<Env>) -> Env {
+    let depth = env.as_ref().map_or(0, |parent| parent.borrow().depth + 1);
+    stats::env_allocated(depth);
+    let data = match env {
+        Some(_) => Bindings::Frame(vec![]),
+        None => Bindings::Globals(FnvHashMap::default()),
+    };
+    let globals = env.as_ref().map(|parent| {
+        parent.borrow().globals.clone().unwrap_or_else(|| Rc::downgrade(parent))
+    });
+    let shared = match env {
+        Some(ref parent) => parent.borrow().shared.clone(),
+        None => Rc::new(Shared::new()),
+    };
     Rc::new(RefCell::new(EnvType {
         parent: env,
-        data: FnvHashMap::default(),
+        data: data,
+        depth: depth,
+        globals: globals,
+        shared: shared,
+        names: 0,
     }))
 }
 
-fn env_find_inner(env: &Env, key: &Rc<String>) -> Option<(Env, AtomVal)> {
+pub fn env_parent(env: &Env) -> Option<Env> {
+    env.borrow().parent.clone()
+}
+
+pub fn env_values(env: &Env) -> Vec<AtomVal> {
+    env.borrow().data.iter().map(|(_, value)| value.clone()).collect()
+}
+
+// Bindings of env itself, not its parents, sorted by name
+pub fn env_bindings(env: &Env) -> Vec<(String, AtomVal)> {
+    let mut bindings = env.borrow()
+        .data
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect::<Vec<_>>();
+    bindings.sort_by(|a, b| a.0.cmp(&b.0));
+    bindings
+}
+
+// Names bound in env and its parents
+pub fn env_names(env: &Env) -> Vec<String> {
+    let mut names = env.borrow().data.iter().map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+    if let Some(ref parent) = env.borrow().parent {
+        names.extend(env_names(parent));
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+// Empties env, handing out what it held so the caller decides when it's dropped
+pub fn env_clear(env: &Env) -> (Option<Env>, Vec<AtomVal>) {
+    let mut env = env.borrow_mut();
+    if let Bindings::Globals(_) = env.data {
+        env.shared.globals_changed();
+    }
+    let values = env.data.drain();
+    env.globals = None;
+    env.names = 0;
+    (env.parent.take(), values)
+}
+
+// The innermost env binding key with its value, and how many parents up it is
+fn env_find_inner(env: &Env, key: &Name) -> Option<(Env, AtomVal, usize)> {
     let env_borrow = env.borrow();
-    match env_borrow.data.get(key) {
-        Some(value) => Some((env.clone(), value.clone())),
+    if let Some(globals) = env_borrow.globals.as_ref().and_then(Weak::upgrade) {
+        if !env_borrow.shared.is_frame_name(key) {
+            let value = globals.borrow().data.get(key).cloned();
+            return value.map(|value| (globals, value, 0));
+        }
+    }
+    drop(env_borrow);
+    find_in_frames(env, key, name_bits(&key.0), 0)
+}
+
+// Frames whose filter doesn't have the bits of key aren't scanned
+fn find_in_frames(env: &Env, key: &Name, bits: u64, hops: usize) -> Option<(Env, AtomVal, usize)> {
+    let env_borrow = env.borrow();
+    let found = match env_borrow.data {
+        Bindings::Frame(_) if env_borrow.names & bits != bits => None,
+        ref data => data.get(key),
+    };
+    match found {
+        Some(value) => Some((env.clone(), value.clone(), hops)),
         None => {
             if let Some(ref parent) = env_borrow.parent {
-                env_find_inner(parent, key)
+                find_in_frames(parent, key, bits, hops + 1)
             } else {
                 None
             }
@@ -63,37 +365,356 @@ fn env_find_inner(env: &Env, key: &Rc<String>) -> Option<(Env, AtomVal)> {
     }
 }
 
-fn env_find(env: &Env, key: &AtomVal) -> Option<(Env, AtomVal)> {
+// The innermost env binding key along with its value
+pub fn env_find(env: &Env, key: &AtomVal) -> Option<(Env, AtomVal)> {
     match **key {
-        AtomType::Symbol(ref str) => env_find_inner(env, str),
+        AtomType::Symbol(ref str, _) => {
+            env_find_inner(env, &Name(str.clone())).map(|(env, value, _)| (env, value))
+        }
         _ => None,
     }
 }
 
 pub fn env_set(env: &Env, key: &AtomVal, value: AtomVal) {
     match **key {
-        AtomType::Symbol(ref str) => {
-            env.borrow_mut().data.insert(str.clone(), value);
+        AtomType::Symbol(ref str, _) => {
+            env.borrow_mut().insert(Name(str.clone()), value);
         }
         _ => unreachable!(),
     }
 }
 
 pub fn env_get(env: &Env, key: &AtomVal) -> Option<AtomVal> {
-    env_find(env, key).map(|(_, value)| value)
+    let (str, cache) = match **key {
+        AtomType::Symbol(ref str, ref cache) => (str, cache),
+        _ => return None,
+    };
+    if let Some(value) = cache.get(env, str) {
+        return Some(value);
+    }
+    let (found, value, hops) = env_find_inner(env, &Name(str.clone()))?;
+    let found = found.borrow();
+    match found.data {
+        Bindings::Globals(_) if !found.shared.is_frame_name(&Name(str.clone())) => {
+            cache.set(Resolved::Global(Rc::downgrade(&found.shared),
+                                       found.shared.generation.get(),
+                                       Rc::downgrade(&value)));
+        }
+        Bindings::Globals(_) => {}
+        Bindings::Frame(ref frame) => {
+            if let Some(slot) = frame.iter().position(|&(ref bound, _)| Rc::ptr_eq(&bound.0, str)) {
+                cache.set(Resolved::Local(hops, slot));
+            }
+        }
+    }
+    Some(value)
+}
+
+// Binds params to args positionally; `&opt` marks the following params as optional,
+// `&` collects the remaining args into a list and `&key` binds them as keyword args
+pub fn env_bind(env: &Env, params: &[AtomVal], args: &[AtomVal]) -> Result<(), AtomError> {
+    let mut position = 0;
+    let mut optional = false;
+    let mut index = 0;
+
+    while index < params.len() {
+        let param = &params[index];
+        index += 1;
+
+        if param.is_symbol("&opt") {
+            optional = true;
+        } else if param.is_symbol("&key") {
+            let allow_other = params[..index].iter().any(|p| p.is_symbol("&"));
+            let rest = &args[cmp::min(position, args.len())..];
+            return env_bind_keys(env, &params[index..], rest, allow_other);
+        } else if param.is_symbol("&") {
+            if let Some(restpar) = params.get(index) {
+                let rest = if args.len() > position {
+                    c_list(args[position..].to_vec())
+                } else {
+                    c_nil()
+                };
+                env_bind_pattern(env, restpar, rest)?;
+                index += 1;
+            }
+        } else if optional {
+            let (pattern, default) = param_default(param)?;
+            let value = match args.get(position) {
+                Some(value) => value.clone(),
+                None => eval(&default, env)?,
+            };
+            env_bind_pattern(env, &pattern, value)?;
+            position += 1;
+        } else {
+            env_bind_pattern(env, param, args.get(position).cloned().unwrap_or_else(c_nil))?;
+            position += 1;
+        }
+    }
+
+    Ok(())
+}
+
+// Forms writing into the env they're evaluated in or handing it out to be written to
+const WRITING_FORMS: &'static [&'static str] = &["def", "defn", "defmacro", "defmethod", "defmulti",
+                                                 "defprotocol", "defrecord", "defstruct", "eval",
+                                                 "extend-type", "loop", "current-env"];
+
+// Whether calls of a function can run its body right in the closure env instead of a frame of
+// their own: there are no params and nothing in the body writes to the env it runs in. A macro
+// could expand to anything, so calling one, or a name that isn't bound yet, counts as writing.
+// Names called can be bound to a macro later, so the answer only holds until the next macro is
+// bound, see macros_bound.
+pub fn binds_nothing(params: &AtomVal, body: &AtomVal, env: &Env) -> bool {
+    params.get_seq().map(|params| params.is_empty()).unwrap_or(false) && !writes_env(body, env)
+}
+
+fn writes_env(form: &AtomVal, env: &Env) -> bool {
+    match **form {
+        AtomType::List(ref items, _) => {
+            let head = match items.get(0).map(|head| &**head) {
+                Some(&AtomType::Symbol(ref name, _)) => Some(name.as_str()),
+                _ => None,
+            };
+            let writes = match head {
+                Some("quote") => return false,
+                Some(name) if WRITING_FORMS.contains(&name) => true,
+                Some(name) if SPECIAL_FORMS.contains(&name) => false,
+                Some(_) => {
+                    env_get(env, &items[0]).map_or(true, |value| match *value {
+                        AtomType::AFunc(ref fd) => fd.is_macro,
+                        _ => false,
+                    })
+                }
+                None => false,
+            };
+            writes || items.iter().any(|item| writes_env(item, env))
+        }
+        AtomType::Vector(ref items) => items.iter().any(|item| writes_env(item, env)),
+        AtomType::Map(ref map) => {
+            map.iter().any(|(key, value)| writes_env(key.value(), env) || writes_env(value, env))
+        }
+        _ => false,
+    }
+}
+
+// Counts the macros bound in the interpreter env is part of
+pub fn macros_bound(env: &Env) -> u64 {
+    env.borrow().shared.macros.get()
+}
+
+fn is_param_marker(param: &AtomVal) -> bool {
+    param.is_symbol("&") || param.is_symbol("&opt") || param.is_symbol("&key")
+}
+
+// Checks the shape of a parameter list once, when the function is created
+pub fn validate_params(params: &[AtomVal]) -> Result<(), AtomError> {
+    let mut optional = false;
+    let mut index = 0;
+
+    while index < params.len() {
+        let param = &params[index];
+        index += 1;
+
+        if param.is_symbol("&opt") {
+            if optional {
+                return Err(AtomError::InvalidArgument("`&opt` can only appear once".to_string()));
+            }
+            optional = true;
+        } else if param.is_symbol("&key") {
+            return validate_key_params(&params[index..]);
+        } else if param.is_symbol("&") {
+            match params.get(index) {
+                Some(restpar) if !is_param_marker(restpar) => validate_pattern(restpar)?,
+                _ => {
+                    return Err(AtomError::InvalidArgument("`&` has to be followed by a rest \
+                                                           parameter"
+                        .to_string()))
+                }
+            }
+
+            return match params.get(index + 1) {
+                None => Ok(()),
+                Some(next) if next.is_symbol("&key") => validate_key_params(&params[index + 2..]),
+                Some(next) => {
+                    Err(AtomError::InvalidArgument(format!("unexpected parameter {} after the \
+                                                            rest parameter",
+                                                           next)))
+                }
+            };
+        } else if optional {
+            validate_pattern(&param_default(param)?.0)?;
+        } else {
+            validate_pattern(param)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Minimum and maximum (None when variadic) number of args a valid param list accepts
+pub fn params_arity(params: &[AtomVal]) -> (usize, Option<usize>) {
+    let mut required = 0;
+    let mut optional = 0;
+    let mut in_optional = false;
+
+    for param in params {
+        if param.is_symbol("&opt") {
+            in_optional = true;
+        } else if param.is_symbol("&") || param.is_symbol("&key") {
+            return (required, None);
+        } else if in_optional {
+            optional += 1;
+        } else {
+            required += 1;
+        }
+    }
+
+    (required, Some(required + optional))
 }
 
-pub fn env_bind(env: &Env, params: &[AtomVal], args: &[AtomVal]) {
-    for (index, param) in params.iter().enumerate() {
-        env_set(env, param, args.get(index).cloned().unwrap_or_else(c_nil));
+fn validate_key_params(params: &[AtomVal]) -> Result<(), AtomError> {
+    for param in params {
+        let (name, _) = param_default(param)?;
+        if is_param_marker(&name) {
+            return Err(AtomError::InvalidArgument(format!("unexpected {} after `&key`", name)));
+        }
+        name.get_symbol()?;
+    }
+
+    Ok(())
+}
+
+fn validate_pattern(pattern: &AtomVal) -> Result<(), AtomError> {
+    match **pattern {
+        AtomType::Symbol(..) if is_param_marker(pattern) => {
+            Err(AtomError::InvalidArgument(format!("unexpected {}", pattern)))
+        }
+        AtomType::Symbol(..) | AtomType::Map(_) => Ok(()),
+        AtomType::List(ref params, _) => validate_params(params),
+        AtomType::Vector(ref params) => validate_params(params.as_slice()),
+        _ => {
+            Err(AtomError::InvalidArgument(format!("can't bind a value to {}",
+                                                   pattern.format(true))))
+        }
+    }
+}
+
+// `name` or `(name default)` in the &opt and &key sections
+fn param_default(param: &AtomVal) -> Result<(AtomVal, AtomVal), AtomError> {
+    match **param {
+        AtomType::List(ref spec, _) => {
+            if spec.len() == 2 {
+                Ok((spec[0].clone(), spec[1].clone()))
+            } else {
+                Err(AtomError::InvalidArgument(format!("expected (name default), received {}",
+                                                       param)))
+            }
+        }
+        _ => Ok((param.clone(), c_nil())),
     }
 }
 
+// Binds `:name value` pairs to the &key params, evaluating defaults for missing ones
+fn env_bind_keys(env: &Env,
+                 params: &[AtomVal],
+                 args: &[AtomVal],
+                 allow_other: bool)
+                 -> Result<(), AtomError> {
+    if args.len() % 2 == 1 {
+        return Err(AtomError::InvalidArgument("keyword arguments have to come in pairs"
+            .to_string()));
+    }
+
+    let mut values = FnvHashMap::default();
+    for pair in args.chunks(2) {
+        match *pair[0] {
+            AtomType::Keyword(_) => {
+                values.insert(AtomKey::new(pair[0].clone())?, pair[1].clone());
+            }
+            _ => return Err(AtomError::InvalidType("Keyword".to_string(), pair[0].format(true))),
+        }
+    }
+
+    for param in params {
+        let (name, default) = param_default(param)?;
+        let key = AtomKey::new(c_keyword(name.get_symbol()?))?;
+        let value = match values.remove(&key) {
+            Some(value) => value,
+            None => eval(&default, env)?,
+        };
+        env_set(env, &name, value);
+    }
+
+    if !allow_other {
+        if let Some(key) = values.keys().next() {
+            return Err(AtomError::InvalidArgument(format!("unknown keyword argument {}",
+                                                          key.value())));
+        }
+    }
+
+    Ok(())
+}
+
+// Binds a single (possibly nested) pattern: symbol, list/vector or map
+pub fn env_bind_pattern(env: &Env, pattern: &AtomVal, value: AtomVal) -> Result<(), AtomError> {
+    match **pattern {
+        AtomType::Symbol(..) => {
+            env_set(env, pattern, value);
+            Ok(())
+        }
+        AtomType::List(..) |
+        AtomType::Vector(_) => {
+            let params = pattern.get_seq()?;
+            match *value {
+                AtomType::Nil => env_bind(env, params, &[]),
+                _ => env_bind(env, params, value.get_seq()?),
+            }
+        }
+        AtomType::Map(ref params) => env_bind_map(env, params, value),
+        _ => {
+            Err(AtomError::InvalidArgument(format!("can't bind a value to {}",
+                                                   pattern.format(true))))
+        }
+    }
+}
+
+// {:keys [a b] :as m} and {a :a} map patterns
+fn env_bind_map(env: &Env,
+                params: &FnvHashMap<AtomKey, AtomVal>,
+                value: AtomVal)
+                -> Result<(), AtomError> {
+    let empty = FnvHashMap::default();
+    let map = match *value {
+        AtomType::Nil => &empty,
+        _ => value.get_map()?,
+    };
+
+    for (key, target) in params.iter() {
+        let pattern = key.value();
+
+        if pattern.is_keyword("keys") {
+            for name in target.get_seq()? {
+                let lookup = AtomKey::new(c_keyword(name.get_symbol()?))?;
+                env_set(env, name, map.get(&lookup).cloned().unwrap_or_else(c_nil));
+            }
+        } else if pattern.is_keyword("as") {
+            target.get_symbol()?;
+            env_set(env, target, value.clone());
+        } else {
+            let lookup = AtomKey::new(target.clone())?;
+            env_bind_pattern(env, pattern, map.get(&lookup).cloned().unwrap_or_else(c_nil))?;
+        }
+    }
+
+    Ok(())
+}
+
 #[allow(unused_must_use)]
 #[cfg(test)]
 mod tests {
-    use super::{c_env, env_set, env_get};
-    use data::{c_symbol, c_int};
+    use super::{binds_nothing, c_env, env_set, env_get};
+    use data::{c_symbol, c_int, c_list, c_vector};
+    use test::Bencher;
 
     #[test]
     fn test_c_env() {
@@ -135,4 +756,120 @@ mod tests {
 
         assert!(env_get(&env, &c_symbol("Missing")).is_none());
     }
+
+    #[test]
+    fn test_frame_rebind() {
+        let env = c_env(None);
+        let frame = c_env(Some(env.clone()));
+        env_set(&frame, &c_symbol("x"), c_int(1));
+        env_set(&frame, &c_symbol("x"), c_int(2));
+
+        assert_eq!(format!("{}", *frame.borrow()), "{x 2}");
+        assert!(env_get(&env, &c_symbol("x")).is_none());
+    }
+
+    #[test]
+    fn test_frame_shadows_global() {
+        let env = c_env(None);
+        let key = c_symbol("shadowed");
+        env_set(&env, &key, c_int(1));
+        let frame = c_env(Some(env.clone()));
+        assert_eq!(env_get(&frame, &key), Some(c_int(1)));
+
+        env_set(&frame, &key, c_int(2));
+        let inner = c_env(Some(frame));
+        assert_eq!(env_get(&inner, &key), Some(c_int(2)));
+        assert_eq!(env_get(&env, &key), Some(c_int(1)));
+    }
+
+    #[test]
+    fn test_symbol_cache() {
+        let env = c_env(None);
+        let key = c_symbol("cached");
+        env_set(&env, &key, c_int(1));
+        let frame = c_env(Some(env.clone()));
+        assert_eq!(env_get(&frame, &key), Some(c_int(1)));
+        assert_eq!(env_get(&frame, &key), Some(c_int(1)));
+
+        env_set(&env, &c_symbol("cached"), c_int(2));
+        assert_eq!(env_get(&frame, &key), Some(c_int(2)));
+        let inner = c_env(Some(frame.clone()));
+        env_set(&inner, &c_symbol("cached"), c_int(3));
+        assert_eq!(env_get(&inner, &key), Some(c_int(3)));
+        assert_eq!(env_get(&frame, &key), Some(c_int(2)));
+
+        let other = c_env(Some(c_env(None)));
+        assert!(env_get(&other, &key).is_none());
+    }
+
+    #[test]
+    fn test_local_cache() {
+        let env = c_env(None);
+        let outer = c_env(Some(env.clone()));
+        let key = c_symbol("local");
+        env_set(&outer, &c_symbol("other"), c_int(0));
+        env_set(&outer, &key, c_int(1));
+        let inner = c_env(Some(outer.clone()));
+        assert_eq!(env_get(&inner, &key), Some(c_int(1)));
+        env_set(&outer, &c_symbol("local"), c_int(2));
+        assert_eq!(env_get(&inner, &key), Some(c_int(2)));
+
+        // bound below where it was found the last time
+        env_set(&inner, &c_symbol("local"), c_int(3));
+        assert_eq!(env_get(&inner, &key), Some(c_int(3)));
+        // the same code in another call
+        let call = c_env(Some(c_env(Some(env.clone()))));
+        assert!(env_get(&call, &key).is_none());
+        let frame = c_env(Some(env));
+        env_set(&frame, &c_symbol("local"), c_int(4));
+        assert_eq!(env_get(&c_env(Some(frame)), &key), Some(c_int(4)));
+    }
+
+    #[test]
+    fn test_binds_nothing() {
+        let env = c_env(None);
+        env_set(&env, &c_symbol("f"), c_int(1));
+        let call = |name: &str| c_list(vec![c_symbol(name), c_int(1)]);
+
+        assert!(binds_nothing(&c_list(vec![]), &call("f"), &env));
+        let quoted = c_list(vec![c_symbol("quote"), call("def")]);
+        assert!(binds_nothing(&c_vector(vec![]), &quoted, &env));
+        assert!(!binds_nothing(&c_list(vec![c_symbol("x")]), &call("f"), &env));
+        assert!(!binds_nothing(&c_list(vec![]), &call("undefined"), &env));
+        assert!(!binds_nothing(&c_list(vec![]), &c_vector(vec![call("def")]), &env));
+        assert!(!binds_nothing(&c_list(vec![]), &c_list(vec![c_symbol("current-env")]), &env));
+    }
+
+    // A builtin looked up from the body of a closure nested in lets
+    #[bench]
+    fn bench_global_lookup(b: &mut Bencher) {
+        let mut env = c_env(None);
+        let key = c_symbol("global");
+        env_set(&env, &key, c_int(1));
+        for depth in 0..10 {
+            env = c_env(Some(env));
+            for i in 0..3 {
+                env_set(&env, &c_symbol(&format!("local{}_{}", depth, i)), c_int(i));
+            }
+        }
+
+        b.iter(|| env_get(&env, &key));
+    }
+
+    // A param of the function the closure is in, looked up from under the lets in its body
+    #[bench]
+    fn bench_local_lookup(b: &mut Bencher) {
+        let mut env = c_env(Some(c_env(None)));
+        let key = c_symbol("param");
+        env_set(&env, &c_symbol("first"), c_int(0));
+        env_set(&env, &key, c_int(1));
+        for depth in 0..10 {
+            env = c_env(Some(env));
+            for i in 0..3 {
+                env_set(&env, &c_symbol(&format!("local{}_{}", depth, i)), c_int(i));
+            }
+        }
+
+        b.iter(|| env_get(&env, &key));
+    }
 }
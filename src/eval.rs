@@ -1,6 +1,24 @@
-use data::{AtomVal, AtomType, AtomRet, AtomError, c_nil, c_list, c_afunc, c_symbol, c_macro};
-use env::{env_set, env_get, env_bind, Env};
-use lexer::lex;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::mem;
+use std::rc::{Rc, Weak};
+use arena::{self, Arena};
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomKey, AFuncData, c_nil, c_list, c_vector,
+           c_map, c_afunc, c_symbol, c_keyword, c_string, c_macro, c_continuation, c_protocol,
+           c_method, c_multi_fn, c_closure, c_record, c_record_type, c_env_value};
+use env::{c_env, env_set, env_get, env_find, env_parent, env_bind, env_bind_pattern,
+          validate_params, Env};
+use fnv::FnvHashMap;
+use lexer::{Lexer, ReaderMacros};
+use tagged::TagHandlers;
+use analysis::SPECIAL_FORMS;
+use stats;
+use debug;
+use diagnostics;
+use profile;
+use output;
+use report;
+use record::{Record, RecordType};
 use parser::Parser;
 
 fn safe_get(args: &[AtomVal], index: usize) -> AtomVal {
@@ -11,244 +29,1586 @@ fn op_quote(args: &[AtomVal]) -> AtomRet {
     Ok(safe_get(args, 1))
 }
 
-fn op_def(args: &[AtomVal], env: &Env) -> AtomRet {
-    trace!("action=op_def args={:?}", args);
+fn op_lambda(args: &[AtomVal], env: &Env) -> AtomRet {
+    let params = safe_get(args, 1);
+    validate_params(params.get_seq()?)?;
+
+    Ok(c_afunc(env.clone(), params, safe_get(args, 2), None, None))
+}
+
+// [defn name "doc"? (params...) body...]
+fn op_defn(args: &[AtomVal], env: &Env) -> AtomRet {
     let name_atom = safe_get(args, 1);
     let name = name_atom.get_symbol()?;
-    let value = eval(&safe_get(args, 2), env)?;
 
-    let _ = env_set(&env, &name_atom, value);
+    let (doc, params_index) = match *safe_get(args, 2) {
+        AtomType::Str(ref doc) => (Some(doc.to_string()), 3),
+        _ => (None, 2),
+    };
+
+    let params = safe_get(args, params_index);
+    validate_params(params.get_seq()?)?;
+
+    let mut body = args.iter().skip(params_index + 1).cloned().collect::<Vec<_>>();
+    let exp = if body.len() == 1 {
+        body.remove(0)
+    } else {
+        body.insert(0, c_symbol("do"));
+        c_list(body)
+    };
+
+    let func = c_afunc(env.clone(), params, exp, Some(name.to_string()), doc);
+    env_set(env, &name_atom, func);
     Ok(c_symbol(name))
 }
 
-fn op_lambda(args: &[AtomVal], env: &Env) -> AtomRet {
-    Ok(c_afunc(env.clone(), safe_get(args, 1), safe_get(args, 2)))
+// [defprotocol Name "doc"? (method (params...) "doc"?)...]
+fn op_defprotocol(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    let name = name_atom.get_symbol()?;
+
+    let mut methods = vec![];
+    for sig in args.iter().skip(2) {
+        if let AtomType::Str(_) = **sig {
+            continue;
+        }
+
+        let sig = sig.get_list()?;
+        let method_name = safe_get(sig, 0);
+        let params = safe_get(sig, 1);
+        validate_params(params.get_seq()?)?;
+        if params.get_seq()?.is_empty() {
+            return Err(AtomError::InvalidArgument(format!("method {} needs a parameter to \
+                                                           dispatch on",
+                                                          method_name)));
+        }
+
+        let method = c_method(method_name.get_symbol()?, name);
+        env_set(env, &method_name, method.clone());
+        methods.push(method);
+    }
+
+    env_set(env, &name_atom, c_protocol(name, methods));
+    Ok(c_symbol(name))
 }
 
-fn op_macro(args: &[AtomVal], env: &Env) -> AtomRet {
-    let result = eval(&safe_get(args, 2), env)?;
-    match *result {
-        AtomType::AFunc(ref fd) => {
-            op_def(&vec![c_nil(), safe_get(args, 1), c_macro(fd)], env)
+// [extend-type type Protocol (method (params...) body...)...]
+fn op_extend_type(args: &[AtomVal], env: &Env) -> AtomRet {
+    let type_key = eval(&safe_get(args, 1), env)?;
+    match *type_key {
+        AtomType::Keyword(_) => {}
+        ref other => {
+            return Err(AtomError::InvalidType("type keyword".to_string(), other.format(true)))
         }
+    }
+
+    let protocol = eval(&safe_get(args, 2), env)?;
+    let protocol = match *protocol {
+        AtomType::Protocol(ref protocol) => protocol,
+        ref other => return Err(AtomError::InvalidType("protocol".to_string(), other.format(true))),
+    };
+
+    for (method_name, func) in clause_funcs(&args[3..], env)? {
+        let method = protocol.method(method_name.get_symbol()?)
+            .ok_or_else(|| {
+                AtomError::InvalidArgument(format!("{} is not a method of {}",
+                                                   method_name,
+                                                   protocol.name()))
+            })?;
+        method.extend(type_key.clone(), func)?;
+    }
+
+    Ok(c_nil())
+}
+
+// [defrecord Name (fields...)] defines the type Name, the constructors ->Name and map->Name
+// and an accessor Name-field per field
+fn op_defrecord(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    let name = name_atom.get_symbol()?;
+
+    let fields = safe_get(args, 2);
+    let fields = fields.get_seq()?
+        .iter()
+        .map(|field| field.get_symbol().map(|field| field.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let rtype_atom = c_record_type(RecordType::new(name, fields.clone()));
+    let rtype = match *rtype_atom {
+        AtomType::RecordType(ref rtype) => rtype.clone(),
         _ => unreachable!(),
+    };
+
+    let positional = rtype.clone();
+    env_set(env,
+            &c_symbol(&format!("->{}", name)),
+            c_closure(move |args| Ok(c_record(Record::positional(&positional, args)?))));
+
+    let keyed = rtype.clone();
+    env_set(env,
+            &c_symbol(&format!("map->{}", name)),
+            c_closure(move |args| {
+                let map = safe_get(args, 0);
+                let pairs = map.get_map()?
+                    .iter()
+                    .map(|(k, v)| (k.value().clone(), v.clone()))
+                    .collect::<Vec<_>>();
+                Ok(c_record(Record::from_pairs(&keyed, &pairs)?))
+            }));
+
+    for (index, field) in fields.iter().enumerate() {
+        let rtype = rtype.clone();
+        env_set(env,
+                &c_symbol(&format!("{}-{}", name, field)),
+                c_closure(move |args| Record::accessor(&rtype, index, args)));
     }
+
+    env_set(env, &name_atom, rtype_atom);
+    Ok(c_symbol(name))
+}
+
+// [defmulti name "doc"? dispatch-fn]
+fn op_defmulti(args: &[AtomVal], env: &Env) -> AtomRet {
+    let name_atom = safe_get(args, 1);
+    let name = name_atom.get_symbol()?;
+
+    let dispatch_index = match *safe_get(args, 2) {
+        AtomType::Str(_) => 3,
+        _ => 2,
+    };
+    let dispatch = eval(&safe_get(args, dispatch_index), env)?;
+
+    env_set(env, &name_atom, c_multi_fn(name, dispatch));
+    Ok(c_symbol(name))
+}
+
+// [defmethod name dispatch-value (params...) body...]
+fn op_defmethod(args: &[AtomVal], env: &Env) -> AtomRet {
+    let multi = eval(&safe_get(args, 1), env)?;
+    let multi = match *multi {
+        AtomType::MultiFn(ref multi) => multi,
+        ref other => {
+            return Err(AtomError::InvalidType("multimethod".to_string(), other.format(true)))
+        }
+    };
+
+    let key = eval(&safe_get(args, 2), env)?;
+    let body = if args.len() > 4 { &args[4..] } else { &[] };
+    multi.add_method(key, body_func(safe_get(args, 3), body, env)?);
+    Ok(c_nil())
 }
 
-fn is_macro_call(ast: &AtomVal, env: &Env) -> bool {
+// The macro ast calls, if it's a call of one
+fn macro_called(ast: &AtomVal, env: &Env) -> Option<AtomVal> {
     match **ast {
-        AtomType::List(ref args) => {
-            if let Some(value) = env_get(&env, &args[0]) {
-                match *value {
+        AtomType::List(ref args, _) => {
+            args.get(0).and_then(|op| env_get(env, op)).and_then(|value| {
+                let is_macro = match *value {
                     AtomType::AFunc(ref fd) => fd.is_macro,
                     _ => false,
-                }
-            } else {
-                false
-            }
+                };
+                if is_macro { Some(value) } else { None }
+            })
         }
-        _ => false,
+        _ => None,
     }
 }
 
-fn op_macroexpand(ast: &AtomVal, env: &Env) -> AtomRet {
-    let mut new_ast = ast.clone();
-    // println!("IS MACRO CALL: {:?}", ast);
-    while is_macro_call(&new_ast, env) {
-        let x_ast = ast.clone();  // TODO
-        let args = match *x_ast {
-            AtomType::List(ref args) => args,
-            _ => break,
-        };
+// What was worked out about a form the first time it was evaluated, kept on the form
+#[derive(Default)]
+pub struct FormCache(RefCell<Option<Compiled>>);
 
-        if let Some(f) = env_get(&env, &args[0]) {
-            new_ast = f.apply(&args[1..])?;
-        } else {
-            break;
+enum Compiled {
+    // a call expanded by the macro, held weakly; while it lives its address can't be reused
+    Expansion(Weak<AtomType, Arena>, AtomVal),
+    // the clauses of a match
+    Match(Rc<[MatchClause]>),
+}
+
+impl FormCache {
+    pub fn expansion(&self) -> Option<AtomVal> {
+        match *self.0.borrow() {
+            Some(Compiled::Expansion(_, ref expansion)) => Some(expansion.clone()),
+            _ => None,
+        }
+    }
+}
+
+// A form is expanded once per macro, redefining the macro makes calls of it expand again
+fn expand_once(ast: &AtomVal, mac: &AtomVal) -> AtomRet {
+    let cache = match **ast {
+        AtomType::List(_, ref cache) => cache,
+        _ => return mac.apply(&ast.get_list()?[1..]),
+    };
+    if let Some(Compiled::Expansion(ref by, ref expansion)) = *cache.0.borrow() {
+        if Weak::as_ptr(by) == Rc::as_ptr(mac) {
+            return Ok(expansion.clone());
         }
     }
 
-    // println!("EXPANDED AST: {:?}", ast);
-    Ok(new_ast)
+    let expansion = mac.apply(&ast.get_list()?[1..])?;
+    *cache.0.borrow_mut() = Some(Compiled::Expansion(Rc::downgrade(mac), expansion.clone()));
+    Ok(expansion)
 }
 
-fn op_if(args: &[AtomVal], env: &Env) -> AtomRet {
-    let result = eval(&safe_get(args, 1), env)?;
-    match *result {
-        AtomType::Nil => eval(&safe_get(args, 3), env),
-        _ => eval(&safe_get(args, 2), env),
+// Expands ast until it's no macro call anymore, forms without macros are handed back as they are
+fn expand_macros(ast: AtomVal, env: &Env) -> AtomRet {
+    let mut ast = ast;
+    while let Some(mac) = macro_called(&ast, env) {
+        ast = expand_once(&ast, &mac)?;
     }
+
+    Ok(ast)
 }
 
-// [loop (args...) (body)]
-#[allow(unused_assignments)]
-fn op_loop(args: &[AtomVal], env: &Env) -> AtomRet {
-    trace!("fn=op_loop args={:?}", args);
+fn op_macroexpand(ast: &AtomVal, env: &Env) -> AtomRet {
+    expand_macros(ast.clone(), env)
+}
 
-    let body = safe_get(args, 2);
-    let _loop_args = safe_get(args, 1);
-    let loop_args = _loop_args.get_list()?;
+// [-> x (f a) g] => (g (f x a)), [->> x (f a) g] => (g (f a x))
+fn thread_form(args: &[AtomVal], last: bool) -> AtomVal {
+    let mut form = safe_get(args, 1);
 
-    if loop_args.len() % 2 == 1 {
-        return Err(AtomError::InvalidArgument("Loop is missing value for one of the \
-                                               param"
+    for step in args.iter().skip(2) {
+        form = match **step {
+            AtomType::List(ref call, _) if !call.is_empty() => {
+                let mut call = call.clone();
+                if last {
+                    call.push(form);
+                } else {
+                    call.insert(1, form);
+                }
+                c_list(call)
+            }
+            _ => c_list(vec![step.clone(), form]),
+        };
+    }
+
+    trace!("fn=thread_form form={}", form);
+    form
+}
+
+// (binding [name value ...] body...) sets each name where it's defined to its value, the
+// values evaluated first; hands out what the names were bound to before for restore
+fn rebind(bindings: &AtomVal, env: &Env) -> Result<Vec<(Env, AtomVal, AtomVal)>, AtomError> {
+    let bindings = bindings.get_seq()?;
+    if bindings.len() % 2 != 0 {
+        return Err(AtomError::InvalidArgument("binding needs an even number of binding forms"
             .to_string()));
     }
 
-    let arguments_chunks = loop_args.chunks(2);
-    let mut arguments_names: Vec<AtomVal> = Vec::with_capacity(arguments_chunks.len());
-    let mut values_for_eval: Vec<AtomVal> = Vec::with_capacity(arguments_chunks.len());
+    let mut values = Vec::with_capacity(bindings.len() / 2);
+    for chunk in bindings.chunks(2) {
+        let name = chunk[0].clone();
+        name.get_symbol()?;
+        values.push((name, eval(&chunk[1], env)?));
+    }
 
-    for chunk in arguments_chunks {
-        arguments_names.push(chunk[0].clone());
-        values_for_eval.push(chunk[1].clone());
+    let mut saved = Vec::with_capacity(values.len());
+    for (name, value) in values {
+        let (owner, old) = match env_find(env, &name) {
+            Some(found) => found,
+            None => {
+                restore(saved);
+                return Err(AtomError::InvalidOperation(format!("binding needs {} to be defined",
+                                                               name)));
+            }
+        };
+        env_set(&owner, &name, value);
+        saved.push((owner, name, old));
     }
-    let mut arguments_values = eval_list_elements(&values_for_eval, env)?;
+    Ok(saved)
+}
 
-    let mut result = None;
-    loop {
-        env_bind(&env, &arguments_names, &arguments_values);
-        result = Some(eval(&body, &env)?);
-
-        if let Some(ref result) = result {
-            match result.get_list() {
-                Ok(list) => {
-                    if safe_get(list, 0).is_symbol("recur") {
-                        arguments_values = eval_list_elements(&list[1..], env)?;
+// Evaluates the cleanup forms of (unwind-protect body cleanups...)
+fn cleanup(ast: &AtomVal, env: &Env) -> Result<(), AtomError> {
+    for form in ast.get_list()?.iter().skip(2) {
+        eval(form, env)?;
+    }
+    Ok(())
+}
+
+fn restore(saved: Vec<(Env, AtomVal, AtomVal)>) {
+    for (owner, name, old) in saved.into_iter().rev() {
+        env_set(&owner, &name, old);
+    }
+}
+
+// (with-open [h (open ..) ...] body...) becomes nested
+// (let [h (open ..)] (unwind-protect (do body...) (close h)))
+fn with_open_form(args: &[AtomVal]) -> AtomRet {
+    let bindings = safe_get(args, 1);
+    let bindings = bindings.get_seq()?;
+    if bindings.len() % 2 != 0 {
+        return Err(AtomError::InvalidArgument("with-open needs an even number of binding forms"
+            .to_string()));
+    }
+
+    let mut body = args.iter().skip(2).cloned().collect::<Vec<_>>();
+    body.insert(0, c_symbol("do"));
+    let mut form = c_list(body);
+
+    for binding in bindings.chunks(2).rev() {
+        let cleanup = c_list(vec![c_symbol("close"), binding[0].clone()]);
+        let protect = c_list(vec![c_symbol("unwind-protect"), form, cleanup]);
+        form = c_list(vec![c_symbol("let"), c_vector(binding.to_vec()), protect]);
+    }
+
+    trace!("fn=with_open_form form={}", form);
+    Ok(form)
+}
+
+// (assert form message) becomes (assert-call 'form message f args...) if form calls the
+// function f, so a failure can show what the arguments were, else (assert-value 'form message
+// form)
+fn assert_form(args: &[AtomVal], env: &Env) -> AtomRet {
+    let form = safe_get(args, 1);
+    let message = safe_get(args, 2);
+    let quoted = c_list(vec![c_symbol("quote"), form.clone()]);
+
+    if let AtomType::List(ref items, _) = *form {
+        let head = safe_get(items, 0);
+        let is_call = match *head {
+            AtomType::Symbol(ref name, _) if !SPECIAL_FORMS.contains(&name.as_str()) => {
+                env_get(env, &head).map_or(false, |func| match *func {
+                    AtomType::AFunc(ref fd) => !fd.is_macro,
+                    AtomType::Func(_) => true,
+                    _ => false,
+                })
+            }
+            _ => false,
+        };
+        if is_call {
+            let mut call = vec![c_symbol("assert-call"), quoted, message, head.clone()];
+            call.extend(items.iter().skip(1).cloned());
+            return Ok(c_list(call));
+        }
+    }
+    Ok(c_list(vec![c_symbol("assert-value"), quoted, message, form.clone()]))
+}
+
+// A match pattern is compiled into checks that run in order on a stack of values, starting with
+// the one matched; each check takes the value on top
+enum Check {
+    // it's equal to a literal or quoted value
+    Equal(AtomVal),
+    Bind(AtomVal),
+    Ignore,
+    // it's a list or vector of fixed items, or at least that many with a &; pushes the rest if
+    // there's a pattern for it, then the items, the first on top
+    Seq { fixed: usize, at_least: bool, rest: bool },
+    // it's a map with all the keys; pushes their values, the first on top
+    Map(Vec<AtomKey>),
+}
+
+pub struct MatchClause {
+    checks: Vec<Check>,
+    guard: Option<AtomVal>,
+    body: AtomVal,
+}
+
+fn compile_pattern(pattern: &AtomVal, checks: &mut Vec<Check>) -> Result<(), AtomError> {
+    match **pattern {
+        AtomType::Symbol(..) if pattern.is_symbol("_") => checks.push(Check::Ignore),
+        AtomType::Symbol(..) => checks.push(Check::Bind(pattern.clone())),
+        AtomType::List(ref patterns, _) if safe_get(patterns, 0).is_symbol("quote") => {
+            checks.push(Check::Equal(safe_get(patterns, 1)))
+        }
+        AtomType::List(..) |
+        AtomType::Vector(_) => {
+            let patterns = pattern.get_seq()?;
+            let rest_index = patterns.iter().position(|p| p.is_symbol("&"));
+            let fixed = rest_index.unwrap_or(patterns.len());
+            let rest_pattern = patterns.get(fixed + 1);
+            checks.push(Check::Seq {
+                fixed: fixed,
+                at_least: rest_index.is_some(),
+                rest: rest_pattern.is_some(),
+            });
+            for pattern in &patterns[..fixed] {
+                compile_pattern(pattern, checks)?;
+            }
+            if let Some(rest_pattern) = rest_pattern {
+                compile_pattern(rest_pattern, checks)?;
+            }
+        }
+        AtomType::Map(ref patterns) => {
+            checks.push(Check::Map(patterns.keys().cloned().collect()));
+            for pattern in patterns.values() {
+                compile_pattern(pattern, checks)?;
+            }
+        }
+        _ => checks.push(Check::Equal(pattern.clone())),
+    }
+    Ok(())
+}
+
+// [match exp pattern (:when guard)? body ...]
+fn compile_match(args: &[AtomVal]) -> Result<Vec<MatchClause>, AtomError> {
+    let mut clauses = vec![];
+    let mut index = 2;
+    while index < args.len() {
+        let mut checks = vec![];
+        compile_pattern(&args[index], &mut checks)?;
+        let guard = if safe_get(args, index + 1).is_keyword("when") {
+            index += 2;
+            Some(safe_get(args, index))
+        } else {
+            None
+        };
+        clauses.push(MatchClause {
+            checks: checks,
+            guard: guard,
+            body: safe_get(args, index + 1),
+        });
+        index += 2;
+    }
+    Ok(clauses)
+}
+
+// The clauses of a match form, compiled the first time it's evaluated
+fn match_clauses(ast: &AtomVal) -> Result<Rc<[MatchClause]>, AtomError> {
+    let cache = match **ast {
+        AtomType::List(_, ref cache) => cache,
+        _ => return Ok(Rc::from(compile_match(ast.get_list()?)?)),
+    };
+    if let Some(Compiled::Match(ref clauses)) = *cache.0.borrow() {
+        return Ok(clauses.clone());
+    }
+
+    let clauses: Rc<[MatchClause]> = Rc::from(compile_match(ast.get_list()?)?);
+    *cache.0.borrow_mut() = Some(Compiled::Match(clauses.clone()));
+    Ok(clauses)
+}
+
+// Collects the bindings of a matching clause, returns false if value doesn't match
+fn run_checks(checks: &[Check], value: &AtomVal, bindings: &mut Vec<(AtomVal, AtomVal)>) -> bool {
+    let mut values = vec![value.clone()];
+    for check in checks {
+        let value = values.pop().unwrap();
+        match *check {
+            Check::Equal(ref expected) => {
+                if value != *expected {
+                    return false;
+                }
+            }
+            Check::Bind(ref name) => bindings.push((name.clone(), value)),
+            Check::Ignore => {}
+            Check::Seq { fixed, at_least, rest } => {
+                let items = match value.get_seq() {
+                    Ok(items) => items,
+                    Err(_) => return false,
+                };
+                if items.len() < fixed || (!at_least && items.len() != fixed) {
+                    return false;
+                }
+                if rest {
+                    values.push(if items.len() > fixed {
+                        c_list(items[fixed..].to_vec())
                     } else {
-                        break;
-                    }
+                        c_nil()
+                    });
                 }
-                _ => {
-                    break;
+                values.extend(items[..fixed].iter().rev().cloned());
+            }
+            Check::Map(ref keys) => {
+                let map = match *value {
+                    AtomType::Map(ref map) => map,
+                    _ => return false,
+                };
+                let first = values.len();
+                for key in keys {
+                    match map.get(key) {
+                        Some(value) => values.push(value.clone()),
+                        None => return false,
+                    }
                 }
+                values[first..].reverse();
             }
         }
+    }
+    true
+}
 
+// (name (params...) body...) clauses of handler-case and restart-case as functions
+fn clause_funcs(clauses: &[AtomVal], env: &Env) -> Result<Vec<(AtomVal, AtomVal)>, AtomError> {
+    let mut funcs = Vec::with_capacity(clauses.len());
+
+    for clause in clauses {
+        let clause = clause.get_list()?;
+        funcs.push((safe_get(clause, 0), body_func(safe_get(clause, 1), &clause[2..], env)?));
     }
 
-    Ok(result.unwrap_or_else(c_nil))
+    Ok(funcs)
 }
 
-pub fn eval_exp(ast: &AtomVal, env: &Env) -> AtomRet {
-    let args = ast.get_list()?;
-    let op_name = match args.get(0) {
-        None => return Ok(ast.clone()),
-        Some(op) => {
-            match **op {
-                AtomType::Symbol(ref v) => v.as_str(),
-                _ => "__func__",
-            }
+fn body_func(params: AtomVal, body: &[AtomVal], env: &Env) -> AtomRet {
+    validate_params(params.get_seq()?)?;
+
+    let mut body = body.to_vec();
+    body.insert(0, c_symbol("do"));
+    Ok(c_afunc(env.clone(), params, c_list(body), None, None))
+}
+
+// Native errors are signalled as {:type :type-error :message "..."}
+// The (name value) pairs of a let written the way it was before it took destructuring patterns,
+// None for [name value ...]. A list of nothing but pairs is read that way, so a let binding
+// (a b) to (f x) has to use a vector.
+pub fn let_pairs(bindings: &AtomVal) -> Option<&[AtomVal]> {
+    match **bindings {
+        AtomType::List(ref items, _) if !items.is_empty() => {
+            let pairs = items.iter().all(|item| match **item {
+                AtomType::List(ref pair, _) => pair.len() == 2,
+                _ => false,
+            });
+            if pairs { Some(items) } else { None }
         }
+        _ => None,
+    }
+}
+
+pub fn error_condition(err: &AtomError) -> AtomVal {
+    let condition_type = match *err {
+        AtomError::Condition(ref condition) => return condition.clone(),
+        AtomError::InvalidType(_, _) => "type-error",
+        AtomError::InvalidOperation(_) => "invalid-operation",
+        AtomError::InvalidArgument(_) => "invalid-argument",
+        AtomError::UndefinedSymbol(_) => "undefined-symbol",
+        AtomError::Restart(_, _) => "restart-error",
+        AtomError::Panic(_) => "panic",
     };
 
-    match op_name {
-        "quote" => op_quote(args),
-        "p_env" => {
-            println!("{:?}", env);
-            Ok(c_nil())
-        }
-        "def" => op_def(args, env),
-        "if" => op_if(args, env),
-        "fn*" => op_lambda(args, env),
-        "loop" => op_loop(args, env),
-        "recur" => Ok(ast.clone()),
-        "defmacro" => op_macro(args, env),
-        "eval" => eval(&eval(&safe_get(args, 1), env)?, env),
-        "do" => {
-            let evaled_args = eval_list_elements(&args[1..], env)?;
-            Ok(evaled_args.last().cloned().unwrap_or_else(c_nil))
-        }
-        "macroexpand" => op_macroexpand(&eval_exp(&safe_get(args, 1), env)?, env),
-        // Some function call with evaled arguments
-        _ => {
-            let evaled_args = eval_ast(ast, env)?;
-            let args = match evaled_args.get_list() {
-                Ok(args) => args,
-                _ => return Err(AtomError::InvalidOperation(op_name.to_string())),
-            };
+    let mut map = FnvHashMap::default();
+    map.insert(AtomKey::new(c_keyword("type")).unwrap(), c_keyword(condition_type));
+    map.insert(AtomKey::new(c_keyword("message")).unwrap(),
+               c_string(&format!("{}", err)));
+    c_map(map)
+}
 
-            trace!("fn=eval_exp op_name={} args={:?}",
-                   op_name,
-                   &args[1..]);
-            let subject_func = &args[0];
-            subject_func.apply(&args[1..])
+// :kind for keywords, (:type condition) for maps and :error for anything else
+fn condition_type(condition: &AtomVal) -> AtomVal {
+    match **condition {
+        AtomType::Keyword(_) => condition.clone(),
+        AtomType::Map(ref map) => {
+            map.get(&AtomKey::new(c_keyword("type")).unwrap())
+                .cloned()
+                .unwrap_or_else(|| c_keyword("error"))
         }
-
+        _ => c_keyword("error"),
     }
 }
 
-fn eval_list_elements(list: &[AtomVal], env: &Env) -> Result<Vec<AtomVal>, AtomError> {
-    let mut evaled_elements = Vec::with_capacity(list.len());
+fn handles(handler_type: &AtomVal, condition_type: &AtomVal) -> bool {
+    handler_type.is_keyword("default") || handler_type == condition_type
+}
+
+#[derive(Clone)]
+enum SeqKind {
+    // function call, the first value is the function
+    Call,
+    Vector,
+    Map,
+    // initial values or recur arguments of the given loop form
+    Loop(AtomVal),
+}
+
+// What to do with the value of the expression evaluated next
+#[derive(Clone)]
+enum Frame {
+    Seq {
+        kind: SeqKind,
+        items: AtomVal,
+        index: usize,
+        step: usize,
+        values: Vec<AtomVal>,
+        env: Env,
+    },
+    Def { name: AtomVal, env: Env },
+    Macro { name: AtomVal },
+    MacroExpand { env: Env },
+    If { ast: AtomVal, env: Env },
+    Do { ast: AtomVal, index: usize, env: Env },
+    Let { ast: AtomVal, index: usize, env: Env },
+    Loop { ast: AtomVal, env: Env },
+    Eval { env: Env },
+    // the env (eval form env) evaluates form in comes first
+    EvalIn { form: AtomVal, env: Env },
+    // depth of the buffer a with-out-str body prints into
+    OutStr { depth: usize },
+    // names a binding body rebound, with the envs they're defined in and their values before
+    Rebound { saved: Vec<(Env, AtomVal, AtomVal)> },
+    Match { ast: AtomVal, env: Env },
+    MatchGuard {
+        ast: AtomVal,
+        clauses: Rc<[MatchClause]>,
+        index: usize,
+        value: AtomVal,
+        env: Env,
+        match_env: Env,
+    },
+    CallCC,
+    Yield,
+    Handlers { handlers: Vec<(AtomVal, AtomVal)> },
+    Catch { handlers: Vec<(AtomVal, AtomVal)> },
+    Restarts { restarts: Vec<(AtomVal, AtomVal)> },
+    Signal {
+        condition: AtomVal,
+        search_from: usize,
+        error: Option<AtomError>,
+    },
+    SignalStart,
+    Protect { ast: AtomVal, env: Env },
+    Cleanup { value: AtomVal },
+    // function running while profiling, a marker which keeps tail calls from dropping it
+    Profile { name: String },
+}
 
-    for element in list {
-        evaled_elements.push(eval(element, env)?);
+impl Frame {
+    // Whether the frames stand for the same form in progress, as far as leaving it needs
+    // cleaning up; other frames only need to be of the same kind
+    fn same_as(&self, other: &Frame) -> bool {
+        match (self, other) {
+            (&Frame::Protect { ref ast, ref env },
+             &Frame::Protect { ast: ref other_ast, env: ref other_env }) => {
+                Rc::ptr_eq(ast, other_ast) && Rc::ptr_eq(env, other_env)
+            }
+            (&Frame::OutStr { depth }, &Frame::OutStr { depth: other_depth }) => {
+                depth == other_depth
+            }
+            (&Frame::Rebound { ref saved }, &Frame::Rebound { saved: ref other_saved }) => {
+                saved.len() == other_saved.len() &&
+                saved.iter().zip(other_saved).all(|(a, b)| {
+                    Rc::ptr_eq(&a.0, &b.0) && Rc::ptr_eq(&a.1, &b.1) && Rc::ptr_eq(&a.2, &b.2)
+                })
+            }
+            _ => mem::discriminant(self) == mem::discriminant(other),
+        }
     }
+}
 
-    Ok(evaled_elements)
+enum State {
+    Eval(AtomVal, Env),
+    Apply(AtomVal, Vec<AtomVal>),
+    Return(AtomVal),
 }
 
-fn eval_ast(ast: &AtomVal, env: &Env) -> AtomRet {
-    trace!("fn=eval_ast ast={}", ast.format(true));
+enum Outcome {
+    Done(AtomVal),
+    Yield(AtomVal),
+}
 
-    match **ast {
-        AtomType::Symbol(ref name) => {
-            if let Some(atom) = env_get(&env, &ast) {
-                Ok(atom)
-            } else {
-                Err(AtomError::UndefinedSymbol(name.to_string()))
+type StateRet = Result<State, AtomError>;
+
+// Evaluator keeping its continuation in an explicit stack of frames instead of
+// the Rust call stack, so evaluation can be suspended (see `yield`) and resumed.
+// Builtins calling back into lisp (e.g. `comp`) start a nested machine.
+#[derive(Clone)]
+struct Machine {
+    id: usize,
+    stack: Vec<Frame>,
+    in_generator: bool,
+    // name the function applied next was called by, tracked while there are breakpoints or
+    // a profile is taken
+    callee: Option<String>,
+    // form evaluated last and its scope, tracked while post-mortem debugging
+    last: Option<(AtomVal, Env)>,
+    // call form whose values are being applied, the innermost form if applying them fails
+    call: Option<AtomVal>,
+}
+
+thread_local! {
+    static MACHINE_ID: Cell<usize> = Cell::new(0);
+    // Argument vectors of finished calls, handed to the next calls instead of allocating; the
+    // atoms in them come from the arena like all others
+    static ARG_BUFFERS: RefCell<Vec<Vec<AtomVal>>> = RefCell::new(vec![]);
+}
+
+const ARG_BUFFERS_KEPT: usize = 64;
+
+fn arg_buffer(capacity: usize) -> Vec<AtomVal> {
+    let mut buffer = ARG_BUFFERS.with(|buffers| buffers.borrow_mut().pop()).unwrap_or_default();
+    buffer.reserve(capacity);
+    buffer
+}
+
+// Drops the args right away like dropping the Vec would, keeps the allocation
+fn recycle(mut args: Vec<AtomVal>) {
+    args.clear();
+    ARG_BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        if buffers.len() < ARG_BUFFERS_KEPT {
+            buffers.push(args);
+        }
+    });
+}
+
+impl Machine {
+    fn new(in_generator: bool) -> Machine {
+        let id = MACHINE_ID.with(|id| {
+            id.set(id.get() + 1);
+            id.get()
+        });
+
+        Machine {
+            id: id,
+            stack: vec![],
+            in_generator: in_generator,
+            callee: None,
+            last: None,
+            call: None,
+        }
+    }
+
+    // Functions running while profiling, outermost first
+    fn profiled(&self) -> Vec<String> {
+        self.stack
+            .iter()
+            .filter_map(|frame| match *frame {
+                Frame::Profile { ref name } => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Forms being evaluated, innermost first, as the debugger shows them
+    fn forms(&self) -> Vec<AtomVal> {
+        self.stack
+            .iter()
+            .rev()
+            .filter_map(|frame| match *frame {
+                Frame::Seq { kind: SeqKind::Call, ref items, .. } => Some(items.clone()),
+                Frame::If { ref ast, .. } |
+                Frame::Do { ref ast, .. } |
+                Frame::Let { ref ast, .. } |
+                Frame::Loop { ref ast, .. } |
+                Frame::Match { ref ast, .. } |
+                Frame::MatchGuard { ref ast, .. } |
+                Frame::Protect { ref ast, .. } => Some(ast.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn run(&mut self, state: State) -> Result<Outcome, AtomError> {
+        let mut state = state;
+        loop {
+            stats::eval_step();
+            if profile::is_profiling() {
+                profile::step(|| self.profiled());
             }
+            let next = match state {
+                State::Eval(ast, env) => {
+                    if debug::is_post_mortem() {
+                        self.last = Some((ast.clone(), env.clone()));
+                    }
+                    self.call = None;
+                    self.eval(ast, env)
+                }
+                State::Apply(func, args) => self.apply(func, args),
+                State::Return(value) => {
+                    self.call = None;
+                    match self.stack.pop() {
+                        None => return Ok(Outcome::Done(value)),
+                        Some(Frame::Yield) => return Ok(Outcome::Yield(value)),
+                        Some(Frame::Signal { condition, search_from, error }) => {
+                            // the handler declined, an unhandled error propagates as is
+                            state = match self.signal(condition, error, search_from) {
+                                Ok(state) => state,
+                                Err(err) => return self.abort(err),
+                            };
+                            continue;
+                        }
+                        Some(frame) => self.resume(frame, value),
+                    }
+                }
+            };
+
+            state = match next.or_else(|err| self.handle_error(err)) {
+                Ok(state) => state,
+                Err(err) => return self.abort(err),
+            };
         }
-        AtomType::List(ref args) => Ok(c_list(eval_list_elements(args, env)?)),
-        _ => Ok(ast.clone()),
     }
-}
 
-pub fn eval(ast: &AtomVal, env: &Env) -> AtomRet {
-    match **ast {
-        AtomType::List(_) => {
-            let ast = op_macroexpand(ast, env)?;
-            match *ast {
-                AtomType::List(_) => eval_exp(&ast, env),
-                _ => eval_ast(&ast, env),
+    // Pops frames down to stack[index], running the cleanups of unwind-protect forms, dropping
+    // what with-out-str bodies printed and restoring what binding forms rebound on the way.
+    // A failing cleanup doesn't stop the others, the first error comes back once all are done.
+    fn unwind_to(&mut self, index: usize) -> Result<(), AtomError> {
+        let mut failed = None;
+        while self.stack.len() > index {
+            match self.stack.pop() {
+                Some(Frame::Protect { ast, env }) => {
+                    if let Err(err) = cleanup(&ast, &env) {
+                        failed = failed.or(Some(err));
+                    }
+                }
+                Some(Frame::OutStr { depth }) => {
+                    output::end_buffer(depth);
+                }
+                Some(Frame::Rebound { saved }) => restore(saved),
+                _ => {}
             }
         }
-        _ => eval_ast(ast, env),
+
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
-}
 
-pub fn eval_str(str: &str, env: &Env) -> AtomRet {
-    let tokens = lex(str);
-    match tokens {
-        Ok(ref tokens) => {
-            // let prefix = format!("exp: {} -> lex: {}", str, format_tokens(tokens));
-            let parser = Parser::new(tokens);
-            match parser.start() {
-                Ok(ast) => {
-                    // print!("{} -> ast: {}\n", prefix, ast.format(true));
-
-                    match eval(&ast, &env) {
-                        Ok(result) => {
-                            return Ok(result);
-                        }
-                        Err(err) => {
-                            println!("=> {}", err);
-                            return Err(err);
-                        }
+    // An unhandled error leaves the machine, after cleaning up
+    fn abort(&mut self, err: AtomError) -> Result<Outcome, AtomError> {
+        let forms = self.forms();
+        if let Some((ast, env)) = self.last.take() {
+            // a symbol or argument failed in the innermost form, a list failed itself
+            let form = match *ast {
+                AtomType::List(..) => ast.clone(),
+                _ => forms.first().cloned().unwrap_or(ast.clone()),
+            };
+            debug::record_failure(&err, env, form, forms.clone());
+        }
+        // the call which failed applying its values isn't pending anymore, it's the innermost
+        let mut forms = forms;
+        if let Some(call) = self.call.take() {
+            forms.insert(0, call);
+        }
+        report::record_trace(&err, forms);
+        // the error which started unwinding is the one to report
+        if let Err(cleanup) = self.unwind_to(0) {
+            warn!("action=abort cleanup failed={}", cleanup);
+        }
+        Err(err)
+    }
+
+    fn handle_error(&mut self, err: AtomError) -> StateRet {
+        trace!("fn=Machine#handle_error err={}", err);
+
+        if let AtomError::Restart(ref name, ref args) = err {
+            let found = self.stack.iter().enumerate().rev().filter_map(|(index, frame)| {
+                match *frame {
+                    Frame::Restarts { ref restarts } => {
+                        restarts.iter()
+                            .find(|restart| restart.0.is_symbol(name))
+                            .map(|restart| (index, restart.1.clone()))
+                    }
+                    _ => None,
+                }
+            }).next();
+
+            return match found {
+                Some((index, func)) => {
+                    // a failed cleanup is signalled from the restart-case instead
+                    match self.unwind_to(index) {
+                        Ok(()) => Ok(State::Apply(func, args.clone())),
+                        Err(cleanup) => self.handle_error(cleanup),
                     }
+                }
+                None => Err(err.clone()),
+            };
+        }
+
+        let from = self.stack.len();
+        self.signal(error_condition(&err), Some(err), from)
+    }
+
+    // Offers condition to the handlers established below stack[from], innermost first.
+    // handler-bind handlers run on top of the stack and may decline by returning,
+    // handler-case unwinds to its frame.
+    fn signal(&mut self,
+              condition: AtomVal,
+              error: Option<AtomError>,
+              from: usize)
+              -> StateRet {
+        let condition_type = condition_type(&condition);
+        let mut index = from;
+
+        while index > 0 {
+            index -= 1;
+
+            let handler = match self.stack[index] {
+                // handlers at and above a running handler's binding are disabled
+                Frame::Signal { search_from, .. } => {
+                    index = search_from;
+                    continue;
+                }
+                Frame::Handlers { ref handlers } => {
+                    handlers.iter()
+                        .find(|handler| handles(&handler.0, &condition_type))
+                        .map(|handler| (false, handler.1.clone()))
+                }
+                Frame::Catch { ref handlers } => {
+                    handlers.iter()
+                        .find(|handler| handles(&handler.0, &condition_type))
+                        .map(|handler| (true, handler.1.clone()))
+                }
+                _ => None,
+            };
+
+            match handler {
+                Some((true, func)) => {
+                    // a failed cleanup is signalled from the handler-case instead
+                    return match self.unwind_to(index) {
+                        Ok(()) => Ok(State::Apply(func, vec![condition])),
+                        Err(cleanup) => self.handle_error(cleanup),
+                    };
+                }
+                Some((false, func)) => {
+                    self.stack.push(Frame::Signal {
+                        condition: condition.clone(),
+                        search_from: index,
+                        error: error,
+                    });
+                    return Ok(State::Apply(func, vec![condition]));
+                }
+                None => {}
+            }
+        }
+
+        match error {
+            Some(err) => Err(err),
+            None => Ok(State::Return(c_nil())),
+        }
+    }
+
+    fn eval(&mut self, ast: AtomVal, env: Env) -> StateRet {
+        trace!("fn=Machine#eval ast={}", ast.format(true));
+
+        match *ast {
+            AtomType::Symbol(ref name, _) => {
+                return match env_get(&env, &ast) {
+                    Some(atom) => Ok(State::Return(atom)),
+                    None => Err(AtomError::UndefinedSymbol(name.to_string())),
+                };
+            }
+            AtomType::List(..) => {}
+            AtomType::Vector(_) => return self.eval_seq(SeqKind::Vector, ast, 0, 1, vec![], env),
+            AtomType::Map(ref map) => {
+                let mut items = Vec::with_capacity(map.len() * 2);
+                for (key, value) in map.iter() {
+                    items.push(key.value().clone());
+                    items.push(value.clone());
+                }
+                return self.eval_seq(SeqKind::Map, c_list(items), 0, 1, vec![], env);
+            }
+            _ => return Ok(State::Return(ast)),
+        }
+
+        if debug::is_stepping() {
+            let mut forms = self.forms();
+            forms.insert(0, ast.clone());
+            debug::enter(&format!("step: {}", debug::summary(&ast)), &env, &forms)?;
+        }
+        let expanded = expand_macros(ast, &env)?;
+        if let AtomType::List(..) = *expanded {
+            self.eval_exp(expanded, env)
+        } else {
+            Ok(State::Eval(expanded, env))
+        }
+    }
+
+    fn eval_exp(&mut self, ast: AtomVal, env: Env) -> StateRet {
+        let args = ast.get_list()?;
+        let op_name = match args.get(0) {
+            None => return Ok(State::Return(ast)),
+            Some(op) => {
+                match **op {
+                    AtomType::Symbol(ref v, _) => v.as_str(),
+                    _ => "__func__",
+                }
+            }
+        };
+
+        match op_name {
+            "quote" => Ok(State::Return(op_quote(args)?)),
+            "p_env" => {
+                println!("{:?}", env);
+                Ok(State::Return(c_nil()))
+            }
+            "def" => {
+                let name = safe_get(args, 1);
+                name.get_symbol()?;
+                self.stack.push(Frame::Def { name: name, env: env.clone() });
+                Ok(State::Eval(safe_get(args, 2), env))
+            }
+            "defn" => Ok(State::Return(op_defn(args, &env)?)),
+            "defrecord" | "defstruct" => Ok(State::Return(op_defrecord(args, &env)?)),
+            "defmulti" => Ok(State::Return(op_defmulti(args, &env)?)),
+            "defmethod" => Ok(State::Return(op_defmethod(args, &env)?)),
+            "defprotocol" => Ok(State::Return(op_defprotocol(args, &env)?)),
+            "extend-type" => Ok(State::Return(op_extend_type(args, &env)?)),
+            "if" => {
+                let test = safe_get(args, 1);
+                self.stack.push(Frame::If { ast: ast, env: env.clone() });
+                Ok(State::Eval(test, env))
+            }
+            "fn*" => Ok(State::Return(op_lambda(args, &env)?)),
+            "let" => self.op_let(ast, env),
+            "loop" => self.op_loop(ast, env),
+            "->" => Ok(State::Eval(thread_form(args, false), env)),
+            "->>" => Ok(State::Eval(thread_form(args, true), env)),
+            "match" => {
+                let value = safe_get(args, 1);
+                self.stack.push(Frame::Match { ast: ast, env: env.clone() });
+                Ok(State::Eval(value, env))
+            }
+            "recur" => Ok(State::Return(ast)),
+            "defmacro" => {
+                let name = safe_get(args, 1);
+                name.get_symbol()?;
+                self.stack.push(Frame::Def { name: name.clone(), env: env.clone() });
+                self.stack.push(Frame::Macro { name: name });
+                Ok(State::Eval(safe_get(args, 2), env))
+            }
+            "eval" => {
+                match args.get(2).cloned() {
+                    Some(target) => {
+                        let form = safe_get(args, 1);
+                        self.stack.push(Frame::EvalIn { form: form, env: env.clone() });
+                        Ok(State::Eval(target, env))
+                    }
+                    None => {
+                        self.stack.push(Frame::Eval { env: env.clone() });
+                        Ok(State::Eval(safe_get(args, 1), env))
+                    }
+                }
+            }
+            "current-env" => Ok(State::Return(c_env_value(env))),
+            "do" => self.eval_do(ast, 1, env),
+            "macroexpand" => {
+                self.stack.push(Frame::MacroExpand { env: env.clone() });
+                Ok(State::Eval(safe_get(args, 1), env))
+            }
+            "call/cc" => {
+                self.stack.push(Frame::CallCC);
+                Ok(State::Eval(safe_get(args, 1), env))
+            }
+            "signal" => {
+                self.stack.push(Frame::SignalStart);
+                Ok(State::Eval(safe_get(args, 1), env))
+            }
+            "handler-bind" => {
+                let bindings = safe_get(args, 1);
+                let bindings = bindings.get_seq()?;
+                let mut handlers = Vec::with_capacity(bindings.len() / 2);
+                for chunk in bindings.chunks(2) {
+                    handlers.push((chunk[0].clone(), eval(&safe_get(chunk, 1), &env)?));
+                }
+
+                self.stack.push(Frame::Handlers { handlers: handlers });
+                self.eval_do(ast, 2, env)
+            }
+            "handler-case" => {
+                let handlers = clause_funcs(&args[2..], &env)?;
+                self.stack.push(Frame::Catch { handlers: handlers });
+                Ok(State::Eval(safe_get(args, 1), env))
+            }
+            "restart-case" => {
+                let restarts = clause_funcs(&args[2..], &env)?;
+                self.stack.push(Frame::Restarts { restarts: restarts });
+                Ok(State::Eval(safe_get(args, 1), env))
+            }
+            "unwind-protect" => {
+                let form = safe_get(args, 1);
+                self.stack.push(Frame::Protect { ast: ast, env: env.clone() });
+                Ok(State::Eval(form, env))
+            }
+            "with-out-str" => {
+                self.stack.push(Frame::OutStr { depth: output::begin_buffer() });
+                self.eval_do(ast, 1, env)
+            }
+            "binding" => {
+                let saved = rebind(&safe_get(args, 1), &env)?;
+                self.stack.push(Frame::Rebound { saved: saved });
+                self.eval_do(ast, 2, env)
+            }
+            "with-open" => Ok(State::Eval(with_open_form(args)?, env)),
+            "assert" => Ok(State::Eval(assert_form(args, &env)?, env)),
+            "break" => {
+                debug::enter("break", &env, &self.forms())?;
+                Ok(State::Return(c_nil()))
+            }
+            "yield" => {
+                if !self.in_generator {
+                    return Err(AtomError::InvalidOperation("yield outside of a generator"
+                        .to_string()));
+                }
+                self.stack.push(Frame::Yield);
+                Ok(State::Eval(safe_get(args, 1), env))
+            }
+            // Some function call with evaled arguments
+            _ => {
+                let values = arg_buffer(args.len());
+                self.eval_seq(SeqKind::Call, ast, 0, 1, values, env)
+            }
+        }
+    }
+
+    // Evaluates every `step`th element of items starting at index, then completes kind
+    fn eval_seq(&mut self,
+                kind: SeqKind,
+                items: AtomVal,
+                index: usize,
+                step: usize,
+                values: Vec<AtomVal>,
+                env: Env)
+                -> StateRet {
+        let next = items.get_seq()?.get(index).cloned();
+        match next {
+            Some(item) => {
+                self.stack.push(Frame::Seq {
+                    kind: kind,
+                    items: items,
+                    index: index + step,
+                    step: step,
+                    values: values,
+                    env: env.clone(),
+                });
+                Ok(State::Eval(item, env))
+            }
+            None => {
+                if let SeqKind::Call = kind {
+                    if debug::has_breakpoints() || profile::is_profiling() ||
+                       diagnostics::has_deprecations() {
+                        self.callee = items.get_seq()?
+                            .get(0)
+                            .and_then(|head| head.get_symbol().ok())
+                            .map(|name| name.to_string());
+                    }
+                    self.call = Some(items);
+                }
+                self.complete_seq(kind, values, env)
+            }
+        }
+    }
+
+    fn complete_seq(&mut self, kind: SeqKind, values: Vec<AtomVal>, env: Env) -> StateRet {
+        match kind {
+            SeqKind::Call => {
+                let mut values = values;
+                let func = values.remove(0);
+                trace!("fn=Machine#complete_seq func={} args={:?}", func, values);
+                Ok(State::Apply(func, values))
+            }
+            SeqKind::Vector => Ok(State::Return(c_vector(values))),
+            SeqKind::Map => {
+                let mut map = FnvHashMap::default();
+                for pair in values.chunks(2) {
+                    map.insert(AtomKey::new(pair[0].clone())?, pair[1].clone());
+                }
+                Ok(State::Return(c_map(map)))
+            }
+            SeqKind::Loop(ast) => {
+                let names = safe_get(ast.get_list()?, 1)
+                    .get_seq()?
+                    .chunks(2)
+                    .map(|chunk| chunk[0].clone())
+                    .collect::<Vec<_>>();
+                env_bind(&env, &names, &values)?;
+                let body = safe_get(ast.get_list()?, 2);
+                self.stack.push(Frame::Loop { ast: ast, env: env.clone() });
+                Ok(State::Eval(body, env))
+            }
+        }
+    }
+
+    // Evaluates the forms of ast from index on, the last one in tail position
+    fn eval_do(&mut self, ast: AtomVal, index: usize, env: Env) -> StateRet {
+        let (form, is_last) = {
+            let forms = ast.get_list()?;
+            match forms.get(index) {
+                None => return Ok(State::Return(c_nil())),
+                Some(form) => (form.clone(), index + 1 >= forms.len()),
+            }
+        };
+
+        if !is_last {
+            self.stack.push(Frame::Do { ast: ast, index: index + 1, env: env.clone() });
+        }
+        Ok(State::Eval(form, env))
+    }
+
+    // [let [name value ...] body...], or (let ((name value) ...) body...) as before let took
+    // destructuring patterns, which binds the names once all values are evaluated
+    fn op_let(&mut self, ast: AtomVal, env: Env) -> StateRet {
+        trace!("fn=op_let ast={}", ast);
+
+        if let Some(pairs) = let_pairs(&safe_get(ast.get_list()?, 1)) {
+            // what the let macro of the prelude expanded to
+            let mut names = vec![];
+            let mut call = vec![c_nil()];
+            for pair in pairs {
+                names.push(safe_get(pair.get_list()?, 0));
+                call.push(safe_get(pair.get_list()?, 1));
+            }
+            let mut lambda = vec![c_symbol("fn*"), c_list(names)];
+            lambda.extend(ast.get_list()?[2..].iter().cloned());
+            call[0] = c_list(lambda);
+            return Ok(State::Eval(c_list(call), env));
+        }
+
+        if safe_get(ast.get_list()?, 1).get_seq()?.len() % 2 == 1 {
+            return Err(AtomError::InvalidArgument("Let is missing value for one of the bindings"
+                .to_string()));
+        }
+
+        self.eval_let(ast, 0, c_env(Some(env)))
+    }
+
+    fn eval_let(&mut self, ast: AtomVal, index: usize, let_env: Env) -> StateRet {
+        let value = safe_get(safe_get(ast.get_list()?, 1).get_seq()?, index + 1);
+        if index < safe_get(ast.get_list()?, 1).get_seq()?.len() {
+            self.stack.push(Frame::Let { ast: ast, index: index, env: let_env.clone() });
+            Ok(State::Eval(value, let_env))
+        } else {
+            self.eval_do(ast, 2, let_env)
+        }
+    }
+
+    // [loop (args...) (body)]
+    fn op_loop(&mut self, ast: AtomVal, env: Env) -> StateRet {
+        trace!("fn=op_loop ast={}", ast);
+
+        let loop_args = safe_get(ast.get_list()?, 1);
+        if loop_args.get_seq()?.len() % 2 == 1 {
+            return Err(AtomError::InvalidArgument("Loop is missing value for one of the \
+                                                   param"
+                .to_string()));
+        }
+
+        self.eval_seq(SeqKind::Loop(ast), loop_args, 1, 2, vec![], env)
+    }
+
+    fn apply(&mut self, func: AtomVal, args: Vec<AtomVal>) -> StateRet {
+        let callee = self.callee.take();
+        if let Some(ref name) = callee {
+            if diagnostics::has_deprecations() {
+                diagnostics::called(name);
+            }
+        }
+        match *func {
+            AtomType::AFunc(ref fd) => {
+                stats::function_applied();
+                let func_env = if fd.frameless() {
+                    fd.env.clone()
+                } else {
+                    let func_env = c_env(Some(fd.env.clone()));
+                    env_bind(&func_env, fd.params.get_seq()?, &args)?;
+                    func_env
+                };
+                recycle(args);
 
+                if debug::has_breakpoints() {
+                    let breakpoint = callee.iter()
+                        .chain(fd.name.iter())
+                        .find(|name| debug::is_breakpoint(name))
+                        .cloned();
+                    if let Some(name) = breakpoint {
+                        debug::enter(&format!("breakpoint in {}", name), &func_env, &self.forms())?;
+                    }
+                }
+                if profile::is_profiling() {
+                    let name = callee.or_else(|| fd.name.clone())
+                        .unwrap_or_else(|| "fn*".to_string());
+                    self.stack.push(Frame::Profile { name: name });
+                }
+
+                trace!("action=Machine#apply env={:?}", func_env);
+                Ok(State::Eval(fd.exp.clone(), func_env))
+            }
+            AtomType::Method(ref method) => Ok(State::Apply(method.dispatch(&args)?, args)),
+            AtomType::MultiFn(ref multi) => {
+                let value = multi.dispatch_fn().apply(&args)?;
+                Ok(State::Apply(multi.select(&value)?, args))
+            }
+            AtomType::Continuation(ref k) if k.machine_id == self.id => {
+                // the frames left behind are unwound like for an error
+                let shared = self.stack
+                    .iter()
+                    .zip(&k.stack)
+                    .take_while(|&(frame, kept)| frame.same_as(kept))
+                    .count();
+                self.unwind_to(shared)?;
+                self.stack = k.stack.clone();
+                Ok(State::Return(safe_get(&args, 0)))
+            }
+            _ if profile::is_profiling() => {
+                profile::suspend(self.profiled());
+                let result = func.apply(&args);
+                profile::resume();
+                recycle(args);
+                Ok(State::Return(result?))
+            }
+            _ => {
+                let result = func.apply(&args);
+                recycle(args);
+                Ok(State::Return(result?))
+            }
+        }
+    }
+
+    fn resume(&mut self, frame: Frame, value: AtomVal) -> StateRet {
+        match frame {
+            Frame::Seq { kind, items, index, step, values, env } => {
+                let mut values = values;
+                values.push(value);
+                self.eval_seq(kind, items, index, step, values, env)
+            }
+            Frame::Def { name, env } => {
+                if diagnostics::has_subscribers() && env_parent(&env).is_none() {
+                    let builtin = env_get(&env, &name).map_or(false, |old| match *old {
+                        AtomType::Func(_) => true,
+                        _ => false,
+                    });
+                    if builtin {
+                        diagnostics::warn(diagnostics::Kind::Redefinition,
+                                          format!("def {} replaces the builtin", name));
+                    }
+                }
+                env_set(&env, &name, value);
+                Ok(State::Return(c_symbol(name.get_symbol()?)))
+            }
+            Frame::Macro { name } => {
+                match *value {
+                    AtomType::AFunc(ref fd) => Ok(State::Return(c_macro(fd, name.get_symbol()?))),
+                    _ => Err(AtomError::InvalidType("function".to_string(), value.format(true))),
                 }
-                Err(err) => {
-                    println!("{} -> ast: {}", str, err);
-                    // FIXME: should return correct err;
-                    return Ok(c_nil());
+            }
+            Frame::MacroExpand { env } => Ok(State::Return(op_macroexpand(&value, &env)?)),
+            Frame::If { ast, env } => {
+                let branch = if value.is_truthy() { 2 } else { 3 };
+                Ok(State::Eval(safe_get(ast.get_list()?, branch), env))
+            }
+            Frame::Do { ast, index, env } => self.eval_do(ast, index, env),
+            Frame::Let { ast, index, env } => {
+                let pattern = safe_get(safe_get(ast.get_list()?, 1).get_seq()?, index);
+                env_bind_pattern(&env, &pattern, value)?;
+                self.eval_let(ast, index + 2, env)
+            }
+            Frame::Loop { ast, env } => {
+                let is_recur = match *value {
+                    AtomType::List(ref list, _) => safe_get(list, 0).is_symbol("recur"),
+                    _ => false,
+                };
+
+                if is_recur {
+                    self.eval_seq(SeqKind::Loop(ast), value, 1, 1, vec![], env)
+                } else {
+                    Ok(State::Return(value))
+                }
+            }
+            Frame::Eval { env } => Ok(State::Eval(value, env)),
+            Frame::EvalIn { form, env } => {
+                let target = match *value {
+                    AtomType::Env(ref target) => target.clone(),
+                    _ => return Err(AtomError::InvalidType("env".to_string(), value.format(true))),
+                };
+                self.stack.push(Frame::Eval { env: target });
+                Ok(State::Eval(form, env))
+            }
+            Frame::Match { ast, env } => {
+                let clauses = match_clauses(&ast)?;
+                self.eval_match(ast, clauses, 0, value, env)
+            }
+            Frame::MatchGuard { ast, clauses, index, value: matched, env, match_env } => {
+                if value.is_truthy() {
+                    Ok(State::Eval(clauses[index].body.clone(), match_env))
+                } else {
+                    self.eval_match(ast, clauses, index + 1, matched, env)
                 }
             }
+            Frame::CallCC => {
+                let k = Continuation {
+                    machine_id: self.id,
+                    stack: self.stack.clone(),
+                };
+                Ok(State::Apply(value, vec![c_continuation(k)]))
+            }
+            Frame::SignalStart => {
+                let from = self.stack.len();
+                self.signal(value, None, from)
+            }
+            Frame::Protect { ast, env } => {
+                self.stack.push(Frame::Cleanup { value: value });
+                self.eval_do(ast, 2, env)
+            }
+            Frame::OutStr { depth } => Ok(State::Return(c_string(&output::end_buffer(depth)))),
+            Frame::Rebound { saved } => {
+                restore(saved);
+                Ok(State::Return(value))
+            }
+            Frame::Cleanup { value } => Ok(State::Return(value)),
+            Frame::Handlers { .. } |
+            Frame::Catch { .. } |
+            Frame::Restarts { .. } |
+            Frame::Profile { .. } => Ok(State::Return(value)),
+            Frame::Yield | Frame::Signal { .. } => unreachable!(),
+        }
+    }
+
+    // Tries the clauses of a match from index on
+    fn eval_match(&mut self,
+                  ast: AtomVal,
+                  clauses: Rc<[MatchClause]>,
+                  index: usize,
+                  value: AtomVal,
+                  env: Env)
+                  -> StateRet {
+        for index in index..clauses.len() {
+            let mut bindings = vec![];
+            if !run_checks(&clauses[index].checks, &value, &mut bindings) {
+                continue;
+            }
+
+            let match_env = c_env(Some(env.clone()));
+            for (name, value) in bindings {
+                env_set(&match_env, &name, value);
+            }
+
+            return match clauses[index].guard.clone() {
+                Some(guard) => {
+                    self.stack.push(Frame::MatchGuard {
+                        ast: ast,
+                        clauses: clauses.clone(),
+                        index: index,
+                        value: value,
+                        env: env,
+                        match_env: match_env.clone(),
+                    });
+                    Ok(State::Eval(guard, match_env))
+                }
+                None => Ok(State::Eval(clauses[index].body.clone(), match_env)),
+            };
+        }
+
+        Err(AtomError::InvalidArgument(format!("no pattern matched {}", value)))
+    }
+}
+
+pub fn eval(ast: &AtomVal, env: &Env) -> AtomRet {
+    match Machine::new(false).run(State::Eval(ast.clone(), env.clone()))? {
+        Outcome::Done(value) => Ok(value),
+        Outcome::Yield(_) => unreachable!(),
+    }
+}
+
+// Evaluates the body of a function applied from outside the evaluator, under its name while
+// profiling
+pub fn eval_body(fd: &AFuncData, env: &Env) -> AtomRet {
+    let mut machine = Machine::new(false);
+    if profile::is_profiling() {
+        let name = fd.name.clone().unwrap_or_else(|| "fn*".to_string());
+        machine.stack.push(Frame::Profile { name: name });
+    }
+    match machine.run(State::Eval(fd.exp.clone(), env.clone()))? {
+        Outcome::Done(value) => Ok(value),
+        Outcome::Yield(_) => unreachable!(),
+    }
+}
+
+// The rest of the computation at a `call/cc`; it can only be invoked (any number
+// of times) within the evaluation which captured it
+pub struct Continuation {
+    machine_id: usize,
+    stack: Vec<Frame>,
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<continuation>")
+    }
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Continuation) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+enum GeneratorState {
+    Pending(AtomVal, Vec<AtomVal>),
+    Suspended(Machine),
+    Done,
+}
+
+// Function application which can be suspended at each `yield` and resumed by `next!`
+pub struct Generator(RefCell<GeneratorState>);
+
+impl Generator {
+    pub fn new(func: AtomVal, args: Vec<AtomVal>) -> Generator {
+        Generator(RefCell::new(GeneratorState::Pending(func, args)))
+    }
+
+    pub fn is_done(&self) -> bool {
+        match *self.0.borrow() {
+            GeneratorState::Done => true,
+            _ => false,
+        }
+    }
+
+    // Runs until the next `yield`, which evaluates to the value sent in; nil once done
+    pub fn resume(&self, sent: AtomVal) -> AtomRet {
+        let mut state = match self.0.try_borrow_mut() {
+            Ok(state) => state,
+            Err(_) => {
+                return Err(AtomError::InvalidOperation("generator is already running".to_string()))
+            }
+        };
+
+        let (mut machine, start) = match mem::replace(&mut *state, GeneratorState::Done) {
+            GeneratorState::Pending(func, args) => (Machine::new(true), State::Apply(func, args)),
+            GeneratorState::Suspended(machine) => (machine, State::Return(sent)),
+            GeneratorState::Done => return Ok(c_nil()),
+        };
+
+        match machine.run(start)? {
+            Outcome::Yield(value) => {
+                *state = GeneratorState::Suspended(machine);
+                Ok(value)
+            }
+            Outcome::Done(_) => Ok(c_nil()),
         }
-        Err(err) => {
-            println!("lex: {} {}", str, err);
-            // FIXME: should return correct err;
-            return Ok(c_nil());
+    }
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<generator>")
+    }
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, other: &Generator) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Evaluates every top-level form in str, returns the value of the last one
+pub fn eval_str(str: &str, env: &Env) -> AtomRet {
+    eval_forms(Parser::new(Lexer::new(str)), env)
+}
+
+// Like eval_str, forms are read after the ones before them were evaluated, so reader macros
+// and tag handlers they set apply to the rest of str
+pub fn eval_str_with_reader(str: &str,
+                            env: &Env,
+                            macros: &ReaderMacros,
+                            tags: &TagHandlers)
+                            -> AtomRet {
+    let tokens = Lexer::new(str).with_reader_macros(macros.clone());
+    eval_forms(Parser::new(tokens).with_tag_handlers(tags.clone()), env)
+}
+
+// Stops at the first error, syntax errors included
+fn eval_forms(mut parser: Parser, env: &Env) -> AtomRet {
+    let mut result = c_nil();
+    loop {
+        match parser.next_form() {
+            Ok(Some(ast)) => {
+                result = eval(&ast, env)?;
+                arena::trim();
+            }
+            Ok(None) => return Ok(result),
+            Err(err) => return Err(AtomError::InvalidArgument(err.to_string())),
         }
     }
 }
@@ -260,6 +1620,7 @@ mod tests {
     use super::eval;
     use data::{c_symbol, c_int, c_list, AtomRet, AtomError};
     use env::Env;
+    use std::rc::Rc;
 
     pub fn print(v: AtomRet) -> String {
         match v {
@@ -273,6 +1634,42 @@ mod tests {
         super::super::core::build()
     }
 
+    #[test]
+    fn test_arg_buffers() {
+        let mut args = super::arg_buffer(3);
+        args.push(c_int(1));
+        super::recycle(args);
+
+        let args = super::arg_buffer(2);
+        assert!(args.is_empty());
+        assert!(args.capacity() >= 3);
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let env = env();
+        let form = c_list(vec![c_symbol("+"), c_int(1)]);
+        let same = super::expand_macros(form.clone(), &env).unwrap();
+        assert!(Rc::ptr_eq(&form, &same));
+
+        let form = super::eval_str("'(is true)", &env).unwrap();
+        assert_eq!(super::expand_macros(form, &env).unwrap().get_list().unwrap()[0],
+                   c_symbol("test-is"));
+    }
+
+    #[test]
+    fn test_match_compiled_once() {
+        let env = env();
+        let form = super::eval_str("'(match '(1 (2 3)) (a (b & c)) c _ 0)", &env).unwrap();
+        assert_eq!(eval(&form, &env).unwrap(), c_list(vec![c_int(3)]));
+
+        let clauses = super::match_clauses(&form).unwrap();
+        assert_eq!(clauses.len(), 2);
+        assert_eq!(clauses[0].checks.len(), 5);
+        assert_eq!(eval(&form, &env).unwrap(), c_list(vec![c_int(3)]));
+        assert!(Rc::ptr_eq(&clauses, &super::match_clauses(&form).unwrap()));
+    }
+
     #[test]
     fn eval_symbol() {
         eval(&c_symbol("Test"), &env()).unwrap_err();
@@ -0,0 +1,151 @@
+// The interpreter for hosts that aren't written in Rust, see rulsp.h. Values stay on this side
+// and are handed out as numbered handles, 0 standing for an error. Panics are caught at each
+// function, unwinding into C would abort the host. The rulsp pointer taken by the unsafe ones
+// must be NULL or one from rulsp_new that wasn't freed yet.
+#![allow(clippy::missing_safety_doc)]
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use fnv::FnvHashMap;
+use data::{catch_panic, AtomVal};
+use interpreter::Interpreter;
+
+pub struct Rulsp {
+    interpreter: Interpreter,
+    values: FnvHashMap<u64, AtomVal>,
+    next: u64,
+    // the last string handed out, valid until the next call
+    text: CString,
+    // the report of the last failed eval, kept apart so getting a string doesn't replace it
+    error: CString,
+}
+
+// nul bytes would cut the text short in C anyway
+fn c_string(text: String) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+impl Rulsp {
+    fn keep(&mut self, text: String) -> *const c_char {
+        self.text = c_string(text);
+        self.text.as_ptr()
+    }
+
+    fn fail(&mut self, error: String) -> u64 {
+        self.error = c_string(error);
+        0
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rulsp_new() -> *mut Rulsp {
+    catch_panic(|| {
+            let rulsp = Rulsp {
+                interpreter: Interpreter::new(),
+                values: FnvHashMap::default(),
+                next: 1,
+                text: CString::default(),
+                error: CString::default(),
+            };
+            Box::into_raw(Box::new(rulsp))
+        })
+        .unwrap_or(ptr::null_mut())
+}
+
+// Evaluates the forms in the nul-terminated source, returns a handle to the value of the last
+// one, or 0 with the error left for rulsp_error
+#[no_mangle]
+pub unsafe extern "C" fn rulsp_eval(rulsp: *mut Rulsp, source: *const c_char) -> u64 {
+    let rulsp = match rulsp.as_mut() {
+        Some(rulsp) => rulsp,
+        None => return 0,
+    };
+    if source.is_null() {
+        return rulsp.fail("source is NULL".to_string());
+    }
+    let source = match CStr::from_ptr(source).to_str() {
+        Ok(source) => source,
+        Err(err) => return rulsp.fail(format!("source is not UTF-8: {}", err)),
+    };
+    match catch_panic(|| rulsp.interpreter.run("<ffi>", source)) {
+        Ok(Ok(value)) => {
+            let handle = rulsp.next;
+            rulsp.next += 1;
+            rulsp.values.insert(handle, value);
+            handle
+        }
+        Ok(Err(report)) => rulsp.fail(report.render(false)),
+        Err(message) => rulsp.fail(format!("<ffi>: panic: {}", message)),
+    }
+}
+
+// The value printed as by println, NULL for a handle that was released or never handed out
+#[no_mangle]
+pub unsafe extern "C" fn rulsp_get_string(rulsp: *mut Rulsp, handle: u64) -> *const c_char {
+    let rulsp = match rulsp.as_mut() {
+        Some(rulsp) => rulsp,
+        None => return ptr::null(),
+    };
+    match catch_panic(|| rulsp.values.get(&handle).map(|value| value.format(false))) {
+        Ok(Some(text)) => rulsp.keep(text),
+        Ok(None) | Err(_) => ptr::null(),
+    }
+}
+
+// The report of the last failed rulsp_eval
+#[no_mangle]
+pub unsafe extern "C" fn rulsp_error(rulsp: *mut Rulsp) -> *const c_char {
+    rulsp.as_ref().map_or(ptr::null(), |rulsp| rulsp.error.as_ptr())
+}
+
+// Dropping a value runs no lisp code, but a panic in a Drop mustn't reach C either
+#[no_mangle]
+pub unsafe extern "C" fn rulsp_release(rulsp: *mut Rulsp, handle: u64) {
+    if let Some(rulsp) = rulsp.as_mut() {
+        let _ = catch_panic(|| rulsp.values.remove(&handle));
+    }
+}
+
+// Frees the interpreter along with the values still held
+#[no_mangle]
+pub unsafe extern "C" fn rulsp_free(rulsp: *mut Rulsp) {
+    if !rulsp.is_null() {
+        let _ = catch_panic(|| drop(Box::from_raw(rulsp)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+    use super::{rulsp_new, rulsp_eval, rulsp_get_string, rulsp_error, rulsp_release, rulsp_free};
+
+    #[test]
+    fn test_ffi() {
+        unsafe {
+            let rulsp = rulsp_new();
+            let eval = |source: &str| rulsp_eval(rulsp, CString::new(source).unwrap().as_ptr());
+            let string = |handle| CStr::from_ptr(rulsp_get_string(rulsp, handle)).to_str().unwrap();
+
+            let handle = eval("(def xs (list 1 2)) (cons 0 xs)");
+            assert!(handle != 0);
+            assert_eq!(string(handle), "(0 1 2)");
+            let other = eval("\"hi\"");
+            assert_eq!(string(other), "hi");
+            assert_eq!(string(handle), "(0 1 2)");
+
+            assert_eq!(eval("(cuont xs)"), 0);
+            let error = CStr::from_ptr(rulsp_error(rulsp)).to_str().unwrap();
+            assert!(error.starts_with("<ffi>:1:2: error: undefined symbol: cuont"));
+            // getting a string leaves the error as it was
+            assert_eq!(string(other), "hi");
+            let error = CStr::from_ptr(rulsp_error(rulsp)).to_str().unwrap();
+            assert!(error.starts_with("<ffi>:1:2: error: undefined symbol: cuont"));
+            assert_eq!(rulsp_eval(rulsp, ptr::null()), 0);
+
+            rulsp_release(rulsp, handle);
+            assert!(rulsp_get_string(rulsp, handle).is_null());
+            rulsp_free(rulsp);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use lexer::Token;
+use parser::{Cst, parse_cst, closer, delimiter};
+
+// Lists starting with these indent their body by two spaces instead of lining the arguments
+// up under the first one
+const BODY_FORMS: &'static [&'static str] = &["binding", "def", "defn", "defmacro", "defmethod",
+                                              "defmulti", "defprotocol", "defstruct", "defrecord",
+                                              "do", "extend-type", "fn", "fn*", "handler-bind",
+                                              "handler-case", "if", "let", "loop", "match",
+                                              "restart-case", "unwind-protect", "when", "when-not",
+                                              "with-open", "with-out-str"];
+
+struct Writer {
+    out: String,
+}
+
+impl Writer {
+    fn push(&mut self, text: &str) {
+        self.out.push_str(text)
+    }
+
+    fn column(&self) -> usize {
+        let line = match self.out.rfind('\n') {
+            Some(pos) => &self.out[pos + 1..],
+            None => &self.out[..],
+        };
+        line.chars().count()
+    }
+
+    // at the start of the output, a line or after a space
+    fn separated(&self) -> bool {
+        self.out.is_empty() || self.out.ends_with('\n') || self.out.ends_with(' ')
+    }
+
+    fn newline(&mut self, lines: usize, indent: usize) {
+        for _ in 0..lines {
+            self.out.push('\n');
+        }
+        for _ in 0..indent {
+            self.out.push(' ');
+        }
+    }
+}
+
+fn is_comment(node: &Cst) -> bool {
+    match *node {
+        Cst::Trivia(_, ref text) => text.starts_with(';'),
+        _ => false,
+    }
+}
+
+fn is_body_form(head: Option<&Cst>) -> bool {
+    match head {
+        Some(&Cst::Atom(_, Token::Identifier(ref name), _)) => BODY_FORMS.contains(&name.as_str()),
+        _ => false,
+    }
+}
+
+// Writes the nodes between two delimiters (or of a whole file), continuation lines start at
+// indent; with align they line up under the first argument if it's on the line of the head
+fn emit_nodes(nodes: &[Cst], mut indent: usize, align: bool, w: &mut Writer) {
+    let mut forms = 0;
+    let mut broken = false;
+    let mut after_comment = false;
+    let mut after_form = false;
+    for (i, node) in nodes.iter().enumerate() {
+        match *node {
+            Cst::Trivia(_, ref text) if is_comment(node) => {
+                if !w.separated() {
+                    w.push(" ");
+                }
+                w.push(text.trim_end());
+                after_comment = true;
+                after_form = false;
+            }
+            Cst::Trivia(_, ref text) => {
+                for _ in text.matches(',') {
+                    w.push(",");
+                }
+                let lines = text.matches('\n').count();
+                if i == 0 || (i + 1 == nodes.len() && !after_comment) {
+                    continue;
+                } else if lines > 0 || after_comment {
+                    w.newline(lines.clamp(1, 2), indent);
+                    broken = true;
+                } else {
+                    w.push(" ");
+                }
+                after_comment = false;
+                after_form = false;
+            }
+            _ => {
+                if after_form {
+                    w.push(" ");
+                }
+                if align && forms == 1 && !broken {
+                    indent = w.column();
+                }
+                emit(node, w);
+                forms += 1;
+                after_form = true;
+            }
+        }
+    }
+    if after_comment {
+        w.newline(1, indent);
+    }
+}
+
+fn emit(node: &Cst, w: &mut Writer) {
+    match *node {
+        Cst::Trivia(_, ref text) |
+        Cst::Atom(_, _, ref text) => w.push(text),
+        Cst::Prefix(_, ref token, ref children) => {
+            match *token {
+                Token::Tag(ref tag) => w.push(&format!("#{} ", tag)),
+                _ => w.push(delimiter(token)),
+            }
+            let column = w.column();
+            emit_nodes(children, column, false, w);
+        }
+        Cst::Seq(_, ref token, ref children) => {
+            let column = w.column();
+            w.push(delimiter(token));
+            let head = children.iter().find(|node| !node.is_trivia());
+            if *token != Token::Oparen {
+                emit_nodes(children, column + 1, false, w);
+            } else if is_body_form(head) {
+                emit_nodes(children, column + 2, false, w);
+            } else {
+                emit_nodes(children, column + 1, true, w);
+            }
+            w.push(delimiter(&closer(token)));
+        }
+    }
+}
+
+// Source reformatted with canonical whitespace, comments kept
+pub fn format_source(source: &str) -> Result<String, String> {
+    let nodes = parse_cst(source).map_err(|err| err.to_string())?;
+    let mut w = Writer { out: String::new() };
+    emit_nodes(&nodes, 0, false, &mut w);
+    let mut out = w.out.trim_end().to_string();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// Formats the file in place, or with check only reports whether it would change;
+// true if the file is (or was) not formatted
+pub fn format_file(path: &str, check: bool) -> Result<bool, String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    let formatted = format_source(&source).map_err(|err| format!("{}: {}", path, err))?;
+    if formatted == source {
+        return Ok(false);
+    }
+    if !check {
+        File::create(path)
+            .and_then(|mut file| file.write_all(formatted.as_bytes()))
+            .map_err(|err| format!("{}: {}", path, err))?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_source;
+
+    fn check(source: &str, expected: &str) {
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_indentation() {
+        check("(defn foo (x)\n(if x\n    (bar x\n  1)\n       2))",
+              "(defn foo (x)\n  (if x\n    (bar x\n         1)\n    2))\n");
+        check("(foo\nbar baz)", "(foo\n bar baz)\n");
+        check("(let [a 1\nb 2]\n    {:a a\n:b b})",
+              "(let [a 1\n      b 2]\n  {:a a\n   :b b})\n");
+    }
+
+    #[test]
+    fn test_whitespace() {
+        check("  ( foo   bar,baz  )  \n\n\n\n(x)", "(foo bar, baz)\n\n(x)\n");
+        check("'  (a(b))#inst   \"2017-01-01\"", "'(a (b)) #inst \"2017-01-01\"\n");
+        check("(def s \"multi\n   line\"\n  #\"a b\")", "(def s \"multi\n   line\"\n  #\"a b\")\n");
+        check("", "");
+    }
+
+    #[test]
+    fn test_comments() {
+        check(";; header   \n(foo ; first\n  bar ; last\n)\n; end",
+              ";; header\n(foo ; first\n bar ; last\n )\n; end\n");
+        check("(do\n  ; note\n  1)", "(do\n  ; note\n  1)\n");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(format_source("(foo").is_err());
+    }
+}
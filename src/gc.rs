@@ -0,0 +1,251 @@
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::hash_map::Entry;
+use std::rc::{Rc, Weak};
+use fnv::FnvHashMap;
+use data::{AtomVal, AtomType};
+use env::{Env, EnvType, env_parent, env_values, env_clear};
+use queue;
+use stats;
+
+// Closures keep the env they were created in alive, so storing a closure where that env can
+// reach it forms an Rc cycle. Such envs are tracked when captured and every so often checked
+// for cycles nothing outside refers to anymore.
+struct Tracked {
+    envs: FnvHashMap<usize, Weak<RefCell<EnvType>>>,
+    threshold: usize,
+}
+
+const MIN_THRESHOLD: usize = 1024;
+
+thread_local! {
+    static TRACKED: RefCell<Tracked> = RefCell::new(Tracked {
+        envs: FnvHashMap::default(),
+        threshold: MIN_THRESHOLD,
+    });
+}
+
+// Called for every env captured by a closure
+pub fn track(env: &Env) {
+    let due = TRACKED.with(|tracked| {
+        let mut tracked = tracked.borrow_mut();
+        tracked.envs.insert(env_address(env), Rc::downgrade(env));
+        tracked.envs.len() >= tracked.threshold
+    });
+
+    if due {
+        collect();
+    }
+}
+
+fn env_address(env: &Env) -> usize {
+    &**env as *const RefCell<EnvType> as usize
+}
+
+fn atom_address(atom: &AtomVal) -> usize {
+    &**atom as *const AtomType as usize
+}
+
+// Queue versions share the nodes of their stacks, so those are nodes here too and each
+// reference to them is counted once
+enum Node {
+    Env(Env),
+    Atom(AtomVal),
+    Link(Rc<queue::Node>),
+}
+
+impl Node {
+    fn address(&self) -> usize {
+        match *self {
+            Node::Env(ref env) => env_address(env),
+            Node::Atom(ref atom) => atom_address(atom),
+            Node::Link(ref link) => &**link as *const queue::Node as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Node::Env(ref env) => Rc::strong_count(env),
+            Node::Atom(ref atom) => Rc::strong_count(atom),
+            Node::Link(ref link) => Rc::strong_count(link),
+        }
+    }
+
+    // None if the node can't be looked into right now
+    fn children(&self) -> Option<Vec<Node>> {
+        let mut children = vec![];
+        match *self {
+            Node::Env(ref env) => {
+                if env.try_borrow_mut().is_err() {
+                    return None;
+                }
+                children.extend(env_parent(env).map(Node::Env));
+                atom_children(env_values(env), &mut children);
+            }
+            Node::Atom(ref atom) => {
+                match **atom {
+                    AtomType::List(ref seq, ref cache) => {
+                        let mut seq = seq.clone();
+                        seq.extend(cache.expansion());
+                        atom_children(seq, &mut children)
+                    }
+                    AtomType::Vector(ref seq) => atom_children(seq.to_vec(), &mut children),
+                    AtomType::Map(ref map) => {
+                        let entries = map.iter()
+                            .flat_map(|(k, v)| vec![k.value().clone(), v.clone()])
+                            .collect();
+                        atom_children(entries, &mut children);
+                    }
+                    AtomType::Queue(ref queue) => {
+                        children.extend(queue.tops().into_iter().map(Node::Link))
+                    }
+                    AtomType::PriorityQueue(ref queue) => {
+                        atom_children(queue.values(), &mut children)
+                    }
+                    AtomType::SortedMap(ref map) => {
+                        let entries = map.iter()
+                            .flat_map(|(k, v)| vec![k.value().clone(), v.clone()])
+                            .collect();
+                        atom_children(entries, &mut children);
+                    }
+                    AtomType::Record(ref record) => {
+                        atom_children(record.fields().map(|(_, v)| v.clone()).collect(),
+                                      &mut children)
+                    }
+                    AtomType::AFunc(ref fd) => {
+                        children.push(Node::Env(fd.env.clone()));
+                        atom_children(vec![fd.exp.clone(), fd.params.clone()], &mut children);
+                    }
+                    AtomType::Env(ref env) => children.push(Node::Env(env.clone())),
+                    _ => {}
+                }
+            }
+            Node::Link(ref link) => {
+                children.extend(link.next().cloned().map(Node::Link));
+                atom_children(vec![link.value().clone()], &mut children);
+            }
+        }
+        Some(children)
+    }
+}
+
+// Atoms which can't lead to an env are left out of the graph
+fn atom_children(atoms: Vec<AtomVal>, children: &mut Vec<Node>) {
+    for atom in atoms {
+        match *atom {
+            AtomType::List(..) |
+            AtomType::Vector(_) |
+            AtomType::Map(_) |
+            AtomType::Queue(_) |
+            AtomType::SortedMap(_) |
+            AtomType::PriorityQueue(_) |
+            AtomType::Record(_) |
+            AtomType::AFunc(_) |
+            AtomType::Env(_) => children.push(Node::Atom(atom.clone())),
+            _ => {}
+        }
+    }
+}
+
+// Frees the tracked envs only kept alive by cycles, returns how many envs were cleared.
+//
+// References between the nodes reachable from the tracked envs are counted; a node with more
+// strong references than that is held from outside and everything it reaches stays. Values
+// whose contents can't be seen, like native closures, just look referenced from outside, so
+// the worst case is a cycle that's kept.
+pub fn collect() -> usize {
+    let envs = TRACKED.with(|tracked| {
+        std::mem::take(&mut tracked.borrow_mut().envs)
+    });
+
+    // nodes hold exactly one reference to everything in the graph
+    let mut nodes = FnvHashMap::default();
+    let mut roots = vec![];
+    for (address, env) in envs {
+        if let Some(env) = env.upgrade() {
+            nodes.insert(address, Node::Env(env));
+            roots.push(address);
+        }
+    }
+
+    let mut edges: FnvHashMap<usize, Vec<usize>> = FnvHashMap::default();
+    let mut queue = roots.clone();
+    let mut complete = true;
+    while let Some(address) = queue.pop() {
+        let children = match nodes[&address].children() {
+            Some(children) => children,
+            None => {
+                complete = false;
+                break;
+            }
+        };
+
+        let mut targets = Vec::with_capacity(children.len());
+        for child in children {
+            let child_address = child.address();
+            targets.push(child_address);
+            if let Entry::Vacant(entry) = nodes.entry(child_address) {
+                entry.insert(child);
+                queue.push(child_address);
+            }
+        }
+        edges.insert(address, targets);
+    }
+
+    let mut live = FnvHashMap::default();
+    let mut garbage = vec![];
+    if complete {
+        let mut internal: FnvHashMap<usize, usize> = FnvHashMap::default();
+        for targets in edges.values() {
+            for target in targets {
+                *internal.entry(*target).or_insert(0) += 1;
+            }
+        }
+
+        let mut reached = nodes.iter()
+            .filter(|&(address, node)| {
+                node.strong_count() > 1 + internal.get(address).cloned().unwrap_or(0)
+            })
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        for address in &reached {
+            live.insert(*address, ());
+        }
+        while let Some(address) = reached.pop() {
+            for target in &edges[&address] {
+                if live.insert(*target, ()).is_none() {
+                    reached.push(*target);
+                }
+            }
+        }
+
+        for (address, node) in &nodes {
+            if let Node::Env(ref env) = *node {
+                if !live.contains_key(address) {
+                    garbage.push(env_clear(env));
+                }
+            }
+        }
+    }
+
+    let freed = garbage.len();
+    trace!("fn=gc::collect nodes={} freed={}", nodes.len(), freed);
+    stats::gc_collected(freed);
+
+    TRACKED.with(|tracked| {
+        let mut tracked = tracked.borrow_mut();
+        for address in roots {
+            if let Node::Env(ref env) = nodes[&address] {
+                if !complete || live.contains_key(&address) {
+                    tracked.envs.insert(address, Rc::downgrade(env));
+                }
+            }
+        }
+        tracked.threshold = cmp::max(MIN_THRESHOLD, tracked.envs.len() * 2);
+    });
+
+    // the cleared contents are dropped last, once nothing here is borrowed
+    drop(nodes);
+    drop(garbage);
+    freed
+}
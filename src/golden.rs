@@ -0,0 +1,128 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use core;
+use eval::eval;
+use parser::{Cst, parse_cst};
+
+// Golden files are .clrs files where a `; expect: value` comment after a top-level form pins
+// what the form evaluates to as printed, `; expect: error: message` that it fails
+#[derive(Debug)]
+pub struct Mismatch {
+    pub file: String,
+    pub line: usize,
+    pub form: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}:{}: {}\n  expected: {}\n    actual: {}",
+               self.file,
+               self.line,
+               self.form,
+               self.expected,
+               self.actual)
+    }
+}
+
+fn expectation(node: &Cst) -> Option<&str> {
+    match *node {
+        Cst::Trivia(_, ref text) if text.starts_with(';') => {
+            let comment = text.trim_start_matches(';').trim_start();
+            comment.strip_prefix("expect:").map(str::trim)
+        }
+        _ => None,
+    }
+}
+
+// Evaluates the forms of source in a fresh environment, comparing them with their expectations
+pub fn run(file: &str, source: &str) -> Result<Vec<Mismatch>, String> {
+    let nodes = parse_cst(source).map_err(|err| format!("{}: {}", file, err))?;
+    let env = core::build();
+    let mut mismatches = vec![];
+    let mut last = None;
+
+    for node in &nodes {
+        if let Some(expected) = expectation(node) {
+            let (form, actual) = match last.take() {
+                Some(last) => last,
+                None => {
+                    return Err(format!("{}:{}: expectation without a form before it",
+                                       file,
+                                       node.span().start.line))
+                }
+            };
+            if actual != expected {
+                mismatches.push(Mismatch {
+                    file: file.to_string(),
+                    line: node.span().start.line,
+                    form: form,
+                    expected: expected.to_string(),
+                    actual: actual,
+                });
+            }
+        } else if !node.is_trivia() {
+            let ast = match node.ast().map_err(|err| format!("{}: {}", file, err))? {
+                Some(ast) => ast,
+                None => continue,
+            };
+            let actual = match eval(&ast, &env) {
+                Ok(value) => value.to_string(),
+                Err(err) => format!("error: {}", err),
+            };
+            last = Some((node.to_string(), actual));
+        }
+    }
+
+    Ok(mismatches)
+}
+
+// Runs every .clrs file in dir
+pub fn run_dir(dir: &str) -> Result<Vec<Mismatch>, String> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|err| format!("{}: {}", dir, err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "clrs"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut mismatches = vec![];
+    for path in paths {
+        let file = path.to_string_lossy().into_owned();
+        let mut source = String::new();
+        File::open(Path::new(&path))
+            .and_then(|mut f| f.read_to_string(&mut source))
+            .map_err(|err| format!("{}: {}", file, err))?;
+        mismatches.extend(run(&file, &source)?);
+    }
+    Ok(mismatches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, run_dir};
+
+    #[test]
+    fn test_expectations() {
+        let source = "(def x 2)\n(+ x 1) ; expect: 3\n(car x)\n; expect: error: nope\n\
+                      (list x) ; expect: (3)";
+        let mismatches = run("t.clrs", source).unwrap();
+        let lines = mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        assert_eq!(lines,
+                   ["t.clrs:4: (car x)\n  expected: error: nope\n    actual: error: \
+                     undefined symbol: car",
+                    "t.clrs:5: (list x)\n  expected: (3)\n    actual: (2)"]);
+        assert!(run("t.clrs", "; expect: 1").is_err());
+    }
+
+    #[test]
+    fn golden_files() {
+        let mismatches = run_dir("tests").unwrap();
+        let report = mismatches.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+        assert!(mismatches.is_empty(), "\n{}", report.join("\n"));
+    }
+}
@@ -0,0 +1,440 @@
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+use fnv::FnvHashMap;
+use core;
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_closure, c_doc_func, c_nil, c_symbol};
+use analysis::SPECIAL_FORMS;
+use diagnostics::{self, Diagnostic};
+use env::{Env, env_names, env_set};
+#[cfg(feature = "std")]
+use env::EnvType;
+use eval::{eval, eval_str_with_reader};
+#[cfg(feature = "std")]
+use io::Handle;
+use lexer::{self, Lexer, ReaderMacros};
+use parser::Parser;
+use report::{self, Report};
+use stats::{self, Stats};
+use tagged::TagHandlers;
+
+// Entry point for embedding: a global environment with the core library loaded
+pub struct Interpreter {
+    env: Env,
+    macros: ReaderMacros,
+    tags: TagHandlers,
+}
+
+// (set-reader-macro! \c f), a nil f removes the macro
+fn set_reader_macro(macros: &Weak<RefCell<FnvHashMap<char, AtomVal>>>,
+                    args: &[AtomVal])
+                    -> AtomRet {
+    let macros = macros.upgrade()
+        .ok_or_else(|| AtomError::InvalidOperation("interpreter is gone".to_string()))?;
+    let c = match args.get(0).map(|c| &**c) {
+        Some(&AtomType::Str(ref s)) if s.chars().count() == 1 => s.chars().next().unwrap(),
+        _ => {
+            return Err(AtomError::InvalidArgument("set-reader-macro! needs a character like \\@"
+                .to_string()))
+        }
+    };
+    if lexer::is_reserved(c) {
+        return Err(AtomError::InvalidArgument(format!("{} can't be a reader macro", c)));
+    }
+
+    match args.get(1) {
+        Some(func) if **func != AtomType::Nil => macros.borrow_mut().insert(c, func.clone()),
+        _ => macros.borrow_mut().remove(&c),
+    };
+    Ok(c_nil())
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    // Files being run or loaded by load-file, outermost first, as named and by canonical path
+    static LOADING: RefCell<Vec<(String, PathBuf)>> = RefCell::new(vec![]);
+}
+
+// Marks a file as being loaded until dropped
+#[cfg(feature = "std")]
+struct Loading;
+
+#[cfg(feature = "std")]
+impl Loading {
+    // Fails with the chain of loads if path is already being loaded
+    fn enter(path: &str) -> Result<Loading, AtomError> {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        LOADING.with(|loading| {
+            let mut loading = loading.borrow_mut();
+            if let Some(start) = loading.iter().position(|entry| entry.1 == canonical) {
+                let mut chain = loading[start..].iter().map(|entry| &*entry.0).collect::<Vec<_>>();
+                chain.push(path);
+                return Err(AtomError::InvalidOperation(format!("load-file cycle: {}",
+                                                               chain.join(" -> "))));
+            }
+            loading.push((path.to_string(), canonical));
+            Ok(Loading)
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Loading {
+    fn drop(&mut self) {
+        let _ = LOADING.try_with(|loading| loading.borrow_mut().pop());
+    }
+}
+
+// Evaluates the forms of source, an error comes with its report
+fn run_forms(file: &str,
+             source: &str,
+             env: &Env,
+             macros: &ReaderMacros,
+             tags: &TagHandlers)
+             -> Result<AtomVal, (AtomError, Report)> {
+    let tokens = Lexer::new(source).with_reader_macros(macros.clone());
+    let mut parser = Parser::new(tokens).with_tag_handlers(tags.clone());
+    let mut result = c_nil();
+    loop {
+        let form = match parser.next_form() {
+            Ok(Some(form)) => form,
+            Ok(None) => return Ok(result),
+            Err(err) => {
+                return Err((AtomError::InvalidArgument(err.to_string()),
+                            Report::from_parse_error(file, source, &err)))
+            }
+        };
+        report::clear_trace();
+        result = eval(&form, env).map_err(|err| {
+            let mut names = env_names(env);
+            names.extend(SPECIAL_FORMS.iter().map(|name| name.to_string()));
+            let report = Report::from_error(file, source, parser.last_span(), &err, &names);
+            (err, report)
+        })?;
+    }
+}
+
+// (load-file path) evaluates the forms in the file, errors in it are located in the file
+#[cfg(feature = "std")]
+fn load_file(env: &Weak<RefCell<EnvType>>,
+             macros: &Weak<RefCell<FnvHashMap<char, AtomVal>>>,
+             tags: &Weak<RefCell<FnvHashMap<String, AtomVal>>>,
+             args: &[AtomVal])
+             -> AtomRet {
+    let gone = || AtomError::InvalidOperation("interpreter is gone".to_string());
+    let (env, macros, tags) = match (env.upgrade(), macros.upgrade(), tags.upgrade()) {
+        (Some(env), Some(macros), Some(tags)) => (env, macros, tags),
+        _ => return Err(gone()),
+    };
+    let path = match args.get(0).map(|path| &**path) {
+        Some(&AtomType::Str(ref path)) => path.clone(),
+        _ => return Err(AtomError::InvalidArgument("load-file needs a path".to_string())),
+    };
+
+    let _loading = Loading::enter(&path)?;
+    let source = Handle::open(&path, "read")?.read_all()?;
+    run_forms(&path, &source, &env, &macros, &tags).map_err(|(err, report)| {
+        let location = match report.snippet {
+            Some((line, _, column, _)) => format!("{}:{}:{}", path, line, column),
+            None => path.to_string(),
+        };
+        match err {
+            AtomError::InvalidOperation(message) => {
+                AtomError::InvalidOperation(format!("{}: {}", location, message))
+            }
+            AtomError::InvalidArgument(message) => {
+                AtomError::InvalidArgument(format!("{}: {}", location, message))
+            }
+            AtomError::Panic(message) => AtomError::Panic(format!("{}: {}", location, message)),
+            // conditions and restarts are handled by the code loading the file, the type and
+            // name of the rest are kept for their hints
+            err => err,
+        }
+    })
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::with_env(core::build())
+    }
+
+    // The builtin only refers to the table weakly, the functions in it usually refer to env
+    fn with_env(env: Env) -> Interpreter {
+        let macros: ReaderMacros = Rc::new(RefCell::new(FnvHashMap::default()));
+        let table = Rc::downgrade(&macros);
+        env_set(&env,
+                &c_symbol("set-reader-macro!"),
+                c_doc_func(move |args| set_reader_macro(&table, args),
+                           "(set-reader-macro! \\c f) makes the reader replace c followed by a \
+                            form with the result of (f form), a nil f removes the macro"));
+
+        let tags: TagHandlers = Rc::new(RefCell::new(FnvHashMap::default()));
+        #[cfg(feature = "std")]
+        {
+            let (scope, table, handlers) = (Rc::downgrade(&env), Rc::downgrade(&macros),
+                                            Rc::downgrade(&tags));
+            env_set(&env,
+                    &c_symbol("load-file"),
+                    c_doc_func(move |args| load_file(&scope, &table, &handlers, args),
+                               "(load-file path) evaluates the forms in the file at path, fails \
+                                if the file is already being loaded"));
+        }
+
+        Interpreter {
+            env: env,
+            macros: macros,
+            tags: tags,
+        }
+    }
+
+    // Only the builtins written in Rust, without the functions and macros of core.clrs
+    #[allow(dead_code)]
+    pub fn bare() -> Interpreter {
+        Interpreter::with_env(core::build_bare(None))
+    }
+
+    // Seeds rand-int, freezes (now) at the given milliseconds since the epoch and disables
+    // builtins depending on the outside world, so runs can be repeated exactly
+    #[allow(dead_code)]
+    pub fn deterministic(seed: u64, now: i64) -> Interpreter {
+        let fixed = core::Deterministic {
+            seed: seed,
+            now: now,
+        };
+        Interpreter::with_env(core::build_with(Some(fixed)))
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn eval(&self, source: &str) -> AtomRet {
+        eval_str_with_reader(source, &self.env, &self.macros, &self.tags)
+    }
+
+    // Makes the reader construct #tag form with f, replacing any handler for tag including
+    // the built-in #inst and #uuid
+    #[allow(dead_code)]
+    pub fn set_tag_handler<F>(&self, tag: &str, f: F)
+        where F: Fn(&AtomVal) -> AtomRet + 'static
+    {
+        let handler = c_closure(move |args| f(args.get(0).unwrap_or(&c_nil())));
+        self.tags.borrow_mut().insert(tag.to_string(), handler);
+    }
+
+    // Evaluates the forms in the file at path, returns the value of the last one
+    #[cfg(feature = "std")]
+    pub fn load(&self, path: &str) -> AtomRet {
+        let source = Handle::open(path, "read")?.read_all()?;
+        self.eval(&source)
+    }
+
+    // Like eval, but errors are reported with where in source they happened, file names it
+    pub fn run(&self, file: &str, source: &str) -> Result<AtomVal, Report> {
+        run_forms(file, source, &self.env, &self.macros, &self.tags).map_err(|(_, report)| report)
+    }
+
+    // Like load, with errors reported as by run
+    #[cfg(feature = "std")]
+    pub fn run_file(&self, path: &str) -> Result<AtomVal, Report> {
+        let _loading = Loading::enter(path).map_err(|err| Report::new(path, err.to_string()))?;
+        let source = Handle::open(path, "read")
+            .and_then(|handle| handle.read_all())
+            .map_err(|err| Report::new(path, err.to_string()))?;
+        self.run(path, &source)
+    }
+
+    // f is called with the warnings of every interpreter on the calling thread
+    pub fn on_diagnostic<F>(&self, f: F)
+        where F: Fn(&Diagnostic) + 'static
+    {
+        diagnostics::subscribe(f);
+    }
+
+    // Counters are kept per thread, so they cover every interpreter on the calling thread
+    pub fn stats(&self) -> Stats {
+        stats::snapshot()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interpreter;
+    use data::{AtomError, c_int, c_list, c_string, c_symbol};
+    use stats::{self, Stats};
+
+    fn live(stats: &Stats, name: &str) -> usize {
+        stats.atoms.iter().find(|atoms| atoms.0 == name).unwrap().1
+    }
+
+    #[test]
+    fn test_stats() {
+        let interpreter = Interpreter::new();
+        let before = interpreter.stats();
+
+        let lists = (0..10).map(|_| c_list(vec![])).collect::<Vec<_>>();
+        assert_eq!(live(&interpreter.stats(), "list"), live(&before, "list") + 10);
+        drop(lists);
+        assert_eq!(live(&interpreter.stats(), "list"), live(&before, "list"));
+
+        assert!(interpreter.eval("(def some-new-symbol 1)").is_ok());
+        let after = interpreter.stats();
+        assert_eq!(after.interned_symbols, before.interned_symbols + 1);
+        assert!(after.eval_steps > before.eval_steps);
+        assert!(after.envs > 0);
+
+        assert_eq!(interpreter.eval("(:envs (runtime-stats))").unwrap(),
+                   c_int(interpreter.stats().envs as i64));
+
+        let before = interpreter.stats();
+        interpreter.eval("(defn nest (n) (if (= n 0) 0 (let [m (- n 1)] (nest m)))) (nest 5)")
+            .unwrap();
+        let after = interpreter.stats();
+        assert!(after.applications >= before.applications + 6);
+        assert!(after.max_env_depth >= 2);
+        assert!(live(&after, "list") < after.allocated.iter().find(|a| a.0 == "list").unwrap().1
+            as usize);
+        let report = stats::report(&before, &after);
+        assert!(report.starts_with("eval steps: "));
+        assert!(report.contains("\nallocations:\n  "));
+    }
+
+    #[test]
+    fn test_gensyms_not_kept() {
+        let interpreter = Interpreter::new();
+        let before = interpreter.stats();
+        for _ in 0..20000 {
+            interpreter.eval("(gensym)").unwrap();
+        }
+        assert!(interpreter.stats().interned_symbols < before.interned_symbols + 2048);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let run = |seed| {
+            let interpreter = Interpreter::deterministic(seed, 1500000000000);
+            interpreter.eval("(map (fn* (_) (rand-int 1000)) (list 1 2 3 4 5 6 7 8))").unwrap()
+        };
+        assert_eq!(run(42), run(42));
+        assert!(run(42) != run(43));
+
+        let interpreter = Interpreter::deterministic(1, 1500000000000);
+        assert_eq!(interpreter.eval("(now)").unwrap(), c_int(1500000000000));
+        assert_eq!(interpreter.eval("(now)").unwrap(), c_int(1500000000000));
+        match interpreter.eval("(connect \"localhost:1\")") {
+            Err(AtomError::InvalidOperation(_)) => {}
+            other => panic!("connect allowed in deterministic mode: {:?}", other),
+        }
+        assert!(interpreter.eval("(runtime-stats)").is_err());
+
+        let interpreter = Interpreter::new();
+        // without io there is no clock to read
+        if cfg!(feature = "io") {
+            assert!(interpreter.eval("(now)").unwrap().get_int().unwrap() > 1500000000000);
+        }
+        assert!(interpreter.eval("(rand-int 0)").is_err());
+    }
+
+    #[test]
+    fn test_bare() {
+        let interpreter = Interpreter::bare();
+        assert_eq!(interpreter.eval("(nth '(1 2) 1)").unwrap(), c_int(2));
+        match interpreter.eval("(inc 1)") {
+            Err(AtomError::UndefinedSymbol(ref name)) if name == "inc" => {}
+            other => panic!("bare interpreter has the prelude: {:?}", other),
+        }
+        interpreter.eval("(def inc (fn* (v) (+ v 1)))").unwrap();
+        assert_eq!(interpreter.eval("(inc 1)").unwrap(), c_int(2));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let interpreter = Interpreter::new();
+        match interpreter.eval("(def x 1) (+ 1") {
+            Err(AtomError::InvalidArgument(_)) => {}
+            other => panic!("syntax error evaluated to {:?}", other),
+        }
+        // the forms before it are read and evaluated first
+        assert_eq!(interpreter.eval("x").unwrap(), c_int(1));
+        assert!(interpreter.eval("\"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_reader_macros() {
+        let interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval("\\@").unwrap(), c_string("@"));
+
+        let source = "(set-reader-macro! \\@ (fn* (form) (list 'quote (list 'deref form))))
+                      @x";
+        assert_eq!(interpreter.eval(source).unwrap(),
+                   c_list(vec![c_symbol("deref"), c_symbol("x")]));
+        assert_eq!(interpreter.eval("'[@(a b) a@b]").unwrap(),
+                   interpreter.eval("'[(quote (deref (a b))) a@b]").unwrap());
+
+        assert!(interpreter.eval("(set-reader-macro! \\a (fn* (f) f))").is_err());
+        assert!(interpreter.eval("(set-reader-macro! \"ab\" (fn* (f) f))").is_err());
+
+        assert_eq!(interpreter.eval("(set-reader-macro! \\@ nil) '@x").unwrap(), c_symbol("@x"));
+        assert_eq!(Interpreter::new().eval("'@x").unwrap(), c_symbol("@x"));
+    }
+
+    #[test]
+    fn test_tag_handlers() {
+        let interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval("#inst \"1970-01-01T00:00:02Z\"").unwrap(), c_int(2000));
+
+        interpreter.set_tag_handler("point",
+                                    |form| Ok(c_list(vec![c_symbol("point"), form.clone()])));
+        interpreter.set_tag_handler("inst", |_| Ok(c_int(0)));
+        assert_eq!(interpreter.eval("'#point [1 2]").unwrap(),
+                   interpreter.eval("'(point [1 2])").unwrap());
+        assert_eq!(interpreter.eval("#inst \"2017-07-14\"").unwrap(), c_int(0));
+
+        assert!(Interpreter::new().eval("'#point [1 2]").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_load_file() {
+        use std::env;
+        use std::fs::File;
+        use std::io::Write;
+
+        let dir = env::temp_dir();
+        let write = |name: &str, source: &str| {
+            let path = dir.join(name).to_str().unwrap().to_string();
+            File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+            path
+        };
+        let lib = write("rulsp-load-lib.clrs", "(def loaded 40)\n(defn plus2 (x) (+ x 2))");
+        let interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval(&format!("(load-file {:?}) (plus2 loaded)", lib)).unwrap(),
+                   c_int(42));
+
+        let a = dir.join("rulsp-load-a.clrs").to_str().unwrap().to_string();
+        let b = write("rulsp-load-b.clrs", &format!("(def b 1)\n\n(load-file {:?})", a));
+        write("rulsp-load-a.clrs", &format!("(load-file {:?})", b));
+        let report = interpreter.run_file(&a).unwrap_err();
+        assert_eq!(report.snippet.as_ref().unwrap().0, 1);
+        assert!(report.message
+            .contains(&format!("{}:3:1: load-file cycle: {} -> {} -> {}", b, a, b, a)));
+        // the chain is unwound after the error
+        assert!(interpreter.run_file(&b).unwrap_err().message.contains(&format!("{} -> {}", b, a)));
+
+        let broken = write("rulsp-load-broken.clrs", "\n(undefined-here)");
+        match interpreter.eval(&format!("(load-file {:?})", broken)) {
+            Err(AtomError::UndefinedSymbol(ref name)) => assert_eq!(name, "undefined-here"),
+            other => panic!("expected the undefined symbol: {:?}", other),
+        }
+        assert!(interpreter.eval("(load-file \"/nonexistent/file.clrs\")").is_err());
+    }
+}
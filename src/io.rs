@@ -0,0 +1,203 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use data::AtomError;
+use output::write_out;
+
+trait Stream: Read + Write {}
+
+impl Stream for File {}
+impl Stream for TcpStream {}
+impl Stream for VecDeque<u8> {}
+
+// The standard streams, stdout going through write_out so it can be captured
+enum Standard {
+    In,
+    Out,
+    Err,
+}
+
+impl Stream for Standard {}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::other(format!("not open for {}", what))
+}
+
+impl Read for Standard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Standard::In => io::stdin().read(buf),
+            _ => Err(unsupported("reading")),
+        }
+    }
+}
+
+impl Write for Standard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Standard::In => return Err(unsupported("writing")),
+            Standard::Out => write_out(&String::from_utf8_lossy(buf)),
+            Standard::Err => io::stderr().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Standard::Err => io::stderr().flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+// File, socket, standard stream or string buffer, the stream is dropped once the handle is
+// closed
+pub struct Handle {
+    name: String,
+    stream: RefCell<Option<Box<dyn Stream>>>,
+}
+
+fn io_error(name: &str, err: io::Error) -> AtomError {
+    AtomError::InvalidOperation(format!("{}: {}", name, err))
+}
+
+impl Handle {
+    fn new(name: &str, stream: Box<dyn Stream>) -> Handle {
+        Handle {
+            name: name.to_string(),
+            stream: RefCell::new(Some(stream)),
+        }
+    }
+
+    // mode is one of "read", "write" or "append"
+    pub fn open(path: &str, mode: &str) -> Result<Handle, AtomError> {
+        let mut options = OpenOptions::new();
+        match mode {
+            "read" => options.read(true),
+            "write" => options.write(true).create(true).truncate(true),
+            "append" => options.append(true).create(true),
+            _ => return Err(AtomError::InvalidArgument(format!("unknown file mode {}", mode))),
+        };
+
+        let file = options.open(path).map_err(|err| io_error(path, err))?;
+        Ok(Handle::new(path, Box::new(file)))
+    }
+
+    // What's written to a buffer is read back from it, first in first out
+    pub fn buffer() -> Handle {
+        Handle::new("buffer", Box::new(VecDeque::new()))
+    }
+
+    pub fn stdin() -> Handle {
+        Handle::new("stdin", Box::new(Standard::In))
+    }
+
+    pub fn stdout() -> Handle {
+        Handle::new("stdout", Box::new(Standard::Out))
+    }
+
+    pub fn stderr() -> Handle {
+        Handle::new("stderr", Box::new(Standard::Err))
+    }
+
+    pub fn connect(addr: &str) -> Result<Handle, AtomError> {
+        let stream = TcpStream::connect(addr).map_err(|err| io_error(addr, err))?;
+        Ok(Handle::new(addr, Box::new(stream)))
+    }
+
+    fn with_stream<T, F>(&self, f: F) -> Result<T, AtomError>
+        where F: FnOnce(&mut dyn Stream) -> io::Result<T>
+    {
+        match *self.stream.borrow_mut() {
+            Some(ref mut stream) => f(&mut **stream).map_err(|err| io_error(&self.name, err)),
+            None => Err(AtomError::InvalidOperation(format!("{} is closed", self.name))),
+        }
+    }
+
+    // None at the end of the stream
+    pub fn read_line(&self) -> Result<Option<String>, AtomError> {
+        let bytes = self.with_stream(|stream| {
+            let mut line = vec![];
+            // a byte at a time, what follows the line stays in the stream for the next read
+            #[allow(clippy::unbuffered_bytes)]
+            for byte in stream.bytes() {
+                let byte = byte?;
+                line.push(byte);
+                if byte == b'\n' {
+                    break;
+                }
+            }
+            Ok(line)
+        })?;
+
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+    }
+
+    pub fn read_all(&self) -> Result<String, AtomError> {
+        self.with_stream(|stream| {
+            let mut s = String::new();
+            stream.read_to_string(&mut s)?;
+            Ok(s)
+        })
+    }
+
+    pub fn write(&self, s: &str) -> Result<(), AtomError> {
+        self.with_stream(|stream| stream.write_all(s.as_bytes()))
+    }
+
+    // Closing twice is harmless
+    pub fn close(&self) -> Result<(), AtomError> {
+        if let Some(mut stream) = self.stream.borrow_mut().take() {
+            stream.flush().map_err(|err| io_error(&self.name, err))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.stream.borrow().is_none()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<handle {}>", self.name)
+    }
+}
+
+// Handles are only equal to themselves
+impl PartialEq for Handle {
+    fn eq(&self, other: &Handle) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Handle;
+    use output::{begin_buffer, end_buffer};
+
+    #[test]
+    fn test_standard_handles() {
+        let buffer = Handle::buffer();
+        buffer.write("a\nb").unwrap();
+        assert_eq!(buffer.read_line(), Ok(Some("a\n".to_string())));
+        assert_eq!(buffer.read_all(), Ok("b".to_string()));
+        assert_eq!(buffer.read_line(), Ok(None));
+
+        let depth = begin_buffer();
+        Handle::stdout().write("out").unwrap();
+        assert_eq!(end_buffer(depth), "out");
+        assert!(Handle::stdout().read_all().is_err());
+        assert!(Handle::stdin().write("in").is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+use std::str::Chars;
+use std::iter::Peekable;
+
+// Just enough JSON for the messages of the language server
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> &Json {
+        static NULL: Json = Json::Null;
+        match *self {
+            Json::Object(ref members) => {
+                members.iter().find(|member| member.0 == key).map_or(&NULL, |member| &member.1)
+            }
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Num(num) if num >= 0.0 => Some(num as usize),
+            _ => None,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        *self == Json::Null
+    }
+}
+
+// {"key": value, ...} from pairs
+pub fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+pub fn string(s: &str) -> Json {
+    Json::Str(s.to_string())
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Num(num) => write!(f, "{}", num),
+            Json::Str(ref s) => write_string(s, f),
+            Json::Array(ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { "," }, item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(ref members) => {
+                write!(f, "{{")?;
+                for (i, &(ref key, ref value)) in members.iter().enumerate() {
+                    write!(f, "{}", if i == 0 { "" } else { "," })?;
+                    write_string(key, f)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(s: &str, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+pub fn parse(text: &str) -> Result<Json, String> {
+    let mut chars = text.chars().peekable();
+    let value = parse_value(&mut chars)?;
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Ok(value),
+        Some(c) => Err(format!("unexpected {:?} after the value", c)),
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect(chars: &mut Peekable<Chars>, word: &str) -> Result<(), String> {
+    for expected in word.chars() {
+        if chars.next() != Some(expected) {
+            return Err(format!("expected {}", word));
+        }
+    }
+    Ok(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+    skip_whitespace(chars);
+    match chars.peek().cloned() {
+        Some('n') => expect(chars, "null").map(|_| Json::Null),
+        Some('t') => expect(chars, "true").map(|_| Json::Bool(true)),
+        Some('f') => expect(chars, "false").map(|_| Json::Bool(false)),
+        Some('"') => parse_string(chars).map(Json::Str),
+        Some('[') => {
+            chars.next();
+            let mut items = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(Json::Array(items));
+            }
+            loop {
+                items.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Json::Array(items)),
+                    _ => return Err("expected , or ]".to_string()),
+                }
+            }
+        }
+        Some('{') => {
+            chars.next();
+            let mut members = vec![];
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(Json::Object(members));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                expect(chars, ":")?;
+                members.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some('}') => return Ok(Json::Object(members)),
+                    _ => return Err("expected , or }".to_string()),
+                }
+            }
+        }
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || "+-.eE".contains(c)) {
+                    break;
+                }
+                number.push(c);
+                chars.next();
+            }
+            number.parse().map(Json::Num).map_err(|_| format!("invalid number {}", number))
+        }
+        Some(c) => Err(format!("unexpected {:?}", c)),
+        None => Err("unexpected end of input".to_string()),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    expect(chars, "\"")?;
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => {
+                match chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let mut unit = parse_hex(chars)?;
+                        // a surrogate pair encodes one code point
+                        if (0xd800..0xdc00).contains(&unit) {
+                            expect(chars, "\\u")?;
+                            let low = parse_hex(chars)?;
+                            unit = 0x10000 + ((unit - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                        }
+                        s.push(::std::char::from_u32(unit).unwrap_or('\u{fffd}'));
+                    }
+                    Some(c) => s.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            Some(c) => s.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+fn parse_hex(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+    let digits = chars.take(4).collect::<String>();
+    u32::from_str_radix(&digits, 16).map_err(|_| format!("invalid escape \\u{}", digits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Json, parse, object, string};
+
+    #[test]
+    fn test_roundtrip() {
+        let value = parse(r#" {"id": 1, "params": {"text": "a\n\"b\"é😀",
+                              "list": [true, false, null, -2.5e1]}} "#)
+            .unwrap();
+        assert_eq!(value.get("id").as_usize(), Some(1));
+        assert_eq!(value.get("params").get("text").as_str(), Some("a\n\"b\"é😀"));
+        assert_eq!(value.get("params").get("list"),
+                   &Json::Array(vec![Json::Bool(true), Json::Bool(false), Json::Null,
+                                     Json::Num(-25.0)]));
+        assert!(value.get("missing").get("deeper").is_null());
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+
+        assert_eq!(object(vec![("a", Json::Num(1.0)), ("b", string("x\ty"))]).to_string(),
+                   r#"{"a":1,"b":"x\ty"}"#);
+        assert!(parse("[1,").is_err());
+        assert!(parse("{} x").is_err());
+    }
+}
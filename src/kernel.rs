@@ -0,0 +1,529 @@
+// A Jupyter kernel: the messaging protocol (version 5.3) over the zmtp sockets, one interpreter
+// keeping its definitions between cells like the REPL does
+use std::fs::File;
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use analysis::completions;
+use data::AtomType;
+use interpreter::Interpreter;
+use output;
+use json::{self, Json, object, string};
+use parser::{ParseError, parse_cst};
+use tagged::format_timestamp;
+use zmtp;
+
+const DELIMITER: &'static [u8] = b"<IDS|MSG>";
+
+const K: [u32; 64] = [0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+                      0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+                      0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+                      0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+                      0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+                      0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+                      0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+                      0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+                      0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+                      0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+                      0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2];
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut h: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+                           0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bits = data.len() as u64 * 8;
+    message.extend((0..8).rev().map(|i| (bits >> (i * 8)) as u8));
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = chunk[i * 4..i * 4 + 4].iter().fold(0, |word, &byte| word << 8 | byte as u32);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+        for i in 0..8 {
+            h[i] = h[i].wrapping_add(v[i]);
+        }
+    }
+    h.iter().flat_map(|word| (0..4).rev().map(move |i| (word >> (i * 8)) as u8)).collect()
+}
+
+// HMAC-SHA256 of the parts one after another as hex, empty without a key as Jupyter does
+fn sign(key: &[u8], parts: &[&[u8]]) -> String {
+    if key.is_empty() {
+        return String::new();
+    }
+    let mut key = if key.len() > 64 { sha256(key) } else { key.to_vec() };
+    key.resize(64, 0);
+    let mut inner = key.iter().map(|byte| byte ^ 0x36).collect::<Vec<_>>();
+    for part in parts {
+        inner.extend(*part);
+    }
+    let mut outer = key.iter().map(|byte| byte ^ 0x5c).collect::<Vec<_>>();
+    outer.extend(sha256(&inner));
+    sha256(&outer).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// A message of the wire protocol, identities are the routing prefix replies go back with
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    identities: Vec<Vec<u8>>,
+    header: Json,
+    parent: Json,
+    metadata: Json,
+    content: Json,
+}
+
+fn decode(frames: &[Vec<u8>], key: &[u8]) -> Result<Message, String> {
+    let split = frames.iter()
+        .position(|frame| frame.as_slice() == DELIMITER)
+        .ok_or("message without <IDS|MSG>")?;
+    let parts = &frames[split + 1..];
+    if parts.len() < 5 {
+        return Err("message with less than 5 parts".to_string());
+    }
+    let signed = parts[1..5].iter().map(|part| part.as_slice()).collect::<Vec<_>>();
+    if sign(key, &signed).as_bytes() != parts[0].as_slice() {
+        return Err("message with a wrong signature".to_string());
+    }
+    let part = |i: usize| {
+        String::from_utf8(parts[i].clone())
+            .map_err(|err| err.to_string())
+            .and_then(|text| json::parse(&text))
+    };
+    Ok(Message {
+        identities: frames[..split].to_vec(),
+        header: part(1)?,
+        parent: part(2)?,
+        metadata: part(3)?,
+        content: part(4)?,
+    })
+}
+
+fn encode(message: &Message, key: &[u8]) -> Vec<Vec<u8>> {
+    let parts = [&message.header, &message.parent, &message.metadata, &message.content]
+        .iter()
+        .map(|part| part.to_string().into_bytes())
+        .collect::<Vec<_>>();
+    let mut frames = message.identities.clone();
+    frames.push(DELIMITER.to_vec());
+    frames.push(sign(key, &parts.iter().map(|part| part.as_slice()).collect::<Vec<_>>())
+        .into_bytes());
+    frames.extend(parts);
+    frames
+}
+
+fn now_millis() -> i64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() as i64 * 1000 + since_epoch.subsec_nanos() as i64 / 1_000_000
+}
+
+// What a request made the kernel publish on iopub and reply, as message types and contents
+#[derive(Debug, Default)]
+struct Outcome {
+    published: Vec<(&'static str, Json)>,
+    reply: Option<(String, Json)>,
+    shutdown: bool,
+}
+
+struct Kernel {
+    interpreter: Interpreter,
+    execution_count: usize,
+}
+
+fn num(n: usize) -> Json {
+    Json::Num(n as f64)
+}
+
+impl Kernel {
+    fn new(interpreter: Interpreter) -> Kernel {
+        output::capture_output(true);
+        Kernel {
+            interpreter: interpreter,
+            execution_count: 0,
+        }
+    }
+
+    fn handle(&mut self, msg_type: &str, content: &Json) -> Outcome {
+        let mut outcome = Outcome::default();
+        let reply = match msg_type {
+            "kernel_info_request" => self.kernel_info(),
+            "execute_request" => self.execute(content, &mut outcome.published),
+            "complete_request" => self.complete(content),
+            "is_complete_request" => is_complete(content),
+            "history_request" => {
+                object(vec![("status", string("ok")), ("history", Json::Array(vec![]))])
+            }
+            "comm_info_request" => {
+                object(vec![("status", string("ok")), ("comms", object(vec![]))])
+            }
+            "interrupt_request" => object(vec![("status", string("ok"))]),
+            "shutdown_request" => {
+                outcome.shutdown = true;
+                object(vec![("status", string("ok")), ("restart", content.get("restart").clone())])
+            }
+            _ => return outcome,
+        };
+        outcome.reply = Some((msg_type.replace("_request", "_reply"), reply));
+        outcome
+    }
+
+    fn kernel_info(&self) -> Json {
+        let language = object(vec![("name", string("rulsp")),
+                                   ("version", string(env!("CARGO_PKG_VERSION"))),
+                                   ("mimetype", string("text/x-rulsp")),
+                                   ("file_extension", string(".clrs"))]);
+        object(vec![("status", string("ok")),
+                    ("protocol_version", string("5.3")),
+                    ("implementation", string("rulsp")),
+                    ("implementation_version", string(env!("CARGO_PKG_VERSION"))),
+                    ("language_info", language),
+                    ("banner", string("rulsp"))])
+    }
+
+    // Runs the cell, what it printed goes out as a stream and its value like the REPL shows it
+    fn execute(&mut self, content: &Json, published: &mut Vec<(&'static str, Json)>) -> Json {
+        let code = content.get("code").as_str().unwrap_or("");
+        let silent = content.get("silent") == &Json::Bool(true);
+        if !silent {
+            self.execution_count += 1;
+            published.push(("execute_input",
+                            object(vec![("code", string(code)),
+                                        ("execution_count", num(self.execution_count))])));
+        }
+
+        output::take_output();
+        let result = self.interpreter.run(&format!("In[{}]", self.execution_count), code);
+        let printed = output::take_output();
+        if !printed.is_empty() && !silent {
+            published.push(("stream",
+                            object(vec![("name", string("stdout")),
+                                        ("text", Json::Str(printed))])));
+        }
+
+        match result {
+            Ok(value) => {
+                if *value != AtomType::Nil && !silent {
+                    let data = object(vec![("text/plain", Json::Str(value.to_string()))]);
+                    published.push(("execute_result",
+                                    object(vec![("execution_count", num(self.execution_count)),
+                                                ("data", data),
+                                                ("metadata", object(vec![]))])));
+                }
+                object(vec![("status", string("ok")),
+                            ("execution_count", num(self.execution_count)),
+                            ("user_expressions", object(vec![]))])
+            }
+            Err(report) => {
+                let traceback = report.render(true)
+                    .lines()
+                    .map(string)
+                    .collect::<Vec<_>>();
+                let error = vec![("ename", string("error")),
+                                 ("evalue", Json::Str(report.message.clone())),
+                                 ("traceback", Json::Array(traceback))];
+                if !silent {
+                    published.push(("error", object(error.clone())));
+                }
+                let mut reply = vec![("status", string("error")),
+                                     ("execution_count", num(self.execution_count))];
+                reply.extend(error);
+                object(reply)
+            }
+        }
+    }
+
+    // Names bound globally and special forms starting like the symbol before the cursor,
+    // which counts characters
+    fn complete(&self, content: &Json) -> Json {
+        let code = content.get("code").as_str().unwrap_or("");
+        let cursor = content.get("cursor_pos").as_usize().unwrap_or(0);
+        let before = code.chars().take(cursor).collect::<Vec<_>>();
+        let start = before.iter()
+            .rposition(|c| c.is_whitespace() || "()[]{}'`~@\"".contains(*c))
+            .map_or(0, |i| i + 1);
+        let prefix = before[start..].iter().cloned().collect::<String>();
+
+        let names = completions(self.interpreter.env(), &prefix);
+        object(vec![("status", string("ok")),
+                    ("matches", Json::Array(names.into_iter().map(Json::Str).collect())),
+                    ("cursor_start", num(start)),
+                    ("cursor_end", num(before.len())),
+                    ("metadata", object(vec![]))])
+    }
+}
+
+// Whether the console should run the code or read another line for it
+fn is_complete(content: &Json) -> Json {
+    let code = content.get("code").as_str().unwrap_or("");
+    match parse_cst(code) {
+        Ok(_) => object(vec![("status", string("complete"))]),
+        Err(ParseError::Unclosed(_, _)) |
+        Err(ParseError::Incomplete) => {
+            object(vec![("status", string("incomplete")), ("indent", string("  "))])
+        }
+        Err(_) => object(vec![("status", string("invalid"))]),
+    }
+}
+
+// A message from the shell or control channel and the connection to reply on
+struct Request {
+    frames: Vec<Vec<u8>>,
+    reply_to: TcpStream,
+}
+
+// Where the channels are and the key messages are signed with, from the file Jupyter passes
+struct Connection {
+    ip: String,
+    key: Vec<u8>,
+    shell_port: usize,
+    iopub_port: usize,
+    stdin_port: usize,
+    control_port: usize,
+    hb_port: usize,
+}
+
+fn read_connection(path: &str) -> Result<Connection, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    let info = json::parse(&text).map_err(|err| format!("{}: {}", path, err))?;
+    if info.get("transport").as_str().unwrap_or("tcp") != "tcp" {
+        return Err(format!("{}: only the tcp transport is supported", path));
+    }
+    match info.get("signature_scheme").as_str() {
+        None | Some("hmac-sha256") => {}
+        Some(scheme) => return Err(format!("{}: unsupported signature scheme {}", path, scheme)),
+    }
+    let port = |name: &str| {
+        info.get(name).as_usize().ok_or_else(|| format!("{}: {} is missing", path, name))
+    };
+    Ok(Connection {
+        ip: info.get("ip").as_str().unwrap_or("127.0.0.1").to_string(),
+        key: info.get("key").as_str().unwrap_or("").as_bytes().to_vec(),
+        shell_port: port("shell_port")?,
+        iopub_port: port("iopub_port")?,
+        stdin_port: port("stdin_port")?,
+        control_port: port("control_port")?,
+        hb_port: port("hb_port")?,
+    })
+}
+
+fn listen(connection: &Connection, port: usize) -> ::std::io::Result<TcpListener> {
+    TcpListener::bind((connection.ip.as_str(), port as u16))
+}
+
+// Runs f with every connection accepted and shaken hands with, each on its own thread
+fn accept<F>(listener: TcpListener, socket_type: &'static str, f: F)
+    where F: Fn(TcpStream) + Send + Sync + 'static
+{
+    let f = Arc::new(f);
+    thread::spawn(move || for stream in listener.incoming() {
+        let f = f.clone();
+        thread::spawn(move || {
+            let mut stream = stream?;
+            zmtp::handshake(&mut stream, socket_type)?;
+            f(stream);
+            Ok(()) as ::std::io::Result<()>
+        });
+    });
+}
+
+// Requests of every connection to the channel go to requests
+fn route(listener: TcpListener, requests: Sender<Request>) {
+    let requests = Mutex::new(requests);
+    accept(listener, "ROUTER", move |mut stream| {
+        let requests = requests.lock().unwrap().clone();
+        while let Ok(Some(frames)) = zmtp::recv(&mut stream) {
+            let reply_to = match stream.try_clone() {
+                Ok(reply_to) => reply_to,
+                Err(_) => return,
+            };
+            let request = Request {
+                frames: frames,
+                reply_to: reply_to,
+            };
+            if requests.send(request).is_err() {
+                return;
+            }
+        }
+    });
+}
+
+// Serves the kernel described by the connection file with interpreter until a shutdown request
+pub fn serve(connection_path: &str, interpreter: Interpreter) -> Result<(), String> {
+    let connection = read_connection(connection_path)?;
+    let listeners = [connection.shell_port,
+                     connection.iopub_port,
+                     connection.stdin_port,
+                     connection.control_port,
+                     connection.hb_port]
+        .iter()
+        .map(|&port| listen(&connection, port).map_err(|err| format!("port {}: {}", port, err)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut listeners = listeners.into_iter();
+    let (shell, iopub, stdin, control, hb) = (listeners.next().unwrap(),
+                                              listeners.next().unwrap(),
+                                              listeners.next().unwrap(),
+                                              listeners.next().unwrap(),
+                                              listeners.next().unwrap());
+
+    // heartbeats are echoed, input requests aren't supported so stdin is just kept open
+    accept(hb, "REP", |mut stream| while let Ok(Some(frames)) = zmtp::recv(&mut stream) {
+        if zmtp::send(&mut stream, &frames).is_err() {
+            return;
+        }
+    });
+    accept(stdin, "ROUTER", |mut stream| while let Ok(Some(_)) = zmtp::recv(&mut stream) {});
+    let subscribers = Arc::new(Mutex::new(Vec::<TcpStream>::new()));
+    let subscribing = subscribers.clone();
+    accept(iopub, "PUB", move |stream| subscribing.lock().unwrap().push(stream));
+    let (requests, incoming) = mpsc::channel();
+    route(shell, requests.clone());
+    route(control, requests);
+
+    let session = format!("rulsp-{}", now_millis());
+    let mut sent = 0;
+    let mut message_to = |identities: Vec<Vec<u8>>, msg_type: &str, parent: &Json, content| {
+        sent += 1;
+        let header = object(vec![("msg_id", Json::Str(format!("{}-{}", session, sent))),
+                                 ("session", Json::Str(session.clone())),
+                                 ("username", string("rulsp")),
+                                 ("date", Json::Str(format_timestamp(now_millis()))),
+                                 ("msg_type", string(msg_type)),
+                                 ("version", string("5.3"))]);
+        let message = Message {
+            identities: identities,
+            header: header,
+            parent: parent.clone(),
+            metadata: object(vec![]),
+            content: content,
+        };
+        encode(&message, &connection.key)
+    };
+    let publish = |frames: Vec<Vec<u8>>| {
+        subscribers.lock().unwrap().retain(|subscriber| {
+            let mut subscriber = subscriber;
+            zmtp::send(&mut subscriber, &frames).is_ok()
+        });
+    };
+
+    let mut kernel = Kernel::new(interpreter);
+    for mut request in incoming {
+        let message = match decode(&request.frames, &connection.key) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("action=kernel dropped={}", err);
+                continue;
+            }
+        };
+        let msg_type = message.header.get("msg_type").as_str().unwrap_or("").to_string();
+        let topic = |msg_type: &str| vec![format!("kernel.rulsp.{}", msg_type).into_bytes()];
+
+        let status = |state| object(vec![("execution_state", string(state))]);
+        publish(message_to(topic("status"), "status", &message.header, status("busy")));
+        let outcome = kernel.handle(&msg_type, &message.content);
+        for (msg_type, content) in outcome.published {
+            publish(message_to(topic(msg_type), msg_type, &message.header, content));
+        }
+        if let Some((msg_type, content)) = outcome.reply {
+            let reply = message_to(message.identities.clone(), &msg_type, &message.header, content);
+            if let Err(err) = zmtp::send(&mut request.reply_to, &reply) {
+                warn!("action=kernel reply={}", err);
+            }
+        }
+        publish(message_to(topic("status"), "status", &message.header, status("idle")));
+        if outcome.shutdown {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kernel, Message, decode, encode, sign};
+    use interpreter::Interpreter;
+    use json::{self, Json, object, string};
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(sign(b"key", &[b"The quick brown fox ", b"jumps over the lazy dog"]),
+                   "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+        assert_eq!(sign(b"", &[b"anything"]), "");
+
+        let message = Message {
+            identities: vec![b"client".to_vec()],
+            header: object(vec![("msg_type", string("kernel_info_request"))]),
+            parent: object(vec![]),
+            metadata: object(vec![]),
+            content: object(vec![]),
+        };
+        let mut frames = encode(&message, b"secret");
+        assert_eq!(frames[1], b"<IDS|MSG>".to_vec());
+        assert_eq!(decode(&frames, b"secret"), Ok(message));
+        frames[6] = b"{\"x\":1}".to_vec();
+        assert!(decode(&frames, b"secret").is_err());
+    }
+
+    fn request(kernel: &mut Kernel, msg_type: &str, content: &str)
+               -> (Vec<(&'static str, Json)>, Json) {
+        let outcome = kernel.handle(msg_type, &json::parse(content).unwrap());
+        (outcome.published, outcome.reply.map_or(Json::Null, |reply| reply.1))
+    }
+
+    #[test]
+    fn test_kernel() {
+        let mut kernel = Kernel::new(Interpreter::new());
+        let (_, info) = request(&mut kernel, "kernel_info_request", "{}");
+        assert_eq!(info.get("language_info").get("file_extension").as_str(), Some(".clrs"));
+
+        let code = r#"{"code": "(defn twice (x) (* 2 x)) (println 1) (twice 21)"}"#;
+        let (published, reply) = request(&mut kernel, "execute_request", code);
+        assert_eq!(reply.get("status").as_str(), Some("ok"));
+        assert_eq!(published.iter().map(|message| message.0).collect::<Vec<_>>(),
+                   vec!["execute_input", "stream", "execute_result"]);
+        assert_eq!(published[1].1.get("text").as_str(), Some("1\n"));
+        assert_eq!(published[2].1.get("data").get("text/plain").as_str(), Some("42"));
+        assert_eq!(published[2].1.get("execution_count"), &Json::Num(1.0));
+
+        let code = r#"{"code": "(twice nope)"}"#;
+        let (published, reply) = request(&mut kernel, "execute_request", code);
+        assert_eq!(reply.get("status").as_str(), Some("error"));
+        assert_eq!(reply.get("evalue").as_str(), Some("undefined symbol: nope"));
+        assert_eq!(published[1].0, "error");
+        assert_eq!(reply.get("execution_count"), &Json::Num(2.0));
+
+        let code = r#"{"code": "(twi 1)", "cursor_pos": 4}"#;
+        let (_, reply) = request(&mut kernel, "complete_request", code);
+        assert_eq!(reply.get("matches").to_string(), r#"["twice"]"#);
+        assert_eq!(reply.get("cursor_start"), &Json::Num(1.0));
+
+        let (_, reply) = request(&mut kernel, "is_complete_request", r#"{"code": "(twice"}"#);
+        assert_eq!(reply.get("status").as_str(), Some("incomplete"));
+        let (_, reply) = request(&mut kernel, "is_complete_request", r#"{"code": "(twice 1))"}"#);
+        assert_eq!(reply.get("status").as_str(), Some("invalid"));
+        assert_eq!(request(&mut kernel, "bogus_request", "{}").1, Json::Null);
+    }
+}
@@ -1,22 +1,65 @@
+use std::cell::RefCell;
 use std::fmt;
 use std::error::Error as StdError;
+use std::rc::Rc;
+use fnv::FnvHashMap;
 use regex::Regex;
+use data::AtomVal;
+
+// Dispatch characters and the functions turning the form after them into the form read,
+// see set-reader-macro!
+pub type ReaderMacros = Rc<RefCell<FnvHashMap<char, AtomVal>>>;
+
+// Characters which can't start a reader macro, they mean something to the reader already
+pub fn is_reserved(c: char) -> bool {
+    c.is_alphanumeric() || c.is_whitespace() || "()[]{}\"';,`~\\:#".contains(c)
+}
+
+// Where a token starts, lines and columns count from 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    // in bytes
+    pub offset: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub enum LexError {
-    Syntax,
+    Syntax(Position),
     InvalidToken(String, String),
+    // where the string missing its closing quote starts
+    UnterminatedString(Position),
+    // an integer literal which doesn't fit in an i64
+    IntegerOverflow(Position),
+    // a \name naming no character
+    UnknownCharacter(Position),
 }
 
 
 impl fmt::Display for LexError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            LexError::Syntax => format!("{}", self.description()).fmt(f),
+            LexError::Syntax(position) => {
+                format!("{} at {}", self.what(), position).fmt(f)
+            }
+            LexError::UnterminatedString(position) => {
+                format!("{} starting at {}", self.what(), position).fmt(f)
+            }
+            LexError::IntegerOverflow(position) |
+            LexError::UnknownCharacter(position) => {
+                format!("{} at {}", self.what(), position).fmt(f)
+            }
             LexError::InvalidToken(ref code_token, ref found_token) => {
                 format!("{} expected: `{}`, found: `{}`",
-                        self.description(),
+                        self.what(),
                         code_token,
                         found_token)
                     .fmt(f)
@@ -25,33 +68,53 @@ impl fmt::Display for LexError {
     }
 }
 
-impl StdError for LexError {
-    fn description(&self) -> &str {
+impl LexError {
+    pub(crate) fn what(&self) -> &'static str {
         match *self {
-            LexError::Syntax => "Syntax error",
+            LexError::Syntax(_) => "Syntax error",
             LexError::InvalidToken(_, _) => "Invalid token",
+            LexError::UnterminatedString(_) => "Unterminated string",
+            LexError::IntegerOverflow(_) => "Integer literal out of range",
+            LexError::UnknownCharacter(_) => "Unknown character name",
         }
     }
+}
 
-    fn cause(&self) -> Option<&StdError> {
-        match *self {
-            _ => None,
-        }
+impl StdError for LexError {
+    fn description(&self) -> &str {
+        self.what()
+    }
+
+    fn cause(&self) -> Option<&dyn StdError> {
+        None
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Oparen,
     Cparen,
+    Obracket,
+    Cbracket,
+    Ocurly,
+    Ccurly,
     Identifier(String),
     Int(i64),
+    Str(String),
     Whitespace,
     Apostrophe,
     Backquote,
     Unquote,
     UnquoteSplicing,
     Comment,
+    // character of a reader macro
+    Dispatch(char),
+    // #? before the branches of a reader conditional
+    ReaderConditional,
+    // pattern of a #"..." regex literal
+    Regex(String),
+    // #name before the form of a tagged literal
+    Tag(String),
 }
 
 impl Token {
@@ -75,13 +138,13 @@ impl fmt::Display for Token {
 }
 
 #[allow(dead_code)]
-pub fn format_tokens(tokens: &Vec<Token>) -> String {
+pub fn format_tokens(tokens: &[Token]) -> String {
     let mut output = String::new();
 
     output.push('[');
     for token in tokens.iter() {
         let formatted_token = &format!("{}", token);
-        if formatted_token != "" {
+        if !formatted_token.is_empty() {
             output.push_str(formatted_token);
             output.push_str(", ")
         }
@@ -91,74 +154,321 @@ pub fn format_tokens(tokens: &Vec<Token>) -> String {
     output
 }
 
+// Source is UTF-8 and read by code points, columns count those. Symbols are made of any
+// characters but whitespace, control characters, commas, brackets and double quotes, so
+// names like λ or ñandú work.
 lazy_static! {
-    static ref TOKEN_MATCHES: Regex = Regex::new(r"(?x)
-        (?P<whitespace>^\s+)                 |
-        (?P<comment>;(.*)\n)                 |
+    static ref TOKEN_MATCHES: Regex = Regex::new(r#"(?x)
+        (?P<whitespace>^[\s,]+)              |
+        (?P<comment>^;[^\n]*)                |
         (?P<oparen>^\()                      |
         (?P<cparen>^\))                      |
         (?P<obracket>^\[)                    |
         (?P<cbracket>^\])                    |
+        (?P<ocurly>^\{)                      |
+        (?P<ccurly>^\})                      |
         (?P<integer>^[0-9]+)                 |
+        (?P<string>^"(?:[^"\\]|\\.)*")       |
         (?P<apostrophe>^')                   |
         (?P<backquote>^`)                    |
         (?P<unquote_splicing>^~@)            |
         (?P<unquote>^~)                      |
-        (?P<identifier>^([^\s\(\)\[\]\{\}]+))
-    ").unwrap();
+        (?P<character>^\\[^\s][^\s\p{Cc},\(\)\[\]\{\}"]*) |
+        (?P<reader_conditional>^\#\?)        |
+        (?P<regex>^\#"(?:[^"\\]|\\.)*")      |
+        (?P<tag>^\#[^\s\p{Cc},\(\)\[\]\{\}"\#]+) |
+        (?P<identifier>^([^\s\p{Cc},\(\)\[\]\{\}"]+))
+    "#).unwrap();
+}
+
+// Yields tokens one at a time as the input is consumed, stops after the first error
+pub struct Lexer<'a> {
+    code: &'a str,
+    position: Position,
+    macros: Option<ReaderMacros>,
 }
 
+impl<'a> Lexer<'a> {
+    pub fn new(content: &'a str) -> Lexer<'a> {
+        Lexer {
+            code: content,
+            position: Position {
+                line: 1,
+                column: 1,
+                offset: 0,
+            },
+            macros: None,
+        }
+    }
+
+    // Yields Dispatch for the characters in macros, the table may change while lexing
+    pub fn with_reader_macros(self, macros: ReaderMacros) -> Lexer<'a> {
+        Lexer { macros: Some(macros), ..self }
+    }
+
+    pub fn reader_macro(&self, c: char) -> Option<AtomVal> {
+        self.macros.as_ref().and_then(|macros| macros.borrow().get(&c).cloned())
+    }
+
+    // Where the next token starts, the end of the last one
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    fn advance(&mut self, len: usize) {
+        for c in self.code[..len].chars() {
+            if c == '\n' {
+                self.position.line += 1;
+                self.position.column = 1;
+            } else {
+                self.position.column += 1;
+            }
+        }
+        self.position.offset += len;
+        self.code = &self.code[len..];
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(Position, Token), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.code.is_empty() {
+            return None;
+        }
+
+        let position = self.position;
+        if let Some(c) = self.code.chars().next() {
+            if self.reader_macro(c).is_some() {
+                self.advance(c.len_utf8());
+                return Some(Ok((position, Token::Dispatch(c))));
+            }
+        }
+
+        match raw_string(self.code) {
+            Some(Some((len, content))) => {
+                let token = Token::Str(content.to_string());
+                self.advance(len);
+                return Some(Ok((position, token)));
+            }
+            Some(None) => {
+                self.code = "";
+                return Some(Err(LexError::UnterminatedString(position)));
+            }
+            None => {}
+        }
+
+        let (name, token) = match lex_single_token(self.code) {
+            Some(found) => found,
+            None if self.code.starts_with('"') => {
+                self.code = "";
+                return Some(Err(LexError::UnterminatedString(position)));
+            }
+            None => {
+                self.code = "";
+                return Some(Err(LexError::Syntax(position)));
+            }
+        };
+        self.advance(token.len());
+
+        let token = match name.as_str() {
+            "whitespace" => Token::Whitespace,
+            "oparen" => Token::Oparen,
+            "cparen" => Token::Cparen,
+            "obracket" => Token::Obracket,
+            "cbracket" => Token::Cbracket,
+            "ocurly" => Token::Ocurly,
+            "ccurly" => Token::Ccurly,
+            "identifier" => Token::Identifier(token),
+            "integer" => {
+                match token.parse::<i64>() {
+                    Ok(value) => Token::Int(value),
+                    Err(_) => return Some(Err(LexError::IntegerOverflow(position))),
+                }
+            }
+            "string" => Token::Str(unescape(&token[1..token.len() - 1])),
+            "character" => {
+                match character(&token[1..]) {
+                    Some(c) => Token::Str(c.to_string()),
+                    None => return Some(Err(LexError::UnknownCharacter(position))),
+                }
+            }
+            "apostrophe" => Token::Apostrophe,
+            "backquote" => Token::Backquote,
+            "unquote" => Token::Unquote,
+            "unquote_splicing" => Token::UnquoteSplicing,
+            "comment" => Token::Comment,
+            "reader_conditional" => Token::ReaderConditional,
+            "regex" => Token::Regex(token[2..token.len() - 1].to_string()),
+            "tag" => Token::Tag(token[1..].to_string()),
+            _ => unreachable!(),
+        };
+
+        Some(Ok((position, token)))
+    }
+}
+
+// The character written \name, a single character or one of the names of whitespace
+fn character(name: &str) -> Option<char> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => {
+            match name {
+                "space" => Some(' '),
+                "newline" => Some('\n'),
+                "tab" => Some('\t'),
+                "return" => Some('\r'),
+                _ => None,
+            }
+        }
+    }
+}
+
+// All tokens of content at once
+#[allow(dead_code)]
 pub fn lex(content: &str) -> Result<Vec<Token>, LexError> {
-    let mut tokens: Vec<Token> = vec![];
-    let mut code = content;
+    Lexer::new(content).map(|token| token.map(|(_, token)| token)).collect()
+}
 
-    while code != "" {
-        let found_token = lex_single_token(code);
+// #r"..." is taken as is, without escapes. Any number of # after the r allow quotes inside:
+// #r#"say "hi""# ends at a quote followed by as many #. None if code doesn't start with a
+// raw string, Some(None) if it isn't terminated, else its length and content.
+fn raw_string(code: &str) -> Option<Option<(usize, &str)>> {
+    if !code.starts_with("#r") {
+        return None;
+    }
+    let fence = code[2..].len() - code[2..].trim_start_matches('#').len();
+    if !code[2 + fence..].starts_with('"') {
+        return None;
+    }
 
-        match found_token {
-            Ok((name, token)) => {
-                // let code_token = &code[0..token.len()];
-                // if token.cmp(&code_token.to_string()) != Ordering::Equal {
-                //     return Result::Err(LexError::InvalidToken(code_token.to_string(),
-                //                                               token.to_string()));
-                // }
-                code = &code[token.len()..code.len()];
+    let start = 2 + fence + 1;
+    let closer = format!("\"{}", "#".repeat(fence));
+    Some(code[start..]
+        .find(&closer)
+        .map(|end| (start + end + closer.len(), &code[start..start + end])))
+}
 
-                tokens.push(match name.as_str() {
-                    "whitespace" => Token::Whitespace,
-                    "oparen" => Token::Oparen,
-                    "cparen" => Token::Cparen,
-                    "obracket" => Token::Oparen,
-                    "cbrakcet" => Token::Cparen,
-                    "identifier" => Token::Identifier(token),
-                    "integer" => Token::Int(token.parse::<i64>().unwrap()),
-                    "apostrophe" => Token::Apostrophe,
-                    "backquote" => Token::Backquote,
-                    "unquote" => Token::Unquote,
-                    "unquote_splicing" => Token::UnquoteSplicing,
-                    "comment" => Token::Comment,
-                    _ => {
-                        println!("NAME: {:?}", name);
-                        unreachable!()
-                    }
+fn unescape(str: &str) -> String {
+    let mut output = String::with_capacity(str.len());
+    let mut chars = str.chars();
 
-                });
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => output.push('\n'),
+                Some('t') => output.push('\t'),
+                Some('r') => output.push('\r'),
+                Some('0') => output.push('\0'),
+                Some(other) => output.push(other),
+                None => {}
             }
-            Err(err) => return Result::Err(err),
+        } else {
+            output.push(c);
         }
     }
 
-    Result::Ok(tokens)
+    output
 }
 
-fn lex_single_token(str: &str) -> Result<(String, String), LexError> {
+fn lex_single_token(str: &str) -> Option<(String, String)> {
     for cap in TOKEN_MATCHES.captures_iter(str) {
         for (name, matched) in cap.iter_named() {
             if let Some(ref token) = matched {
-                return Result::Ok((name.to_string(), token.to_string()));
+                return Some((name.to_string(), token.to_string()));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lexer, LexError, Token, lex};
+
+    #[test]
+    fn test_unicode() {
+        let tokens = Lexer::new("(λ \"ü→\" \\é ☃x)")
+            .map(|token| token.unwrap())
+            .filter(|&(_, ref token)| !token.is_hidden())
+            .map(|(position, token)| (position.column, token))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens,
+                   vec![(1, Token::Oparen),
+                        (2, Token::Identifier("λ".to_string())),
+                        (4, Token::Str("ü→".to_string())),
+                        (9, Token::Str("é".to_string())),
+                        (12, Token::Identifier("☃x".to_string())),
+                        (14, Token::Cparen)]);
+
+        match lex("(ñandú \u{7})") {
+            Err(LexError::Syntax(position)) => assert_eq!(position.column, 8),
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let source = "(\"two\nlines\" #r\"C:\\dir\\n\" #r##\"say \"#hi\"\"## #r)";
+        let tokens = Lexer::new(source)
+            .map(|token| token.unwrap())
+            .filter(|&(_, ref token)| !token.is_hidden())
+            .map(|(position, token)| ((position.line, position.column), token))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens,
+                   vec![((1, 1), Token::Oparen),
+                        ((1, 2), Token::Str("two\nlines".to_string())),
+                        ((2, 8), Token::Str("C:\\dir\\n".to_string())),
+                        ((2, 21), Token::Str("say \"#hi\"".to_string())),
+                        ((2, 39), Token::Tag("r".to_string())),
+                        ((2, 41), Token::Cparen)]);
+
+        match lex("(a\n  \"open") {
+            Err(LexError::UnterminatedString(position)) => {
+                assert_eq!((position.line, position.column), (2, 3))
             }
+            other => panic!("expected an unterminated string, got {:?}", other),
         }
+        assert!(lex("#r#\"no end\"").is_err());
     }
 
-    return Result::Err(LexError::Syntax);
+    #[test]
+    fn test_integers() {
+        assert_eq!(lex("(9223372036854775807)").unwrap(),
+                   vec![Token::Oparen, Token::Int(i64::MAX), Token::Cparen]);
+
+        match lex("(+ 1\n   99999999999999999999)") {
+            Err(LexError::IntegerOverflow(position)) => {
+                assert_eq!((position.line, position.column), (2, 4))
+            }
+            other => panic!("expected an integer overflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_characters() {
+        assert_eq!(lex("(\\a \\space \\newline \\tab \\( \\é)").unwrap(),
+                   vec![Token::Oparen,
+                        Token::Str("a".to_string()),
+                        Token::Whitespace,
+                        Token::Str(" ".to_string()),
+                        Token::Whitespace,
+                        Token::Str("\n".to_string()),
+                        Token::Whitespace,
+                        Token::Str("\t".to_string()),
+                        Token::Whitespace,
+                        Token::Str("(".to_string()),
+                        Token::Whitespace,
+                        Token::Str("é".to_string()),
+                        Token::Cparen]);
+
+        match lex("(list\n  \\spac)") {
+            Err(LexError::UnknownCharacter(position)) => {
+                assert_eq!((position.line, position.column), (2, 3))
+            }
+            other => panic!("expected an unknown character, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,88 @@
+#![feature(test)]
+#![feature(allocator_api)]
+
+// #![feature(alloc_system)]
+// extern crate alloc_system;
+
+extern crate test;
+extern crate regex;
+#[macro_use]
+extern crate lazy_static;
+extern crate fnv;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "plugins")]
+extern crate libloading;
+#[cfg(feature = "watch")]
+extern crate notify;
+#[cfg(target_arch = "wasm32")]
+extern crate wasm_bindgen;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_test;
+
+pub mod arena;
+pub mod data;
+pub mod lexer;
+pub mod parser;
+pub mod env;
+pub mod eval;
+pub mod core;
+pub mod gc;
+#[cfg(feature = "std")]
+pub mod io;
+pub mod output;
+pub mod meta;
+pub mod protocol;
+pub mod record;
+pub mod queue;
+pub mod printf;
+pub mod term;
+pub mod opts;
+pub mod sorted;
+pub mod seq;
+pub mod priority;
+pub mod transient;
+pub mod vector;
+pub mod shared;
+pub mod stats;
+pub mod fmt;
+pub mod json;
+pub mod analysis;
+pub mod check;
+#[cfg(feature = "std")]
+pub mod lsp;
+pub mod doc;
+pub mod dump;
+pub mod tagged;
+pub mod testing;
+pub mod debug;
+pub mod diagnostics;
+pub mod profile;
+pub mod report;
+pub mod golden;
+pub mod interpreter;
+#[cfg(feature = "std")]
+pub mod ffi;
+pub mod emit;
+pub mod embed;
+#[cfg(feature = "io")]
+pub mod zmtp;
+#[cfg(feature = "io")]
+pub mod kernel;
+#[cfg(feature = "io")]
+pub mod remote;
+#[cfg(feature = "io")]
+pub mod bundle;
+#[cfg(feature = "io")]
+pub mod bench;
+#[cfg(feature = "io")]
+pub mod path;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(feature = "serde")]
+pub mod serialize;
+pub mod wasm;
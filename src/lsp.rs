@@ -0,0 +1,451 @@
+use std::io::{self, BufRead, Write};
+use fnv::FnvHashMap;
+use analysis::{self, Analysis, SPECIAL_FORMS};
+use check::{self, Severity};
+use data::{AtomType, c_symbol};
+use env::{Env, env_get, env_names, params_arity};
+use json::{self, Json, object, string};
+use lexer::Position;
+use parser::{Cst, parse_cst};
+
+// Language server speaking LSP over stdio (or whatever input and output it's given),
+// the documents are analysed against the names bound in env
+struct Server<'a, W: Write> {
+    env: &'a Env,
+    output: W,
+    documents: FnvHashMap<String, String>,
+    shutdown: bool,
+}
+
+// Reads one message framed by a Content-Length header, None at the end of input
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        if parts.next().map(|name| name.trim().to_lowercase()) == Some("content-length".into()) {
+            length = parts.next().and_then(|value| value.trim().parse::<usize>().ok());
+        }
+    }
+
+    let length = length.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length")
+        })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8(body)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    json::parse(&body).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+// LSP positions count lines from 0 and characters in UTF-16 code units
+fn lsp_position(source: &str, position: Position) -> Json {
+    let line_start = source[..position.offset].rfind('\n').map_or(0, |pos| pos + 1);
+    let character = source[line_start..position.offset].encode_utf16().count();
+    object(vec![("line", Json::Num((position.line - 1) as f64)),
+                ("character", Json::Num(character as f64))])
+}
+
+fn lsp_range(source: &str, start: Position, end: Position) -> Json {
+    object(vec![("start", lsp_position(source, start)), ("end", lsp_position(source, end))])
+}
+
+// Byte offset of an LSP position
+fn offset_at(source: &str, position: &Json) -> usize {
+    let line = position.get("line").as_usize().unwrap_or(0);
+    let character = position.get("character").as_usize().unwrap_or(0);
+    let line_start = source.split('\n').take(line).map(|line| line.len() + 1).sum::<usize>();
+    let mut units = 0;
+    for (offset, c) in source[line_start.min(source.len())..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + offset;
+        }
+        units += c.len_utf16();
+    }
+    source.len()
+}
+
+fn contains(start: Position, end: Position, offset: usize) -> bool {
+    start.offset <= offset && offset <= end.offset
+}
+
+impl<'a, W: Write> Server<'a, W> {
+    fn send(&mut self, message: Json) -> io::Result<()> {
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn analyze(&self, nodes: &[Cst]) -> Analysis {
+        analysis::analyze(nodes, |name| check::is_macro(self.env, name))
+    }
+
+    fn diagnostics(&self, source: &str) -> Vec<Json> {
+        check::check(source, self.env, true)
+            .into_iter()
+            .map(|problem| {
+                let severity = match problem.severity {
+                    Severity::Error => 1.0,
+                    Severity::Warning => 2.0,
+                };
+                object(vec![("range", lsp_range(source, problem.start, problem.end)),
+                            ("severity", Json::Num(severity)),
+                            ("source", string("rulsp")),
+                            ("message", Json::Str(problem.message))])
+            })
+            .collect()
+    }
+
+    fn publish(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(source) => self.diagnostics(source),
+            None => vec![],
+        };
+        self.send(object(vec![("jsonrpc", string("2.0")),
+                              ("method", string("textDocument/publishDiagnostics")),
+                              ("params",
+                               object(vec![("uri", string(uri)),
+                                           ("diagnostics", Json::Array(diagnostics))]))]))
+    }
+
+    // Document and byte offset a textDocument/position request is about
+    fn document_at(&self, params: &Json) -> Option<(String, &str, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let source = self.documents.get(uri)?;
+        Some((uri.to_string(), source, offset_at(source, params.get("position"))))
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let mut items = vec![];
+        let mut add = |label: &str, kind: f64, detail: Option<String>| {
+            let mut item = vec![("label", string(label)), ("kind", Json::Num(kind))];
+            if let Some(detail) = detail {
+                item.push(("detail", Json::Str(detail)));
+            }
+            items.push(object(item));
+        };
+
+        for name in SPECIAL_FORMS.iter().filter(|name| !name.starts_with('&')) {
+            add(name, 14.0, None);
+        }
+        for name in env_names(self.env) {
+            let (kind, detail) = match env_get(self.env, &c_symbol(&name)).map(|value| {
+                match *value {
+                    AtomType::Func(ref f) => (3.0, f.doc().map(|doc| doc.to_string())),
+                    AtomType::AFunc(ref fd) => (3.0, fd.doc.clone()),
+                    _ => (6.0, None),
+                }
+            }) {
+                Some(found) => found,
+                None => continue,
+            };
+            add(&name, kind, detail);
+        }
+        if let Some((_, source, _)) = self.document_at(params) {
+            if let Ok(nodes) = parse_cst(source) {
+                for definition in self.analyze(&nodes).definitions {
+                    let kind = if definition.params.is_some() { 3.0 } else { 6.0 };
+                    add(&definition.name, kind, definition.doc);
+                }
+            }
+        }
+        Json::Array(items)
+    }
+
+    // Markdown describing the symbol at the position and the range of the symbol
+    fn hover(&self, params: &Json) -> Json {
+        let (_, source, offset) = match self.document_at(params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+        let analysis = match parse_cst(source) {
+            Ok(nodes) => self.analyze(&nodes),
+            Err(_) => return Json::Null,
+        };
+
+        let found = analysis.definitions
+            .iter()
+            .find(|definition| contains(definition.span.start, definition.span.end, offset))
+            .map(|definition| (definition.name.clone(), definition.span, Some(definition.span)))
+            .or_else(|| {
+                analysis.references
+                    .iter()
+                    .find(|reference| contains(reference.span.start, reference.span.end, offset))
+                    .map(|reference| (reference.name.clone(), reference.span, reference.target))
+            });
+        let (name, span, target) = match found {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+
+        let text = match target {
+            Some(target) => {
+                match analysis.definitions.iter().find(|definition| definition.span == target) {
+                    Some(definition) => {
+                        signature(&definition.name,
+                                  definition.params.as_deref(),
+                                  definition.doc.as_deref())
+                    }
+                    None => format!("local `{}`", name),
+                }
+            }
+            None if SPECIAL_FORMS.contains(&name.as_str()) => format!("special form `{}`", name),
+            None => {
+                let value = match env_get(self.env, &c_symbol(&name)) {
+                    Some(value) => value,
+                    None => return Json::Null,
+                };
+                match *value {
+                    AtomType::Func(ref f) => signature(&name, None, f.doc()),
+                    AtomType::AFunc(ref fd) => {
+                        let doc = fd.doc.as_deref();
+                        let mut text = signature(&name, Some(&fd.params.to_string()), doc);
+                        if let Ok(params) = fd.params.get_seq() {
+                            text.push_str(&arity(params_arity(params)));
+                        }
+                        text
+                    }
+                    _ => format!("`{}` = `{}`", name, value.format(true)),
+                }
+            }
+        };
+
+        object(vec![("contents",
+                     object(vec![("kind", string("markdown")), ("value", Json::Str(text))])),
+                    ("range", lsp_range(source, span.start, span.end))])
+    }
+
+    // Location where the symbol at the position is bound in the same file
+    fn definition(&self, params: &Json) -> Json {
+        let (uri, source, offset) = match self.document_at(params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+        let analysis = match parse_cst(source) {
+            Ok(nodes) => self.analyze(&nodes),
+            Err(_) => return Json::Null,
+        };
+
+        let target = analysis.references
+            .iter()
+            .find(|reference| contains(reference.span.start, reference.span.end, offset))
+            .and_then(|reference| reference.target)
+            .or_else(|| {
+                analysis.definitions
+                    .iter()
+                    .find(|definition| contains(definition.span.start, definition.span.end, offset))
+                    .map(|definition| definition.span)
+            });
+        match target {
+            Some(span) => {
+                object(vec![("uri", Json::Str(uri)),
+                            ("range", lsp_range(source, span.start, span.end))])
+            }
+            None => Json::Null,
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (f64, String)> {
+        match method {
+            "initialize" => {
+                let capabilities = object(vec![("textDocumentSync", Json::Num(1.0)),
+                                               ("completionProvider", object(vec![])),
+                                               ("hoverProvider", Json::Bool(true)),
+                                               ("definitionProvider", Json::Bool(true))]);
+                Ok(object(vec![("capabilities", capabilities),
+                               ("serverInfo", object(vec![("name", string("rulsp"))]))]))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/completion" => Ok(self.completion(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/definition" => Ok(self.definition(params)),
+            _ => Err((-32601.0, format!("unknown method {}", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> io::Result<()> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").get("text").as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish(&uri)
+            }
+            "textDocument/didChange" => {
+                // full sync, the last change holds the whole text
+                if let Json::Array(ref changes) = *params.get("contentChanges") {
+                    if let Some(text) = changes.last().and_then(|last| last.get("text").as_str()) {
+                        self.documents.insert(uri.clone(), text.to_string());
+                    }
+                }
+                self.publish(&uri)
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish(&uri)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn arity(arity: (usize, Option<usize>)) -> String {
+    match arity {
+        (min, None) => format!("\n\ntakes {} or more args", min),
+        (min, Some(max)) if min == max => format!("\n\ntakes {} args", min),
+        (min, Some(max)) => format!("\n\ntakes {} to {} args", min, max),
+    }
+}
+
+fn signature(name: &str, params: Option<&str>, doc: Option<&str>) -> String {
+    let mut text = match params {
+        Some(params) => {
+            let params = params.trim_matches(|c| c == '(' || c == ')' || c == '[' || c == ']');
+            format!("```\n({}{}{})\n```", name, if params.is_empty() { "" } else { " " }, params)
+        }
+        None => format!("`{}`", name),
+    };
+    if let Some(doc) = doc {
+        text.push_str("\n\n");
+        text.push_str(doc);
+    }
+    text
+}
+
+// Serves requests until the exit notification or the end of input, true if the client
+// asked for shutdown before (the exit code should be 0 then)
+pub fn serve<R: BufRead, W: Write>(env: &Env, mut input: R, output: W) -> io::Result<bool> {
+    let mut server = Server {
+        env: env,
+        output: output,
+        documents: FnvHashMap::default(),
+        shutdown: false,
+    };
+
+    while let Some(message) = read_message(&mut input)? {
+        let method = message.get("method").as_str().unwrap_or("").to_string();
+        if method == "exit" {
+            break;
+        }
+        let id = message.get("id").clone();
+        if id.is_null() {
+            server.notification(&method, message.get("params"))?;
+            continue;
+        }
+
+        let response = match server.request(&method, message.get("params")) {
+            Ok(result) => vec![("jsonrpc", string("2.0")), ("id", id), ("result", result)],
+            Err((code, message)) => {
+                let error = object(vec![("code", Json::Num(code)),
+                                        ("message", Json::Str(message))]);
+                vec![("jsonrpc", string("2.0")), ("id", id), ("error", error)]
+            }
+        };
+        server.send(object(response))?;
+    }
+
+    Ok(server.shutdown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, offset_at};
+    use core;
+    use json::{self, Json};
+
+    fn frame(messages: &[&str]) -> String {
+        messages.iter()
+            .map(|message| format!("Content-Length: {}\r\n\r\n{}", message.len(), message))
+            .collect()
+    }
+
+    fn responses(input: &str) -> Vec<Json> {
+        let mut output = vec![];
+        assert!(serve(&core::build(), input.as_bytes(), &mut output).unwrap());
+        let output = String::from_utf8(output).unwrap();
+        output.split("Content-Length: ")
+            .skip(1)
+            .map(|message| json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_offsets() {
+        let source = "ab\n😀x\n";
+        let position = |line, character| {
+            json::parse(&format!("{{\"line\":{},\"character\":{}}}", line, character)).unwrap()
+        };
+        assert_eq!(offset_at(source, &position(0, 1)), 1);
+        assert_eq!(offset_at(source, &position(1, 2)), 7);
+        assert_eq!(offset_at(source, &position(1, 9)), 8);
+        assert_eq!(offset_at(source, &position(5, 0)), source.len());
+    }
+
+    #[test]
+    fn test_session() {
+        let open = r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":
+            {"uri":"file:///a.clrs","text":"(defn twice \"Doubles\" (x) (+ x x))\n(twice y)"}}}"#;
+        let at = |id: usize, method: &str, line: usize, character: usize| {
+            format!(r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{{"textDocument":
+                {{"uri":"file:///a.clrs"}},"position":{{"line":{},"character":{}}}}}}}"#,
+                    id, method, line, character)
+        };
+        let change = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":
+            {"textDocument":{"uri":"file:///a.clrs"},"contentChanges":[{"text":"(foo"}]}}"#;
+        let messages = [r#"{"jsonrpc":"2.0","id":0,"method":"initialize","params":{}}"#,
+                        open,
+                        &at(1, "textDocument/hover", 1, 2),
+                        &at(2, "textDocument/definition", 0, 29),
+                        &at(3, "textDocument/hover", 0, 27),
+                        &at(4, "textDocument/completion", 0, 0),
+                        change,
+                        r#"{"jsonrpc":"2.0","id":5,"method":"bogus"}"#,
+                        r#"{"jsonrpc":"2.0","id":6,"method":"shutdown"}"#,
+                        r#"{"jsonrpc":"2.0","method":"exit"}"#];
+        let responses = responses(&frame(&messages));
+        assert_eq!(responses.len(), 9);
+
+        let capabilities = responses[0].get("result").get("capabilities");
+        assert_eq!(capabilities.get("hoverProvider"), &Json::Bool(true));
+
+        let diagnostics = responses[1].get("params").get("diagnostics");
+        assert_eq!(diagnostics.to_string(),
+                   concat!(r#"[{"range":{"start":{"line":1,"character":7},"#,
+                           r#""end":{"line":1,"character":8}},"severity":2,"#,
+                           r#""source":"rulsp","message":"unresolved symbol y"}]"#));
+
+        let hover = responses[2].get("result").get("contents").get("value");
+        assert_eq!(hover.as_str(), Some("```\n(twice x)\n```\n\nDoubles"));
+        let definition = responses[3].get("result").get("range").get("start");
+        assert_eq!(definition.to_string(), r#"{"line":0,"character":23}"#);
+        let hover = responses[4].get("result").get("contents").get("value");
+        assert_eq!(hover.as_str(), Some("```\n(+ & xs)\n```\n\ntakes 0 or more args"));
+
+        if let Json::Array(ref items) = *responses[5].get("result") {
+            let labels = items.iter().map(|item| item.get("label").as_str().unwrap());
+            let labels = labels.collect::<Vec<_>>();
+            assert!(labels.contains(&"twice") && labels.contains(&"let") &&
+                    labels.contains(&"first"));
+        } else {
+            panic!("completion should return a list");
+        }
+
+        let diagnostics = responses[6].get("params").get("diagnostics");
+        assert_eq!(diagnostics.to_string(),
+                   concat!(r#"[{"range":{"start":{"line":0,"character":0},"#,
+                           r#""end":{"line":0,"character":0}},"severity":1,"#,
+                           r#""source":"rulsp","message":"Unclosed `(` opened at 1:1"}]"#));
+        assert_eq!(responses[7].get("error").get("code"), &Json::Num(-32601.0));
+        assert!(responses[8].get("result").is_null());
+    }
+}
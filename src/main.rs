@@ -1,47 +1,51 @@
-#![feature(field_init_shorthand)]
 #![feature(test)]
 
-// #![feature(alloc_system)]
-// extern crate alloc_system;
-
 extern crate test;
-extern crate regex;
-#[macro_use]
-extern crate lazy_static;
+extern crate rulsp;
 extern crate rustyline;
-extern crate fnv;
-#[macro_use]
 extern crate log;
 extern crate env_logger;
 
-mod data;
-mod lexer;
-mod parser;
-mod env;
-mod eval;
-mod core;
-
+use rulsp::{data, parser, env, eval, stats, fmt, check, lsp, doc, dump, testing, debug, profile,
+            report, interpreter, emit};
+// building executables, the kernel, the network repl and bench need the io feature
+#[cfg(feature = "io")]
+use rulsp::{bundle, kernel, remote, bench};
+use std::io::{Read, Write};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use env::{Env};
+use env::{Env, env_set};
+use data::{AtomVal, c_list, c_string, c_symbol};
 use eval::eval_str;
+use interpreter::Interpreter;
+use report::Report;
 
 #[allow(dead_code)]
-fn repl(env: Env) {
+fn repl(interpreter: Interpreter) {
     let mut rl = Editor::<()>::new();
-    if let Err(_) = rl.load_history("history.txt") {
+    if rl.load_history("history.txt").is_err() {
         println!("No previous history.");
     }
 
+    // errors can be inspected where they happened
+    debug::set_post_mortem(true);
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(&line);
-                let result = eval_str(line.as_str(), &env);
-                match result {
+                debug::clear_failure();
+                match interpreter.run("<repl>", line.as_str()) {
                     Ok(result) => println!(">> {}", result),
-                    Err(err) => println!(">> {:?}", err)
+                    Err(report) => {
+                        print_error(&report.render(use_color()));
+                        if debug::has_failure() {
+                            let answer = rl.readline("debug where it failed? [y/N] ");
+                            if answer.map_or(false, |answer| answer.trim() == "y") {
+                                debug::post_mortem();
+                            }
+                        }
+                    }
                 };
             }
             Err(ReadlineError::Interrupted) => {
@@ -61,6 +65,477 @@ fn repl(env: Env) {
     rl.save_history("history.txt").unwrap();
 }
 
+#[cfg(unix)]
+fn is_terminal(fd: i32) -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    unsafe { isatty(fd) == 1 }
+}
+
+#[cfg(not(unix))]
+fn is_terminal(_fd: i32) -> bool {
+    false
+}
+
+// Errors and warnings are colored when stderr is a terminal, honors NO_COLOR and dumb terminals
+fn use_color() -> bool {
+    is_terminal(2) && std::env::var_os("NO_COLOR").is_none() &&
+    std::env::var("TERM").map(|term| term != "dumb").unwrap_or(false)
+}
+
+// Errors go to stderr, so they don't end up among what the program prints
+fn print_error(text: &str) {
+    let _ = write!(std::io::stderr(), "{}", text);
+}
+
+// Warnings go to stderr, in yellow where errors are red
+fn print_diagnostics(interpreter: &Interpreter) {
+    let color = use_color();
+    interpreter.on_diagnostic(move |diagnostic| {
+        let text = diagnostic.to_string();
+        let _ = if color {
+            writeln!(std::io::stderr(), "\x1b[1;33m{}\x1b[0m", text)
+        } else {
+            writeln!(std::io::stderr(), "{}", text)
+        };
+    });
+}
+
+// Only the repl echoes values, a script or -e prints what it prints itself, like a built
+// executable does
+fn print_report(result: Result<AtomVal, Report>) {
+    if let Err(report) = result {
+        print_error(&report.render(use_color()));
+        std::process::exit(1)
+    }
+}
+
+// Prints the API docs of the files as Markdown, or with --html as HTML
+fn document_files(args: &[String]) {
+    let html = args.first().map_or(false, |arg| arg == "--html");
+    let paths = if html { &args[1..] } else { args };
+    if paths.is_empty() {
+        println!("doc needs files or directories to document");
+        std::process::exit(1)
+    }
+
+    match doc::collect(paths) {
+        Ok(ref modules) if html => print!("{}", doc::html(modules)),
+        Ok(ref modules) => print!("{}", doc::markdown(modules)),
+        Err(err) => {
+            print_error(&format!("error: {}\n", err));
+            std::process::exit(1)
+        }
+    }
+}
+
+// Reports syntax errors, unresolved symbols and bad macro calls, with --lint also likely
+// mistakes, fails if there are any
+fn check_files(args: &[String], env: &Env) {
+    let lint = args.first().map_or(false, |arg| arg == "--lint");
+    let paths = if lint { &args[1..] } else { args };
+    if paths.is_empty() {
+        println!("check needs files to check");
+        std::process::exit(1)
+    }
+
+    let mut failed = false;
+    for path in paths {
+        match check::check_file(path, env, lint) {
+            Ok(problems) => {
+                for problem in &problems {
+                    println!("{}:{}", path, problem);
+                }
+                failed |= !problems.is_empty();
+            }
+            Err(err) => {
+                print_error(&format!("error: {}\n", err));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1)
+    }
+}
+
+// Runs the tests of each .clrs file under the paths in a fresh interpreter, fails if any
+// assertion fails or a test raises an error
+fn test_files(paths: &[String]) {
+    let files = paths.iter().try_fold(vec![], |mut files, path| -> Result<Vec<String>, String> {
+        doc::collect_files(std::path::Path::new(path), &mut files)?;
+        Ok(files)
+    });
+    let files = match files {
+        Ok(ref files) if files.is_empty() => {
+            println!("test needs files or directories with tests");
+            std::process::exit(1)
+        }
+        Ok(files) => files,
+        Err(err) => {
+            print_error(&format!("error: {}\n", err));
+            std::process::exit(1)
+        }
+    };
+
+    let mut failed = false;
+    for file in files {
+        println!("Testing {}", file);
+        let interpreter = Interpreter::new();
+        let result = interpreter.load(&file).and_then(|_| interpreter.eval("(run-tests)"));
+        match result.and_then(|result| testing::failures(&result)) {
+            Ok(failures) => failed |= failures > 0,
+            Err(err) => {
+                print_error(&format!("error: {}\n", err));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1)
+    }
+}
+
+// Reformats the files, with --check just lists the unformatted ones and fails if there are any
+fn format_files(args: &[String]) {
+    let check = args.first().map_or(false, |arg| arg == "--check");
+    let paths = if check { &args[1..] } else { args };
+    if paths.is_empty() {
+        println!("fmt needs files to format");
+        std::process::exit(1)
+    }
+
+    let mut failed = false;
+    for path in paths {
+        match fmt::format_file(path, check) {
+            Ok(true) if check => {
+                println!("{} is not formatted", path);
+                failed = true;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                print_error(&format!("error: {}\n", err));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1)
+    }
+}
+
+// Prints the tokens or the AST of a file, or of the expression after -e
+fn dump_source(args: &[String], dump: fn(&str) -> Result<String, parser::ParseError>) {
+    let source = match (args.get(0).map(|arg| arg.as_str()), args.get(1)) {
+        (Some("-e"), Some(source)) => Ok(source.clone()),
+        (Some(path), None) if path != "-e" => {
+            let mut source = String::new();
+            std::fs::File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut source))
+                .map(|_| source)
+                .map_err(|err| format!("{}: {}", path, err))
+        }
+        _ => {
+            println!("dumping needs a file or -e and an expression");
+            std::process::exit(1)
+        }
+    };
+    match source.and_then(|source| dump(&source).map_err(|err| err.to_string())) {
+        Ok(dump) => print!("{}", dump),
+        Err(err) => {
+            print_error(&format!("error: {}\n", err));
+            std::process::exit(1)
+        }
+    }
+}
+
+// Runs the file. With --stats then prints to stderr what the run took, with --profile out
+// writes samples of the lisp call stack taken every --profile-interval (1000) evaluation steps
+// to out, folded for flamegraph tools.
+fn run_file(args: &[String], interpreter: &Interpreter) {
+    let mut report = false;
+    let mut profile_path = None;
+    let mut interval = 1000;
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stats" => report = true,
+            "--profile" if profile_path.is_none() => profile_path = args.next(),
+            "--profile-interval" => {
+                match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) if n > 0 => interval = n,
+                    _ => {
+                        println!("--profile-interval needs a number of steps");
+                        std::process::exit(1)
+                    }
+                }
+            }
+            _ => {
+                path = Some(arg);
+                break;
+            }
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            println!("run needs a file to run, after --stats, --profile and a file to write or \
+                      --profile-interval and a number of steps");
+            std::process::exit(1)
+        }
+    };
+
+    set_command_line_args(interpreter, &args.cloned().collect::<Vec<_>>());
+    let before = interpreter.stats();
+    if profile_path.is_some() {
+        profile::start(interval);
+    }
+    let result = interpreter.run_file(path);
+    if let Some(profile_path) = profile_path {
+        let folded = profile::stop();
+        let written = std::fs::File::create(profile_path)
+            .and_then(|mut file| file.write_all(folded.as_bytes()));
+        if let Err(err) = written {
+            print_error(&format!("error: {}: {}\n", profile_path, err));
+            std::process::exit(1)
+        }
+    }
+    if report {
+        let _ = write!(std::io::stderr(), "{}", stats::report(&before, &interpreter.stats()));
+    }
+    print_report(result)
+}
+
+// Times the form over --runs (100) calls after --warmup (10) more, --save writes the
+// measurement to a file and --baseline compares against one written before.
+#[cfg(feature = "io")]
+fn bench_form(args: &[String], interpreter: &Interpreter) {
+    let mut warmup = 10;
+    let mut runs = 100;
+    let mut baseline_path = None;
+    let mut save_path = None;
+    let mut form = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--warmup" | "--runs" => {
+                match args.next().and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) if arg == "--warmup" => warmup = n,
+                    Some(n) => runs = n,
+                    None => {
+                        println!("{} needs a number of calls", arg);
+                        std::process::exit(1)
+                    }
+                }
+            }
+            "--baseline" if baseline_path.is_none() => baseline_path = args.next(),
+            "--save" if save_path.is_none() => save_path = args.next(),
+            _ => {
+                form = Some(arg);
+                break;
+            }
+        }
+    }
+    let form = match form {
+        Some(form) => form,
+        None => {
+            println!("bench needs a form to time, after --warmup and --runs and a number of \
+                      calls or --baseline and --save and a file");
+            std::process::exit(1)
+        }
+    };
+
+    let baseline = baseline_path.map(|path| {
+        let mut source = String::new();
+        std::fs::File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(|err| err.to_string())
+            .and_then(|_| interpreter.eval(&source).map_err(|err| err.to_string()))
+            .and_then(|value| bench::Measurement::from_value(&value))
+            .map_err(|err| format!("{}: {}", path, err))
+    });
+    let result = bench::run(interpreter, form, warmup, runs).and_then(|measurement| {
+        match baseline {
+            Some(baseline) => print!("{}", measurement.compare(&baseline?)),
+            None => print!("{}", measurement.report()),
+        }
+        match save_path {
+            Some(path) => {
+                std::fs::File::create(path)
+                    .and_then(|mut file| writeln!(file, "{}", measurement.to_value().format(false)))
+                    .map_err(|err| format!("{}: {}", path, err))
+            }
+            None => Ok(()),
+        }
+    });
+    if let Err(err) = result {
+        print_error(&format!("error: {}\n", err));
+        std::process::exit(1)
+    }
+}
+
+// Writes a copy of this binary running the script, which gets its arguments as
+// *command-line-args*
+#[cfg(feature = "io")]
+fn build_executable(args: &[String]) {
+    let (path, out) = match (args.get(0), args.get(1).map(|arg| arg.as_str()), args.get(2)) {
+        (Some(path), Some("-o"), Some(out)) if args.len() == 3 => (path, out),
+        _ => {
+            println!("build needs a file to build, then -o and the executable to write");
+            std::process::exit(1)
+        }
+    };
+    let mut source = String::new();
+    let mut runtime = vec![];
+    let read = std::fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| Report::new(path, err.to_string()))
+        .and_then(|_| {
+            std::env::current_exe()
+                .and_then(std::fs::File::open)
+                .and_then(|mut file| file.read_to_end(&mut runtime))
+                .map_err(|err| Report::new(path, format!("can't read rulsp itself: {}", err)))
+        });
+    let written = read.and_then(|_| {
+            bundle::bundle(&runtime, path, &source)
+                .map_err(|err| Report::from_parse_error(path, &source, &err))
+        })
+        .and_then(|bundled| {
+            std::fs::File::create(out)
+                .and_then(|mut file| file.write_all(&bundled))
+                .and_then(|_| make_executable(out))
+                .map_err(|err| Report::new(out, err.to_string()))
+        });
+    if let Err(report) = written {
+        print_error(&report.render(use_color()));
+        std::process::exit(1)
+    }
+}
+
+#[cfg(all(unix, feature = "io"))]
+fn make_executable(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(all(not(unix), feature = "io"))]
+fn make_executable(_path: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+// The arguments after the script's path, which parse-opts reads
+fn set_command_line_args(interpreter: &Interpreter, args: &[String]) {
+    env_set(interpreter.env(),
+            &c_symbol("*command-line-args*"),
+            c_list(args.iter().map(|arg| c_string(arg)).collect()));
+}
+
+// An executable written by build runs its script instead of doing what the arguments say
+#[cfg(feature = "io")]
+fn run_embedded(args: &[String]) {
+    let embedded = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.to_str().and_then(|exe| bundle::embedded(exe).ok()))
+        .and_then(|embedded| embedded);
+    let (name, source) = match embedded {
+        Some(embedded) => embedded,
+        None => return,
+    };
+    let _ = env_logger::init();
+    let interpreter = Interpreter::new();
+    print_diagnostics(&interpreter);
+    set_command_line_args(&interpreter, &args[1..]);
+    if let Err(report) = interpreter.run(&name, &source) {
+        print_error(&report.render(use_color()));
+        std::process::exit(1)
+    }
+    std::process::exit(0)
+}
+
+// Prints the file as a Rust module, see emit::emit_rust
+fn emit_rust(args: &[String], env: &Env) {
+    let path = match args.get(0) {
+        Some(path) if args.len() == 1 => path,
+        _ => {
+            println!("emit-rust needs a file to translate");
+            std::process::exit(1)
+        }
+    };
+    let mut source = String::new();
+    let rust = std::fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|err| format!("{}: {}", path, err))
+        .and_then(|_| emit::emit_rust(path, &source, env));
+    match rust {
+        Ok(rust) => print!("{}", rust),
+        Err(err) => {
+            print_error(&format!("error: {}\n", err));
+            std::process::exit(1)
+        }
+    }
+}
+
+// Logging is filtered by RUST_LOG as usual, --log-level before the other arguments sets the
+// level of everything it doesn't mention, script logs included
+fn init_logging(args: &mut Vec<String>) {
+    let mut builder = env_logger::LogBuilder::new();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    if args.get(1).map_or(false, |arg| arg == "--log-level") {
+        match args.get(2).and_then(|level| level.parse::<log::LogLevelFilter>().ok()) {
+            Some(level) => {
+                builder.filter(None, level);
+                args.drain(1..3);
+            }
+            None => {
+                println!("--log-level needs one of off, error, warn, info, debug or trace");
+                std::process::exit(1)
+            }
+        }
+    }
+    builder.init().unwrap();
+}
+
+// repl --listen port [--token token], the token can come from RULSP_REPL_TOKEN too
+#[cfg(feature = "io")]
+fn serve_remote(args: &[String], interpreter: Interpreter) {
+    let mut address = None;
+    let mut token = std::env::var("RULSP_REPL_TOKEN").ok();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen" => address = args.next(),
+            "--token" => token = args.next().cloned(),
+            _ => {
+                println!("repl takes --listen and a port or address and --token and a token");
+                std::process::exit(1)
+            }
+        }
+    }
+    let address = match address {
+        Some(address) => address,
+        None => {
+            println!("--listen needs a port or address");
+            std::process::exit(1)
+        }
+    };
+    match remote::listen(address) {
+        Ok(listener) => {
+            if let Ok(address) = listener.local_addr() {
+                println!("listening on {}", address);
+            }
+            remote::serve(listener, interpreter, token)
+        }
+        Err(err) => {
+            print_error(&format!("error: {}: {}\n", address, err));
+            std::process::exit(1)
+        }
+    }
+}
+
 #[allow(unused_must_use)]
 fn count(n: String, env: Env) {
     eval_str("(def count-1 (fn* (n) (loop (n n acc 0) (if (= n 0) acc (recur (- n 1) (+ acc 1))))))", &env);
@@ -68,19 +543,79 @@ fn count(n: String, env: Env) {
 }
 
 fn main() {
-    env_logger::init().unwrap();
-    let env = core::build();
+    let mut args = std::env::args().collect::<Vec<_>>();
+    #[cfg(feature = "io")]
+    run_embedded(&args);
+    init_logging(&mut args);
+    // formatting, documenting and dumping don't need the core library loaded
+    match args.get(1).map(|arg| arg.as_str()) {
+        Some("fmt") => return format_files(&args[2..]),
+        Some("doc") => return document_files(&args[2..]),
+        Some("--dump-tokens") => return dump_source(&args[2..], dump::tokens),
+        Some("--dump-ast") => return dump_source(&args[2..], dump::ast),
+        #[cfg(feature = "io")]
+        Some("build") => return build_executable(&args[2..]),
+        _ => {}
+    }
+    let interpreter = Interpreter::new();
+    print_diagnostics(&interpreter);
 
-    match std::env::args().nth(1) {
-        Some(value) => {
-            if "repl" == value  {
-                repl(env);
-            } else {
-                count(value, env)
+    match args.get(1).map(|arg| arg.as_str()) {
+        #[cfg(feature = "io")]
+        Some("repl") if args.len() > 2 => serve_remote(&args[2..], interpreter),
+        Some("repl") => repl(interpreter),
+        Some("lsp") => {
+            let stdin = std::io::stdin();
+            match lsp::serve(interpreter.env(), stdin.lock(), std::io::stdout()) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(err) => {
+                    print_error(&format!("error: {}\n", err));
+                    std::process::exit(1)
+                }
+            }
+        }
+        Some("check") => check_files(&args[2..], interpreter.env()),
+        Some("test") => test_files(&args[2..]),
+        Some("run") => run_file(&args[2..], &interpreter),
+        #[cfg(feature = "io")]
+        Some("bench") => bench_form(&args[2..], &interpreter),
+        Some("emit-rust") => emit_rust(&args[2..], interpreter.env()),
+        #[cfg(feature = "io")]
+        Some("kernel") => {
+            let served = match args.get(2) {
+                Some(connection) => kernel::serve(connection, interpreter),
+                None => Err("kernel needs the connection file Jupyter passes".to_string()),
+            };
+            if let Err(err) = served {
+                print_error(&format!("error: {}\n", err));
+                std::process::exit(1)
+            }
+        }
+        Some("-e") => {
+            match args.get(2) {
+                Some(source) => print_report(interpreter.run("-e", source)),
+                None => println!("-e needs an expression to evaluate"),
             }
-        },
+        }
+        Some(value) if value.parse::<i64>().is_ok() => {
+            count(value.to_string(), interpreter.env().clone())
+        }
+        Some(path) => {
+            set_command_line_args(&interpreter, &args[2..]);
+            print_report(interpreter.run_file(path))
+        }
         None => {
-            println!("Pass repl or any number as a first param to count")
+            println!("Pass --log-level and a level first to change what's logged, then repl, \
+                      lsp, -e and an expression, check [--lint] and files to check, test and \
+                      paths with tests, fmt [--check] and files to format, doc [--html] and \
+                      paths to document, --dump-tokens or --dump-ast and a file or -e and an \
+                      expression, run [--stats] [--profile out] and a file or just a file to run, \
+                      bench [--warmup n] [--runs n] [--baseline file] [--save file] and a form to \
+                      time, repl --listen and a port or address [--token token] to serve the \
+                      repl, build, a file and -o and an executable to write, emit-rust and a \
+                      file to print as Rust, kernel and a Jupyter connection file or any number \
+                      as a first param to count")
         }
     };
 }
@@ -88,10 +623,12 @@ fn main() {
 #[allow(unused_must_use)]
 #[cfg(test)]
 mod tests {
-    use ::eval::eval_str;
-    use ::core;
-    use ::env::{Env, env_get};
-    use ::data::{AtomError, c_int, c_symbol, c_list, c_nil};
+    use rulsp::eval::eval_str;
+    use rulsp::core;
+    use rulsp::env::{Env, env_get, env_set};
+    use rulsp::data::{AtomError, c_bool, c_int, c_string, c_symbol, c_keyword, c_list, c_vector,
+                      c_nil, c_closure};
+    use std::rc::Rc;
 
     fn env() -> Env {
         core::build()
@@ -136,12 +673,32 @@ mod tests {
     #[test]
     fn eval_str_predicated() {
         assert_eq!(eval_str("(= 2 2)", &env()).unwrap(),
-                   c_int(1));
+                   c_bool(true));
 
         let env = env();
         eval_str("(def foo 2)", &env);
         assert_eq!(eval_str("(= 2 foo)", &env).unwrap(),
-                   c_int(1));
+                   c_bool(true));
+        assert_eq!(eval_str("(= 2 foo 3)", &env).unwrap(), c_bool(false));
+        assert_eq!(eval_str("(list (not= 2 foo) (not= 2 3) (nil? nil) (list? 1))", &env)
+                       .unwrap(),
+                   c_list(vec![c_bool(false), c_bool(true), c_bool(true), c_bool(false)]));
+    }
+
+    #[test]
+    fn eval_str_booleans() {
+        let env = env();
+
+        assert_eq!(eval_str("(list true false)", &env).unwrap(),
+                   c_list(vec![c_bool(true), c_bool(false)]));
+        assert_eq!(eval_str("(list (if false 1 2) (if nil 1 2) (if 0 1 2) (if '() 1 2))", &env)
+                       .unwrap(),
+                   c_list(vec![c_int(2), c_int(2), c_int(1), c_int(1)]));
+        assert_eq!(eval_str("(list (not nil) (not false) (not 0) (not true))", &env).unwrap(),
+                   c_list(vec![c_bool(true), c_bool(true), c_bool(false), c_bool(false)]));
+        assert_eq!(eval_str("(list (empty? '()) (empty? nil) (empty? '(1)))", &env).unwrap(),
+                   c_list(vec![c_bool(true), c_bool(true), c_bool(false)]));
+        assert_eq!(eval_str("({true 1} (= 1 1))", &env).unwrap(), c_int(1));
     }
 
     #[test]
@@ -164,10 +721,281 @@ mod tests {
                    c_int(12));
     }
 
+    #[test]
+    fn eval_str_variadic_semantics() {
+        let env = env();
+        eval_str("(def f (fn* (a b & c) (list a b c)))", &env);
+
+        assert_eq!(eval_str("(f 1 2 3 4)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_list(vec![c_int(3), c_int(4)])]));
+        assert_eq!(eval_str("(f 1 2 3)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_list(vec![c_int(3)])]));
+        assert_eq!(eval_str("(f 1 2)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_nil()]));
+        assert_eq!(eval_str("(f 1)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_nil(), c_nil()]));
+    }
+
+    #[test]
+    fn eval_str_invalid_params() {
+        let env = env();
+        let invalid = |msg: &str| Err(AtomError::InvalidArgument(msg.to_string()));
+
+        assert_eq!(eval_str("(fn* (x &) x)", &env),
+                   invalid("`&` has to be followed by a rest parameter"));
+        assert_eq!(eval_str("(fn* (x & &opt y) x)", &env),
+                   invalid("`&` has to be followed by a rest parameter"));
+        assert_eq!(eval_str("(fn* (x & y z) x)", &env),
+                   invalid("unexpected parameter z after the rest parameter"));
+        assert_eq!(eval_str("(fn* (&opt x &opt y) x)", &env),
+                   invalid("`&opt` can only appear once"));
+        assert_eq!(eval_str("(fn* (&key x & y) x)", &env),
+                   invalid("unexpected & after `&key`"));
+        assert_eq!(eval_str("(fn* ((a &)) a)", &env),
+                   invalid("`&` has to be followed by a rest parameter"));
+        assert!(eval_str("(fn* (1) 1)", &env).is_err());
+        assert!(eval_str("(fn* x x)", &env).is_err());
+
+        assert!(eval_str("(fn* (x & y &key z) x)", &env).is_ok());
+    }
+
+    #[test]
+    fn eval_str_defn() {
+        let env = env();
+        eval_str("(defn add (x y) (+ x y))", &env);
+        eval_str("(defn add-doc \"adds things\" (x & ys) (println x) (+ x (count ys)))", &env);
+
+        assert_eq!(eval_str("(add 1 2)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(add-doc 1 2 3)", &env).unwrap(), c_int(3));
+        assert!(eval_str("(defn bad (x &) x)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_doc() {
+        let env = env();
+        eval_str("(defn add \"adds things\" (x y) (+ x y))", &env);
+
+        assert_eq!(eval_str("(doc add)", &env).unwrap(), c_string("adds things"));
+        assert_eq!(eval_str("(doc (fn* (x) x))", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(doc cons)", &env).unwrap(),
+                   c_string("(cons x coll) returns coll with x prepended"));
+        assert!(eval_str("(doc 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_introspection() {
+        let env = env();
+        eval_str("(defn add (x &opt y) (+ x y))", &env);
+        eval_str("(defmacro ignore (fn* (x) (list 'quote x)))", &env);
+
+        assert_eq!(eval_str("(fn-name add)", &env).unwrap(), c_symbol("add"));
+        assert_eq!(eval_str("(fn-name ignore)", &env).unwrap(), c_symbol("ignore"));
+        assert_eq!(eval_str("(fn-name (fn* (x) x))", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(fn-params add)", &env).unwrap(),
+                   eval_str("'(x &opt y)", &env).unwrap());
+        assert_eq!(eval_str("(fn-arity add)", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(fn-arity (fn* (x & xs) x))", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_nil()]));
+        assert_eq!(eval_str("(macro? ignore)", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(macro? add)", &env).unwrap(), c_bool(false));
+        assert!(eval_str("(fn-name 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_callable_keywords() {
+        let env = env();
+        eval_str("(def person {:name 1 \"age\" 2})", &env);
+
+        assert_eq!(eval_str("(:name person)", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(:other person)", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(:other person 3)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(:name nil)", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(map :name (list person {}))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_nil()]));
+    }
+
+    #[test]
+    fn eval_str_callable_collections() {
+        let env = env();
+
+        assert_eq!(eval_str("({:a 1} :a)", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("({:a 1} '(1) 2)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("([1 2 3] 1)", &env).unwrap(), c_int(2));
+        assert!(eval_str("([1 2 3] 3)", &env).is_err());
+        assert!(eval_str("([1 2 3] (- 0 1))", &env).is_err());
+        assert!(eval_str("([1 2 3] :a)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_threading() {
+        let env = env();
+
+        assert_eq!(eval_str("(-> 10 (- 3) inc (list 1))", &env).unwrap(),
+                   c_list(vec![c_int(8), c_int(1)]));
+        assert_eq!(eval_str("(->> 10 (- 3) inc (list 1))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(-6)]));
+        assert_eq!(eval_str("(-> {:a {:b 2}} :a :b)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(->> '(1 2) (map inc) (reduce + 0))", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("(-> 1)", &env).unwrap(), c_int(1));
+    }
+
+    #[test]
+    fn eval_str_match() {
+        let env = env();
+        eval_str("(defn describe (x)
+                    (match x
+                      0 :zero
+                      \"s\" :string
+                      'sym :symbol
+                      [a b] :when (= a b) (list :same a)
+                      (a b) (list :pair a b)
+                      (a & rest) (list :many a rest)
+                      {:name n} (list :named n)
+                      _ :other))",
+                 &env);
+
+        assert_eq!(eval_str("(describe 0)", &env).unwrap(), c_keyword("zero"));
+        assert_eq!(eval_str("(describe \"s\")", &env).unwrap(), c_keyword("string"));
+        assert_eq!(eval_str("(describe 'sym)", &env).unwrap(), c_keyword("symbol"));
+        assert_eq!(eval_str("(describe [1 1])", &env).unwrap(),
+                   c_list(vec![c_keyword("same"), c_int(1)]));
+        assert_eq!(eval_str("(describe [1 2])", &env).unwrap(),
+                   c_list(vec![c_keyword("pair"), c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(describe '(1 2 3))", &env).unwrap(),
+                   c_list(vec![c_keyword("many"), c_int(1), c_list(vec![c_int(2), c_int(3)])]));
+        assert_eq!(eval_str("(describe {:name 3 :age 4})", &env).unwrap(),
+                   c_list(vec![c_keyword("named"), c_int(3)]));
+        assert_eq!(eval_str("(describe {:age 4})", &env).unwrap(), c_keyword("other"));
+        assert_eq!(eval_str("(describe 1)", &env).unwrap(), c_keyword("other"));
+
+        assert!(eval_str("(match 1 2 3)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_generator() {
+        let env = env();
+        eval_str("(defn numbers (from)
+                    (loop (n from)
+                      (do (yield n) (recur (inc n)))))",
+                 &env);
+        eval_str("(def gen (generator numbers 5))", &env);
+
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_int(6));
+        assert_eq!(eval_str("(list (next! gen) (next! gen))", &env).unwrap(),
+                   c_list(vec![c_int(7), c_int(8)]));
+    }
+
+    #[test]
+    fn eval_str_generator_nested_yield() {
+        let env = env();
+        eval_str("(defn emit-all (xs) (map (fn* (x) (yield x)) xs))", &env);
+        eval_str("(def gen (generator (fn* () (do (emit-all '(1 2)) (yield 3)))))", &env);
+
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(done? gen)", &env).unwrap(), c_bool(false));
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(done? gen)", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(next! gen)", &env).unwrap(), c_nil());
+    }
+
+    #[test]
+    fn eval_str_generator_send() {
+        let env = env();
+        eval_str("(def acc (generator (fn* () (loop (sum 0) (recur (+ sum (yield sum)))))))",
+                 &env);
+
+        assert_eq!(eval_str("(next! acc)", &env).unwrap(), c_int(0));
+        assert_eq!(eval_str("(next! acc 5)", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("(next! acc 2)", &env).unwrap(), c_int(7));
+    }
+
+    #[test]
+    fn eval_str_yield_outside_generator() {
+        let env = env();
+
+        assert!(eval_str("(yield 1)", &env).is_err());
+        eval_str("(def gen (generator (fn* () ((comp (fn* (x) (yield x))) 1))))", &env);
+        assert!(eval_str("(next! gen)", &env).is_err());
+        assert_eq!(eval_str("(done? gen)", &env).unwrap(), c_bool(true));
+    }
+
+    #[test]
+    fn eval_str_call_cc_escape() {
+        let env = env();
+
+        assert_eq!(eval_str("(+ 1 (call/cc (fn* (k) (+ 10 (k 2)))))", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(+ 1 (call/cc (fn* (k) 2)))", &env).unwrap(), c_int(3));
+
+        eval_str("(defn find-first (pred xs)
+                    (call/cc (fn* (return)
+                      (do (map (fn* (x) (if (pred x) (return x) nil)) xs) nil))))",
+                 &env);
+        assert_eq!(eval_str("(find-first (fn* (x) (= x 2)) '(1 2 3))", &env).unwrap(),
+                   c_int(2));
+        assert_eq!(eval_str("(find-first (fn* (x) (= x 4)) '(1 2 3))", &env).unwrap(),
+                   c_nil());
+    }
+
+    #[test]
+    fn eval_str_call_cc_unwinds() {
+        let env = env();
+        eval_str("(def log (open \"/dev/null\" :write))", &env);
+        eval_str("(def x 1)", &env);
+
+        assert_eq!(eval_str("(call/cc (fn* (k) (unwind-protect (k 1) (close log))))", &env)
+                       .unwrap(),
+                   c_int(1));
+        assert_eq!(eval_str("(closed? log)", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(call/cc (fn* (k) (binding [x 2] (k x))))", &env).unwrap(),
+                   c_int(2));
+        assert_eq!(eval_str("x", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(with-out-str
+                               (call/cc (fn* (k) (with-out-str (print \"lost\") (k 3))))
+                               (print \"seen\"))",
+                            &env)
+                       .unwrap(),
+                   c_string("seen"));
+    }
+
+    #[test]
+    fn eval_str_call_cc_reentry() {
+        let env = env();
+
+        assert_eq!(eval_str("(let [k (call/cc identity)] (if (= k 5) (list k) (k 5)))", &env)
+                       .unwrap(),
+                   c_list(vec![c_int(5)]));
+        assert_eq!(eval_str("(let [[n k] (call/cc (fn* (k) (list 0 k)))]
+                               (if (= n 3) n (k (list (inc n) k))))",
+                            &env)
+                       .unwrap(),
+                   c_int(3));
+    }
+
+    #[test]
+    fn eval_str_call_cc_outside_evaluation() {
+        let env = env();
+        eval_str("(def k (call/cc identity))", &env);
+
+        assert!(eval_str("(k 1)", &env).is_err());
+        assert!(eval_str("((comp (call/cc identity)) 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_deep_recursion() {
+        let env = env();
+        eval_str("(defn sum-to (n) (if (= n 0) 0 (+ n (sum-to (- n 1)))))", &env);
+
+        assert_eq!(eval_str("(sum-to 20000)", &env).unwrap(), c_int(200010000));
+    }
+
     #[test]
     fn eval_str_macro() {
         let env = env();
-        eval_str("(defmacro ignore (fn* (x) (list 'quote x))))", &env);
+        eval_str("(defmacro ignore (fn* (x) (list 'quote x)))", &env);
 
         assert_eq!(eval_str("(ignore foo)", &env).expect("This shouldn't fail because foo is ignored"),
                    c_symbol("foo"));
@@ -192,6 +1020,1062 @@ mod tests {
     }
 
 
+    #[test]
+    fn eval_str_multiple_forms() {
+        let env = env();
+
+        assert_eq!(eval_str("(def a 1) (def b (+ a 1)) ; sum\n(+ a b)", &env).unwrap(),
+                   c_int(3));
+        assert_eq!(eval_str("", &env).unwrap(), c_nil());
+        assert!(eval_str("(def c 1) (undefined-fn) (def c 2)", &env).is_err());
+        assert_eq!(eval_str("c", &env).unwrap(), c_int(1));
+    }
+
+    #[test]
+    fn eval_str_unicode() {
+        let env = env();
+
+        assert_eq!(eval_str("(def λ \"ñandú\") (count λ)", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("(nth λ 4)", &env).unwrap(), c_string("ú"));
+        assert_eq!(eval_str("(nth λ 5)", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(count \"\")", &env).unwrap(), c_int(0));
+    }
+
+    #[test]
+    fn eval_str_regex() {
+        let env = env();
+
+        assert_eq!(eval_str("(re-find #\"(\\d+)-(\\d+)?\" \"x 12-\")", &env).unwrap(),
+                   c_vector(vec![c_string("12-"), c_string("12"), c_nil()]));
+        assert_eq!(eval_str("(re-find #\"\\d+\" \"ab\")", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(re-matches #\"a|ab\" \"ab\")", &env).unwrap(), c_string("ab"));
+        assert_eq!(eval_str("(re-matches #\"a\" \"ab\")", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(= (re-pattern \"x+\") #\"x+\")", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(type-of #\"x\")", &env).unwrap(), c_keyword("regex"));
+        assert!(eval_str("(re-pattern \"(\")", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_unit_tests() {
+        let env = env();
+
+        eval_str("(deftest arithmetic (is (= 2 (+ 1 1))) (assert= 3 (+ 1 1)) (is (list? 1)))",
+                 &env);
+        eval_str("(deftest broken (is true) (undefined-fn))", &env);
+        let counts = eval_str("(run-tests)", &env).unwrap();
+        for &(key, count) in &[("test", 2), ("pass", 2), ("fail", 2), ("error", 1)] {
+            assert_eq!(eval_str(&format!("(:{} {})", key, counts), &env).unwrap(), c_int(count));
+        }
+        assert_eq!(::testing::failures(&counts), Ok(3));
+        assert_eq!(eval_str("(with-out-str (assert= '(3) (list (+ 1 1))))", &env).unwrap(),
+                   c_string("FAIL: (assert= (quote (3)) (list (+ 1 1)))\n  expected: (3)\n    \
+                             actual: (2)\n"));
+
+        eval_str("(deftest broken (is (= '(1) (list 1))))", &env);
+        let counts = eval_str("(run-tests)", &env).unwrap();
+        assert_eq!(::testing::failures(&counts), Ok(2));
+        assert_eq!(eval_str("(is (= 1 1))", &env).unwrap(), c_bool(true));
+    }
+
+    #[test]
+    fn eval_str_let() {
+        let env = env();
+
+        assert_eq!(eval_str("(let [x 1 y (+ x 1)] (+ x y))", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(let (x 2) x)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("x", &env).unwrap_err(), AtomError::UndefinedSymbol("x".to_string()));
+
+        // pairs as before destructuring, the values don't see the names
+        assert_eq!(eval_str("(let ((a 1) (b (list 2 3))) (cons a b))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(let ((x 1)) (let ((x 2) (y x)) (list x y)))", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(1)]));
+        assert_eq!(eval_str("(let [(a b) (list 1 2)] b)", &env).unwrap(), c_int(2));
+    }
+
+    #[test]
+    fn eval_str_log() {
+        let env = env();
+
+        assert_eq!(eval_str("(log/info \"loaded\" 3 :items)", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(log/error)", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(map (fn* (f) (f 1)) (list log/debug log/warn))", &env).unwrap(),
+                   c_list(vec![c_nil(), c_nil()]));
+    }
+
+    #[test]
+    fn eval_str_read_string() {
+        let env = env();
+
+        assert_eq!(eval_str("(read-string \"(+ 1 2) 4\")", &env).unwrap(),
+                   c_list(vec![c_symbol("+"), c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(eval (read-string \"'[x]\"))", &env).unwrap(),
+                   c_vector(vec![c_symbol("x")]));
+        assert_eq!(eval_str("(read-string \" ; nothing\")", &env).unwrap(), c_nil());
+        assert!(eval_str("(read-string \"(1\")", &env).is_err());
+        assert_eq!(eval_str("(dump-tokens \"'a\")", &env).unwrap(),
+                   c_string("1:1-1:2 Apostrophe\n1:2-1:3 Identifier(\"a\")\n"));
+        assert_eq!(eval_str("(dump-ast \"(a)\")", &env).unwrap(),
+                   c_string("1:1-1:4 List\n  1:2-1:3 Identifier(\"a\")\n"));
+    }
+
+    #[test]
+    fn eval_str_destructuring() {
+        let env = env();
+
+        assert_eq!(eval_str("((fn* ((a b) & rest) (list a b rest)) '(1 2) 3 4)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_list(vec![c_int(3), c_int(4)])]));
+        assert_eq!(eval_str("(let [[x [y z]] [1 [2 3]]] (+ x y z))", &env).unwrap(),
+                   c_int(6));
+        assert_eq!(eval_str("(let [[x & xs] '(1)] (list x xs))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_nil()]));
+        assert_eq!(eval_str("(let [[x y] nil] (list x y))", &env).unwrap(),
+                   c_list(vec![c_nil(), c_nil()]));
+    }
+
+    #[test]
+    fn eval_str_map_destructuring() {
+        let env = env();
+        eval_str("(def point {:x 1 :y 2})", &env);
+
+        assert_eq!(eval_str("(let [{:keys [x y z]} point] (list x y z))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_nil()]));
+        assert_eq!(eval_str("(let [{px :x :as p} point] (list px p))", &env).unwrap(),
+                   c_list(vec![c_int(1), env_get(&env, &c_symbol("point")).unwrap()]));
+        assert_eq!(eval_str("((fn* ({:keys [y]}) y) point)", &env).unwrap(),
+                   c_int(2));
+    }
+
+    #[test]
+    fn eval_str_optional_params() {
+        let env = env();
+        eval_str("(def f (fn* (a &opt b (c (+ a 10))) (list a b c)))", &env);
+
+        assert_eq!(eval_str("(f 1)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_nil(), c_int(11)]));
+        assert_eq!(eval_str("(f 1 2 3)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_int(3)]));
+    }
+
+    #[test]
+    fn eval_str_keyword_params() {
+        let env = env();
+        eval_str("(def f (fn* (xs &key key (reverse 0)) (list xs key reverse)))", &env);
+
+        assert_eq!(eval_str("(f 1)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_nil(), c_int(0)]));
+        assert_eq!(eval_str("(f 1 :reverse 1 :key 2)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_int(1)]));
+        assert!(eval_str("(f 1 :reverse)", &env).is_err());
+        assert!(eval_str("(f 1 :other 2)", &env).is_err());
+
+        eval_str("(def g (fn* (& opts &key a) (list a opts)))", &env);
+        assert_eq!(eval_str("(g :a 1 :b 2)", &env).unwrap(),
+                   eval_str("'(1 (:a 1 :b 2))", &env).unwrap());
+    }
+
+    #[test]
+    fn eval_str_handler_case() {
+        let env = env();
+
+        assert_eq!(eval_str("(handler-case (error :oops) (:oops (e) (list e)))", &env).unwrap(),
+                   c_list(vec![c_keyword("oops")]));
+        assert_eq!(eval_str("(handler-case (+ 1 2) (:default (e) 0))", &env).unwrap(),
+                   c_int(3));
+        assert_eq!(eval_str("(handler-case (error \"bad\") (:error (e) (:message e)))", &env)
+                       .unwrap(),
+                   c_string("bad"));
+        assert_eq!(eval_str("(handler-case (undefined-fn 1) (:undefined-symbol (e) 1))", &env)
+                       .unwrap(),
+                   c_int(1));
+        assert_eq!(eval_str("(handler-case (error {:type :inner})
+                               (:other (e) 1)
+                               (:inner (e) 2))",
+                            &env)
+                       .unwrap(),
+                   c_int(2));
+        assert_eq!(eval_str("(error :oops)", &env),
+                   Err(AtomError::Condition(c_keyword("oops"))));
+    }
+
+    #[test]
+    fn eval_str_ex_info() {
+        let env = env();
+
+        assert_eq!(eval_str("(handler-case (error (ex-info \"no stock\" {:item 7 :left 0}))
+                               (:ex-info (e) (list (ex-message e) (:item (ex-data e)))))",
+                            &env)
+                       .unwrap(),
+                   c_list(vec![c_string("no stock"), c_int(7)]));
+        assert_eq!(eval_str("(ex-data (ex-info \"empty\" nil))", &env).unwrap(),
+                   eval_str("{}", &env).unwrap());
+        assert_eq!(eval_str("(handler-case (undefined-fn) (:default (e) (ex-message e)))", &env)
+                       .unwrap(),
+                   c_string("undefined symbol: undefined-fn"));
+        assert_eq!(eval_str("(handler-case (error :oops) (:default (e) (ex-data e)))", &env)
+                       .unwrap(),
+                   c_nil());
+        assert!(eval_str("(ex-info \"bad\" [1])", &env).is_err());
+        assert!(eval_str("(ex-info :bad {})", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_assert() {
+        let env = env();
+        eval_str("(def x 0)", &env);
+
+        assert_eq!(eval_str("(assert (= x 0))", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(assert (if true :yes nil) \"unused\")", &env).unwrap(),
+                   c_keyword("yes"));
+        assert_eq!(eval_str("(handler-case (assert (= x 1) \"x should be 1\")
+                               (:assertion-error (e)
+                                 (list (ex-message e) (:values (ex-data e)))))",
+                            &env)
+                       .unwrap(),
+                   eval_str("'(\"x should be 1: (= x 1), x = 0\" [[x 0]])", &env).unwrap());
+        assert_eq!(eval_str("(handler-case (assert (if (= x 0) nil true))
+                               (:assertion-error (e) (ex-message e)))",
+                            &env)
+                       .unwrap(),
+                   c_string("assert failed: (if (= x 0) nil true)"));
+        assert!(eval_str("(assert (undefined-fn x))", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_handler_bind() {
+        let env = env();
+
+        // a declining handler falls through to the outer one
+        assert_eq!(eval_str("(handler-case
+                               (handler-bind [:oops (fn* (e) nil)] (error :oops))
+                               (:oops (e) 2))",
+                            &env)
+                       .unwrap(),
+                   c_int(2));
+        assert_eq!(eval_str("(handler-bind [:note (fn* (e) nil)] (signal :note) 1)", &env)
+                       .unwrap(),
+                   c_int(1));
+        assert!(eval_str("(handler-bind [:oops (fn* (e) nil)] (error :oops))", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_restarts() {
+        let env = env();
+        eval_str("(defn parse (x)
+                    (restart-case (if (= x 0) (error {:type :zero :value x}) x)
+                      (use-value (v) v)))",
+                 &env);
+
+        assert_eq!(eval_str("(handler-bind [:zero (fn* (e) (invoke-restart 'use-value 42))]
+                               (list (parse 1) (parse 0)))",
+                            &env)
+                       .unwrap(),
+                   c_list(vec![c_int(1), c_int(42)]));
+        assert_eq!(eval_str("(invoke-restart 'nope)", &env),
+                   Err(AtomError::Restart("nope".to_string(), vec![])));
+    }
+
+    #[test]
+    fn eval_str_native_panics() {
+        let env = core::build();
+        env_set(&env, &c_symbol("boom"), c_closure(|_| panic!("boom")));
+
+        match eval_str("(boom)", &env) {
+            Err(AtomError::Panic(message)) => assert_eq!(message, "boom"),
+            other => panic!("expected a caught panic, got {:?}", other),
+        }
+        assert_eq!(eval_str("(handler-case (boom) (:panic (e) :caught))", &env).unwrap(),
+                   c_keyword("caught"));
+    }
+
+    #[test]
+    fn eval_str_arithmetic_errors() {
+        let env = core::build();
+
+        match eval_str("(/ 1 0)", &env) {
+            Err(AtomError::InvalidArgument(message)) => {
+                assert_eq!(message, "division by zero in (/ 1 0)")
+            }
+            other => panic!("expected division by zero, got {:?}", other),
+        }
+        match eval_str("(+ 9223372036854775807 1)", &env) {
+            Err(AtomError::InvalidOperation(message)) => assert!(message.ends_with(" overflows")),
+            other => panic!("expected an overflow, got {:?}", other),
+        }
+        assert!(eval_str("(/ (- 0 9223372036854775807 1) (- 0 1))", &env).is_err());
+        assert!(eval_str("(* 4611686018427387904 2)", &env).is_err());
+        assert!(eval_str("(- (- 0 9223372036854775807) 2)", &env).is_err());
+        assert_eq!(eval_str("(/ 6 3)", &env).unwrap(), c_int(2));
+    }
+
+    #[test]
+    fn eval_str_unwind_protect() {
+        let env = env();
+        eval_str("(def log (open \"/dev/null\" :write))", &env);
+
+        assert_eq!(eval_str("(unwind-protect 1 (close log))", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(closed? log)", &env).unwrap(), c_bool(true));
+
+        eval_str("(def log (open \"/dev/null\" :write))", &env);
+        assert_eq!(eval_str("(unwind-protect (error :oops) (close log))", &env),
+                   Err(AtomError::Condition(c_keyword("oops"))));
+        assert_eq!(eval_str("(closed? log)", &env).unwrap(), c_bool(true));
+
+        eval_str("(def log (open \"/dev/null\" :write))", &env);
+        assert_eq!(eval_str("(handler-case (unwind-protect (error :oops) (close log))
+                               (:oops (e) (closed? log)))",
+                            &env)
+                       .unwrap(),
+                   c_bool(true));
+
+        // a failing cleanup doesn't keep the others from running and is signalled from the
+        // handler-case
+        eval_str("(def cleaned false)", &env);
+        assert_eq!(eval_str("(handler-case
+                               (handler-case
+                                 (unwind-protect
+                                   (unwind-protect (error :oops) (error :cleanup))
+                                   (def cleaned true))
+                                 (:oops (e) :oops))
+                               (:cleanup (e) :cleanup))",
+                            &env)
+                       .unwrap(),
+                   c_keyword("cleanup"));
+        assert_eq!(eval_str("cleaned", &env).unwrap(), c_bool(true));
+    }
+
+    #[test]
+    fn eval_str_with_open() {
+        let env = env();
+        let path = std::env::temp_dir().join("rulsp-with-open-test");
+        let path = c_string(path.to_str().unwrap());
+        env_set(&env, &c_symbol("path"), path);
+
+        assert_eq!(eval_str("(with-open [h (open path :write)]
+                               (write h \"a\" 1 \"\\n\" 2)
+                               (closed? h))",
+                            &env)
+                       .unwrap(),
+                   c_bool(false));
+        assert_eq!(eval_str("(with-open [h (open path)] (list (read-line h) (read-all h)))",
+                            &env)
+                       .unwrap(),
+                   c_list(vec![c_string("a1\n"), c_string("2")]));
+
+        eval_str("(def h (with-open [h (open path)] h))", &env);
+        assert_eq!(eval_str("(closed? h)", &env).unwrap(), c_bool(true));
+        assert!(eval_str("(read-line h)", &env).is_err());
+        assert_eq!(eval_str("(handler-case (with-open [h (open path) g (open path)] (error g))
+                               (:default (g) (closed? g)))",
+                            &env)
+                       .unwrap(),
+                   c_bool(true));
+    }
+
+    #[test]
+    fn eval_str_protocols() {
+        let env = env();
+        eval_str("(defprotocol Sized \"things with a size\" (size (x)) (bigger? (x n)))", &env);
+        eval_str("(extend-type :list Sized (size (x) (count x)) (bigger? (x n) (= n 0)))", &env);
+        eval_str("(extend-type :int Sized (size (x) x))", &env);
+
+        assert_eq!(eval_str("(list (size '(1 2)) (size 5))", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(5)]));
+        assert_eq!(eval_str("(bigger? '(1) 0)", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(type-of [1])", &env).unwrap(), c_keyword("vector"));
+        assert!(eval_str("(size [1])", &env).is_err());
+        assert!(eval_str("(extend-type :int Sized (length (x) x))", &env).is_err());
+
+        assert_eq!(eval_str("(satisfies? Sized '(1))", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(satisfies? Sized 1)", &env).unwrap(), c_bool(false));
+        eval_str("(extend-type :default Sized (size (x) 0) (bigger? (x n) nil))", &env);
+        assert_eq!(eval_str("(list (size [1]) (satisfies? Sized 1))", &env).unwrap(),
+                   c_list(vec![c_int(0), c_bool(true)]));
+    }
+
+    #[test]
+    fn eval_str_records() {
+        let env = env();
+        eval_str("(defrecord Point (x y))", &env);
+        eval_str("(def p (->Point 1 2))", &env);
+
+        assert_eq!(eval_str("(list (Point-x p) (:y p) (:z p 0))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_int(0)]));
+        assert_eq!(eval_str("p", &env).unwrap().format(false), "#Point{:x 1 :y 2}");
+        assert_eq!(eval_str("(= p (map->Point {:y 2 :x 1}))", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(:y (map->Point {:x 1}))", &env).unwrap(), c_nil());
+        assert!(eval_str("(map->Point {:z 1})", &env).is_err());
+        assert!(eval_str("(->Point 1)", &env).is_err());
+
+        eval_str("(defstruct Other [x y])", &env);
+        assert_eq!(eval_str("(list (instance? Point p) (instance? Other p))", &env).unwrap(),
+                   c_list(vec![c_bool(true), c_bool(false)]));
+        assert_eq!(eval_str("(= p (->Other 1 2))", &env).unwrap(), c_bool(false));
+        assert!(eval_str("(Other-x p)", &env).is_err());
+        assert_eq!(eval_str("(type-of p)", &env).unwrap(), c_keyword("Point"));
+    }
+
+    #[test]
+    fn eval_str_queues() {
+        let env = env();
+        eval_str("(def q (enqueue (queue 1 2) 3))", &env);
+
+        assert_eq!(eval_str("(list (peek q) (peek-back q) (count q))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(3), c_int(3)]));
+        assert_eq!(eval_str("(queue->list (dequeue q))", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(queue->list (dequeue-back (enqueue-front q 0)))", &env).unwrap(),
+                   c_list(vec![c_int(0), c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(= q (queue 1 2 3))", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(empty? (dequeue (dequeue (queue 1))))", &env).unwrap(),
+                   c_bool(true));
+        assert_eq!(eval_str("(peek (queue))", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("q", &env).unwrap().format(false), "#queue(1 2 3)");
+        assert_eq!(eval_str("(type-of q)", &env).unwrap(), c_keyword("queue"));
+        assert!(eval_str("(enqueue '(1) 2)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_sorted() {
+        let env = env();
+        eval_str("(def m (sorted-map :c 3 :a 1 :b 2))", &env);
+        eval_str("(def s (sorted-set 5 1 [2] 3 1))", &env);
+
+        assert_eq!(eval_str("m", &env).unwrap().format(false), "{:a 1 :b 2 :c 3}");
+        assert_eq!(eval_str("s", &env).unwrap().format(false), "#{1 3 5 [2]}");
+        assert_eq!(eval_str("(list (first m) (last m) (count m))", &env).unwrap(),
+                   c_list(vec![c_vector(vec![c_keyword("a"), c_int(1)]),
+                               c_vector(vec![c_keyword("c"), c_int(3)]),
+                               c_int(3)]));
+        assert_eq!(eval_str("(map last m)", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(list (:b m) (m :d 0) (s 3) (s 4))", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(0), c_int(3), c_nil()]));
+        assert_eq!(eval_str("(subrange s 2 nil)", &env).unwrap().format(false),
+                   "#{3 5 [2]}");
+        assert_eq!(eval_str("(subrange m :b :c)", &env).unwrap().format(false), "{:b 2}");
+        assert_eq!(eval_str("(count (subrange s 5 1))", &env).unwrap(), c_int(0));
+        assert_eq!(eval_str("(= s (sorted-set 1 3 5 [2]))", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(type-of m)", &env).unwrap(), c_keyword("sorted-map"));
+        assert!(eval_str("(sorted-set {})", &env).is_err());
+        assert!(eval_str("(sorted-map :a)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_priority_queues() {
+        let env = env();
+        eval_str("(def pq (pq-push (priority-queue 5 1 3) 0))", &env);
+        eval_str("(def tasks (pq-push (priority-queue) {:p 2 :t :b} :p))", &env);
+        eval_str("(def tasks (pq-push tasks {:p 1 :t :a} :p))", &env);
+
+        assert_eq!(eval_str("(list (pq-peek pq) (pq-peek (pq-pop pq)) (count pq))", &env).unwrap(),
+                   c_list(vec![c_int(0), c_int(1), c_int(4)]));
+        assert_eq!(eval_str("(:t (pq-peek tasks))", &env).unwrap(), c_keyword("a"));
+        assert_eq!(eval_str("pq", &env).unwrap().format(false), "#priority-queue(0 1 3 5)");
+        assert_eq!(eval_str("(pq-peek (pq-pop (priority-queue)))", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(type-of pq)", &env).unwrap(), c_keyword("priority-queue"));
+        assert!(eval_str("(pq-push pq {})", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_transients() {
+        let env = env();
+        eval_str("(def t (transient []))", &env);
+        eval_str("(conj! t 1 2)", &env);
+        eval_str("(conj! t 3)", &env);
+
+        assert_eq!(eval_str("(count t)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("t", &env).unwrap().format(false), "#transient(vector)");
+        assert_eq!(eval_str("(persistent! t)", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2), c_int(3)]));
+        assert!(eval_str("(conj! t 4)", &env).is_err());
+        assert_eq!(eval_str("(:b (persistent! (assoc! (transient {:a 1}) :b 2)))", &env).unwrap(),
+                   c_int(2));
+        assert_eq!(eval_str("(persistent! (conj! (transient '(1)) 2))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2)]));
+        assert!(eval_str("(assoc! (transient []) 0 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_with_out_str() {
+        let env = env();
+        assert_eq!(eval_str("(with-out-str (print 1 \"a\") (println [2]))", &env).unwrap(),
+                   c_string("1 a[2]\n"));
+        assert_eq!(eval_str("(with-out-str)", &env).unwrap(), c_string(""));
+        assert_eq!(eval_str("(with-out-str (print 1) (print (with-out-str (print 2))) (print 3))",
+                            &env)
+                       .unwrap(),
+                   c_string("123"));
+        eval_str("(with-out-str (def printed (count (with-out-str (print \"xy\")))))", &env);
+        assert_eq!(eval_str("printed", &env).unwrap(), c_int(2));
+
+        // a failing body doesn't leave output captured
+        assert!(eval_str("(with-out-str (print 1) (undefined))", &env).is_err());
+        assert_eq!(rulsp::output::end_buffer(0), "");
+        assert_eq!(eval_str("(handler-case (with-out-str (print 1) (error \"no\")) \
+                             (:error (e) (with-out-str (print 2))))",
+                            &env)
+                       .unwrap(),
+                   c_string("2"));
+    }
+
+    #[test]
+    fn eval_str_type_predicates() {
+        let env = env();
+        let checks = [("(int? 1)", true),
+                      ("(int? \"1\")", false),
+                      ("(string? \"a\")", true),
+                      ("(string? 'a)", false),
+                      ("(symbol? 'a)", true),
+                      ("(symbol? :a)", false),
+                      ("(keyword? :a)", true),
+                      ("(keyword? \"a\")", false),
+                      ("(fn? +)", true),
+                      ("(fn? (fn* (x) x))", true),
+                      ("(fn? backquote)", false),
+                      ("(fn? :a)", false),
+                      ("(map? {:a 1})", true),
+                      ("(map? (sorted-map 1 2))", true),
+                      ("(map? [1 2])", false),
+                      ("(vector? [1 2])", true),
+                      ("(vector? '(1 2))", false)];
+        for &(form, expected) in checks.iter() {
+            assert_eq!(eval_str(form, &env).unwrap(), c_bool(expected), "{}", form);
+        }
+        assert_eq!(eval_str("(map type-of (list 1 \"a\" '(1) + backquote))", &env).unwrap(),
+                   eval_str("'(:int :string :list :fn :macro)", &env).unwrap());
+    }
+
+    #[test]
+    fn eval_str_nth_get() {
+        let env = env();
+        assert_eq!(eval_str("(nth [1 2 3] (- 0 1))", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(nth '(1 2 3) (- 0 3))", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(nth \"abc\" (- 0 2))", &env).unwrap(), c_string("b"));
+        assert_eq!(eval_str("(nth [1 2 3] (- 0 4))", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(nth [1 2 3] 3 :none)", &env).unwrap(), c_keyword("none"));
+        assert_eq!(eval_str("(nth nil 0)", &env).unwrap(), c_nil());
+        assert!(eval_str("(nth [1 2 3] \"1\")", &env).is_err());
+        assert!(eval_str("(nth [1 2 3] nil)", &env).is_err());
+
+        assert_eq!(eval_str("(get [1 2 3] (- 0 1))", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(get [1 2 3] 5 0)", &env).unwrap(), c_int(0));
+        assert_eq!(eval_str("(get [1 2 3] :a 0)", &env).unwrap(), c_int(0));
+        assert_eq!(eval_str("(get {:a 1} :a)", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(get {:a 1} :b 2)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(get (sorted-map 1 :x) 1)", &env).unwrap(), c_keyword("x"));
+        assert_eq!(eval_str("(get nil :a :d)", &env).unwrap(), c_keyword("d"));
+        eval_str("(defrecord Point (x y))", &env);
+        assert_eq!(eval_str("(get (->Point 1 2) :y)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(get (->Point 1 2) :z 0)", &env).unwrap(), c_int(0));
+    }
+
+    #[test]
+    fn eval_str_trampoline() {
+        let env = env();
+        eval_str("(defn my-even? (n) (if (= n 0) true (bounce (my-odd? (- n 1)))))", &env);
+        eval_str("(defn my-odd? (n) (if (= n 0) false (bounce (my-even? (- n 1)))))", &env);
+        assert_eq!(eval_str("(trampoline my-even? 100000)", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(trampoline my-odd? 7)", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(trampoline (bounce (bounce 3)))", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(trampoline + 1 2)", &env).unwrap(), c_int(3));
+        assert!(eval_str("(trampoline (bounce (undefined)))", &env).is_err());
+        assert!(eval_str("(trampoline 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_auto_gensym() {
+        let env = env();
+        eval_str("(defmacro my-or (fn* (a b) `(let (v# ~a) (if v# v# ~b))))", &env);
+        eval_str("(def v 5)", &env);
+        assert_eq!(eval_str("(my-or nil v)", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("(my-or 1 v)", &env).unwrap(), c_int(1));
+
+        // one gensym per name throughout the template
+        eval_str("(def expanded (macroexpand '(my-or 1 2)))", &env);
+        assert_eq!(eval_str("(= (first (nth expanded 1)) (second (nth expanded 2)))", &env)
+                       .unwrap(),
+                   c_bool(true));
+        assert_eq!(eval_str("(= (first (nth expanded 1)) 'v#)", &env).unwrap(), c_bool(false));
+        let template = eval_str("(let (x# 1) `[x# ~'x#])", &env).unwrap();
+        assert!(template.format(false).starts_with("[x__"));
+
+        // a new gensym for each expansion, even of the same call form
+        eval_str("(defmacro w (fn* (n e) (if (= n 0) e `(let (x# ~n) (w ~(- n 1) (+ x# ~e))))))",
+                 &env);
+        assert_eq!(eval_str("(w 2 0)", &env).unwrap(), c_int(3));
+        eval_str("(defmacro g (fn* () `'x#))", &env);
+        assert_eq!(eval_str("(let (xs (list (g) (g))) (= (first xs) (second xs)))", &env)
+                       .unwrap(),
+                   c_bool(false));
+
+        // vectors and maps in templates
+        eval_str("(defmacro with-v (fn* (v & body) `(let [x# ~v] (+ x# ~@body))))", &env);
+        assert_eq!(eval_str("(with-v 1 2)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(let (a 1 b '(2 3)) `[a ~a ~@b])", &env).unwrap(),
+                   eval_str("['a 1 2 3]", &env).unwrap());
+        assert_eq!(eval_str("(let (a 1) `{:a ~a :b [~a]})", &env).unwrap(),
+                   eval_str("{:a 1 :b [1]}", &env).unwrap());
+        assert_eq!(eval_str("`[]", &env).unwrap(), c_vector(vec![]));
+        assert_eq!(eval_str("(= (gensym) (gensym))", &env).unwrap(), c_bool(false));
+        assert!(eval_str("(gensym 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_char_utilities() {
+        let env = env();
+        assert_eq!(eval_str("(char->int \"a\")", &env).unwrap(), c_int(97));
+        assert_eq!(eval_str("(int->char 955)", &env).unwrap(), c_string("λ"));
+        assert_eq!(eval_str("(upper-case \"a\")", &env).unwrap(), c_string("A"));
+        assert_eq!(eval_str("(lower-case \"Ab\")", &env).unwrap(), c_string("ab"));
+        assert_eq!(eval_str("(map digit? (chars \"1a \"))", &env).unwrap(),
+                   eval_str("'(true false false)", &env).unwrap());
+        assert_eq!(eval_str("(map alpha? (chars \"1a \"))", &env).unwrap(),
+                   eval_str("'(false true false)", &env).unwrap());
+        assert_eq!(eval_str("(map whitespace? (chars \"1a\n\"))", &env).unwrap(),
+                   eval_str("'(false false true)", &env).unwrap());
+        assert_eq!(eval_str("(reduce (fn* (c n) (+ (* n 10) (- (char->int c) (char->int \"0\")))) \
+                             0 (filter digit? (chars \"4 x 2\")))",
+                            &env)
+                       .unwrap(),
+                   c_int(42));
+
+        assert_eq!(eval_str("(map char->int (list \\a \\space \\newline \\tab))", &env).unwrap(),
+                   eval_str("'(97 32 10 9)", &env).unwrap());
+        assert_eq!(eval_str("(map whitespace? (list \\space \\x))", &env).unwrap(),
+                   eval_str("'(true false)", &env).unwrap());
+        assert!(eval_str("(char->int \\spac)", &env).is_err());
+
+        assert!(eval_str("(char->int \"ab\")", &env).is_err());
+        assert!(eval_str("(digit? 1)", &env).is_err());
+        assert!(eval_str("(int->char -1)", &env).is_err());
+        assert!(eval_str("(int->char 55296)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_format() {
+        let env = env();
+        assert_eq!(eval_str("(with-out-str (print (format \"%-6s|%4d|%04x\" 'abc 7 255)))", &env)
+                       .unwrap(),
+                   c_string("abc   |   7|00ff"));
+        assert_eq!(eval_str("(format \"%.2f%%\" 12)", &env).unwrap(), c_string("12.00%"));
+        assert!(eval_str("(format \"%d\" \"x\")", &env).is_err());
+        assert!(eval_str("(format 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_binding_streams() {
+        let env = env();
+        eval_str("(def buffer (string-buffer))", &env);
+        assert_eq!(eval_str("(binding [*out* buffer] (print 1 \"a\") (println [2]) 3)", &env)
+                       .unwrap(),
+                   c_int(3));
+        assert_eq!(eval_str("(read-all buffer)", &env).unwrap(), c_string("1 a[2]\n"));
+        assert_eq!(eval_str("(with-out-str (binding [*out* buffer] (print 1)) (print 2))", &env)
+                       .unwrap(),
+                   c_string("2"));
+        assert_eq!(eval_str("(binding [*in* buffer] (read-line))", &env).unwrap(),
+                   c_string("1"));
+
+        // rebound names are restored however the body is left
+        eval_str("(def level 1)", &env);
+        eval_str("(defn level-now () level)", &env);
+        assert_eq!(eval_str("(binding [level 2] (level-now))", &env).unwrap(), c_int(2));
+        assert!(eval_str("(binding [level 3] (undefined))", &env).is_err());
+        assert_eq!(eval_str("(handler-case (binding [level 4] (error \"no\")) \
+                             (:error (e) (level-now)))",
+                            &env)
+                       .unwrap(),
+                   c_int(1));
+        assert_eq!(eval_str("(level-now)", &env).unwrap(), c_int(1));
+        assert!(eval_str("(binding [unknown 1] unknown)", &env).is_err());
+        assert!(eval_str("(binding [level] level)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_eval_read_string() {
+        let env = env();
+        assert_eq!(eval_str("(eval (read-string \"(+ 1 2)\"))", &env).unwrap(), c_int(3));
+        eval_str("(def sandbox (new-env '{x 10}))", &env);
+        assert_eq!(eval_str("(eval '(inc x) sandbox)", &env).unwrap(), c_int(11));
+        eval_str("(eval '(def y 1) sandbox)", &env);
+        assert_eq!(eval_str("(eval 'y sandbox)", &env).unwrap(), c_int(1));
+        assert!(eval_str("y", &env).is_err());
+        assert!(eval_str("(eval 'x)", &env).is_err());
+
+        eval_str("(def local (let [z 5] (current-env)))", &env);
+        assert_eq!(eval_str("(eval '(+ z 1) local)", &env).unwrap(), c_int(6));
+        assert_eq!(eval_str("(type-of local)", &env).unwrap(), c_keyword("env"));
+        assert!(eval_str("(eval 1 2)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_macro_expansions_cached() {
+        let env = env();
+        eval_str("(def expanded (transient []))", &env);
+        eval_str("(defmacro m (fn* () (do (conj! expanded 1) 1)))", &env);
+        eval_str("(def f (fn* () (m)))", &env);
+
+        assert_eq!(eval_str("(+ (f) (f) (f))", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("(count expanded)", &env).unwrap(), c_int(1));
+        eval_str("(defmacro m (fn* () (do (conj! expanded 2) 2)))", &env);
+        assert_eq!(eval_str("(f)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(count expanded)", &env).unwrap(), c_int(2));
+    }
+
+    #[test]
+    fn eval_str_macro_expansions_dropped() {
+        let env = env();
+        eval_str("(defmacro m (fn* () (list 'quote (list 1 2))))", &env);
+        let form = eval_str("'(m)", &env).unwrap();
+        assert_eq!(rulsp::eval::eval(&form, &env).unwrap(), eval_str("'(1 2)", &env).unwrap());
+
+        let expansion = match *form {
+            rulsp::data::AtomType::List(_, ref cache) => Rc::downgrade(&cache.expansion().unwrap()),
+            _ => unreachable!(),
+        };
+        assert!(expansion.upgrade().is_some());
+        drop(form);
+        assert!(expansion.upgrade().is_none());
+    }
+
+    #[test]
+    fn eval_str_frameless_calls() {
+        let env = env();
+        eval_str("(def answer (fn* () 42))", &env);
+        eval_str("(def local (fn* () (do (def x 1) x)))", &env);
+        eval_str("(def counted (fn* () (loop (n 3) (if (= n 0) n (recur (- n 1))))))", &env);
+
+        assert_eq!(eval_str("(answer 1 2)", &env).unwrap(), c_int(42));
+        assert_eq!(eval_str("(local)", &env).unwrap(), c_int(1));
+        assert!(eval_str("x", &env).is_err());
+        assert_eq!(eval_str("(counted)", &env).unwrap(), c_int(0));
+        assert!(eval_str("n", &env).is_err());
+        assert_eq!(eval_str("((let [y 2] (fn* () y)))", &env).unwrap(), c_int(2));
+
+        // a callee turning into a macro which defines
+        eval_str("(def g (fn* () 1))", &env);
+        eval_str("(def h (fn* () (g)))", &env);
+        assert_eq!(eval_str("(h)", &env).unwrap(), c_int(1));
+        eval_str("(defmacro g (fn* () '(def y 2)))", &env);
+        eval_str("(h)", &env).unwrap();
+        assert!(eval_str("y", &env).is_err());
+
+        eval_str("(def evaluating (fn* () (eval '(def z 3) (current-env))))", &env);
+        eval_str("(evaluating)", &env).unwrap();
+        assert!(eval_str("z", &env).is_err());
+        eval_str("(def leak (fn* () (current-env)))", &env);
+        eval_str("(eval '(def w 4) (leak))", &env).unwrap();
+        assert!(eval_str("w", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_assoc_update() {
+        let env = env();
+        eval_str("(def v [1 2 3])", &env);
+
+        assert_eq!(eval_str("(assoc v 0 :a 3 :d)", &env).unwrap(),
+                   c_vector(vec![c_keyword("a"), c_int(2), c_int(3), c_keyword("d")]));
+        assert_eq!(eval_str("(update v 1 + 10)", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(12), c_int(3)]));
+        assert_eq!(eval_str("v", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(:b (update {:a 1} :b (fn* (x) (nil? x))))", &env).unwrap(),
+                   c_bool(true));
+        assert_eq!(eval_str("(:a (assoc nil :a 1))", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(assoc (sorted-map :b 2) :a 1)", &env).unwrap().format(false),
+                   "{:a 1 :b 2}");
+        assert!(eval_str("(assoc v 4 0)", &env).is_err());
+        assert!(eval_str("(assoc v -1 0)", &env).is_err());
+        assert!(eval_str("(assoc '(1) 0 0)", &env).is_err());
+
+        // deep enough for a trie with two levels of branches
+        let big = c_vector((0..2000).map(c_int).collect());
+        env_set(&env, &c_symbol("big"), big.clone());
+        eval_str("(def big2 (update (assoc big 1500 :x) 1999 - 1))", &env);
+        assert_eq!(eval_str("(list (nth big2 1500) (nth big2 1999) (count big2))", &env)
+                       .unwrap(),
+                   eval_str("'(:x 1998 2000)", &env).unwrap());
+        assert_eq!(eval_str("big", &env).unwrap(), big);
+        assert_eq!(eval_str("(count (conj big 1 2))", &env).unwrap(), c_int(2002));
+    }
+
+    #[test]
+    fn eval_str_subsequences() {
+        let env = env();
+        assert_eq!(eval_str("(subvec [1 2 3 4] 1 3)", &env).unwrap(),
+                   c_vector(vec![c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(subvec [1 2 3] 1)", &env).unwrap(),
+                   c_vector(vec![c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(subs \"héllo\" 1 3)", &env).unwrap(), c_string("él"));
+        assert_eq!(eval_str("(= (subs \"abc\" 0) \"abc\")", &env).unwrap(), c_bool(true));
+        assert!(eval_str("(subvec [1 2] 2 1)", &env).is_err());
+        assert!(eval_str("(subs \"ab\" 0 3)", &env).is_err());
+
+        // views print, compare and hash like the values they show
+        eval_str("(def v (subvec [0 1 2 3 4 5] 1 5))", &env);
+        assert_eq!(eval_str("(list (subvec v 1 3) (conj (subvec v 0 2) :x) (assoc v 0 :y))", &env)
+                       .unwrap()
+                       .format(false),
+                   "([2 3] [1 2 :x] [:y 2 3 4])");
+        assert_eq!(eval_str("(= (subvec v 2) [3 4])", &env).unwrap(), c_bool(true));
+        eval_str("(def s (subs \"a wörd here\" 2 6))", &env);
+        assert_eq!(eval_str("(list s (subs s 1 2) (count s))", &env).unwrap(),
+                   eval_str("'(\"wörd\" \"ö\" 4)", &env).unwrap());
+        assert_eq!(eval_str("(get {\"wörd\" 1 [3 4] 2} s)", &env).unwrap(), c_int(1));
+        assert_eq!(eval_str("(get {\"wörd\" 1 [3 4] 2} (subvec v 2))", &env).unwrap(),
+                   c_int(2));
+    }
+
+    #[test]
+    fn eval_str_conj_into() {
+        let env = env();
+        assert_eq!(eval_str("(conj '(1 2) 3 4)", &env).unwrap(),
+                   c_list(vec![c_int(4), c_int(3), c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(conj [1 2] 3 4)", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2), c_int(3), c_int(4)]));
+        assert_eq!(eval_str("(conj nil 1)", &env).unwrap(), c_list(vec![c_int(1)]));
+        assert_eq!(eval_str("(:b (conj {:a 1} [:b 2]))", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(conj (sorted-set 3) 1 2)", &env).unwrap().format(false),
+                   "#{1 2 3}");
+        assert_eq!(eval_str("(peek-back (conj (queue 1) 2))", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(into [] '(1 2))", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(into '() [1 2])", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(1)]));
+        assert_eq!(eval_str("(into (sorted-map) {:b 2 :a 1})", &env).unwrap().format(false),
+                   "{:a 1 :b 2}");
+        assert_eq!(eval_str("(into [] (sorted-set 2 1))", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2)]));
+        assert!(eval_str("(conj {} 1)", &env).is_err());
+        assert!(eval_str("(conj 1 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_seqs() {
+        let env = env();
+        assert_eq!(eval_str("(map inc [1 2])", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(3)]));
+        assert_eq!(eval_str("(filter (fn* (x) (= x 2)) (sorted-set 3 2 1))", &env).unwrap(),
+                   c_list(vec![c_int(2)]));
+        assert_eq!(eval_str("(reduce + 0 (queue 1 2 3))", &env).unwrap(), c_int(6));
+        assert_eq!(eval_str("(map first {:a 1})", &env).unwrap(),
+                   c_list(vec![c_keyword("a")]));
+        assert_eq!(eval_str("(list (count {:a 1 :b 2}) (nth [1 2] 1) (first \"ab\"))", &env)
+                       .unwrap(),
+                   c_list(vec![c_int(2), c_int(2), c_string("a")]));
+        assert_eq!(eval_str("(rest [1 2])", &env).unwrap(), c_list(vec![c_int(2)]));
+        assert_eq!(eval_str("(seq [])", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(seq \"ab\")", &env).unwrap(),
+                   c_list(vec![c_string("a"), c_string("b")]));
+        assert_eq!(eval_str("(seqable? 1)", &env).unwrap(), c_bool(false));
+        assert!(eval_str("(count 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_string_seqs() {
+        let env = env();
+        assert_eq!(eval_str("(string (reverse \"héllo\"))", &env).unwrap(),
+                   c_string("olléh"));
+        assert_eq!(eval_str("(chars \"ab\")", &env).unwrap(),
+                   c_list(vec![c_string("a"), c_string("b")]));
+        assert_eq!(eval_str("(string (map (fn* (c) (if (= c \"a\") \"4\" c)) \"banana\"))",
+                            &env)
+                       .unwrap(),
+                   c_string("b4n4n4"));
+        assert_eq!(eval_str("(string [])", &env).unwrap(), c_string(""));
+        assert_eq!(eval_str("(reverse [1 2])", &env).unwrap(),
+                   c_list(vec![c_int(2), c_int(1)]));
+        assert!(eval_str("(chars [1])", &env).is_err());
+        assert!(eval_str("(string [1])", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_conversions() {
+        let env = env();
+        assert_eq!(eval_str("(vec '(1 2))", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(list* 1 2 [3 4])", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(2), c_int(3), c_int(4)]));
+        assert_eq!(eval_str("(list* nil)", &env).unwrap(), c_list(vec![]));
+        assert_eq!(eval_str("(set [3 1 3])", &env).unwrap().format(false), "#{1 3}");
+        assert_eq!(eval_str("(:b (hash-map :a 1 :b 2))", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(= (zipmap [:a :b :c] '(1 2)) {:a 1 :b 2})", &env).unwrap(),
+                   c_bool(true));
+        assert!(eval_str("(hash-map :a)", &env).is_err());
+        assert!(eval_str("(vec 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_multimethods() {
+        let env = env();
+        eval_str("(defmulti area :shape)", &env);
+        eval_str("(defmethod area :square (s) (* (:side s) (:side s)))", &env);
+        eval_str("(defmethod area :default (s) 0)", &env);
+
+        assert_eq!(eval_str("(area {:shape :square :side 3})", &env).unwrap(), c_int(9));
+        assert_eq!(eval_str("(area {:shape :circle})", &env).unwrap(), c_int(0));
+
+        eval_str("(defmulti collide \"by both kinds\" (fn* (a b) [a b]))", &env);
+        eval_str("(defmethod collide [:ship :default] (a b) 1)", &env);
+        eval_str("(defmethod collide [:default :rock] (a b) 2)", &env);
+        eval_str("(defmethod collide [:rock :rock] (a b) 3)", &env);
+
+        assert_eq!(eval_str("(list (collide :ship :ship) (collide :rock :rock))", &env).unwrap(),
+                   c_list(vec![c_int(1), c_int(3)]));
+        assert!(eval_str("(collide :ship :rock)", &env).is_err());
+        assert!(eval_str("(collide :rock :ship)", &env).is_err());
+
+        eval_str("(prefer-method collide [:default :rock] [:ship :default])", &env);
+        assert_eq!(eval_str("(collide :ship :rock)", &env).unwrap(), c_int(2));
+    }
+
+    #[test]
+    fn eval_str_metadata() {
+        let env = env();
+        eval_str("(def xs (with-meta [1 2] {:tag :pair}))", &env);
+
+        assert_eq!(eval_str("(:tag (meta xs))", &env).unwrap(), c_keyword("pair"));
+        assert_eq!(eval_str("(= xs [1 2])", &env).unwrap(), c_bool(true));
+        assert_eq!(eval_str("(meta [1 2])", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(meta (with-meta xs nil))", &env).unwrap(), c_nil());
+        assert_eq!(eval_str("(:line (meta (with-meta 'x {:line 3})))", &env).unwrap(),
+                   c_int(3));
+        assert!(eval_str("(with-meta 1 {})", &env).is_err());
+        assert!(eval_str("(with-meta [] 1)", &env).is_err());
+
+        eval_str("(defn f \"adds one\" (x) (+ x 1))", &env);
+        eval_str("(def g (with-meta f {:doc \"increments\" :pure 1}))", &env);
+        assert_eq!(eval_str("(list (:name (meta f)) (:doc (meta f)))", &env).unwrap(),
+                   c_list(vec![c_symbol("f"), c_string("adds one")]));
+        assert_eq!(eval_str("(list (:doc (meta g)) (:pure (meta g)) (g 1))", &env).unwrap(),
+                   c_list(vec![c_string("increments"), c_int(1), c_int(2)]));
+        assert_eq!(eval_str("(doc g)", &env).unwrap(), c_string("increments"));
+    }
+
+    #[test]
+    fn eval_str_equality() {
+        let env = env();
+        eval_str("(def xs [1 (list 2)])", &env);
+        eval_str("(defn f (x) x)", &env);
+        eval_str("(defn make () (fn* (x) x))", &env);
+
+        assert_eq!(eval_str("(list (= xs [1 '(2)]) (eq? xs [1 '(2)]) (eq? xs xs))", &env)
+                       .unwrap(),
+                   c_list(vec![c_bool(true), c_bool(false), c_bool(true)]));
+        assert_eq!(eval_str("(list (= 1 1) (eq? 1 1))", &env).unwrap(),
+                   c_list(vec![c_bool(true), c_bool(false)]));
+
+        assert_eq!(eval_str("(list (= f f) (= cons cons) (= f cons) (= cons list))", &env)
+                       .unwrap(),
+                   c_list(vec![c_bool(true), c_bool(true), c_bool(false), c_bool(false)]));
+        assert_eq!(eval_str("(list (= f (with-meta f {:a 1})) (eq? f (with-meta f {:a 1})))",
+                            &env)
+                       .unwrap(),
+                   c_list(vec![c_bool(true), c_bool(false)]));
+        assert_eq!(eval_str("(list (= (fn* (x) x) (fn* (x) x)) (= (make) (make)))", &env)
+                       .unwrap(),
+                   c_list(vec![c_bool(false), c_bool(false)]));
+    }
+
+    #[test]
+    fn eval_str_structured_keys() {
+        let env = env();
+
+        assert_eq!(eval_str("({[1 '(2)] :a} [1 '(2)])", &env).unwrap(), c_keyword("a"));
+        assert_eq!(eval_str("({{:a 1 :b 2} :c} {:b 2 :a 1})", &env).unwrap(), c_keyword("c"));
+        assert_eq!(eval_str("({[1] :a} '(1))", &env).unwrap(), c_nil());
+        assert!(eval_str("{inc 1}", &env).is_err());
+        assert_eq!(eval_str("({} [inc])", &env).unwrap(), c_nil());
+
+        assert_eq!(eval_str("(distinct '([1] 2 [1] \"x\" 2 \"x\"))", &env).unwrap(),
+                   c_list(vec![c_vector(vec![c_int(1)]), c_int(2), c_string("x")]));
+        assert!(eval_str("(distinct (list inc))", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_gc() {
+        let env = env();
+        eval_str("(gc)", &env);
+        eval_str("(defn make (n) (let [f (fn* () n)] (def self f) self))", &env);
+
+        // each call leaves behind a function env and a let env holding a closure over itself
+        eval_str("(do (make 1) (make 2) nil)", &env);
+        let kept = eval_str("(make 3)", &env).unwrap();
+        assert_eq!(eval_str("(gc)", &env).unwrap(), c_int(4));
+
+        assert_eq!(kept.apply(&[]).unwrap(), c_int(3));
+        drop(kept);
+        assert_eq!(eval_str("(gc)", &env).unwrap(), c_int(2));
+        assert_eq!(eval_str("(gc)", &env).unwrap(), c_int(0));
+        assert_eq!(eval_str("((make 4))", &env).unwrap(), c_int(4));
+    }
+
+    #[test]
+    fn eval_str_gc_shared_queue() {
+        let env = env();
+        eval_str("(defn make (n) (let [m n] (fn* () (+ m 1))))", &env);
+        // native closures aren't looked into, so the closure looks held from outside
+        eval_str("(def box (constantly (make 5)))", &env);
+
+        // a dropped cycle holding two queues which share the node holding x with base
+        eval_str("(defn junk (x)
+                    (let [base (enqueue (queue 1 2) x) a (enqueue base 3) b (enqueue base 4)
+                          self (fn* () [a b])]
+                      nil))",
+                 &env);
+        eval_str("(junk (box))", &env);
+        eval_str("(gc)", &env);
+        assert_eq!(eval_str("((box))", &env).unwrap(), c_int(6));
+    }
+
+    #[test]
+    fn eval_str_weak_refs() {
+        let env = env();
+        eval_str("(def big [1 2])", &env);
+        eval_str("(def r (weak-ref big))", &env);
+
+        assert_eq!(eval_str("(weak-deref r)", &env).unwrap(),
+                   c_vector(vec![c_int(1), c_int(2)]));
+        eval_str("(def big nil)", &env);
+        assert_eq!(eval_str("(weak-deref r)", &env).unwrap(), c_nil());
+
+        eval_str("(defn make () (let [f (fn* () 1)] (def self f) self))", &env);
+        eval_str("(def r (weak-ref (make)))", &env);
+        assert!(eval_str("(weak-deref r)", &env).unwrap() != c_nil());
+        eval_str("(gc)", &env);
+        assert_eq!(eval_str("(weak-deref r)", &env).unwrap(), c_nil());
+        assert!(eval_str("(weak-deref 1)", &env).is_err());
+    }
+
+    #[test]
+    fn eval_str_combinators() {
+        let env = env();
+
+        assert_eq!(eval_str("(identity 3)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("((constantly 3) 1 2)", &env).unwrap(), c_int(3));
+        assert_eq!(eval_str("((partial - 10) 1 2)", &env).unwrap(), c_int(7));
+        assert_eq!(eval_str("((comp inc inc -) 5 2)", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("((comp) 5)", &env).unwrap(), c_int(5));
+        assert_eq!(eval_str("((juxt inc list) 5)", &env).unwrap(),
+                   c_vector(vec![c_int(6), c_list(vec![c_int(5)])]));
+    }
+
+    #[test]
+    fn eval_str_memoize() {
+        let env = env();
+        eval_str("(def wrap (memoize (fn* (& xs) (list xs))))", &env);
+
+        let first = eval_str("(wrap 1 :a)", &env).unwrap();
+        assert_eq!(first, c_list(vec![c_list(vec![c_int(1), c_keyword("a")])]));
+        assert!(Rc::ptr_eq(&first, &eval_str("(wrap 1 :a)", &env).unwrap()));
+        assert!(!Rc::ptr_eq(&first, &eval_str("(wrap 2 :a)", &env).unwrap()));
+        assert!(Rc::ptr_eq(&eval_str("(wrap '(1) {:a [2]})", &env).unwrap(),
+                           &eval_str("(wrap '(1) {:a [2]})", &env).unwrap()));
+        assert!(!Rc::ptr_eq(&eval_str("(wrap inc)", &env).unwrap(),
+                            &eval_str("(wrap inc)", &env).unwrap()));
+    }
+
     use test::Bencher;
 
     #[bench]
@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use fnv::FnvHashMap;
+use arena::Arena;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomKey, alloc, c_map, c_nil, c_string, c_symbol,
+           c_keyword};
+use env::SymbolCache;
+use eval::FormCache;
+
+// Metadata lives beside the values it's attached to, so it never takes part in equality.
+// Entries are keyed by address, the weak reference keeps the address from being reused
+// while the entry exists.
+thread_local! {
+    static META: RefCell<FnvHashMap<usize, (Weak<AtomType, Arena>, AtomVal)>> =
+        RefCell::new(FnvHashMap::default());
+}
+
+fn address(value: &AtomVal) -> usize {
+    &**value as *const AtomType as usize
+}
+
+// Copy of value carrying meta, a nil meta gives a copy without metadata
+pub fn with_meta(value: &AtomVal, meta: &AtomVal) -> AtomRet {
+    match **meta {
+        AtomType::Map(_) | AtomType::Nil => {}
+        _ => return Err(AtomError::InvalidType("Map".to_string(), meta.format(true))),
+    }
+
+    let copy = alloc(match **value {
+        AtomType::Symbol(ref symbol, _) => AtomType::Symbol(symbol.clone(), SymbolCache::default()),
+        AtomType::List(ref seq, _) => AtomType::List(seq.clone(), FormCache::default()),
+        AtomType::Vector(ref seq) => AtomType::Vector(seq.clone()),
+        AtomType::Map(ref map) => AtomType::Map(map.clone()),
+        AtomType::Func(ref f) => AtomType::Func(f.clone()),
+        AtomType::AFunc(ref fd) => AtomType::AFunc(fd.clone()),
+        _ => {
+            return Err(AtomError::InvalidType("symbol, collection or function".to_string(),
+                                              value.format(true)))
+        }
+    });
+
+    if let AtomType::Map(_) = **meta {
+        META.with(|table| {
+            let mut table = table.borrow_mut();
+            if table.len() >= 64 && table.len().is_power_of_two() {
+                table.retain(|_, entry| entry.0.upgrade().is_some());
+            }
+            table.insert(address(&copy), (Rc::downgrade(&copy), meta.clone()));
+        });
+    }
+
+    Ok(copy)
+}
+
+fn attached_meta(value: &AtomVal) -> Option<AtomVal> {
+    META.with(|table| table.borrow().get(&address(value)).map(|entry| entry.1.clone()))
+}
+
+// Metadata of value, for functions merged over their :name and :doc
+pub fn meta(value: &AtomVal) -> AtomVal {
+    let (name, doc) = match **value {
+        AtomType::AFunc(ref fd) => (fd.name.clone(), fd.doc.clone()),
+        AtomType::Func(ref f) => (None, f.doc().map(|doc| doc.to_string())),
+        _ => return attached_meta(value).unwrap_or_else(c_nil),
+    };
+
+    let mut map = FnvHashMap::default();
+    if let Some(name) = name {
+        map.insert(AtomKey::new(c_keyword("name")).unwrap(), c_symbol(&name));
+    }
+    if let Some(doc) = doc {
+        map.insert(AtomKey::new(c_keyword("doc")).unwrap(), c_string(&doc));
+    }
+    if let Some(attached) = attached_meta(value) {
+        for (k, v) in attached.get_map().unwrap() {
+            map.insert(k.clone(), v.clone());
+        }
+    }
+
+    if map.is_empty() { c_nil() } else { c_map(map) }
+}
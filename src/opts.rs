@@ -0,0 +1,328 @@
+// Command line parsing for scripts: (parse-opts *command-line-args* spec) with spec a vector of
+// maps, one per option or positional argument, like
+//   {:name :output :short "o" :default "out.txt" :doc "file to write"}
+// with :flag true for options without a value, :parse :int for numbers and :positional true for
+// arguments named by position. Options are --name, --name=value, -o value, -ovalue and bundled
+// flags like -vq; -- ends them.
+use fnv::FnvHashMap;
+use data::{AtomError, AtomKey, AtomRet, AtomType, AtomVal, c_bool, c_doc_func, c_int, c_keyword,
+           c_list, c_map, c_string, c_symbol, c_vector};
+use env::{Env, env_set};
+
+struct Opt {
+    name: String,
+    short: Option<char>,
+    flag: bool,
+    int: bool,
+    positional: bool,
+    default: Option<AtomVal>,
+    doc: String,
+}
+
+fn key(name: &str) -> AtomKey {
+    AtomKey::new(c_keyword(name)).unwrap()
+}
+
+impl Opt {
+    fn from_value(value: &AtomVal) -> Result<Opt, AtomError> {
+        let entry = value.get_map()?;
+        let field = |name: &str| entry.get(&key(name)).cloned();
+        let is_set = |name: &str| field(name).map_or(false, |value| value.is_truthy());
+        let invalid = |what: &str| {
+            AtomError::InvalidArgument(format!("{} in option spec {}", what, value.format(true)))
+        };
+
+        let name = match field("name").as_deref() {
+            Some(&AtomType::Keyword(ref name)) => name.to_string(),
+            _ => return Err(invalid(":name needs to be a keyword")),
+        };
+        let short = match field("short").as_deref() {
+            None => None,
+            Some(&AtomType::Str(ref short)) if short.chars().count() == 1 => short.chars().next(),
+            Some(_) => return Err(invalid(":short needs to be one character")),
+        };
+        let int = match field("parse") {
+            None => false,
+            Some(ref parse) if parse.is_keyword("int") => true,
+            Some(_) => return Err(invalid(":parse can only be :int")),
+        };
+        let doc = match field("doc") {
+            Some(doc) => doc.format(false),
+            None => String::new(),
+        };
+
+        Ok(Opt {
+            name: name,
+            short: short,
+            flag: is_set("flag"),
+            int: int,
+            positional: is_set("positional"),
+            default: field("default"),
+            doc: doc,
+        })
+    }
+
+    fn value(&self, text: &str) -> Result<AtomVal, String> {
+        if !self.int {
+            return Ok(c_string(text));
+        }
+        text.parse()
+            .map(c_int)
+            .map_err(|_| format!("{} needs an integer, got {}", self.label(), text))
+    }
+
+    fn label(&self) -> String {
+        if self.positional {
+            self.name.clone()
+        } else {
+            format!("--{}", self.name)
+        }
+    }
+
+    // The left column of the usage text
+    fn synopsis(&self) -> String {
+        let mut synopsis = match self.short {
+            Some(short) if !self.positional => format!("-{}, {}", short, self.label()),
+            _ => self.label(),
+        };
+        if !self.flag && !self.positional {
+            synopsis.push_str(" VALUE");
+        }
+        synopsis
+    }
+}
+
+struct Parsed {
+    options: FnvHashMap<AtomKey, AtomVal>,
+    args: Vec<AtomVal>,
+    errors: Vec<String>,
+}
+
+impl Parsed {
+    fn set(&mut self, opt: &Opt, text: &str) {
+        match opt.value(text) {
+            Ok(value) => {
+                self.options.insert(key(&opt.name), value);
+            }
+            Err(err) => self.errors.push(err),
+        }
+    }
+}
+
+fn parse(args: &[String], opts: &[Opt]) -> Parsed {
+    let mut parsed = Parsed {
+        options: FnvHashMap::default(),
+        args: vec![],
+        errors: vec![],
+    };
+    for opt in opts {
+        if opt.flag {
+            parsed.options.insert(key(&opt.name), c_bool(false));
+        } else if let Some(ref default) = opt.default {
+            parsed.options.insert(key(&opt.name), default.clone());
+        }
+    }
+
+    let named = |opt: &&Opt| !opt.positional;
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positional.extend(args.by_ref());
+        } else if let Some(arg) = arg.strip_prefix("--") {
+            let (name, value) = match arg.find('=') {
+                Some(at) => (&arg[..at], Some(&arg[at + 1..])),
+                None => (arg, None),
+            };
+            match opts.iter().filter(named).find(|opt| opt.name == name) {
+                Some(opt) if opt.flag && value.is_none() => {
+                    parsed.options.insert(key(&opt.name), c_bool(true));
+                }
+                Some(opt) if opt.flag => {
+                    parsed.errors.push(format!("{} doesn't take a value", opt.label()))
+                }
+                Some(opt) => {
+                    match value.or_else(|| args.next().map(|arg| arg.as_str())) {
+                        Some(value) => parsed.set(opt, value),
+                        None => parsed.errors.push(format!("{} needs a value", opt.label())),
+                    }
+                }
+                None => parsed.errors.push(format!("unknown option --{}", name)),
+            }
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            // flags bundled together, an option taking a value takes the rest
+            for (at, short) in arg.char_indices().skip(1) {
+                match opts.iter().filter(named).find(|opt| opt.short == Some(short)) {
+                    Some(opt) if opt.flag => {
+                        parsed.options.insert(key(&opt.name), c_bool(true));
+                    }
+                    Some(opt) => {
+                        let rest = &arg[at + short.len_utf8()..];
+                        let value = if rest.is_empty() {
+                            args.next().map(|arg| arg.as_str())
+                        } else {
+                            Some(rest)
+                        };
+                        match value {
+                            Some(value) => parsed.set(opt, value),
+                            None => parsed.errors.push(format!("{} needs a value", opt.label())),
+                        }
+                        break;
+                    }
+                    None => parsed.errors.push(format!("unknown option -{}", short)),
+                }
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    for opt in opts.iter().filter(|opt| opt.positional) {
+        match positional.next() {
+            Some(arg) => parsed.set(opt, arg),
+            None if opt.default.is_some() => {}
+            None => parsed.errors.push(format!("missing {}", opt.name)),
+        }
+    }
+    parsed.args.extend(positional.map(|arg| c_string(arg)));
+    parsed
+}
+
+fn usage(opts: &[Opt]) -> String {
+    let width = opts.iter().map(|opt| opt.synopsis().chars().count()).max().unwrap_or(0);
+    let mut usage = String::new();
+    for &(title, positional) in &[("Arguments", true), ("Options", false)] {
+        let section = opts.iter().filter(|opt| opt.positional == positional).collect::<Vec<_>>();
+        if section.is_empty() {
+            continue;
+        }
+        if !usage.is_empty() {
+            usage.push('\n');
+        }
+        usage.push_str(&format!("{}:\n", title));
+        for opt in section {
+            let mut line = format!("  {:2$}  {}", opt.synopsis(), opt.doc, width);
+            match opt.default {
+                Some(ref default) if !opt.flag => {
+                    line.push_str(&format!(" (default {})", default.format(false)))
+                }
+                _ => {}
+            }
+            usage.push_str(line.trim_end());
+            usage.push('\n');
+        }
+    }
+    usage
+}
+
+// (parse-opts args spec) is a map of the :options found in args along with the remaining
+// :args, the :errors in args and the :usage text spec makes
+fn parse_opts(args: &[AtomVal]) -> AtomRet {
+    let given = match args.get(0).map(|args| &**args) {
+        Some(&AtomType::Nil) | None => vec![],
+        Some(_) => args[0].get_seq()?.iter().map(|arg| arg.format(false)).collect(),
+    };
+    let spec = match args.get(1) {
+        Some(spec) => spec.get_seq()?.iter().map(Opt::from_value).collect::<Result<Vec<_>, _>>()?,
+        None => return Err(AtomError::InvalidArgument("parse-opts needs a spec".to_string())),
+    };
+
+    let parsed = parse(&given, &spec);
+    let mut result = FnvHashMap::default();
+    result.insert(key("options"), c_map(parsed.options));
+    result.insert(key("args"), c_list(parsed.args));
+    result.insert(key("errors"),
+                  c_vector(parsed.errors.iter().map(|err| c_string(err)).collect()));
+    result.insert(key("usage"), c_string(&usage(&spec)));
+    Ok(c_map(result))
+}
+
+pub fn register(env: &Env) {
+    env_set(env,
+            &c_symbol("parse-opts"),
+            c_doc_func(parse_opts,
+                       "(parse-opts args spec) parses the command line args by spec, a vector of \
+                        maps with :name, :short, :doc, :default, :flag, :parse :int or \
+                        :positional, returns {:options :args :errors :usage}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{AtomVal, c_bool, c_int, c_string};
+    use super::{Opt, key, parse, usage};
+    use interpreter::Interpreter;
+
+    fn spec() -> Vec<Opt> {
+        let spec = Interpreter::new()
+            .eval("[{:name :verbose :short \"v\" :flag true :doc \"talk more\"}
+                    {:name :quiet :short \"q\" :flag true}
+                    {:name :output :short \"o\" :default \"out.txt\" :doc \"file to write\"}
+                    {:name :jobs :parse :int}
+                    {:name :input :positional true :doc \"file to read\"}]")
+            .unwrap();
+        spec.get_seq().unwrap().iter().map(|opt| Opt::from_value(opt).unwrap()).collect()
+    }
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn option(parsed: &super::Parsed, name: &str) -> Option<AtomVal> {
+        parsed.options.get(&key(name)).cloned()
+    }
+
+    #[test]
+    fn test_parse() {
+        let spec = spec();
+        let parsed = parse(&strings(&["-vofile", "--jobs=4", "in.txt", "--", "-x"]), &spec);
+        assert_eq!(parsed.errors, Vec::<String>::new());
+        assert_eq!(option(&parsed, "verbose"), Some(c_bool(true)));
+        assert_eq!(option(&parsed, "quiet"), Some(c_bool(false)));
+        assert_eq!(option(&parsed, "output"), Some(c_string("file")));
+        assert_eq!(option(&parsed, "jobs"), Some(c_int(4)));
+        assert_eq!(option(&parsed, "input"), Some(c_string("in.txt")));
+        assert_eq!(parsed.args, vec![c_string("-x")]);
+
+        let parsed = parse(&strings(&["--output", "o.txt", "-q", "a"]), &spec);
+        assert_eq!(option(&parsed, "output"), Some(c_string("o.txt")));
+        assert_eq!(option(&parsed, "quiet"), Some(c_bool(true)));
+        assert_eq!(option(&parsed, "jobs"), None);
+
+        let parsed = parse(&strings(&["--jobs", "many", "--verbose=1", "-z", "--nope", "-o"]),
+                           &spec);
+        assert_eq!(parsed.errors,
+                   strings(&["--jobs needs an integer, got many",
+                             "--verbose doesn't take a value",
+                             "unknown option -z",
+                             "unknown option --nope",
+                             "--output needs a value",
+                             "missing input"]));
+        assert_eq!(option(&parsed, "output"), Some(c_string("out.txt")));
+    }
+
+    #[test]
+    fn test_usage() {
+        assert_eq!(usage(&spec()).lines().collect::<Vec<_>>(),
+                   ["Arguments:",
+                    "  input               file to read",
+                    "",
+                    "Options:",
+                    "  -v, --verbose       talk more",
+                    "  -q, --quiet",
+                    "  -o, --output VALUE  file to write (default out.txt)",
+                    "  --jobs VALUE"]);
+    }
+
+    #[test]
+    fn test_spec_errors() {
+        let interpreter = Interpreter::new();
+        assert!(interpreter.eval("(parse-opts '() [{:name \"x\"}])").is_err());
+        assert!(interpreter.eval("(parse-opts '() [{:name :x :short \"xy\"}])").is_err());
+        assert!(interpreter.eval("(parse-opts '() [{:name :x :parse :float}])").is_err());
+        assert!(interpreter.eval("(parse-opts '() [1])").is_err());
+        assert_eq!(interpreter.eval("(:options (parse-opts nil [{:name :x :flag true}]))")
+                       .unwrap(),
+                   interpreter.eval("{:x false}").unwrap());
+    }
+}
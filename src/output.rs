@@ -0,0 +1,107 @@
+// What print and friends write, to stdout unless it's captured or the body of a with-out-str
+// collects it
+use std::cell::RefCell;
+
+thread_local! {
+    // what scripts printed while output is captured
+    static CAPTURED: RefCell<Option<String>> = RefCell::new(None);
+    // what the bodies of with-out-str forms printed so far, innermost last
+    static BUFFERS: RefCell<Vec<String>> = RefCell::new(vec![]);
+}
+
+// Text printed by print and friends, goes to stdout unless captured
+pub fn write_out(text: &str) {
+    let buffered = BUFFERS.with(|buffers| match buffers.borrow_mut().last_mut() {
+        Some(out) => {
+            out.push_str(text);
+            true
+        }
+        None => false,
+    });
+    if buffered {
+        return;
+    }
+    let captured = CAPTURED.with(|captured| match *captured.borrow_mut() {
+        Some(ref mut out) => {
+            out.push_str(text);
+            true
+        }
+        None => false,
+    });
+    if !captured {
+        print_out(text);
+    }
+}
+
+#[cfg(feature = "std")]
+fn print_out(text: &str) {
+    print!("{}", text);
+}
+
+// without std there's no stdout, what isn't captured is dropped
+#[cfg(not(feature = "std"))]
+fn print_out(_text: &str) {}
+
+// Collects what's printed from now on in a new buffer, returns its depth for end_buffer
+pub fn begin_buffer() -> usize {
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        buffers.push(String::new());
+        buffers.len() - 1
+    })
+}
+
+// Text of the buffer at depth, dropping the buffers begun since
+pub fn end_buffer(depth: usize) -> String {
+    BUFFERS.with(|buffers| {
+        let mut buffers = buffers.borrow_mut();
+        let text = buffers.get_mut(depth).map(|out| out.split_off(0)).unwrap_or_default();
+        buffers.truncate(depth);
+        text
+    })
+}
+
+// Keeps printed text for take_output instead of writing it to stdout, where there may be none
+pub fn capture_output(capture: bool) {
+    CAPTURED.with(|captured| {
+        *captured.borrow_mut() = if capture { Some(String::new()) } else { None };
+    });
+}
+
+// Text printed since the last call, output stays captured
+pub fn take_output() -> String {
+    CAPTURED.with(|captured| {
+        captured.borrow_mut().as_mut().map(|out| out.split_off(0)).unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{begin_buffer, capture_output, end_buffer, take_output, write_out};
+    use interpreter::Interpreter;
+
+    #[test]
+    fn test_capture_output() {
+        let interpreter = Interpreter::new();
+        capture_output(true);
+        interpreter.eval("(print 1 \"a\") (println [2])").unwrap();
+        assert_eq!(take_output(), "1 a[2]\n");
+        assert_eq!(take_output(), "");
+        capture_output(false);
+        assert_eq!(take_output(), "");
+    }
+
+    #[test]
+    fn test_buffers() {
+        let outer = begin_buffer();
+        write_out("a");
+        let inner = begin_buffer();
+        write_out("b");
+        assert_eq!(end_buffer(inner), "b");
+        write_out("c");
+        begin_buffer();
+        // ending a buffer drops the ones begun inside it
+        assert_eq!(end_buffer(outer), "ac");
+        assert_eq!(end_buffer(outer), "");
+    }
+}
@@ -1,17 +1,86 @@
 use std::fmt;
 use std::error::Error as StdError;
-use lexer::Token;
-use data::{AtomVal, c_int, c_nil, c_list, c_symbol};
+use lexer::{Lexer, LexError, Position, Token};
+use data::{AtomVal, AtomType, AtomKey, AtomRet, AtomError, c_bool, c_int, c_nil, c_string, c_list,
+           c_vector, c_map, c_symbol, c_keyword, c_regex};
+use fnv::FnvHashMap;
+use regex::Regex;
+use tagged::{self, TagHandlers};
 
 #[derive(Debug)]
 pub enum ParseError {
-    Syntax,
+    Syntax(Position),
+    // the input ended inside a form
+    Incomplete,
+    // a form nested deeper than the limit starts here
+    TooDeep(Position),
+    // opening delimiter left open at the end of the input, where it is
+    Unclosed(&'static str, Position),
+    // closing delimiter without an open one
+    Unmatched(&'static str, Position),
+    // opening delimiter and where it is, the wrong closing delimiter and where that is
+    Mismatched(&'static str, Position, &'static str, Position),
+    // error raised by the reader macro applied here
+    Macro(Position, AtomError),
+    // tag of the tagged literal here and why it couldn't be constructed
+    Tag(Position, String, AtomError),
+    // pattern of the regex literal here which doesn't compile
+    Regex(Position, String),
+    Lex(LexError),
+}
+
+impl ParseError {
+    // Where the error is, for Mismatched the closing delimiter
+    pub fn position(&self) -> Option<Position> {
+        match *self {
+            ParseError::Syntax(position) |
+            ParseError::TooDeep(position) |
+            ParseError::Unclosed(_, position) |
+            ParseError::Unmatched(_, position) |
+            ParseError::Mismatched(_, _, _, position) |
+            ParseError::Macro(position, _) |
+            ParseError::Tag(position, _, _) |
+            ParseError::Regex(position, _) => Some(position),
+            ParseError::Lex(LexError::Syntax(position)) |
+            ParseError::Lex(LexError::UnterminatedString(position)) |
+            ParseError::Lex(LexError::IntegerOverflow(position)) |
+            ParseError::Lex(LexError::UnknownCharacter(position)) => Some(position),
+            ParseError::Incomplete |
+            ParseError::Lex(LexError::InvalidToken(_, _)) => None,
+        }
+    }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            &ParseError::Syntax => write!(f, "Syntax error"),
+            &ParseError::Syntax(position) => write!(f, "Syntax error at {}", position),
+            &ParseError::Incomplete => write!(f, "Unexpected end of input"),
+            &ParseError::TooDeep(position) => write!(f, "Nesting too deep at {}", position),
+            &ParseError::Unclosed(opener, position) => {
+                write!(f, "Unclosed `{}` opened at {}", opener, position)
+            }
+            &ParseError::Unmatched(closer, position) => {
+                write!(f, "Unmatched `{}` at {}", closer, position)
+            }
+            &ParseError::Mismatched(opener, opened, closer, position) => {
+                write!(f,
+                       "`{}` at {} doesn't close `{}` opened at {}",
+                       closer,
+                       position,
+                       opener,
+                       opened)
+            }
+            &ParseError::Macro(position, ref err) => {
+                write!(f, "Reader macro at {} failed: {}", position, err)
+            }
+            &ParseError::Tag(position, ref tag, ref err) => {
+                write!(f, "Tagged literal #{} at {} failed: {}", tag, position, err)
+            }
+            &ParseError::Regex(position, ref err) => {
+                write!(f, "Invalid regex at {}: {}", position, err)
+            }
+            &ParseError::Lex(ref err) => err.fmt(f),
         }
     }
 }
@@ -19,119 +88,601 @@ impl fmt::Display for ParseError {
 impl StdError for ParseError {
     fn description(&self) -> &str {
         match *self {
-            ParseError::Syntax => "Syntax error",
+            ParseError::Syntax(_) => "Syntax error",
+            ParseError::Incomplete => "Unexpected end of input",
+            ParseError::TooDeep(_) => "Nesting too deep",
+            ParseError::Unclosed(_, _) => "Unclosed delimiter",
+            ParseError::Unmatched(_, _) => "Unmatched delimiter",
+            ParseError::Mismatched(_, _, _, _) => "Mismatched delimiter",
+            ParseError::Macro(_, _) => "Reader macro failed",
+            ParseError::Tag(_, _, _) => "Tagged literal failed",
+            ParseError::Regex(_, _) => "Invalid regex",
+            ParseError::Lex(ref err) => err.what(),
         }
     }
 
-    fn cause(&self) -> Option<&StdError> {
+    fn cause(&self) -> Option<&dyn StdError> {
         match *self {
-            ParseError::Syntax => None,
+            ParseError::Lex(ref err) => Some(err),
+            _ => None,
         }
     }
 }
 
 
-pub struct Parser {
-    tokens: Vec<Token>,
+// Forms are read recursively, the limit keeps hostile input from overflowing the stack
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
+// Reads forms pulling tokens from the lexer as needed, input after a form isn't looked at
+pub struct Parser<'a> {
+    tokens: Lexer<'a>,
+    depth: usize,
+    max_depth: usize,
+    features: Vec<String>,
+    tags: Option<TagHandlers>,
+    // where the last top-level form started
+    form_start: Option<Position>,
 }
 
-impl Parser {
-    pub fn new(tokens: &Vec<Token>) -> Parser {
-        // TODO: avoid clone!!!
-        Parser { tokens: tokens.clone() }
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Lexer<'a>) -> Parser<'a> {
+        Parser {
+            tokens: tokens,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            features: vec!["rulsp".to_string()],
+            tags: None,
+            form_start: None,
+        }
     }
 
-    pub fn start(&self) -> Result<AtomVal, ParseError> {
-        self.parse(0).and_then(|(atom, _end)| Result::Ok(atom))
+    // Handlers for tagged literals, looked up before the built-in #inst and #uuid
+    pub fn with_tag_handlers(self, tags: TagHandlers) -> Parser<'a> {
+        Parser { tags: Some(tags), ..self }
     }
 
-    pub fn parse(&self, pos: usize) -> Result<(AtomVal, usize), ParseError> {
-        if let Some(token) = self.pop(pos) {
-            if token.is_hidden() {
-                self.parse(pos + 1)
-            } else {
-                match token {
-                    &Token::Oparen => self.read_list(pos + 1),
-                    &Token::Cparen => Result::Ok((c_nil(), pos)),
-                    &Token::Int(num) => Result::Ok((c_int(num), pos)),
-                    &Token::Identifier(ref str) => {
-                        if str.to_uppercase() == "NIL" {
-                            Result::Ok((c_nil(), pos))
-                        } else {
-                            Result::Ok((c_symbol(str), pos))
-                        }
-                    }
-                    &Token::Apostrophe => {
-                        let (body, pos) = self.parse(pos + 1)?;
-                        Result::Ok((c_list(vec![c_symbol("quote"), body]), pos))
-                    }
-                    &Token::Unquote => {
-                        let (body, pos) = self.parse(pos + 1)?;
-                        Result::Ok((c_list(vec![c_symbol("unquote"), body]), pos))
-                    }
-                    &Token::Backquote => {
-                        let (body, pos) = self.parse(pos + 1)?;
-                        Result::Ok((c_list(vec![c_symbol("backquote"), body]), pos))
-                    }
-                    &Token::UnquoteSplicing => {
-                        let (body, pos) = self.parse(pos + 1)?;
-                        Result::Ok((c_list(vec![c_symbol("unquote-splicing"), body]),
-                                    pos))
-                    }
-                    _ => Result::Err(ParseError::Syntax),
-                }
+    // Features reader conditionals choose branches by, instead of just :rulsp
+    #[allow(dead_code)]
+    pub fn with_features(self, features: Vec<String>) -> Parser<'a> {
+        Parser { features: features, ..self }
+    }
+
+    // How many collections and quotes may be nested in each other
+    #[allow(dead_code)]
+    pub fn with_max_depth(self, max_depth: usize) -> Parser<'a> {
+        Parser { max_depth: max_depth, ..self }
+    }
+
+    // The first form only
+    #[allow(dead_code)]
+    pub fn start(&mut self) -> Result<AtomVal, ParseError> {
+        self.parse()
+    }
+
+    // All top-level forms up to the end of the input
+    #[allow(dead_code)]
+    pub fn parse_program(&mut self) -> Result<Vec<AtomVal>, ParseError> {
+        let mut forms = vec![];
+        while let Some(form) = self.next_form()? {
+            forms.push(form);
+        }
+
+        Result::Ok(forms)
+    }
+
+    // The next top-level form, None at the end of the input
+    pub fn next_form(&mut self) -> Result<Option<AtomVal>, ParseError> {
+        while let Some((position, token)) = self.next_token()? {
+            self.form_start = Some(position);
+            if let Some(form) = self.read_form(position, token)? {
+                return Result::Ok(Some(form));
             }
-        } else {
-            Result::Err(ParseError::Syntax)
         }
+
+        Result::Ok(None)
+    }
+
+    // Where the form last returned by next_form is in the source
+    pub fn last_span(&self) -> Option<Span> {
+        self.form_start.map(|start| {
+            Span {
+                start: start,
+                end: self.tokens.position(),
+            }
+        })
     }
 
-    fn pop(&self, pos: usize) -> Option<&Token> {
-        self.tokens.get(pos)
+    pub fn parse(&mut self) -> Result<AtomVal, ParseError> {
+        let (position, token) = self.pop()?;
+        self.parse_token(position, token)
     }
 
-    fn read_list(&self, pos: usize) -> Result<(AtomVal, usize), ParseError> {
+    // Like parse_token, but a reader conditional without a branch for our features reads as
+    // nothing at all
+    fn read_form(&mut self,
+                 position: Position,
+                 token: Token)
+                 -> Result<Option<AtomVal>, ParseError> {
+        if token != Token::ReaderConditional {
+            return self.parse_token(position, token).map(Some);
+        }
+
+        if self.depth == self.max_depth {
+            return Result::Err(ParseError::TooDeep(position));
+        }
+        self.depth += 1;
+        let result = self.parse()
+            .and_then(|branches| select_branch(&branches, &self.features, position));
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_token(&mut self, position: Position, token: Token) -> Result<AtomVal, ParseError> {
+        if let Some(atom) = token_atom(&token) {
+            return Result::Ok(atom);
+        }
+        // where a form has to follow, like after a quote
+        if token == Token::ReaderConditional {
+            return self.read_form(position, token)?.ok_or(ParseError::Syntax(position));
+        }
+
+        match token {
+            ref closer if is_closer(closer) => {
+                return Result::Err(ParseError::Unmatched(delimiter(closer), position))
+            }
+            Token::Regex(ref pattern) => return regex_literal(pattern, position),
+            Token::Oparen | Token::Obracket | Token::Ocurly => {}
+            ref prefix if quote_name(prefix).is_some() => {}
+            Token::Dispatch(_) | Token::Tag(_) => {}
+            _ => return Result::Err(ParseError::Syntax(position)),
+        }
+
+        if self.depth == self.max_depth {
+            return Result::Err(ParseError::TooDeep(position));
+        }
+        self.depth += 1;
+        let result = match token {
+            Token::Oparen => self.read_seq(&token, position).map(c_list),
+            Token::Obracket => self.read_seq(&token, position).map(c_vector),
+            Token::Ocurly => {
+                self.read_seq(&token, position).and_then(|atoms| make_map(atoms, position))
+            }
+            Token::Dispatch(c) => self.read_dispatch(c, position),
+            Token::Tag(ref tag) => self.read_tagged(tag, position),
+            ref prefix => {
+                self.parse().map(|body| c_list(vec![c_symbol(quote_name(prefix).unwrap()), body]))
+            }
+        };
+        self.depth -= 1;
+        result
+    }
+
+    // next token which isn't whitespace or a comment, None at the end of the input
+    fn next_token(&mut self) -> Result<Option<(Position, Token)>, ParseError> {
+        loop {
+            match self.tokens.next() {
+                Some(Ok((_, ref token))) if token.is_hidden() => {}
+                Some(Ok(token)) => return Result::Ok(Some(token)),
+                Some(Err(err)) => return Result::Err(ParseError::Lex(err)),
+                None => return Result::Ok(None),
+            }
+        }
+    }
+
+    // next token inside a form
+    fn pop(&mut self) -> Result<(Position, Token), ParseError> {
+        self.next_token()?.ok_or(ParseError::Incomplete)
+    }
+
+    // The reader macro is looked up again, it may have been replaced since the lexer saw it
+    fn read_dispatch(&mut self, c: char, position: Position) -> Result<AtomVal, ParseError> {
+        let form = self.parse()?;
+        match self.tokens.reader_macro(c) {
+            Some(func) => func.apply(&[form]).map_err(|err| ParseError::Macro(position, err)),
+            None => Result::Err(ParseError::Syntax(position)),
+        }
+    }
+
+    fn read_tagged(&mut self, tag: &str, position: Position) -> Result<AtomVal, ParseError> {
+        let form = self.parse()?;
+        let handler = self.tags.as_ref().and_then(|tags| tags.borrow().get(tag).cloned());
+        match handler {
+            Some(handler) => handler.apply(&[form]),
+            None => tagged_literal(tag, &form),
+        }.map_err(|err| ParseError::Tag(position, tag.to_string(), err))
+    }
+
+    // forms up to the delimiter closing opener
+    fn read_seq(&mut self, opener: &Token, opened: Position) -> Result<Vec<AtomVal>, ParseError> {
         let mut atoms = vec![];
-        let mut pos = pos;
 
         loop {
-            if let Some(token) = self.pop(pos) {
-                if !token.is_hidden() {
-                    match token {
-                        &Token::Cparen => {
-                            break;
-                        }
-                        _other => {
-                            match self.parse(pos) {
-                                Ok((atom, end)) => {
-                                    pos = end;
-                                    atoms.push(atom)
-                                }
-                                Err(err) => return Result::Err(err),
-                            }
-                        }
+            let (position, token) = self.next_token()?
+                .ok_or(ParseError::Unclosed(delimiter(opener), opened))?;
+            if closes(opener, opened, &token, position)? {
+                break;
+            }
+            atoms.extend(self.read_form(position, token)?);
+        }
+
+        Result::Ok(atoms)
+    }
+}
+
+// Value of a token which stands for itself
+fn token_atom(token: &Token) -> Option<AtomVal> {
+    match *token {
+        Token::Int(num) => Some(c_int(num)),
+        Token::Str(ref str) => Some(c_string(str)),
+        Token::Identifier(ref str) => {
+            Some(if str.to_uppercase() == "NIL" {
+                c_nil()
+            } else if str == "true" || str == "false" {
+                c_bool(str == "true")
+            } else if str.len() > 1 && str.starts_with(':') {
+                c_keyword(&str[1..])
+            } else {
+                c_symbol(str)
+            })
+        }
+        _ => None,
+    }
+}
+
+// The form 'x and friends expand to (quote x)
+fn quote_name(token: &Token) -> Option<&'static str> {
+    match *token {
+        Token::Apostrophe => Some("quote"),
+        Token::Unquote => Some("unquote"),
+        Token::Backquote => Some("backquote"),
+        Token::UnquoteSplicing => Some("unquote-splicing"),
+        _ => None,
+    }
+}
+
+// #?(:feature form ...) reads as the form of the first feature the reader has, :default
+// is always had
+fn select_branch(branches: &AtomVal,
+                 features: &[String],
+                 position: Position)
+                 -> Result<Option<AtomVal>, ParseError> {
+    let branches = match **branches {
+        AtomType::List(ref branches, _) if branches.len() % 2 == 0 => branches,
+        _ => return Result::Err(ParseError::Syntax(position)),
+    };
+
+    for branch in branches.chunks(2) {
+        match *branch[0] {
+            AtomType::Keyword(ref feature) if **feature == "default" ||
+                                              features.contains(feature) => {
+                return Result::Ok(Some(branch[1].clone()))
+            }
+            AtomType::Keyword(_) => {}
+            _ => return Result::Err(ParseError::Syntax(position)),
+        }
+    }
+
+    Result::Ok(None)
+}
+
+fn regex_literal(pattern: &str, position: Position) -> Result<AtomVal, ParseError> {
+    Regex::new(pattern)
+        .map(c_regex)
+        .map_err(|err| ParseError::Regex(position, err.to_string()))
+}
+
+fn tagged_literal(tag: &str, form: &AtomVal) -> AtomRet {
+    tagged::builtin(tag, form)
+        .unwrap_or_else(|| Err(AtomError::InvalidOperation(format!("unknown tag #{}", tag))))
+}
+
+fn make_map(atoms: Vec<AtomVal>, position: Position) -> Result<AtomVal, ParseError> {
+    if atoms.len() % 2 == 1 {
+        return Result::Err(ParseError::Syntax(position));
+    }
+
+    let mut map = FnvHashMap::default();
+    for pair in atoms.chunks(2) {
+        match AtomKey::new(pair[0].clone()) {
+            Ok(key) => map.insert(key, pair[1].clone()),
+            Err(_) => return Result::Err(ParseError::Syntax(position)),
+        };
+    }
+
+    Result::Ok(c_map(map))
+}
+
+// Source range, end is where the next token starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+// Concrete syntax tree, unlike the AST it keeps whitespace, commas and comments, so printing
+// it reproduces the source exactly. Meant for tools like formatters.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cst {
+    // whitespace, commas and comments
+    Trivia(Span, String),
+    // token of a number, string or symbol and its source text
+    Atom(Span, Token, String),
+    // quote prefix like ' or ~@, followed by the trivia before and the form it applies to
+    Prefix(Span, Token, Vec<Cst>),
+    // list, vector or map by its opening delimiter, the nodes between the delimiters
+    Seq(Span, Token, Vec<Cst>),
+}
+
+#[allow(dead_code)]
+impl Cst {
+    pub fn span(&self) -> Span {
+        match *self {
+            Cst::Trivia(span, _) |
+            Cst::Atom(span, _, _) |
+            Cst::Prefix(span, _, _) |
+            Cst::Seq(span, _, _) => span,
+        }
+    }
+
+    pub fn is_trivia(&self) -> bool {
+        match *self {
+            Cst::Trivia(_, _) => true,
+            _ => false,
+        }
+    }
+
+    pub fn children(&self) -> &[Cst] {
+        match *self {
+            Cst::Prefix(_, _, ref children) |
+            Cst::Seq(_, _, ref children) => children,
+            _ => &[],
+        }
+    }
+
+    // Calls f on this node and everything below it, parents before children
+    pub fn walk<F>(&self, f: &mut F)
+        where F: FnMut(&Cst)
+    {
+        f(self);
+        for child in self.children() {
+            child.walk(f);
+        }
+    }
+
+    // The form this node reads as, None for trivia
+    pub fn ast(&self) -> Result<Option<AtomVal>, ParseError> {
+        let forms = || -> Result<Vec<AtomVal>, ParseError> {
+            let mut forms = vec![];
+            for child in self.children() {
+                forms.extend(child.ast()?);
+            }
+            Result::Ok(forms)
+        };
+
+        Result::Ok(match *self {
+            Cst::Trivia(_, _) => None,
+            Cst::Atom(span, Token::Regex(ref pattern), _) => {
+                Some(regex_literal(pattern, span.start)?)
+            }
+            Cst::Atom(_, ref token, _) => token_atom(token),
+            Cst::Prefix(span, Token::ReaderConditional, _) => {
+                match forms()?.pop() {
+                    Some(branches) => select_branch(&branches, &["rulsp".to_string()], span.start)?,
+                    None => return Result::Err(ParseError::Syntax(span.start)),
+                }
+            }
+            Cst::Prefix(span, Token::Tag(ref tag), _) => {
+                match forms()?.pop() {
+                    Some(form) => {
+                        Some(tagged_literal(tag, &form)
+                            .map_err(|err| ParseError::Tag(span.start, tag.clone(), err))?)
                     }
+                    None => return Result::Err(ParseError::Syntax(span.start)),
                 }
-            } else {
-                return Result::Err(ParseError::Syntax);
             }
+            Cst::Prefix(_, ref token, _) => {
+                let mut form = vec![c_symbol(quote_name(token).unwrap())];
+                form.extend(forms()?);
+                Some(c_list(form))
+            }
+            Cst::Seq(span, Token::Ocurly, _) => Some(make_map(forms()?, span.start)?),
+            Cst::Seq(_, Token::Obracket, _) => Some(c_vector(forms()?)),
+            Cst::Seq(_, _, _) => Some(c_list(forms()?)),
+        })
+    }
+}
+
+pub fn closer(opener: &Token) -> Token {
+    match *opener {
+        Token::Obracket => Token::Cbracket,
+        Token::Ocurly => Token::Ccurly,
+        _ => Token::Cparen,
+    }
+}
+
+// Tokens the CST keeps together with the form after them
+fn is_prefix(token: &Token) -> bool {
+    match *token {
+        Token::ReaderConditional | Token::Tag(_) => true,
+        ref other => quote_name(other).is_some(),
+    }
+}
+
+fn is_closer(token: &Token) -> bool {
+    match *token {
+        Token::Cparen | Token::Cbracket | Token::Ccurly => true,
+        _ => false,
+    }
+}
+
+// true if token closes opener, an error if it's any other closing delimiter
+fn closes(opener: &Token,
+          opened: Position,
+          token: &Token,
+          position: Position)
+          -> Result<bool, ParseError> {
+    if *token == closer(opener) {
+        Result::Ok(true)
+    } else if is_closer(token) {
+        Result::Err(ParseError::Mismatched(delimiter(opener), opened, delimiter(token), position))
+    } else {
+        Result::Ok(false)
+    }
+}
+
+pub fn delimiter(token: &Token) -> &'static str {
+    match *token {
+        Token::Oparen => "(",
+        Token::Cparen => ")",
+        Token::Obracket => "[",
+        Token::Cbracket => "]",
+        Token::Ocurly => "{",
+        Token::Ccurly => "}",
+        Token::Apostrophe => "'",
+        Token::Backquote => "`",
+        Token::Unquote => "~",
+        Token::UnquoteSplicing => "~@",
+        Token::ReaderConditional => "#?",
+        _ => "",
+    }
+}
+
+// Prints the source the node was read from
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cst::Trivia(_, ref text) |
+            Cst::Atom(_, _, ref text) => write!(f, "{}", text),
+            Cst::Prefix(_, ref token, ref children) => {
+                match *token {
+                    Token::Tag(ref tag) => write!(f, "#{}", tag)?,
+                    _ => write!(f, "{}", delimiter(token))?,
+                }
+                for child in children {
+                    write!(f, "{}", child)?;
+                }
+                Ok(())
+            }
+            Cst::Seq(_, ref token, ref children) => {
+                write!(f, "{}", delimiter(token))?;
+                for child in children {
+                    write!(f, "{}", child)?;
+                }
+                write!(f, "{}", delimiter(&closer(token)))
+            }
+        }
+    }
+}
+
+struct CstReader<'a> {
+    source: &'a str,
+    tokens: Lexer<'a>,
+    depth: usize,
+}
 
-            pos += 1;
+impl<'a> CstReader<'a> {
+    fn next(&mut self) -> Result<Option<(Span, Token)>, ParseError> {
+        match self.tokens.next() {
+            Some(Ok((start, token))) => {
+                let span = Span {
+                    start: start,
+                    end: self.tokens.position(),
+                };
+                Result::Ok(Some((span, token)))
+            }
+            Some(Err(err)) => Result::Err(ParseError::Lex(err)),
+            None => Result::Ok(None),
         }
+    }
 
-        Result::Ok((c_list(atoms), pos))
+    fn pop(&mut self) -> Result<(Span, Token), ParseError> {
+        self.next()?.ok_or(ParseError::Incomplete)
+    }
+
+    fn text(&self, span: Span) -> String {
+        self.source[span.start.offset..span.end.offset].to_string()
+    }
+
+    fn read(&mut self, span: Span, token: Token) -> Result<Cst, ParseError> {
+        let nests = match token {
+            Token::Oparen | Token::Obracket | Token::Ocurly => true,
+            ref other => is_prefix(other),
+        };
+        if nests && self.depth == DEFAULT_MAX_DEPTH {
+            return Result::Err(ParseError::TooDeep(span.start));
+        }
+
+        match token {
+            ref hidden if hidden.is_hidden() => Result::Ok(Cst::Trivia(span, self.text(span))),
+            Token::Int(_) | Token::Str(_) | Token::Identifier(_) | Token::Regex(_) => {
+                let text = self.text(span);
+                Result::Ok(Cst::Atom(span, token, text))
+            }
+            Token::Oparen | Token::Obracket | Token::Ocurly => {
+                self.depth += 1;
+                let mut children = vec![];
+                loop {
+                    let (child_span, child) = self.next()?
+                        .ok_or(ParseError::Unclosed(delimiter(&token), span.start))?;
+                    if closes(&token, span.start, &child, child_span.start)? {
+                        let span = Span { start: span.start, ..child_span };
+                        self.depth -= 1;
+                        return Result::Ok(Cst::Seq(span, token, children));
+                    }
+                    children.push(self.read(child_span, child)?);
+                }
+            }
+            ref prefix if is_prefix(prefix) => {
+                self.depth += 1;
+                let mut children = vec![];
+                loop {
+                    let (child_span, child) = self.pop()?;
+                    let child = self.read(child_span, child)?;
+                    let end = child.span().end;
+                    let done = !child.is_trivia();
+                    children.push(child);
+                    if done {
+                        let span = Span { start: span.start, end: end };
+                        self.depth -= 1;
+                        return Result::Ok(Cst::Prefix(span, token, children));
+                    }
+                }
+            }
+            ref closer if is_closer(closer) => {
+                Result::Err(ParseError::Unmatched(delimiter(closer), span.start))
+            }
+            _ => Result::Err(ParseError::Syntax(span.start)),
+        }
     }
 }
 
+// Top-level nodes of source, trivia between forms included
+#[allow(dead_code)]
+pub fn parse_cst(source: &str) -> Result<Vec<Cst>, ParseError> {
+    let mut reader = CstReader {
+        source: source,
+        tokens: Lexer::new(source),
+        depth: 0,
+    };
+
+    let mut nodes = vec![];
+    while let Some((span, token)) = reader.next()? {
+        nodes.push(reader.read(span, token)?);
+    }
+
+    Result::Ok(nodes)
+}
+
+
 #[cfg(test)]
 mod tests {
-    use data::{c_symbol, c_int, c_list};
-    use lexer::lex;
-    use super::Parser;
+    use data::{c_symbol, c_keyword, c_string, c_int, c_list, c_vector};
+    use lexer::{lex, Lexer};
+    use super::{Parser, ParseError, Cst, parse_cst};
 
     #[test]
     fn test_apostrophe() {
-        let parser = Parser::new(&lex("'(1 2)").unwrap());
+        let mut parser = Parser::new(Lexer::new("'(1 2)"));
 
         let expected = c_list(vec![c_symbol("quote"),
                                    c_list(vec![c_int(1), c_int(2)])]);
@@ -141,7 +692,7 @@ mod tests {
 
     #[test]
     fn test_unquote() {
-        let parser = Parser::new(&lex("~(1 2)").unwrap());
+        let mut parser = Parser::new(Lexer::new("~(1 2)"));
 
         let expected = c_list(vec![c_symbol("unquote"),
                                    c_list(vec![c_int(1), c_int(2)])]);
@@ -151,7 +702,7 @@ mod tests {
 
     #[test]
     fn test_backquote() {
-        let parser = Parser::new(&lex("`(1 2)").unwrap());
+        let mut parser = Parser::new(Lexer::new("`(1 2)"));
 
         let expected = c_list(vec![c_symbol("backquote"),
                                    c_list(vec![c_int(1), c_int(2)])]);
@@ -161,11 +712,181 @@ mod tests {
 
     #[test]
     fn test_unquote_splicing() {
-        let parser = Parser::new(&lex("~@(1 2)").unwrap());
+        let mut parser = Parser::new(Lexer::new("~@(1 2)"));
 
         let expected = c_list(vec![c_symbol("unquote-splicing"),
                                    c_list(vec![c_int(1), c_int(2)])]);
 
         assert_eq!(parser.start().unwrap(), expected);
     }
+
+    #[test]
+    fn test_string() {
+        let mut parser = Parser::new(Lexer::new("(\"a (b)\\n\" \"\\\"\")"));
+
+        let expected = c_list(vec![c_string("a (b)\n"), c_string("\"")]);
+
+        assert_eq!(parser.start().unwrap(), expected);
+        assert!(lex("\"abc").is_err());
+    }
+
+    #[test]
+    fn test_vector() {
+        let mut parser = Parser::new(Lexer::new("[1 (a) [b]]"));
+
+        let expected = c_vector(vec![c_int(1),
+                                     c_list(vec![c_symbol("a")]),
+                                     c_vector(vec![c_symbol("b")])]);
+
+        assert_eq!(parser.start().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_map() {
+        let mut parser = Parser::new(Lexer::new("{:a 1 b :c}"));
+        let map = parser.start().unwrap();
+        let map = map.get_map().unwrap();
+
+        assert_eq!(map.len(), 2);
+        assert!(map.values().any(|v| *v == c_int(1)));
+        assert!(map.values().any(|v| *v == c_keyword("c")));
+
+        assert!(Parser::new(Lexer::new("{:a}")).start().is_err());
+    }
+
+    #[test]
+    fn test_lazy() {
+        // only the first form is lexed
+        assert_eq!(Parser::new(Lexer::new("(a) \"unterminated")).start().unwrap(),
+                   c_list(vec![c_symbol("a")]));
+
+        match Parser::new(Lexer::new("'")).start() {
+            Err(ParseError::Incomplete) => {}
+            other => panic!("expected incomplete input, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reader_conditionals() {
+        let read = |source, features: &[&str]| {
+            let features = features.iter().map(|f| f.to_string()).collect();
+            Parser::new(Lexer::new(source)).with_features(features).parse_program()
+        };
+
+        let source = "[1 #?(:clj 2 :rulsp 3) #?(:cljs 4)] #?(:sandbox :s :default :d)";
+        assert_eq!(read(source, &["rulsp"]).unwrap(),
+                   vec![c_vector(vec![c_int(1), c_int(3)]), c_keyword("d")]);
+        assert_eq!(read(source, &["cljs", "sandbox"]).unwrap(),
+                   vec![c_vector(vec![c_int(1), c_int(4)]), c_keyword("s")]);
+        assert_eq!(read("'#?(:rulsp a)", &["rulsp"]).unwrap(),
+                   vec![c_list(vec![c_symbol("quote"), c_symbol("a")])]);
+
+        assert!(read("'#?(:clj a)", &["rulsp"]).is_err());
+        assert!(read("#?(:rulsp)", &["rulsp"]).is_err());
+        assert!(read("#?[:rulsp 1]", &["rulsp"]).is_err());
+
+        let nodes = parse_cst(source).unwrap();
+        assert_eq!(nodes.iter().map(|node| node.to_string()).collect::<String>(), source);
+        let forms = nodes.iter().filter_map(|node| node.ast().unwrap()).collect::<Vec<_>>();
+        assert_eq!(forms, read(source, &["rulsp"]).unwrap());
+    }
+
+    #[test]
+    fn test_tagged_literals() {
+        let source = "[#inst \"1970-01-01T00:00:01Z\"
+                       #uuid \"0A0B0C0D-1111-2222-3333-444455556666\" #\"a(\\d+)\"]";
+        let forms = Parser::new(Lexer::new(source)).parse_program().unwrap();
+        assert_eq!(format!("{}", forms[0]),
+                   "[1000 0a0b0c0d-1111-2222-3333-444455556666 #\"a(\\d+)\"]");
+
+        let nodes = parse_cst(source).unwrap();
+        assert_eq!(nodes.iter().map(|node| node.to_string()).collect::<String>(), source);
+        assert_eq!(nodes[0].ast().unwrap().unwrap(), forms[0]);
+
+        let error = |source| format!("{}", Parser::new(Lexer::new(source)).start().unwrap_err());
+        assert_eq!(error("#point [1 2]"),
+                   "Tagged literal #point at 1:1 failed: invalid operation: unknown tag #point");
+        assert_eq!(error("(#inst \"2017-13-01\")"),
+                   "Tagged literal #inst at 1:2 failed: invalid argument: invalid timestamp \
+                    \"2017-13-01\"");
+        assert!(error("#uuid 1").starts_with("Tagged literal #uuid"));
+        assert!(error("#\"(\"").starts_with("Invalid regex at 1:1"));
+    }
+
+    #[test]
+    fn test_delimiters() {
+        let error = |source| {
+            let parsed = Parser::new(Lexer::new(source)).parse_program();
+            let cst = parse_cst(source);
+            assert_eq!(format!("{}", cst.unwrap_err()), format!("{}", parsed.unwrap_err()));
+            format!("{}", Parser::new(Lexer::new(source)).parse_program().unwrap_err())
+        };
+
+        assert_eq!(error("(a [b"), "Unclosed `[` opened at 1:4");
+        assert_eq!(error("(a\n  (b)"), "Unclosed `(` opened at 1:1");
+        assert_eq!(error("(a\n  b}"), "`}` at 2:4 doesn't close `(` opened at 1:1");
+        assert_eq!(error("(a) ]"), "Unmatched `]` at 1:5");
+        assert_eq!(error(")"), "Unmatched `)` at 1:1");
+    }
+
+    #[test]
+    fn test_program() {
+        let forms = Parser::new(Lexer::new("(def a 1) ; one\n[a] :b\n")).parse_program().unwrap();
+        assert_eq!(forms,
+                   vec![c_list(vec![c_symbol("def"), c_symbol("a"), c_int(1)]),
+                        c_vector(vec![c_symbol("a")]),
+                        c_keyword("b")]);
+
+        assert_eq!(Parser::new(Lexer::new("  ")).parse_program().unwrap(), vec![]);
+        assert!(Parser::new(Lexer::new("(a) (b")).parse_program().is_err());
+    }
+
+    #[test]
+    fn test_cst() {
+        let source = "; numbers\n(def xs [1, 2 ; two\n  3])\n'{:a \"\\\"q\"}";
+        let nodes = parse_cst(source).unwrap();
+        assert_eq!(nodes.iter().map(|node| node.to_string()).collect::<String>(), source);
+
+        let mut comments = vec![];
+        for node in &nodes {
+            node.walk(&mut |node| {
+                if let Cst::Trivia(span, ref text) = *node {
+                    if text.starts_with(';') {
+                        comments.push((span.start.line, text.clone()));
+                    }
+                }
+            });
+        }
+        assert_eq!(comments, vec![(1, "; numbers".to_string()), (2, "; two".to_string())]);
+
+        let forms = nodes.iter().filter_map(|node| node.ast().unwrap()).collect::<Vec<_>>();
+        assert_eq!(forms, Parser::new(Lexer::new(source)).parse_program().unwrap());
+
+        let def = &nodes[2];
+        assert_eq!((def.span().start.line, def.span().end.line, def.span().end.column), (2, 3, 6));
+        assert!(parse_cst("(a [b)").is_err());
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let deep = format!("{}{}", "(".repeat(100000), ")".repeat(100000));
+        match Parser::new(Lexer::new(&deep)).start() {
+            Err(ParseError::TooDeep(position)) => assert_eq!(position.column, 257),
+            other => panic!("expected nesting error, got {:?}", other),
+        }
+        assert!(parse_cst(&deep).is_err());
+        assert!(Parser::new(Lexer::new("'''a")).with_max_depth(2).start().is_err());
+        assert!(Parser::new(Lexer::new("'[[a]]")).with_max_depth(3).start().is_ok());
+    }
+
+    #[test]
+    fn test_positions() {
+        let positions = Lexer::new("(a\n \"b\")")
+            .map(|token| token.unwrap())
+            .filter(|&(_, ref token)| !token.is_hidden())
+            .map(|(position, _)| (position.line, position.column))
+            .collect::<Vec<_>>();
+
+        assert_eq!(positions, vec![(1, 1), (1, 2), (2, 2), (2, 5)]);
+    }
 }
@@ -0,0 +1,312 @@
+// Paths for build scripts and file tools: glob patterns and absolute and relative paths. Only
+// canonicalize asks the filesystem about links, the others work on the path's components.
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_list, c_string};
+use env::Env;
+use core::set_builtin;
+
+// Whether name matches the glob pattern: * is any run of characters, ? one character and [a-z]
+// or [!a-z] one character of a set or not
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&'*') => (0..name.len() + 1).any(|skip| matches(&pattern[1..], &name[skip..])),
+        Some(&'?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        // a ] right after the [ belongs to the set
+        Some(&'[') if pattern.iter().skip(2).any(|&c| c == ']') => {
+            let close = pattern.iter().skip(2).position(|&c| c == ']').unwrap() + 2;
+            let negated = pattern[1] == '!' || pattern[1] == '^';
+            let set = &pattern[if negated { 2 } else { 1 }..close];
+            match name.first() {
+                Some(&c) => in_set(set, c) != negated && matches(&pattern[close + 1..], &name[1..]),
+                None => false,
+            }
+        }
+        Some(&c) => name.first() == Some(&c) && matches(&pattern[1..], &name[1..]),
+    }
+}
+
+fn in_set(set: &[char], c: char) -> bool {
+    let mut at = 0;
+    while at < set.len() {
+        if at + 2 < set.len() && set[at + 1] == '-' {
+            if set[at] <= c && c <= set[at + 2] {
+                return true;
+            }
+            at += 3;
+        } else {
+            if set[at] == c {
+                return true;
+            }
+            at += 1;
+        }
+    }
+    false
+}
+
+// Every [ has to be closed by a ] after the first character of its set
+fn check_pattern(pattern: &str) -> Result<(), AtomError> {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let mut at = 0;
+    while at < pattern.len() {
+        if pattern[at] == '[' {
+            match pattern.iter().skip(at + 2).position(|&c| c == ']') {
+                Some(close) => at += close + 2,
+                None => {
+                    let pattern = pattern.iter().collect::<String>();
+                    return Err(AtomError::InvalidArgument(format!("unclosed [ in glob pattern {}",
+                                                                  pattern)));
+                }
+            }
+        }
+        at += 1;
+    }
+    Ok(())
+}
+
+fn is_pattern(part: &str) -> bool {
+    part.contains(['*', '?', '['])
+}
+
+fn join(shown: &str, name: &str) -> String {
+    if shown.is_empty() || shown.ends_with('/') {
+        format!("{}{}", shown, name)
+    } else {
+        format!("{}/{}", shown, name)
+    }
+}
+
+// Entries of dir as names, hidden ones only if the pattern asks for them; unreadable
+// directories have none
+fn entries(dir: &Path, part: &str) -> Vec<(String, PathBuf)> {
+    let mut entries = fs::read_dir(dir)
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok())
+                .map(|entry| (entry.file_name().to_string_lossy().into_owned(), entry.path()))
+                .filter(|&(ref name, _)| !name.starts_with('.') || part.starts_with('.'))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    entries.sort();
+    entries
+}
+
+// Adds the paths under dir, shown as below shown, which match parts
+fn walk(dir: &Path, shown: &str, parts: &[&str], found: &mut Vec<String>) {
+    let (part, rest) = match parts.split_first() {
+        Some((part, rest)) => (*part, rest),
+        None => {
+            found.push(shown.to_string());
+            return;
+        }
+    };
+
+    if part == "**" {
+        // none or any number of directories
+        walk(dir, shown, rest, found);
+        for (name, path) in entries(dir, "") {
+            if path.is_dir() {
+                walk(&path, &join(shown, &name), parts, found);
+            }
+        }
+    } else if !is_pattern(part) {
+        let path = dir.join(part);
+        if fs::symlink_metadata(&path).is_ok() {
+            walk(&path, &join(shown, part), rest, found);
+        }
+    } else {
+        let pattern = part.chars().collect::<Vec<_>>();
+        for (name, path) in entries(dir, part) {
+            if matches(&pattern, &name.chars().collect::<Vec<_>>()) &&
+               (rest.is_empty() || path.is_dir()) {
+                walk(&path, &join(shown, &name), rest, found);
+            }
+        }
+    }
+}
+
+// The paths matching pattern, sorted, relative ones to the working directory; ** in it matches
+// any number of directories
+pub fn glob(pattern: &str) -> Result<Vec<String>, AtomError> {
+    check_pattern(pattern)?;
+    let (root, shown) = if pattern.starts_with('/') {
+        (PathBuf::from("/"), "/")
+    } else {
+        (PathBuf::from("."), "")
+    };
+    let parts = pattern.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>();
+    let mut found = vec![];
+    if !parts.is_empty() {
+        walk(&root, shown, &parts, &mut found);
+    }
+    found.sort();
+    found.dedup();
+    Ok(found)
+}
+
+// path without . and with .. taking back the component before it
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                match normal.components().next_back() {
+                    Some(Component::Normal(_)) => {
+                        normal.pop();
+                    }
+                    Some(Component::RootDir) => {}
+                    _ => normal.push(".."),
+                }
+            }
+            other => normal.push(other.as_os_str()),
+        }
+    }
+    normal
+}
+
+fn absolute(path: &str) -> Result<PathBuf, AtomError> {
+    let cwd = env::current_dir().map_err(|err| AtomError::InvalidOperation(err.to_string()))?;
+    Ok(normalize(&cwd.join(path)))
+}
+
+// How to get from base to path, both taken from the working directory
+pub fn relative(base: &str, path: &str) -> Result<PathBuf, AtomError> {
+    let (base, path) = (absolute(base)?, absolute(path)?);
+    let base = base.components().collect::<Vec<_>>();
+    let path = path.components().collect::<Vec<_>>();
+    let common = base.iter().zip(&path).take_while(|&(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &path[common..] {
+        relative.push(component.as_os_str());
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Ok(relative)
+}
+
+fn string_arg(args: &[AtomVal], index: usize) -> Result<&str, AtomError> {
+    match args.get(index).map(|arg| &**arg) {
+        Some(&AtomType::Str(ref s)) => Ok(s),
+        Some(other) => Err(AtomError::InvalidType("String".to_string(), other.format(true))),
+        None => Err(AtomError::InvalidArgument("missing path".to_string())),
+    }
+}
+
+fn path_value(path: &Path) -> AtomVal {
+    c_string(&path.to_string_lossy())
+}
+
+fn glob_builtin(args: &[AtomVal]) -> AtomRet {
+    Ok(c_list(glob(string_arg(args, 0)?)?.iter().map(|path| c_string(path)).collect()))
+}
+
+fn path_absolute(args: &[AtomVal]) -> AtomRet {
+    Ok(path_value(&absolute(string_arg(args, 0)?)?))
+}
+
+fn path_relative(args: &[AtomVal]) -> AtomRet {
+    Ok(path_value(&relative(string_arg(args, 0)?, string_arg(args, 1)?)?))
+}
+
+fn canonicalize(args: &[AtomVal]) -> AtomRet {
+    let path = string_arg(args, 0)?;
+    fs::canonicalize(path)
+        .map(|path| path_value(&path))
+        .map_err(|err| AtomError::InvalidOperation(format!("{}: {}", path, err)))
+}
+
+pub fn register(env: &Env) {
+    set_builtin(env,
+                "glob",
+                glob_builtin,
+                "(glob pattern) returns the sorted paths matching pattern, where * matches any \
+                 characters, ? one, [a-z] one of a set and ** any number of directories");
+    set_builtin(env,
+                "path-absolute",
+                path_absolute,
+                "(path-absolute p) returns p from the root, without . and .. components");
+    set_builtin(env,
+                "path-relative",
+                path_relative,
+                "(path-relative base p) returns the path leading from base to p");
+    set_builtin(env,
+                "canonicalize",
+                canonicalize,
+                "(canonicalize p) returns the absolute path of p with links resolved, fails if \
+                 there's no such file");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::{Path, PathBuf};
+    use super::{glob, matches, normalize, relative};
+
+    fn glob_matches(pattern: &str, name: &str) -> bool {
+        matches(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(glob_matches("*.clrs", "core.clrs"));
+        assert!(!glob_matches("*.clrs", "core.rs"));
+        assert!(glob_matches("a?c", "abc"));
+        assert!(glob_matches("[a-c]x", "bx"));
+        assert!(!glob_matches("[!a-c]x", "bx"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("[]", "[]"));
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(normalize(Path::new("/a/./b/../c")), PathBuf::from("/a/c"));
+        assert_eq!(normalize(Path::new("/../a")), PathBuf::from("/a"));
+        assert_eq!(relative("/a/b/c", "/a/d").unwrap(), PathBuf::from("../../d"));
+        assert_eq!(relative("/a", "/a/b").unwrap(), PathBuf::from("b"));
+        assert_eq!(relative("/a", "/a/.").unwrap(), PathBuf::from("."));
+    }
+
+    #[test]
+    fn test_glob() {
+        let dir = env::temp_dir().join("rulsp-glob");
+        let _ = fs::remove_dir_all(&dir);
+        for sub in &["src/deep/er", "src/.hidden", "docs"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in &["src/a.clrs", "src/b.rs", "src/deep/c.clrs", "src/deep/er/d.clrs",
+                      "src/.hidden/e.clrs", "docs/f.clrs"] {
+            File::create(dir.join(file)).unwrap();
+        }
+
+        let root = dir.to_str().unwrap();
+        let found = |pattern: &str| {
+            glob(&format!("{}/{}", root, pattern))
+                .unwrap()
+                .iter()
+                .map(|path| path[root.len() + 1..].to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(found("src/**/*.clrs"),
+                   ["src/a.clrs", "src/deep/c.clrs", "src/deep/er/d.clrs"]);
+        assert_eq!(found("*/*.clrs"), ["docs/f.clrs", "src/a.clrs"]);
+        assert_eq!(found("src/.hidden/*"), ["src/.hidden/e.clrs"]);
+        assert_eq!(found("src/?.rs"), ["src/b.rs"]);
+        assert_eq!(found("**/er"), ["src/deep/er"]);
+        assert_eq!(found("nothing/*"), Vec::<String>::new());
+        assert_eq!(found("src/[ab].*"), ["src/a.clrs", "src/b.rs"]);
+        assert_eq!(found("src/[]a].clrs"), ["src/a.clrs"]);
+        assert!(glob("src/[").is_err());
+        assert!(glob("src/[]").is_err());
+        assert!(glob("[a]/[b").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+// Native extensions loaded at runtime. A plugin is a dylib crate depending on rulsp, built with
+// the same compiler, calling rulsp_plugin! with its register function:
+//
+//     #[macro_use] extern crate rulsp;
+//     fn register(env: &rulsp::env::Env) { ... env_set(env, ...) ... }
+//     rulsp_plugin!(register);
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use libloading::Library;
+use data::{AtomVal, AtomType, AtomRet, AtomError, Intern, c_doc_func, c_nil, c_symbol, intern};
+use env::{Env, EnvType, env_set};
+
+// Bumped whenever Env, the atoms, their allocator or the register call change shape, plugins
+// built against another one are refused
+pub const ABI_VERSION: u32 = 6;
+
+// Exports the entry points load-plugin looks for, register is called with the global env
+#[macro_export]
+macro_rules! rulsp_plugin {
+    ($register:path) => {
+        #[no_mangle]
+        pub extern "C" fn rulsp_plugin_abi() -> u32 {
+            $crate::plugin::ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn rulsp_plugin_register(env: &$crate::env::Env,
+                                                intern: $crate::data::Intern) {
+            $crate::data::share_symbols(intern);
+            $register(env)
+        }
+    }
+}
+
+thread_local! {
+    // The builtins plugins register point into their code, so they are never unloaded
+    static LOADED: RefCell<Vec<(String, Library)>> = RefCell::new(vec![]);
+}
+
+fn load_error(path: &str, err: &dyn ToString) -> AtomError {
+    AtomError::InvalidOperation(format!("load-plugin {}: {}", path, err.to_string()))
+}
+
+// Registers the builtins of the plugin at path into env, loading it the first time
+pub fn load(env: &Env, path: &str) -> Result<(), AtomError> {
+    let loaded = LOADED.with(|loaded| loaded.borrow().iter().any(|&(ref known, _)| known == path));
+    if !loaded {
+        let library = Library::new(path).map_err(|err| load_error(path, &err))?;
+        let abi = unsafe {
+            let abi = library.get::<extern "C" fn() -> u32>(b"rulsp_plugin_abi\0")
+                .map_err(|err| load_error(path, &err))?;
+            abi()
+        };
+        if abi != ABI_VERSION {
+            let message = format!("built for plugin ABI {}, this is {}", abi, ABI_VERSION);
+            return Err(load_error(path, &message));
+        }
+        LOADED.with(|loaded| loaded.borrow_mut().push((path.to_string(), library)));
+    }
+
+    LOADED.with(|loaded| {
+        let loaded = loaded.borrow();
+        let &(_, ref library) = loaded.iter().find(|&&(ref known, _)| known == path).unwrap();
+        unsafe {
+            let register = library.get::<extern "C" fn(&Env, Intern)>(b"rulsp_plugin_register\0")
+                .map_err(|err| load_error(path, &err))?;
+            register(env, intern);
+        }
+        Ok(())
+    })
+}
+
+// (load-plugin "libfoo.so")
+fn load_plugin(env: &Weak<RefCell<EnvType>>, args: &[AtomVal]) -> AtomRet {
+    let env = env.upgrade()
+        .ok_or_else(|| AtomError::InvalidOperation("interpreter is gone".to_string()))?;
+    match args.get(0).map(|path| &**path) {
+        Some(&AtomType::Str(ref path)) => load(&env, path).map(|_| c_nil()),
+        Some(other) => Err(AtomError::InvalidType("String".to_string(), other.format(true))),
+        None => Err(AtomError::InvalidArgument("load-plugin needs a path".to_string())),
+    }
+}
+
+pub fn register(env: &Env) {
+    // weakly, env holds the builtins
+    let global = Rc::downgrade(env);
+    env_set(env,
+            &c_symbol("load-plugin"),
+            c_doc_func(move |args| load_plugin(&global, args),
+                       "(load-plugin \"libfoo.so\") loads the native plugin at the path and \
+                        lets it define its builtins globally"));
+}
+
+#[cfg(test)]
+mod tests {
+    use data::AtomError;
+    use interpreter::Interpreter;
+
+    #[test]
+    fn test_load_plugin() {
+        let interpreter = Interpreter::new();
+        match interpreter.eval("(load-plugin \"no/such/libplugin.so\")") {
+            Err(AtomError::InvalidOperation(ref message)) => {
+                assert!(message.starts_with("load-plugin no/such/libplugin.so: "))
+            }
+            other => panic!("missing plugin loaded: {:?}", other),
+        }
+        assert!(interpreter.eval("(load-plugin 1)").is_err());
+    }
+}
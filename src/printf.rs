@@ -0,0 +1,191 @@
+// printf-style format strings: %[flags][width][.precision]conversion, where the flags are
+// - (align left), 0 (pad numbers with zeros), + (always sign) and space (space for plus).
+// %s prints any value, precision cutting it short; %d, %x, %X, %o and %b take ints, %f too,
+// written with precision digits after the point as there are no floats; %% is a percent sign.
+use std::iter::Peekable;
+use std::str::Chars;
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_doc_func, c_nil, c_string, c_symbol};
+use env::{Env, env_set};
+
+#[derive(Debug, Default, PartialEq)]
+struct Directive {
+    left: bool,
+    zeros: bool,
+    plus: bool,
+    space: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+// Widths and precisions above this are taken for mistakes rather than padding out megabytes
+const MAX_WIDTH: usize = 4096;
+
+fn number(chars: &mut Peekable<Chars>, what: &str) -> Result<Option<usize>, AtomError> {
+    let mut number = None;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        chars.next();
+        let value = number.unwrap_or(0) * 10 + digit as usize;
+        if value > MAX_WIDTH {
+            return Err(AtomError::InvalidArgument(format!("format {} above {}", what, MAX_WIDTH)));
+        }
+        number = Some(value);
+    }
+    Ok(number)
+}
+
+// The directive after a %, which has been taken already
+fn directive(chars: &mut Peekable<Chars>) -> Result<Directive, AtomError> {
+    let mut directive = Directive::default();
+    loop {
+        match chars.peek() {
+            Some(&'-') => directive.left = true,
+            Some(&'0') => directive.zeros = true,
+            Some(&'+') => directive.plus = true,
+            Some(&' ') => directive.space = true,
+            _ => break,
+        }
+        chars.next();
+    }
+    directive.width = number(chars, "width")?.unwrap_or(0);
+    if chars.peek() == Some(&'.') {
+        chars.next();
+        directive.precision = Some(number(chars, "precision")?.unwrap_or(0));
+    }
+    match chars.next() {
+        Some(c) if "sdxXobf%".contains(c) => {
+            directive.conversion = c;
+            Ok(directive)
+        }
+        Some(c) => Err(AtomError::InvalidArgument(format!("unknown format directive %{}", c))),
+        None => Err(AtomError::InvalidArgument("format string ends in a %".to_string())),
+    }
+}
+
+// The digits of num, without its sign
+fn digits(num: i64, directive: &Directive) -> String {
+    let magnitude = num.wrapping_abs() as u64;
+    match directive.conversion {
+        'x' => format!("{:x}", magnitude),
+        'X' => format!("{:X}", magnitude),
+        'o' => format!("{:o}", magnitude),
+        'b' => format!("{:b}", magnitude),
+        'f' => {
+            match directive.precision.unwrap_or(6) {
+                0 => magnitude.to_string(),
+                precision => format!("{}.{}", magnitude, "0".repeat(precision)),
+            }
+        }
+        _ => magnitude.to_string(),
+    }
+}
+
+impl Directive {
+    fn apply(&self, value: &AtomVal) -> Result<String, AtomError> {
+        let (sign, body) = match self.conversion {
+            's' => {
+                let text = value.format(false);
+                let text = match self.precision {
+                    Some(precision) => text.chars().take(precision).collect(),
+                    None => text,
+                };
+                ("", text)
+            }
+            _ => {
+                let num = value.get_int()?;
+                let sign = match num {
+                    num if num < 0 => "-",
+                    _ if self.plus => "+",
+                    _ if self.space => " ",
+                    _ => "",
+                };
+                (sign, digits(num, self))
+            }
+        };
+
+        let len = sign.len() + body.chars().count();
+        let padding = self.width.saturating_sub(len);
+        Ok(if self.left {
+            format!("{}{}{}", sign, body, " ".repeat(padding))
+        } else if self.zeros && self.conversion != 's' {
+            format!("{}{}{}", sign, "0".repeat(padding), body)
+        } else {
+            format!("{}{}{}", " ".repeat(padding), sign, body)
+        })
+    }
+}
+
+// fmt with its directives replaced by the values in turn, values left over are ignored
+pub fn format(fmt: &str, values: &[AtomVal]) -> Result<String, AtomError> {
+    let mut out = String::with_capacity(fmt.len());
+    let mut values = values.iter();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let directive = directive(&mut chars)?;
+        if directive.conversion == '%' {
+            out.push('%');
+            continue;
+        }
+        match values.next() {
+            Some(value) => out.push_str(&directive.apply(value)?),
+            None => {
+                return Err(AtomError::InvalidArgument(format!("format needs a value for %{}",
+                                                              directive.conversion)))
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn format_builtin(args: &[AtomVal]) -> AtomRet {
+    let fmt = args.get(0).cloned().unwrap_or_else(c_nil);
+    match *fmt {
+        AtomType::Str(ref fmt) => Ok(c_string(&format(fmt, &args[1..])?)),
+        _ => Err(AtomError::InvalidType("String".to_string(), fmt.format(true))),
+    }
+}
+
+pub fn register(env: &Env) {
+    env_set(env,
+            &c_symbol("format"),
+            c_doc_func(format_builtin,
+                       "(format fmt & xs) returns fmt with its printf-style directives like \
+                        %-10s, %05d, %x or %.3f replaced by xs"));
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{c_int, c_keyword, c_string, c_vector};
+    use super::format;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format("%d%%, %s", &[c_int(50), c_string("done")]).unwrap(), "50%, done");
+        assert_eq!(format("[%5d|%-5d|%05d]", &[c_int(42), c_int(42), c_int(-42)]).unwrap(),
+                   "[   42|42   |-0042]");
+        assert_eq!(format("%x %X %o %b %+d % d",
+                          &[c_int(255), c_int(255), c_int(8), c_int(5), c_int(1), c_int(1)])
+                       .unwrap(),
+                   "ff FF 10 101 +1  1");
+        assert_eq!(format("%.3f %f %.0f", &[c_int(3), c_int(-1), c_int(2)]).unwrap(),
+                   "3.000 -1.000000 2");
+        assert_eq!(format("[%-6s|%6s|%.2s|%s]",
+                          &[c_keyword("ab"), c_vector(vec![c_int(1)]), c_string("xyz"),
+                            c_string("é")])
+                       .unwrap(),
+                   "[:ab   |   [1]|xy|é]");
+
+        assert!(format("%d", &[]).is_err());
+        assert!(format("%d", &[c_string("1")]).is_err());
+        assert!(format("%q", &[c_int(1)]).is_err());
+        assert!(format("100%", &[]).is_err());
+        assert_eq!(format("%4096d", &[c_int(1)]).unwrap().len(), 4096);
+        assert!(format("%4097d", &[c_int(1)]).is_err());
+        assert!(format("%99999999999999999999999d", &[c_int(1)]).is_err());
+        assert!(format("%.5000f", &[c_int(1)]).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+// Priority queue on a binary heap. Each value is kept with its key, the value itself or what a
+// key function gave for it when pushed, the least key comes out first and equal keys in the
+// order they went in. Keys are ordered like those of sorted maps. Values are immutable, so
+// pushing and popping work on a copy of the heap.
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_bool, c_nil, c_priority_queue};
+use env::Env;
+use core::{safe_get, set_builtin};
+use sorted::SortKey;
+
+#[derive(Clone)]
+struct Entry {
+    key: SortKey,
+    order: u64,
+    value: AtomVal,
+}
+
+// Reversed, so the max-heap gives the least key and the earliest push first
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (&other.key, other.order).cmp(&(&self.key, self.order))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+#[derive(Clone)]
+pub struct PriorityQueue {
+    heap: BinaryHeap<Entry>,
+    pushed: u64,
+}
+
+impl Default for PriorityQueue {
+    fn default() -> PriorityQueue {
+        PriorityQueue::new()
+    }
+}
+
+impl PriorityQueue {
+    pub fn new() -> PriorityQueue {
+        PriorityQueue {
+            heap: BinaryHeap::new(),
+            pushed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+
+    pub fn push(&self, value: AtomVal, key: AtomVal) -> Result<PriorityQueue, AtomError> {
+        let mut heap = self.heap.clone();
+        heap.push(Entry {
+            key: SortKey::new(key)?,
+            order: self.pushed,
+            value: value,
+        });
+        Ok(PriorityQueue {
+            heap: heap,
+            pushed: self.pushed + 1,
+        })
+    }
+
+    pub fn peek(&self) -> Option<&AtomVal> {
+        self.heap.peek().map(|entry| &entry.value)
+    }
+
+    // An empty queue stays empty
+    pub fn pop(&self) -> PriorityQueue {
+        let mut heap = self.heap.clone();
+        heap.pop();
+        PriorityQueue {
+            heap: heap,
+            pushed: self.pushed,
+        }
+    }
+
+    // In the order they'd be popped
+    pub fn values(&self) -> Vec<AtomVal> {
+        self.heap.clone().into_sorted_vec().into_iter().rev().map(|entry| entry.value).collect()
+    }
+}
+
+impl PartialEq for PriorityQueue {
+    fn eq(&self, other: &PriorityQueue) -> bool {
+        self.len() == other.len() && self.values() == other.values()
+    }
+}
+
+fn get_priority_queue(value: &AtomVal) -> Result<&PriorityQueue, AtomError> {
+    match **value {
+        AtomType::PriorityQueue(ref queue) => Ok(queue),
+        _ => Err(AtomError::InvalidType("PriorityQueue".to_string(), value.format(true))),
+    }
+}
+
+fn priority_queue(args: &[AtomVal]) -> AtomRet {
+    let mut queue = PriorityQueue::new();
+    for value in args {
+        queue = queue.push(value.clone(), value.clone())?;
+    }
+    Ok(c_priority_queue(queue))
+}
+
+fn is_priority_queue(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(get_priority_queue(&safe_get(args, 0)).is_ok()))
+}
+
+fn pq_push(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    let queue = get_priority_queue(&coll)?;
+    let value = safe_get(args, 1);
+    let key = match args.get(2) {
+        Some(key_fn) => key_fn.apply(std::slice::from_ref(&value))?,
+        None => value.clone(),
+    };
+    Ok(c_priority_queue(queue.push(value, key)?))
+}
+
+fn pq_pop(args: &[AtomVal]) -> AtomRet {
+    Ok(c_priority_queue(get_priority_queue(&safe_get(args, 0))?.pop()))
+}
+
+fn pq_peek(args: &[AtomVal]) -> AtomRet {
+    Ok(get_priority_queue(&safe_get(args, 0))?.peek().cloned().unwrap_or_else(c_nil))
+}
+
+pub fn register(env: &Env) {
+    set_builtin(env,
+                "priority-queue",
+                priority_queue,
+                "(priority-queue & xs) returns a priority queue of xs, each its own key");
+    set_builtin(env,
+                "priority-queue?",
+                is_priority_queue,
+                "(priority-queue? x) returns true if x is a priority queue, false otherwise");
+    set_builtin(env,
+                "pq-push",
+                pq_push,
+                "(pq-push pq x key-fn?) returns pq with x added, keyed by (key-fn x) or else x \
+                 itself");
+    set_builtin(env,
+                "pq-pop",
+                pq_pop,
+                "(pq-pop pq) returns pq without the value with the least key, an empty pq stays \
+                 empty");
+    set_builtin(env,
+                "pq-peek",
+                pq_peek,
+                "(pq-peek pq) returns the value with the least key in pq, nil if it's empty");
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{c_int, c_priority_queue, c_string};
+    use super::PriorityQueue;
+
+    #[test]
+    fn test_priority_queue() {
+        let mut queue = PriorityQueue::new();
+        for &(value, key) in &[("c", 3), ("a", 1), ("b", 2), ("a2", 1)] {
+            queue = queue.push(c_string(value), c_int(key)).unwrap();
+        }
+        assert_eq!(queue.peek(), Some(&c_string("a")));
+        assert_eq!(queue.values(),
+                   vec![c_string("a"), c_string("a2"), c_string("b"), c_string("c")]);
+
+        let popped = queue.pop().pop();
+        assert_eq!(popped.peek(), Some(&c_string("b")));
+        // the older version is unchanged
+        assert_eq!(queue.len(), 4);
+        assert_eq!(PriorityQueue::new().pop().peek(), None);
+        assert!(queue.push(c_int(1), c_priority_queue(PriorityQueue::new())).is_err());
+    }
+}
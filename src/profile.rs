@@ -0,0 +1,133 @@
+use std::cell::{Cell, RefCell};
+use fnv::FnvHashMap;
+
+// Samples of the lisp-level call stack taken every `interval` evaluation steps, counted per
+// distinct stack. Functions called while a builtin runs are evaluated by a nested machine, the
+// stacks of the machines waiting for the builtin are kept in `suspended`.
+struct Profiler {
+    interval: u64,
+    countdown: u64,
+    suspended: Vec<Vec<String>>,
+    samples: FnvHashMap<String, u64>,
+}
+
+thread_local! {
+    // checked on every step, so kept apart from the rest
+    static PROFILING: Cell<bool> = Cell::new(false);
+    static PROFILER: RefCell<Profiler> = RefCell::new(Profiler {
+        interval: 1,
+        countdown: 1,
+        suspended: vec![],
+        samples: FnvHashMap::default(),
+    });
+}
+
+pub fn is_profiling() -> bool {
+    PROFILING.with(|profiling| profiling.get())
+}
+
+// Drops the samples of an earlier run
+pub fn start(interval: u64) {
+    let interval = if interval == 0 { 1 } else { interval };
+    PROFILER.with(|profiler| {
+        let mut profiler = profiler.borrow_mut();
+        profiler.interval = interval;
+        profiler.countdown = interval;
+        profiler.suspended.clear();
+        profiler.samples.clear();
+    });
+    PROFILING.with(|profiling| profiling.set(true));
+}
+
+// The samples in the folded format flamegraph tools read, one `rulsp;outer;inner count` line
+// per stack
+pub fn stop() -> String {
+    PROFILING.with(|profiling| profiling.set(false));
+    let mut lines = PROFILER.with(|profiler| {
+        profiler.borrow_mut()
+            .samples
+            .drain()
+            .map(|(stack, count)| format!("{} {}", stack, count))
+            .collect::<Vec<_>>()
+    });
+    lines.sort();
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+// Called on every step with the functions running in the current machine, outermost first
+pub fn step<F>(current: F)
+    where F: FnOnce() -> Vec<String>
+{
+    let due = PROFILER.with(|profiler| {
+        let mut profiler = profiler.borrow_mut();
+        profiler.countdown -= 1;
+        if profiler.countdown == 0 {
+            profiler.countdown = profiler.interval;
+            true
+        } else {
+            false
+        }
+    });
+    if !due {
+        return;
+    }
+
+    let current = current();
+    PROFILER.with(|profiler| {
+        let mut profiler = profiler.borrow_mut();
+        let mut stack = vec!["rulsp".to_string()];
+        for names in &profiler.suspended {
+            stack.extend(names.iter().map(|name| name.replace(';', ":")));
+        }
+        stack.extend(current.iter().map(|name| name.replace(';', ":")));
+        *profiler.samples.entry(stack.join(";")).or_insert(0) += 1;
+    });
+}
+
+// The machine calling a builtin waits with the functions it's running until resume
+pub fn suspend(names: Vec<String>) {
+    PROFILER.with(|profiler| profiler.borrow_mut().suspended.push(names));
+}
+
+pub fn resume() {
+    PROFILER.with(|profiler| profiler.borrow_mut().suspended.pop());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{start, stop};
+    use core;
+    use eval::eval_str;
+
+    #[test]
+    fn test_profile() {
+        let env = core::build();
+        eval_str("(defn double (x) (* x 2))\n\
+                  (defn twice (x) (double (double x)))\n\
+                  (defn via-comp (x) ((comp twice) x))",
+                 &env)
+            .unwrap();
+
+        start(1);
+        eval_str("(via-comp 1)", &env).unwrap();
+        let folded = stop();
+        let stacks = folded.lines()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect::<Vec<_>>();
+        assert!(stacks.contains(&"rulsp"));
+        assert!(stacks.contains(&"rulsp;via-comp"));
+        assert!(stacks.contains(&"rulsp;via-comp;twice"));
+        assert!(stacks.contains(&"rulsp;via-comp;twice;double"));
+        assert!(stacks.iter().all(|stack| stack.starts_with("rulsp")));
+
+        // every step is sampled
+        let samples = folded.lines()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum::<u64>();
+        assert!(samples > 10);
+
+        start(1000000);
+        eval_str("(via-comp 1)", &env).unwrap();
+        assert_eq!(stop(), "");
+    }
+}
@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::fmt;
+use fnv::FnvHashMap;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomKey, c_keyword};
+
+// The type keyword generic methods dispatch on
+pub fn type_of(value: &AtomVal) -> AtomVal {
+    let name = match **value {
+        AtomType::Nil => "nil",
+        AtomType::Bool(_) => "bool",
+        AtomType::Int(_) => "int",
+        AtomType::Str(_) => "string",
+        AtomType::Symbol(..) => "symbol",
+        AtomType::Keyword(_) => "keyword",
+        AtomType::List(..) => "list",
+        AtomType::Vector(_) => "vector",
+        AtomType::Map(_) => "map",
+        AtomType::Queue(_) => "queue",
+        AtomType::SortedMap(_) => "sorted-map",
+        AtomType::SortedSet(_) => "sorted-set",
+        AtomType::PriorityQueue(_) => "priority-queue",
+        AtomType::Transient(_) => "transient",
+        AtomType::Env(_) => "env",
+        AtomType::AFunc(ref fd) if fd.is_macro => "macro",
+        AtomType::Func(_) | AtomType::AFunc(_) => "fn",
+        AtomType::Generator(_) => "generator",
+        AtomType::Continuation(_) => "continuation",
+        #[cfg(feature = "std")]
+        AtomType::Handle(_) => "handle",
+        AtomType::Protocol(_) => "protocol",
+        AtomType::Method(_) => "method",
+        AtomType::MultiFn(_) => "multimethod",
+        AtomType::RecordType(_) => "record-type",
+        AtomType::WeakRef(_) => "weak-ref",
+        AtomType::Regex(_) => "regex",
+        AtomType::Record(ref record) => return c_keyword(record.rtype().name()),
+    };
+
+    c_keyword(name)
+}
+
+// Named set of methods, created by defprotocol
+pub struct Protocol {
+    name: String,
+    methods: Vec<AtomVal>,
+}
+
+impl Protocol {
+    pub fn new(name: &str, methods: Vec<AtomVal>) -> Protocol {
+        Protocol {
+            name: name.to_string(),
+            methods: methods,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn method(&self, name: &str) -> Option<&Method> {
+        self.methods.iter().filter_map(|method| {
+            match **method {
+                AtomType::Method(ref method) if method.name == name => Some(method),
+                _ => None,
+            }
+        }).next()
+    }
+
+    // true if every method is implemented for the type of value
+    pub fn satisfied_by(&self, value: &AtomVal) -> bool {
+        let type_key = AtomKey::new(type_of(value)).unwrap();
+        let default_key = AtomKey::new(c_keyword("default")).unwrap();
+        self.methods.iter().all(|method| {
+            match **method {
+                AtomType::Method(ref method) => {
+                    let impls = method.impls.borrow();
+                    impls.contains_key(&type_key) || impls.contains_key(&default_key)
+                }
+                _ => false,
+            }
+        })
+    }
+}
+
+// Generic function with one implementation per type, selected by its first argument
+pub struct Method {
+    name: String,
+    protocol: String,
+    impls: RefCell<FnvHashMap<AtomKey, AtomVal>>,
+}
+
+impl Method {
+    pub fn new(name: &str, protocol: &str) -> Method {
+        Method {
+            name: name.to_string(),
+            protocol: protocol.to_string(),
+            impls: RefCell::new(FnvHashMap::default()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Implements the method for values of type_key, :default covers all other types
+    pub fn extend(&self, type_key: AtomVal, func: AtomVal) -> Result<(), AtomError> {
+        self.impls.borrow_mut().insert(AtomKey::new(type_key)?, func);
+        Ok(())
+    }
+
+    // The implementation to apply to args
+    pub fn dispatch(&self, args: &[AtomVal]) -> AtomRet {
+        let type_key = match args.get(0) {
+            Some(value) => type_of(value),
+            None => {
+                return Err(AtomError::InvalidArgument(format!("{} needs at least one argument",
+                                                              self.name)))
+            }
+        };
+
+        let impls = self.impls.borrow();
+        impls.get(&AtomKey::new(type_key.clone())?)
+            .or_else(|| impls.get(&AtomKey::new(c_keyword("default")).unwrap()))
+            .cloned()
+            .ok_or_else(|| {
+                AtomError::InvalidOperation(format!("no implementation of {}/{} for {}",
+                                                    self.protocol,
+                                                    self.name,
+                                                    type_key))
+            })
+    }
+}
+
+impl fmt::Debug for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<protocol {}>", self.name)
+    }
+}
+
+impl fmt::Debug for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<method {}/{}>", self.protocol, self.name)
+    }
+}
+
+// Protocols, methods and multimethods are only equal to themselves
+impl PartialEq for Protocol {
+    fn eq(&self, other: &Protocol) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialEq for Method {
+    fn eq(&self, other: &Method) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// Method of a multimethod applies if its dispatch value equals the computed one, a vector
+// dispatch value may use :default to match anything in its place
+fn dispatch_matches(key: &AtomVal, value: &AtomVal) -> bool {
+    if key == value {
+        return true;
+    }
+
+    match (&**key, &**value) {
+        (&AtomType::Vector(ref keys), &AtomType::Vector(ref values)) => {
+            keys.len() == values.len() &&
+            keys.iter().zip(values.iter()).all(|(k, v)| k.is_keyword("default") || k == v)
+        }
+        _ => false,
+    }
+}
+
+// Generic function dispatching on the result of a user supplied function, created by defmulti
+pub struct MultiFn {
+    name: String,
+    dispatch: AtomVal,
+    methods: RefCell<Vec<(AtomVal, AtomVal)>>,
+    prefers: RefCell<Vec<(AtomVal, AtomVal)>>,
+}
+
+impl MultiFn {
+    pub fn new(name: &str, dispatch: AtomVal) -> MultiFn {
+        MultiFn {
+            name: name.to_string(),
+            dispatch: dispatch,
+            methods: RefCell::new(vec![]),
+            prefers: RefCell::new(vec![]),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dispatch_fn(&self) -> &AtomVal {
+        &self.dispatch
+    }
+
+    // Replaces the method for the same dispatch value
+    pub fn add_method(&self, key: AtomVal, func: AtomVal) {
+        let mut methods = self.methods.borrow_mut();
+        match methods.iter().position(|method| method.0 == key) {
+            Some(index) => methods[index].1 = func,
+            None => methods.push((key, func)),
+        }
+    }
+
+    // Makes preferred win over other when both match a dispatch value
+    pub fn prefer(&self, preferred: AtomVal, other: AtomVal) {
+        self.prefers.borrow_mut().push((preferred, other));
+    }
+
+    fn is_preferred(&self, key: &AtomVal, over: &AtomVal) -> bool {
+        self.prefers.borrow().iter().any(|prefer| &prefer.0 == key && &prefer.1 == over)
+    }
+
+    // The method for a computed dispatch value: an exact match, the one matching method
+    // left after applying preferences, or the :default method
+    pub fn select(&self, value: &AtomVal) -> AtomRet {
+        let methods = self.methods.borrow();
+
+        if let Some(method) = methods.iter().find(|method| &method.0 == value) {
+            return Ok(method.1.clone());
+        }
+
+        let candidates = methods.iter()
+            .filter(|method| dispatch_matches(&method.0, value))
+            .collect::<Vec<_>>();
+        let best = candidates.iter()
+            .filter(|method| {
+                !candidates.iter().any(|other| self.is_preferred(&other.0, &method.0))
+            })
+            .collect::<Vec<_>>();
+
+        match best.len() {
+            1 => Ok(best[0].1.clone()),
+            0 if candidates.is_empty() => {
+                methods.iter()
+                    .find(|method| method.0.is_keyword("default"))
+                    .map(|method| method.1.clone())
+                    .ok_or_else(|| {
+                        AtomError::InvalidOperation(format!("no method of {} for dispatch value \
+                                                             {}",
+                                                            self.name,
+                                                            value))
+                    })
+            }
+            _ => {
+                Err(AtomError::InvalidOperation(format!("multiple methods of {} match dispatch \
+                                                         value {}, use prefer-method",
+                                                        self.name,
+                                                        value)))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MultiFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<multimethod {}>", self.name)
+    }
+}
+
+impl PartialEq for MultiFn {
+    fn eq(&self, other: &MultiFn) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
@@ -0,0 +1,304 @@
+// Persistent double-ended queue: a front stack in order and a back stack reversed, both shared
+// between versions. Neither runs empty while the other holds more than one value, when one
+// would, the other is split in half and the older half turned around, so adding and taking
+// at either end is amortized O(1) and peeking is O(1).
+use std::rc::Rc;
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_bool, c_list, c_nil, c_queue};
+use env::Env;
+use core::{safe_get, set_builtin};
+
+pub struct Node {
+    value: AtomVal,
+    next: Link,
+}
+
+type Link = Option<Rc<Node>>;
+
+impl Node {
+    pub fn value(&self) -> &AtomVal {
+        &self.value
+    }
+
+    pub fn next(&self) -> Option<&Rc<Node>> {
+        self.next.as_ref()
+    }
+}
+
+// Long chains are unlinked one node at a time rather than dropped recursively
+impl Drop for Node {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(node) = next {
+            next = match Rc::try_unwrap(node) {
+                Ok(mut node) => node.next.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Stack {
+    top: Link,
+    len: usize,
+}
+
+impl Stack {
+    fn empty() -> Stack {
+        Stack { top: None, len: 0 }
+    }
+
+    // The last of values ends up on top
+    fn of(values: &[AtomVal]) -> Stack {
+        values.iter().fold(Stack::empty(), |stack, value| stack.push(value.clone()))
+    }
+
+    fn push(&self, value: AtomVal) -> Stack {
+        let node = Node {
+            value: value,
+            next: self.top.clone(),
+        };
+        Stack {
+            top: Some(Rc::new(node)),
+            len: self.len + 1,
+        }
+    }
+
+    fn peek(&self) -> Option<&AtomVal> {
+        self.top.as_ref().map(|node| &node.value)
+    }
+
+    fn pop(&self) -> Stack {
+        match self.top {
+            Some(ref node) => {
+                Stack {
+                    top: node.next.clone(),
+                    len: self.len - 1,
+                }
+            }
+            None => self.clone(),
+        }
+    }
+
+    // From the top down
+    fn values(&self) -> Vec<AtomVal> {
+        let mut values = Vec::with_capacity(self.len);
+        let mut link = &self.top;
+        while let Some(ref node) = *link {
+            values.push(node.value.clone());
+            link = &node.next;
+        }
+        values
+    }
+}
+
+#[derive(Clone)]
+pub struct Queue {
+    front: Stack,
+    back: Stack,
+}
+
+impl Queue {
+    pub fn new(values: &[AtomVal]) -> Queue {
+        Queue::balanced(Stack::empty(), Stack::of(values))
+    }
+
+    fn balanced(front: Stack, back: Stack) -> Queue {
+        if (front.len == 0 && back.len > 1) || (back.len == 0 && front.len > 1) {
+            let mut values = front.values();
+            values.extend(back.values().into_iter().rev());
+            let half = values.len() / 2;
+            let older = values[..half].iter().rev().cloned().collect::<Vec<_>>();
+            return Queue {
+                front: Stack::of(&older),
+                back: Stack::of(&values[half..]),
+            };
+        }
+        Queue {
+            front: front,
+            back: back,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.front.len + self.back.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push_back(&self, value: AtomVal) -> Queue {
+        Queue::balanced(self.front.clone(), self.back.push(value))
+    }
+
+    pub fn push_front(&self, value: AtomVal) -> Queue {
+        Queue::balanced(self.front.push(value), self.back.clone())
+    }
+
+    pub fn peek_front(&self) -> Option<&AtomVal> {
+        self.front.peek().or_else(|| self.back.peek())
+    }
+
+    pub fn peek_back(&self) -> Option<&AtomVal> {
+        self.back.peek().or_else(|| self.front.peek())
+    }
+
+    // An empty queue stays empty
+    pub fn pop_front(&self) -> Queue {
+        if self.front.len == 0 {
+            return Queue::balanced(self.front.clone(), self.back.pop());
+        }
+        Queue::balanced(self.front.pop(), self.back.clone())
+    }
+
+    pub fn pop_back(&self) -> Queue {
+        if self.back.len == 0 {
+            return Queue::balanced(self.front.pop(), self.back.clone());
+        }
+        Queue::balanced(self.front.clone(), self.back.pop())
+    }
+
+    // The top nodes of both stacks, which versions share with each other
+    pub fn tops(&self) -> Vec<Rc<Node>> {
+        self.front.top.iter().chain(self.back.top.iter()).cloned().collect()
+    }
+
+    // From the front to the back
+    pub fn values(&self) -> Vec<AtomVal> {
+        let mut values = self.front.values();
+        values.extend(self.back.values().into_iter().rev());
+        values
+    }
+}
+
+impl PartialEq for Queue {
+    fn eq(&self, other: &Queue) -> bool {
+        self.len() == other.len() && self.values() == other.values()
+    }
+}
+
+fn get_queue(value: &AtomVal) -> Result<&Queue, AtomError> {
+    match **value {
+        AtomType::Queue(ref queue) => Ok(queue),
+        _ => Err(AtomError::InvalidType("Queue".to_string(), value.format(true))),
+    }
+}
+
+fn queue(args: &[AtomVal]) -> AtomRet {
+    Ok(c_queue(Queue::new(args)))
+}
+
+fn is_queue(args: &[AtomVal]) -> AtomRet {
+    Ok(c_bool(get_queue(&safe_get(args, 0)).is_ok()))
+}
+
+fn enqueue(args: &[AtomVal]) -> AtomRet {
+    Ok(c_queue(get_queue(&safe_get(args, 0))?.push_back(safe_get(args, 1))))
+}
+
+fn enqueue_front(args: &[AtomVal]) -> AtomRet {
+    Ok(c_queue(get_queue(&safe_get(args, 0))?.push_front(safe_get(args, 1))))
+}
+
+fn dequeue(args: &[AtomVal]) -> AtomRet {
+    Ok(c_queue(get_queue(&safe_get(args, 0))?.pop_front()))
+}
+
+fn dequeue_back(args: &[AtomVal]) -> AtomRet {
+    Ok(c_queue(get_queue(&safe_get(args, 0))?.pop_back()))
+}
+
+fn peek(args: &[AtomVal]) -> AtomRet {
+    Ok(get_queue(&safe_get(args, 0))?.peek_front().cloned().unwrap_or_else(c_nil))
+}
+
+fn peek_back(args: &[AtomVal]) -> AtomRet {
+    Ok(get_queue(&safe_get(args, 0))?.peek_back().cloned().unwrap_or_else(c_nil))
+}
+
+fn queue_to_list(args: &[AtomVal]) -> AtomRet {
+    Ok(c_list(get_queue(&safe_get(args, 0))?.values()))
+}
+
+pub fn register(env: &Env) {
+    set_builtin(env, "queue", queue, "(queue & xs) returns a queue of xs, the first at the front");
+    set_builtin(env,
+                "queue?",
+                is_queue,
+                "(queue? x) returns true if x is a queue, false otherwise");
+    set_builtin(env, "enqueue", enqueue, "(enqueue q x) returns q with x added at the back");
+    set_builtin(env,
+                "enqueue-front",
+                enqueue_front,
+                "(enqueue-front q x) returns q with x added at the front");
+    set_builtin(env,
+                "dequeue",
+                dequeue,
+                "(dequeue q) returns q without the value at the front, an empty q stays empty");
+    set_builtin(env,
+                "dequeue-back",
+                dequeue_back,
+                "(dequeue-back q) returns q without the value at the back, an empty q stays empty");
+    set_builtin(env,
+                "peek",
+                peek,
+                "(peek q) returns the value at the front of q, nil if it's empty");
+    set_builtin(env,
+                "peek-back",
+                peek_back,
+                "(peek-back q) returns the value at the back of q, nil if it's empty");
+    set_builtin(env,
+                "queue->list",
+                queue_to_list,
+                "(queue->list q) returns the values in q as a list, from front to back");
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{AtomVal, c_int};
+    use super::Queue;
+
+    fn range(n: i64) -> Vec<AtomVal> {
+        (0..n).map(c_int).collect()
+    }
+
+    #[test]
+    fn test_queue() {
+        let queue = Queue::new(&range(3));
+        assert_eq!(queue.peek_front(), Some(&c_int(0)));
+        assert_eq!(queue.peek_back(), Some(&c_int(2)));
+
+        let taken = queue.pop_front().pop_front();
+        assert_eq!(taken.values(), vec![c_int(2)]);
+        assert_eq!(taken.peek_front(), Some(&c_int(2)));
+        // the older version is unchanged
+        assert_eq!(queue.values(), range(3));
+
+        let mut deque = Queue::new(&[]);
+        for i in 0..10 {
+            deque = deque.push_front(c_int(i)).push_back(c_int(i));
+        }
+        assert_eq!(deque.len(), 20);
+        for i in (0..10).rev() {
+            assert_eq!(deque.peek_front(), Some(&c_int(i)));
+            assert_eq!(deque.peek_back(), Some(&c_int(i)));
+            deque = deque.pop_back().pop_front();
+        }
+        assert_eq!(deque.len(), 0);
+        assert_eq!(deque.pop_front().peek_back(), None);
+    }
+
+    #[test]
+    fn test_long_queue() {
+        let mut queue = Queue::new(&[]);
+        for i in 0..200000 {
+            queue = queue.push_back(c_int(i));
+        }
+        for _ in 0..100000 {
+            queue = queue.pop_front();
+        }
+        assert_eq!(queue.peek_front(), Some(&c_int(100000)));
+        assert!(queue == Queue::new(&range(200000)[100000..]));
+    }
+}
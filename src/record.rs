@@ -0,0 +1,119 @@
+use std::fmt;
+use std::rc::Rc;
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_nil};
+
+// Named aggregate type, created by defrecord
+#[derive(Debug)]
+pub struct RecordType {
+    name: String,
+    fields: Vec<String>,
+}
+
+impl RecordType {
+    pub fn new(name: &str, fields: Vec<String>) -> RecordType {
+        RecordType {
+            name: name.to_string(),
+            fields: fields,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn field_index(&self, field: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f == field)
+    }
+}
+
+// Record types are only equal to themselves
+impl PartialEq for RecordType {
+    fn eq(&self, other: &RecordType) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+pub struct Record {
+    rtype: Rc<RecordType>,
+    values: Vec<AtomVal>,
+}
+
+impl Record {
+    // (->Name field-values...)
+    pub fn positional(rtype: &Rc<RecordType>, args: &[AtomVal]) -> Result<Record, AtomError> {
+        if args.len() != rtype.fields.len() {
+            return Err(AtomError::InvalidArgument(format!("->{} expects {} arguments, got {}",
+                                                          rtype.name,
+                                                          rtype.fields.len(),
+                                                          args.len())));
+        }
+
+        Ok(Record {
+            rtype: rtype.clone(),
+            values: args.to_vec(),
+        })
+    }
+
+    // (map->Name :field value ...), missing fields are nil
+    pub fn from_pairs(rtype: &Rc<RecordType>,
+                      pairs: &[(AtomVal, AtomVal)])
+                      -> Result<Record, AtomError> {
+        let mut values = vec![c_nil(); rtype.fields.len()];
+
+        for &(ref key, ref value) in pairs {
+            let index = match **key {
+                AtomType::Keyword(ref field) => rtype.field_index(field),
+                _ => None,
+            };
+            match index {
+                Some(index) => values[index] = value.clone(),
+                None => {
+                    return Err(AtomError::InvalidArgument(format!("{} has no field {}",
+                                                                  rtype.name,
+                                                                  key)))
+                }
+            }
+        }
+
+        Ok(Record {
+            rtype: rtype.clone(),
+            values: values,
+        })
+    }
+
+    pub fn rtype(&self) -> &Rc<RecordType> {
+        &self.rtype
+    }
+
+    pub fn get(&self, field: &str) -> Option<&AtomVal> {
+        self.rtype.field_index(field).map(|index| &self.values[index])
+    }
+
+    // fields and values in declaration order
+    pub fn fields<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a String, &'a AtomVal)> + 'a> {
+        Box::new(self.rtype.fields.iter().zip(self.values.iter()))
+    }
+
+    // The value of the field accessor fn
+    pub fn accessor(rtype: &Rc<RecordType>, index: usize, args: &[AtomVal]) -> AtomRet {
+        match args.get(0).map(|record| &**record) {
+            Some(&AtomType::Record(ref record)) if Rc::ptr_eq(&record.rtype, rtype) => {
+                Ok(record.values[index].clone())
+            }
+            Some(other) => Err(AtomError::InvalidType(rtype.name.clone(), other.format(true))),
+            None => Err(AtomError::InvalidType(rtype.name.clone(), "Nil()".to_string())),
+        }
+    }
+}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{:?}", self.rtype.name, self.values)
+    }
+}
+
+impl PartialEq for Record {
+    fn eq(&self, other: &Record) -> bool {
+        Rc::ptr_eq(&self.rtype, &other.rtype) && self.values == other.values
+    }
+}
@@ -0,0 +1,263 @@
+// A REPL over TCP for editors and other processes: messages are JSON objects preceded by their
+// length as four big-endian bytes, requests name an op and get a reply echoing their id. All
+// connections evaluate in the one session, one request at a time.
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use analysis::completions;
+use interpreter::Interpreter;
+use output::{capture_output, take_output};
+use json::{self, Json, object, string};
+
+// Bigger messages are refused rather than allocated for
+const MAX_MESSAGE: usize = 16 << 20;
+
+const OPS: &'static [&'static str] = &["auth", "eval", "complete", "describe"];
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string().into_bytes();
+    let len = body.len();
+    output.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    output.write_all(&body)?;
+    output.flush()
+}
+
+// The next message, None at the end of input
+pub fn read_message<R: Read>(input: &mut R) -> io::Result<Option<Json>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = len.iter().fold(0, |len, &byte| len << 8 | byte as usize);
+    if len > MAX_MESSAGE {
+        return Err(invalid(format!("message of {} bytes is too long", len)));
+    }
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    let text = String::from_utf8(body).map_err(|_| invalid("message isn't UTF-8".to_string()))?;
+    json::parse(&text).map(Some).map_err(invalid)
+}
+
+fn reply(request: &Json, mut members: Vec<(&str, Json)>) -> Json {
+    members.insert(0, ("id", request.get("id").clone()));
+    object(members)
+}
+
+fn failure(request: &Json, err: &str) -> Json {
+    reply(request, vec![("status", string("error")), ("err", string(err))])
+}
+
+// Compares without stopping at the first difference, so timing doesn't give the token away
+fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len() &&
+    expected.bytes().zip(given.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+pub struct Session {
+    interpreter: Interpreter,
+}
+
+impl Session {
+    pub fn new(interpreter: Interpreter) -> Session {
+        capture_output(true);
+        Session { interpreter: interpreter }
+    }
+
+    pub fn handle(&mut self, request: &Json) -> Json {
+        match request.get("op").as_str() {
+            Some("eval") => self.eval(request),
+            Some("complete") => {
+                let prefix = request.get("prefix").as_str().unwrap_or("");
+                let names = completions(self.interpreter.env(), prefix).into_iter();
+                reply(request,
+                      vec![("status", string("done")),
+                           ("completions", Json::Array(names.map(Json::Str).collect()))])
+            }
+            Some("describe") => {
+                let ops = OPS.iter().map(|op| string(op)).collect();
+                reply(request,
+                      vec![("status", string("done")),
+                           ("version", string(env!("CARGO_PKG_VERSION"))),
+                           ("ops", Json::Array(ops))])
+            }
+            // authenticating again on an authenticated connection changes nothing
+            Some("auth") => reply(request, vec![("status", string("done"))]),
+            Some(op) => failure(request, &format!("unknown op {}", op)),
+            None => failure(request, "request without an op"),
+        }
+    }
+
+    // The value comes back like the REPL shows it, what evaluating printed as out
+    fn eval(&mut self, request: &Json) -> Json {
+        let code = match request.get("code").as_str() {
+            Some(code) => code,
+            None => return failure(request, "eval needs code"),
+        };
+        take_output();
+        let result = self.interpreter.run("<remote>", code);
+        let out = Json::Str(take_output());
+        match result {
+            Ok(value) => {
+                reply(request,
+                      vec![("status", string("done")),
+                           ("value", Json::Str(value.to_string())),
+                           ("out", out)])
+            }
+            Err(report) => {
+                reply(request,
+                      vec![("status", string("error")),
+                           ("err", Json::Str(report.render(false))),
+                           ("message", Json::Str(report.message.clone())),
+                           ("out", out)])
+            }
+        }
+    }
+}
+
+// A request and the connection to reply on
+struct Request {
+    message: Json,
+    reply_to: TcpStream,
+}
+
+// Reads the requests of one connection; with a token, the first one has to be an auth op
+// carrying it or the connection is closed
+fn connection(mut stream: TcpStream, token: Option<String>, requests: Sender<Request>)
+              -> io::Result<()> {
+    let mut authorized = token.is_none();
+    while let Some(message) = read_message(&mut stream)? {
+        if !authorized {
+            let given = message.get("token").as_str().unwrap_or("");
+            if message.get("op").as_str() != Some("auth") ||
+               !same_token(token.as_ref().unwrap(), given) {
+                return write_message(&mut stream, &failure(&message, "unauthorized"));
+            }
+            authorized = true;
+        }
+        let request = Request {
+            message: message,
+            reply_to: stream.try_clone()?,
+        };
+        if requests.send(request).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// The listener for --listen, a bare port is on localhost only
+pub fn listen(address: &str) -> io::Result<TcpListener> {
+    match address.parse::<u16>() {
+        Ok(port) => TcpListener::bind(("127.0.0.1", port)),
+        Err(_) => TcpListener::bind(address),
+    }
+}
+
+// Evaluates what the connections to listener send with interpreter, for as long as it runs
+pub fn serve(listener: TcpListener, interpreter: Interpreter, token: Option<String>) {
+    let (requests, incoming) = mpsc::channel();
+    thread::spawn(move || for stream in listener.incoming() {
+        let (token, requests) = (token.clone(), requests.clone());
+        thread::spawn(move || {
+            let peer = stream.as_ref().ok().and_then(|stream| stream.peer_addr().ok());
+            let result = stream.and_then(|stream| connection(stream, token, requests));
+            if let Err(err) = result {
+                warn!("action=remote peer={:?} dropped={}", peer, err);
+            }
+        });
+    });
+
+    let mut session = Session::new(interpreter);
+    for mut request in incoming {
+        let response = session.handle(&request.message);
+        if let Err(err) = write_message(&mut request.reply_to, &response) {
+            warn!("action=remote reply={}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use interpreter::Interpreter;
+    use json::{self, Json};
+    use super::{Session, connection, read_message, write_message};
+
+    fn message(text: &str) -> Json {
+        json::parse(text).unwrap()
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut out = vec![];
+        write_message(&mut out, &message(r#"{"op": "eval", "code": "(+ 1 2)"}"#)).unwrap();
+        assert_eq!(&out[..4], &[0, 0, 0, out.len() as u8 - 4]);
+        write_message(&mut out, &message(r#"{"op": "describe"}"#)).unwrap();
+
+        let mut input = Cursor::new(out);
+        assert_eq!(read_message(&mut input).unwrap().unwrap().get("code").as_str(),
+                   Some("(+ 1 2)"));
+        assert_eq!(read_message(&mut input).unwrap().unwrap().get("op").as_str(),
+                   Some("describe"));
+        assert!(read_message(&mut input).unwrap().is_none());
+        assert!(read_message(&mut Cursor::new(vec![0xff, 0, 0, 0])).is_err());
+    }
+
+    #[test]
+    fn test_session() {
+        let mut session = Session::new(Interpreter::new());
+        let defined = session.handle(&message(r#"{"id": 1, "op": "eval",
+                                                  "code": "(defn twice (x) (* 2 x))"}"#));
+        assert_eq!(defined.get("id").as_usize(), Some(1));
+        assert_eq!(defined.get("status").as_str(), Some("done"));
+
+        let reply = session.handle(&message(r#"{"op": "eval",
+                                                "code": "(println \"hi\") (twice 21)"}"#));
+        assert_eq!(reply.get("value").as_str(), Some("42"));
+        assert_eq!(reply.get("out").as_str(), Some("hi\n"));
+
+        let reply = session.handle(&message(r#"{"op": "eval", "code": "(nope)"}"#));
+        assert_eq!(reply.get("status").as_str(), Some("error"));
+        assert!(reply.get("err").as_str().unwrap().contains("nope"));
+
+        let reply = session.handle(&message(r#"{"op": "complete", "prefix": "twi"}"#));
+        assert_eq!(reply.get("completions"), &message(r#"["twice"]"#));
+        let reply = session.handle(&message(r#"{"op": "frobnicate"}"#));
+        assert_eq!(reply.get("err").as_str(), Some("unknown op frobnicate"));
+    }
+
+    #[test]
+    fn test_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests, incoming) = mpsc::channel();
+        thread::spawn(move || for stream in listener.incoming().take(2) {
+            connection(stream.unwrap(), Some("s3cret".to_string()), requests.clone()).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write_message(&mut stream, &message(r#"{"op": "eval", "code": "1"}"#)).unwrap();
+        let refused = read_message(&mut stream).unwrap().unwrap();
+        assert_eq!(refused.get("err").as_str(), Some("unauthorized"));
+        assert!(read_message(&mut stream).unwrap().is_none());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        write_message(&mut stream, &message(r#"{"op": "auth", "token": "s3cret"}"#)).unwrap();
+        write_message(&mut stream, &message(r#"{"op": "eval", "code": "1"}"#)).unwrap();
+        drop(stream);
+        let ops = incoming.iter()
+            .map(|request| request.message.get("op").as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ops, vec!["auth", "eval"]);
+    }
+}
@@ -0,0 +1,386 @@
+use std::cell::RefCell;
+use data::{AtomVal, AtomType, AtomError, AtomKey, c_keyword};
+use debug;
+use lexer::{Lexer, Position, Token};
+use parser::{Cst, ParseError, Span, parse_cst};
+
+// Forms shown in the call trace at most
+const MAX_TRACE: usize = 8;
+
+// Characters of a source line shown at most, longer ones are cut around what's underlined
+const MAX_LINE: usize = 100;
+
+thread_local! {
+    // The error being unwound and the forms pending when it went through each machine,
+    // innermost first
+    static TRACE: RefCell<Option<(AtomError, Vec<AtomVal>)>> = RefCell::new(None);
+}
+
+// Called by a machine giving up on err, nested machines abort before the ones waiting for them
+pub fn record_trace(err: &AtomError, forms: Vec<AtomVal>) {
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        match *trace {
+            Some((ref last, ref mut pending)) if last == err => {
+                pending.extend(forms);
+                return;
+            }
+            _ => {}
+        }
+        *trace = Some((err.clone(), forms));
+    })
+}
+
+pub fn clear_trace() {
+    TRACE.with(|trace| *trace.borrow_mut() = None);
+}
+
+// The forms pending when err was raised, empty if it was never unwound
+fn take_trace(err: &AtomError) -> Vec<AtomVal> {
+    TRACE.with(|trace| match trace.borrow_mut().take() {
+        Some((last, pending)) if last == *err => pending,
+        _ => vec![],
+    })
+}
+
+// An error as shown to people: where it is in the source, how evaluation got there and maybe
+// what was meant instead
+#[derive(Debug)]
+pub struct Report {
+    pub file: String,
+    pub message: String,
+    // line number, the line and the columns to underline
+    pub snippet: Option<(usize, String, usize, usize)>,
+    pub trace: Vec<String>,
+    pub hint: Option<String>,
+}
+
+impl Report {
+    // An error without a place in the source, like a file that can't be read
+    pub fn new(file: &str, message: String) -> Report {
+        Report {
+            file: file.to_string(),
+            message: message,
+            snippet: None,
+            trace: vec![],
+            hint: None,
+        }
+    }
+
+    pub fn from_parse_error(file: &str, source: &str, err: &ParseError) -> Report {
+        let mut report = Report::new(file, err.to_string());
+        report.snippet = err.position().map(|position| snippet(source, position, 1));
+        report
+    }
+
+    // err raised evaluating the top-level form at span, names are the ones bound where it was
+    // evaluated, to suggest instead of undefined symbols. The innermost form pending when err
+    // was raised which can be found in the source is shown, else the top-level form
+    pub fn from_error(file: &str,
+                      source: &str,
+                      span: Option<Span>,
+                      err: &AtomError,
+                      names: &[String])
+                      -> Report {
+        let mut report = Report::new(file, message(err));
+        let pending = take_trace(err);
+        report.trace = pending.iter().map(debug::summary).collect();
+        report.trace.dedup();
+        if let AtomError::UndefinedSymbol(ref name) = *err {
+            report.hint = closest(name, names).map(|name| format!("did you mean `{}`?", name));
+        }
+
+        let span = match span {
+            Some(span) => span,
+            None => return report,
+        };
+        report.snippet = match *err {
+            AtomError::UndefinedSymbol(ref name) => {
+                find_symbol(source, span, name)
+                    .map(|position| snippet(source, position, name.chars().count()))
+            }
+            // the asserted form, wherever in the source it is
+            AtomError::Condition(ref condition) => {
+                field(condition, "data")
+                    .and_then(|data| field(&data, "form"))
+                    .and_then(|form| find_form(source, &form))
+                    .map(|span| snippet(source, span.start, first_line(source, span)))
+            }
+            _ => None,
+        };
+        if report.snippet.is_none() {
+            let innermost = pending.iter()
+                .filter_map(|form| find_form_in(source, Some(span), form))
+                .next()
+                .or_else(|| pending.iter().filter_map(|form| find_form(source, form)).next())
+                .unwrap_or(span);
+            report.snippet = Some(snippet(source, innermost.start, first_line(source, innermost)));
+        }
+        report
+    }
+
+    // Like `file:line:col: error: message`, then the line with the span underlined, with ANSI
+    // colors if color
+    pub fn render(&self, color: bool) -> String {
+        let paint = |text: &str, code: &str| if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        };
+
+        let mut out = String::new();
+        match self.snippet {
+            Some((line, _, column, _)) => {
+                out += &format!("{}:{}:{}: ", self.file, line, column);
+            }
+            None => out += &format!("{}: ", self.file),
+        }
+        out += &format!("{} {}\n", paint("error:", "1;31"), paint(&self.message, "1"));
+
+        if let Some((line, ref text, column, width)) = self.snippet {
+            let (text, column, width) = excerpt(text, column, width);
+            let number = line.to_string();
+            let gutter = " ".repeat(number.len());
+            out += &format!("{} {}\n", gutter, paint("|", "1;34"));
+            out += &format!("{} {} {}\n", paint(&number, "1;34"), paint("|", "1;34"), text);
+            // tabs are kept so the caret lines up in the terminal
+            let indent = text.chars()
+                .take(column - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            out += &format!("{} {} {}{}\n",
+                            gutter,
+                            paint("|", "1;34"),
+                            indent,
+                            paint(&"^".repeat(width.max(1)), "1;31"));
+        }
+
+        for form in self.trace.iter().take(MAX_TRACE) {
+            out += &format!("  {} in {}\n", paint("=", "1;34"), form);
+        }
+        if self.trace.len() > MAX_TRACE {
+            out += &format!("  {} ... {} more\n",
+                            paint("=", "1;34"),
+                            self.trace.len() - MAX_TRACE);
+        }
+        if let Some(ref hint) = self.hint {
+            out += &format!("  {} {}\n", paint("= help:", "1;32"), hint);
+        }
+        out
+    }
+}
+
+fn field(condition: &AtomVal, name: &str) -> Option<AtomVal> {
+    condition.get_map().ok().and_then(|map| map.get(&AtomKey::new(c_keyword(name)).ok()?).cloned())
+}
+
+// Conditions with a message are shown as `type: message`
+fn message(err: &AtomError) -> String {
+    if let AtomError::Condition(ref condition) = *err {
+        if let Some(message) = field(condition, "message") {
+            if let AtomType::Str(ref message) = *message {
+                let kind = field(condition, "type").map_or("error".to_string(), |kind| {
+                    kind.to_string().trim_start_matches(':').to_string()
+                });
+                return format!("{}: {}", kind, message);
+            }
+        }
+    }
+    err.to_string()
+}
+
+// Characters of the text at span on its first line, to underline
+fn first_line(source: &str, span: Span) -> usize {
+    source[span.start.offset..span.end.offset]
+        .trim_end()
+        .lines()
+        .next()
+        .unwrap_or("")
+        .chars()
+        .count()
+}
+
+// Where in source a list reading as form is first
+fn find_form(source: &str, form: &AtomVal) -> Option<Span> {
+    find_form_in(source, None, form)
+}
+
+// Like find_form, only looking inside within if it's given
+fn find_form_in(source: &str, within: Option<Span>, form: &AtomVal) -> Option<Span> {
+    if form.get_list().is_err() {
+        return None;
+    }
+    let nodes = parse_cst(source).ok()?;
+    let inside = |span: Span| {
+        within.map_or(true,
+                      |within| within.start.offset <= span.start.offset &&
+                               span.end.offset <= within.end.offset)
+    };
+    let mut found = None;
+    for node in &nodes {
+        node.walk(&mut |node| if found.is_none() {
+            if let Cst::Seq(span, Token::Oparen, _) = *node {
+                if inside(span) &&
+                   node.ast().ok().and_then(|ast| ast).map_or(false, |ast| ast == *form) {
+                    found = Some(span);
+                }
+            }
+        });
+    }
+    found
+}
+
+// text cut down to MAX_LINE characters around the width underlined from column, with ... where
+// it's cut, and column and width in what's left
+fn excerpt(text: &str, column: usize, width: usize) -> (String, usize, usize) {
+    let chars = text.chars().collect::<Vec<_>>();
+    if chars.len() <= MAX_LINE {
+        return (text.to_string(), column, width);
+    }
+    // a little of what comes before the underlined part is kept
+    let start = (column - 1).saturating_sub(MAX_LINE / 5).min(chars.len() - MAX_LINE);
+    let end = start + MAX_LINE;
+    let width = width.min(end.saturating_sub(column - 1));
+    let mut excerpt = String::new();
+    if start > 0 {
+        excerpt.push_str("...");
+    }
+    excerpt.extend(&chars[start..end]);
+    if end < chars.len() {
+        excerpt.push_str("...");
+    }
+    let column = column - start + if start > 0 { 3 } else { 0 };
+    (excerpt, column, width)
+}
+
+// The line position is on, with width characters from there underlined unless that would go
+// past its end
+fn snippet(source: &str, position: Position, width: usize) -> (usize, String, usize, usize) {
+    let text = source.lines().nth(position.line - 1).unwrap_or("").to_string();
+    let rest = text.chars().count().saturating_sub(position.column - 1);
+    (position.line, text, position.column, width.min(rest))
+}
+
+// Where the symbol is first used in the form at span
+fn find_symbol(source: &str, span: Span, name: &str) -> Option<Position> {
+    let form = &source[span.start.offset..span.end.offset];
+    Lexer::new(form)
+        .take_while(|token| token.is_ok())
+        .filter_map(|token| token.ok())
+        .find(|&(_, ref token)| *token == Token::Identifier(name.to_string()))
+        .map(|(position, _)| {
+            Position {
+                line: span.start.line + position.line - 1,
+                column: if position.line == 1 {
+                    span.start.column + position.column - 1
+                } else {
+                    position.column
+                },
+                offset: span.start.offset + position.offset,
+            }
+        })
+}
+
+// Insertions, deletions, replacements and swaps of neighbours needed to turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..a.len() + 1 {
+        d[i][0] = i;
+    }
+    for j in 0..b.len() + 1 {
+        d[0][j] = j;
+    }
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+// The name closest to name, if it's close enough to be a typo: a third of its length
+// rounded up, but at most 3 edits away
+pub fn closest<'a>(name: &str, names: &'a [String]) -> Option<&'a String> {
+    let limit = name.chars().count().div_ceil(3).min(3);
+    names.iter()
+        .filter(|candidate| candidate.as_str() != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Report, closest};
+    use interpreter::Interpreter;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_closest() {
+        let names = names(&["count", "cons", "conj", "first", "map"]);
+        assert_eq!(closest("cuont", &names).unwrap(), "count");
+        assert_eq!(closest("fisrt", &names).unwrap(), "first");
+        assert_eq!(closest("conss", &names).unwrap(), "cons");
+        assert_eq!(closest("mapp", &names).unwrap(), "map");
+        assert_eq!(closest("xyz", &names), None);
+        assert_eq!(closest("filter", &names), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let interpreter = Interpreter::new();
+        let report = interpreter.run("t.clrs", "(def xs [1 2])\n(+ 1\n   (cuont xs))").unwrap_err();
+        assert_eq!(report.render(false),
+                   "t.clrs:3:5: error: undefined symbol: cuont\n  \
+                    |\n\
+                    3 |    (cuont xs))\n  \
+                    |     ^^^^^\n  \
+                    = in (cuont xs)\n  \
+                    = in (+ 1 (cuont xs))\n  \
+                    = help: did you mean `count`?\n");
+        assert!(report.render(true).contains("\x1b[1;31m^^^^^\x1b[0m"));
+
+        let source = "(defn f (x) (list (g x)))\n(defn g (x) (list (nth x 0)))\n(f 1)";
+        let report = interpreter.run("t.clrs", source).unwrap_err();
+        assert_eq!(report.snippet,
+                   Some((2, "(defn g (x) (list (nth x 0)))".to_string(), 19, 9)));
+        assert_eq!(report.trace, vec!["(nth x 0)", "(list (nth x 0))", "(list (g x))"]);
+        assert!(report.render(false).ends_with("  = in (list (nth x 0))\n  = in (list (g x))\n"));
+
+        // the same form elsewhere doesn't take the place of the one which failed
+        let source = "(defn h () (nth 5 0))\n(list 1\n      (list (nth 5 0)))";
+        let report = interpreter.run("t.clrs", source).unwrap_err();
+        assert_eq!(report.snippet, Some((3, "      (list (nth 5 0)))".to_string(), 13, 9)));
+
+        let source = format!("(list {} (nth 5 0) {})", "1 ".repeat(100), "2 ".repeat(100));
+        let report = interpreter.run("t.clrs", &source).unwrap_err();
+        let rendered = report.render(false);
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "t.clrs:1:208: error: expected: sequence, received: Int(5)");
+        assert_eq!(lines[2].len(), "1 | ".len() + 3 + 100 + 3);
+        assert!(lines[2].starts_with("1 | ...") && lines[2].ends_with(" 2 2 ..."));
+        assert_eq!(lines[2].find("(nth").unwrap(), lines[3].find('^').unwrap());
+        assert!(lines[3].ends_with("^^^^^^^^^"));
+
+        let report = interpreter.run("t.clrs", "1\n  (+ 1 2").unwrap_err();
+        assert_eq!(report.snippet.map(|snippet| snippet.0), Some(2));
+
+        let source = "(defn check (n)\n  (assert (= n 3)))\n(check 5)";
+        let report = interpreter.run("t.clrs", source).unwrap_err();
+        assert_eq!(report.message, "assertion-error: assert failed: (= n 3), n = 5");
+        assert_eq!(report.snippet, Some((2, "  (assert (= n 3)))".to_string(), 11, 7)));
+
+        let report = Report::new("missing.clrs", "no such file".to_string());
+        assert_eq!(report.render(false), "missing.clrs: error: no such file\n");
+    }
+}
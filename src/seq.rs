@@ -0,0 +1,58 @@
+// Collections seen as sequences, what count, nth, rest and so first, map, filter and reduce walk:
+// nil is empty, strings give their characters, maps their entries as [key value] vectors and
+// queues their values in the order they'd be taken out.
+use data::{AtomError, AtomType, AtomVal, c_string, c_vector};
+use sorted;
+
+pub fn elements(value: &AtomVal) -> Result<Vec<AtomVal>, AtomError> {
+    if let Some(elements) = sorted::elements(value) {
+        return Ok(elements);
+    }
+    match **value {
+        AtomType::Nil => Ok(vec![]),
+        AtomType::Str(ref s) => Ok(s.chars().map(|c| c_string(&c.to_string())).collect()),
+        AtomType::List(ref seq, _) => Ok(seq.clone()),
+        AtomType::Vector(ref seq) => Ok(seq.to_vec()),
+        AtomType::Map(ref map) => {
+            Ok(map.iter().map(|(k, v)| c_vector(vec![k.value().clone(), v.clone()])).collect())
+        }
+        AtomType::Queue(ref queue) => Ok(queue.values()),
+        AtomType::PriorityQueue(ref queue) => Ok(queue.values()),
+        _ => Err(AtomError::InvalidType("sequence".to_string(), value.format(true))),
+    }
+}
+
+pub fn is_seqable(value: &AtomVal) -> bool {
+    match **value {
+        AtomType::Nil |
+        AtomType::Str(_) |
+        AtomType::List(..) |
+        AtomType::Vector(_) |
+        AtomType::Map(_) |
+        AtomType::SortedMap(_) |
+        AtomType::SortedSet(_) |
+        AtomType::Queue(_) |
+        AtomType::PriorityQueue(_) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{c_int, c_keyword, c_list, c_nil, c_string, c_vector};
+    use interpreter::Interpreter;
+    use super::{elements, is_seqable};
+
+    #[test]
+    fn test_elements() {
+        assert_eq!(elements(&c_nil()).unwrap(), vec![]);
+        assert_eq!(elements(&c_string("hé")).unwrap(),
+                   vec![c_string("h"), c_string("é")]);
+        assert_eq!(elements(&c_list(vec![c_int(1)])).unwrap(), vec![c_int(1)]);
+        let map = Interpreter::new().eval("{:a 1}").unwrap();
+        assert_eq!(elements(&map).unwrap(),
+                   vec![c_vector(vec![c_keyword("a"), c_int(1)])]);
+        assert!(!is_seqable(&c_int(1)));
+        assert!(elements(&c_int(1)).is_err());
+    }
+}
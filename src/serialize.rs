@@ -0,0 +1,226 @@
+// Values in serde's data model: nil is unit, lists, vectors, queues, priority queues and sorted
+// sets are sequences, maps, sorted maps and records are maps, symbols and keywords the string
+// of their name. Deserializing gives lists for sequences and strings for strings, floats and
+// the like are refused.
+use std::fmt;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+use fnv::FnvHashMap;
+use data::{AtomKey, AtomType, AtomVal, c_bool, c_int, c_list, c_map, c_nil, c_string};
+
+impl Serialize for AtomType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            AtomType::Nil => serializer.serialize_unit(),
+            AtomType::Bool(b) => serializer.serialize_bool(b),
+            AtomType::Int(num) => serializer.serialize_i64(num),
+            AtomType::Str(ref string) => serializer.serialize_str(string),
+            AtomType::Symbol(ref name, _) |
+            AtomType::Keyword(ref name) => serializer.serialize_str(name),
+            AtomType::List(ref seq, _) => {
+                let mut out = serializer.serialize_seq(Some(seq.len()))?;
+                for item in seq {
+                    out.serialize_element(&**item)?;
+                }
+                out.end()
+            }
+            AtomType::Vector(ref seq) => {
+                let mut out = serializer.serialize_seq(Some(seq.len()))?;
+                for item in seq {
+                    out.serialize_element(&**item)?;
+                }
+                out.end()
+            }
+            AtomType::Queue(ref queue) => {
+                let values = queue.values();
+                let mut out = serializer.serialize_seq(Some(values.len()))?;
+                for item in &values {
+                    out.serialize_element(&**item)?;
+                }
+                out.end()
+            }
+            AtomType::PriorityQueue(ref queue) => {
+                let values = queue.values();
+                let mut out = serializer.serialize_seq(Some(values.len()))?;
+                for item in &values {
+                    out.serialize_element(&**item)?;
+                }
+                out.end()
+            }
+            AtomType::Map(ref map) => {
+                let mut out = serializer.serialize_map(Some(map.len()))?;
+                for (key, value) in map {
+                    out.serialize_entry(&**key.value(), &**value)?;
+                }
+                out.end()
+            }
+            AtomType::SortedMap(ref map) => {
+                let mut out = serializer.serialize_map(Some(map.len()))?;
+                for (key, value) in map {
+                    out.serialize_entry(&**key.value(), &**value)?;
+                }
+                out.end()
+            }
+            AtomType::SortedSet(ref set) => {
+                let mut out = serializer.serialize_seq(Some(set.len()))?;
+                for key in set {
+                    out.serialize_element(&**key.value())?;
+                }
+                out.end()
+            }
+            AtomType::Record(ref record) => {
+                let fields = record.fields().collect::<Vec<_>>();
+                let mut out = serializer.serialize_map(Some(fields.len()))?;
+                for (name, value) in fields {
+                    out.serialize_entry(name, &**value)?;
+                }
+                out.end()
+            }
+            _ => Err(ser::Error::custom(format!("{} can't be serialized", self.format(true)))),
+        }
+    }
+}
+
+// A value serde can construct too, since atoms have to be allocated by rulsp
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atom(pub AtomVal);
+
+impl Serialize for Atom {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (*self.0).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Atom {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Atom, D::Error> {
+        deserializer.deserialize_any(AtomVisitor).map(Atom)
+    }
+}
+
+struct AtomVisitor;
+
+impl<'de> Visitor<'de> for AtomVisitor {
+    type Value = AtomVal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("nil, a boolean, an integer, a string, a sequence or a map")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<AtomVal, E> {
+        Ok(c_nil())
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<AtomVal, E> {
+        Ok(c_nil())
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<AtomVal, D::Error> {
+        deserialize(deserializer)
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<AtomVal, E> {
+        Ok(c_bool(b))
+    }
+
+    fn visit_i64<E: de::Error>(self, num: i64) -> Result<AtomVal, E> {
+        Ok(c_int(num))
+    }
+
+    fn visit_u64<E: de::Error>(self, num: u64) -> Result<AtomVal, E> {
+        if num > i64::max_value() as u64 {
+            return Err(E::custom(format!("{} is too big for an integer", num)));
+        }
+        Ok(c_int(num as i64))
+    }
+
+    fn visit_str<E: de::Error>(self, string: &str) -> Result<AtomVal, E> {
+        Ok(c_string(string))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<AtomVal, A::Error> {
+        let mut items = vec![];
+        while let Some(Atom(item)) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(c_list(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut entries: A) -> Result<AtomVal, A::Error> {
+        let mut map = FnvHashMap::default();
+        while let Some((Atom(key), Atom(value))) = entries.next_entry()? {
+            map.insert(AtomKey::new(key).map_err(de::Error::custom)?, value);
+        }
+        Ok(c_map(map))
+    }
+}
+
+// For #[serde(with = "rulsp::serialize")] on AtomVal fields
+pub fn serialize<S: Serializer>(value: &AtomVal, serializer: S) -> Result<S::Ok, S::Error> {
+    (**value).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<AtomVal, D::Error> {
+    deserializer.deserialize_any(AtomVisitor)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_test::{Token, assert_de_tokens, assert_de_tokens_error, assert_ser_tokens,
+                     assert_ser_tokens_error, assert_tokens};
+    use fnv::FnvHashMap;
+    use data::{AtomKey, c_bool, c_int, c_keyword, c_list, c_map, c_nil, c_string, c_symbol,
+               c_vector};
+    use interpreter::Interpreter;
+    use super::Atom;
+
+    #[test]
+    fn test_round_trip() {
+        assert_tokens(&Atom(c_list(vec![c_int(1), c_string("a"), c_nil()])),
+                      &[Token::Seq { len: Some(3) },
+                        Token::I64(1),
+                        Token::Str("a"),
+                        Token::Unit,
+                        Token::SeqEnd]);
+        let mut map = FnvHashMap::default();
+        map.insert(AtomKey::new(c_string("k")).unwrap(), c_bool(true));
+        assert_tokens(&Atom(c_map(map)),
+                      &[Token::Map { len: Some(1) },
+                        Token::Str("k"),
+                        Token::Bool(true),
+                        Token::MapEnd]);
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_ser_tokens(&Atom(c_vector(vec![c_keyword("a"), c_symbol("b")])),
+                          &[Token::Seq { len: Some(2) },
+                            Token::Str("a"),
+                            Token::Str("b"),
+                            Token::SeqEnd]);
+        let record = Interpreter::new()
+            .eval("(defrecord Point (x y)) (->Point 1 2)")
+            .unwrap();
+        assert_ser_tokens(&Atom(record),
+                          &[Token::Map { len: Some(2) },
+                            Token::Str("x"),
+                            Token::I64(1),
+                            Token::Str("y"),
+                            Token::I64(2),
+                            Token::MapEnd]);
+        let inc = Interpreter::new().eval("inc").unwrap();
+        assert_ser_tokens_error(&Atom(inc.clone()),
+                                &[],
+                                &format!("{} can't be serialized", inc.format(true)));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        assert_de_tokens(&Atom(c_nil()), &[Token::None]);
+        assert_de_tokens(&Atom(c_int(7)), &[Token::Some, Token::U8(7)]);
+        assert_de_tokens_error::<Atom>(&[Token::U64(u64::max_value())],
+                                       "18446744073709551615 is too big for an integer");
+        assert_de_tokens_error::<Atom>(&[Token::F64(1.5)],
+                                       "invalid type: floating point `1.5`, expected nil, a \
+                                        boolean, an integer, a string, a sequence or a map");
+    }
+}
@@ -0,0 +1,99 @@
+// The text of strings, which substrings share: a range of bytes in a buffer that's never
+// changed, so taking part of a string copies nothing but keeps all of the buffer alive.
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+
+#[derive(Clone)]
+pub struct SharedStr {
+    buffer: Rc<str>,
+    start: usize,
+    end: usize,
+}
+
+impl SharedStr {
+    pub fn new(text: &str) -> SharedStr {
+        SharedStr {
+            buffer: Rc::from(text),
+            start: 0,
+            end: text.len(),
+        }
+    }
+
+    // The bytes from start up to end of this string, None unless both are char boundaries
+    pub fn slice(&self, start: usize, end: usize) -> Option<SharedStr> {
+        if start > end || !self.is_char_boundary(start) || !self.is_char_boundary(end) {
+            return None;
+        }
+        Some(SharedStr {
+            buffer: self.buffer.clone(),
+            start: self.start + start,
+            end: self.start + end,
+        })
+    }
+}
+
+impl Deref for SharedStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.buffer[self.start..self.end]
+    }
+}
+
+impl PartialEq for SharedStr {
+    fn eq(&self, other: &SharedStr) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for SharedStr {}
+
+impl PartialOrd for SharedStr {
+    fn partial_cmp(&self, other: &SharedStr) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SharedStr {
+    fn cmp(&self, other: &SharedStr) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for SharedStr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Display for SharedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl fmt::Debug for SharedStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedStr;
+
+    #[test]
+    fn test_slice() {
+        let text = SharedStr::new("héllo wörld");
+        let world = text.slice(7, 13).unwrap();
+        assert_eq!(&*world, "wörld");
+        assert_eq!(&*world.slice(1, 3).unwrap(), "ö");
+        assert!(world == SharedStr::new("wörld"));
+        assert!(text.slice(2, 3).is_none());
+        assert!(text.slice(3, 2).is_none());
+        assert!(world.slice(0, 7).is_none());
+    }
+}
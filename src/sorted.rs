@@ -0,0 +1,195 @@
+// Sorted maps and sets, kept in BTrees. Keys are ordered nil first, then booleans, integers,
+// strings, keywords, symbols, lists and vectors, each kind among itself in its natural order,
+// lists and vectors element by element; other values can't be keys.
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, Bound};
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_nil, c_sorted_map, c_sorted_set, c_vector};
+use env::Env;
+use core::set_builtin;
+
+fn rank(value: &AtomVal) -> Option<u8> {
+    match **value {
+        AtomType::Nil => Some(0),
+        AtomType::Bool(_) => Some(1),
+        AtomType::Int(_) => Some(2),
+        AtomType::Str(_) => Some(3),
+        AtomType::Keyword(_) => Some(4),
+        AtomType::Symbol(..) => Some(5),
+        AtomType::List(ref seq, _) if orderable(seq) => Some(6),
+        AtomType::Vector(ref seq) if orderable(seq) => Some(7),
+        _ => None,
+    }
+}
+
+fn orderable<'a, I: IntoIterator<Item = &'a AtomVal>>(seq: I) -> bool {
+    seq.into_iter().all(|v| rank(v).is_some())
+}
+
+fn compare_seqs<'a, I: ExactSizeIterator<Item = &'a AtomVal>>(a: I, b: I) -> Ordering {
+    let lens = a.len().cmp(&b.len());
+    a.zip(b)
+        .map(|(a, b)| compare(a, b))
+        .find(|&ordering| ordering != Ordering::Equal)
+        .unwrap_or(lens)
+}
+
+// Total over the values rank accepts
+pub fn compare(a: &AtomVal, b: &AtomVal) -> Ordering {
+    match (&**a, &**b) {
+        (&AtomType::Bool(a), &AtomType::Bool(b)) => a.cmp(&b),
+        (&AtomType::Int(a), &AtomType::Int(b)) => a.cmp(&b),
+        (&AtomType::Str(ref a), &AtomType::Str(ref b)) => a.cmp(b),
+        (&AtomType::Keyword(ref a), &AtomType::Keyword(ref b)) |
+        (&AtomType::Symbol(ref a, _), &AtomType::Symbol(ref b, _)) => a.cmp(b),
+        (&AtomType::List(ref a, _), &AtomType::List(ref b, _)) => compare_seqs(a.iter(), b.iter()),
+        (&AtomType::Vector(ref a), &AtomType::Vector(ref b)) => compare_seqs(a.iter(), b.iter()),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SortKey(AtomVal);
+
+impl SortKey {
+    pub fn new(value: AtomVal) -> Result<SortKey, AtomError> {
+        match rank(&value) {
+            Some(_) => Ok(SortKey(value)),
+            None => Err(AtomError::InvalidType("orderable value".to_string(), value.format(true))),
+        }
+    }
+
+    pub fn value(&self) -> &AtomVal {
+        &self.0
+    }
+}
+
+impl PartialEq for SortKey {
+    fn eq(&self, other: &SortKey) -> bool {
+        compare(&self.0, &other.0) == Ordering::Equal
+    }
+}
+
+impl Eq for SortKey {}
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &SortKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortKey {
+    fn cmp(&self, other: &SortKey) -> Ordering {
+        compare(&self.0, &other.0)
+    }
+}
+
+// The elements in order, entries of a map as [key value] vectors; None for other values
+pub fn elements(value: &AtomVal) -> Option<Vec<AtomVal>> {
+    match **value {
+        AtomType::SortedMap(ref map) => {
+            Some(map.iter().map(|(k, v)| c_vector(vec![k.value().clone(), v.clone()])).collect())
+        }
+        AtomType::SortedSet(ref set) => Some(set.iter().map(|k| k.value().clone()).collect()),
+        _ => None,
+    }
+}
+
+fn sorted_map(args: &[AtomVal]) -> AtomRet {
+    if !args.len().is_multiple_of(2) {
+        return Err(AtomError::InvalidArgument("sorted-map needs keys and values in pairs"
+            .to_string()));
+    }
+    let mut map = BTreeMap::new();
+    for pair in args.chunks(2) {
+        map.insert(SortKey::new(pair[0].clone())?, pair[1].clone());
+    }
+    Ok(c_sorted_map(map))
+}
+
+fn sorted_set(args: &[AtomVal]) -> AtomRet {
+    let mut set = BTreeSet::new();
+    for value in args {
+        set.insert(SortKey::new(value.clone())?);
+    }
+    Ok(c_sorted_set(set))
+}
+
+// nil leaves that end of the range open
+fn bound(value: Option<&AtomVal>, inclusive: bool) -> Result<Bound<SortKey>, AtomError> {
+    match value {
+        None => Ok(Bound::Unbounded),
+        Some(value) if **value == AtomType::Nil => Ok(Bound::Unbounded),
+        Some(value) if inclusive => SortKey::new(value.clone()).map(Bound::Included),
+        Some(value) => SortKey::new(value.clone()).map(Bound::Excluded),
+    }
+}
+
+// (subrange coll from to), BTree ranges panic when they'd be backwards
+fn subrange(args: &[AtomVal]) -> AtomRet {
+    let from = bound(args.get(1), true)?;
+    let to = bound(args.get(2), false)?;
+    let empty = match (&from, &to) {
+        (&Bound::Included(ref from), &Bound::Excluded(ref to)) => from >= to,
+        _ => false,
+    };
+    let coll = args.get(0).cloned().unwrap_or_else(c_nil);
+    match *coll {
+        AtomType::SortedMap(ref map) => {
+            if empty {
+                return Ok(c_sorted_map(BTreeMap::new()));
+            }
+            let range = map.range((from, to)).map(|(k, v)| (k.clone(), v.clone())).collect();
+            Ok(c_sorted_map(range))
+        }
+        AtomType::SortedSet(ref set) => {
+            if empty {
+                return Ok(c_sorted_set(BTreeSet::new()));
+            }
+            Ok(c_sorted_set(set.range((from, to)).cloned().collect()))
+        }
+        _ => Err(AtomError::InvalidType("sorted map or set".to_string(), coll.format(true))),
+    }
+}
+
+pub fn register(env: &Env) {
+    set_builtin(env,
+                "sorted-map",
+                sorted_map,
+                "(sorted-map & kvs) returns a map of the key value pairs kvs, kept sorted by key");
+    set_builtin(env,
+                "sorted-set",
+                sorted_set,
+                "(sorted-set & xs) returns a set of xs, kept sorted");
+    set_builtin(env,
+                "subrange",
+                subrange,
+                "(subrange coll from to) returns the entries of the sorted map or set coll with \
+                 keys from from up to but not including to, a nil from or to leaves that end open");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use data::{c_bool, c_int, c_keyword, c_list, c_nil, c_string, c_vector};
+    use interpreter::Interpreter;
+    use super::{SortKey, compare};
+
+    #[test]
+    fn test_compare() {
+        let ordered = vec![c_nil(),
+                           c_bool(false),
+                           c_bool(true),
+                           c_int(2),
+                           c_int(10),
+                           c_string("a"),
+                           c_keyword("a"),
+                           c_list(vec![c_int(1)]),
+                           c_list(vec![c_int(1), c_int(0)]),
+                           c_vector(vec![])];
+        for pair in ordered.windows(2) {
+            assert_eq!(compare(&pair[0], &pair[1]), Ordering::Less);
+            assert_eq!(compare(&pair[1], &pair[0]), Ordering::Greater);
+        }
+        assert!(SortKey::new(Interpreter::new().eval("{}").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::cell::{Cell, RefCell};
+use data::{ATOM_TYPES, ATOM_TYPE_NAMES, interned_symbols};
+
+// Counters behind (runtime-stats) and Interpreter::stats
+thread_local! {
+    static LIVE_ATOMS: RefCell<[usize; ATOM_TYPES]> = RefCell::new([0; ATOM_TYPES]);
+    static ALLOCATED_ATOMS: RefCell<[u64; ATOM_TYPES]> = RefCell::new([0; ATOM_TYPES]);
+    static LIVE_ENVS: Cell<usize> = Cell::new(0);
+    static MAX_ENV_DEPTH: Cell<usize> = Cell::new(0);
+    static EVAL_STEPS: Cell<u64> = Cell::new(0);
+    static APPLICATIONS: Cell<u64> = Cell::new(0);
+    // collections run and envs they freed
+    static GC: Cell<(u64, u64)> = Cell::new((0, 0));
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    // live atoms per type name, see AtomType::type_name
+    pub atoms: Vec<(&'static str, usize)>,
+    // atoms ever allocated per type name
+    pub allocated: Vec<(&'static str, u64)>,
+    pub envs: usize,
+    // most envs nested in each other, the global env is at depth 0
+    pub max_env_depth: usize,
+    pub interned_symbols: usize,
+    pub eval_steps: u64,
+    pub applications: u64,
+    pub gc_runs: u64,
+    pub gc_freed: u64,
+}
+
+pub fn atom_allocated(index: usize) {
+    LIVE_ATOMS.with(|live| live.borrow_mut()[index] += 1);
+    ALLOCATED_ATOMS.with(|allocated| allocated.borrow_mut()[index] += 1);
+}
+
+// Atoms may outlive the counters while threads shut down
+pub fn atom_freed(index: usize) {
+    let _ = LIVE_ATOMS.try_with(|live| {
+        let mut live = live.borrow_mut();
+        live[index] = live[index].saturating_sub(1);
+    });
+}
+
+pub fn env_allocated(depth: usize) {
+    LIVE_ENVS.with(|live| live.set(live.get() + 1));
+    MAX_ENV_DEPTH.with(|max| if depth > max.get() {
+        max.set(depth);
+    });
+}
+
+pub fn env_freed() {
+    let _ = LIVE_ENVS.try_with(|live| live.set(live.get().saturating_sub(1)));
+}
+
+pub fn eval_step() {
+    EVAL_STEPS.with(|steps| steps.set(steps.get() + 1));
+}
+
+pub fn function_applied() {
+    APPLICATIONS.with(|applications| applications.set(applications.get() + 1));
+}
+
+pub fn gc_collected(freed: usize) {
+    GC.with(|gc| {
+        let (runs, total) = gc.get();
+        gc.set((runs + 1, total + freed as u64));
+    });
+}
+
+pub fn snapshot() -> Stats {
+    let live = LIVE_ATOMS.with(|live| *live.borrow());
+    let allocated = ALLOCATED_ATOMS.with(|allocated| *allocated.borrow());
+    let (gc_runs, gc_freed) = GC.with(|gc| gc.get());
+
+    Stats {
+        atoms: ATOM_TYPE_NAMES.iter().cloned().zip(live.iter().cloned()).collect(),
+        allocated: ATOM_TYPE_NAMES.iter().cloned().zip(allocated.iter().cloned()).collect(),
+        envs: LIVE_ENVS.with(|live| live.get()),
+        max_env_depth: MAX_ENV_DEPTH.with(|max| max.get()),
+        interned_symbols: interned_symbols(),
+        eval_steps: EVAL_STEPS.with(|steps| steps.get()),
+        applications: APPLICATIONS.with(|applications| applications.get()),
+        gc_runs: gc_runs,
+        gc_freed: gc_freed,
+    }
+}
+
+// What happened between two snapshots, for rulsp run --stats. The max env depth is the
+// deepest so far, the live counts are the ones at the end.
+pub fn report(before: &Stats, after: &Stats) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("eval steps: {}\n", after.eval_steps - before.eval_steps));
+    out.push_str(&format!("function applications: {}\n",
+                          after.applications - before.applications));
+    out.push_str(&format!("max env depth: {}\n", after.max_env_depth));
+    out.push_str(&format!("live envs: {}\n", after.envs));
+    out.push_str(&format!("interned symbols: {} ({} new)\n",
+                          after.interned_symbols,
+                          after.interned_symbols.saturating_sub(before.interned_symbols)));
+    out.push_str(&format!("gc: {} runs, {} envs freed\n",
+                          after.gc_runs - before.gc_runs,
+                          after.gc_freed - before.gc_freed));
+
+    let mut allocated = after.allocated
+        .iter()
+        .zip(before.allocated.iter())
+        .zip(after.atoms.iter())
+        .map(|((&(name, total), &(_, earlier)), &(_, live))| (name, total - earlier, live))
+        .filter(|&(_, count, _)| count > 0)
+        .collect::<Vec<_>>();
+    allocated.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    out.push_str("allocations:\n");
+    for (name, count, live) in allocated {
+        out.push_str(&format!("  {}: {} ({} live)\n", name, count, live));
+    }
+    out
+}
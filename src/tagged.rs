@@ -0,0 +1,200 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use fnv::FnvHashMap;
+use data::{AtomVal, AtomType, AtomRet, AtomError, c_int, c_string};
+
+// Tags and the functions constructing the value of #tag form from the form,
+// see Interpreter::set_tag_handler
+pub type TagHandlers = Rc<RefCell<FnvHashMap<String, AtomVal>>>;
+
+// The value of #tag form for the tags every reader knows
+pub fn builtin(tag: &str, form: &AtomVal) -> Option<AtomRet> {
+    match tag {
+        "inst" => Some(inst(form)),
+        "uuid" => Some(uuid(form)),
+        _ => None,
+    }
+}
+
+fn tag_string<'a>(tag: &str, form: &'a AtomVal) -> Result<&'a str, AtomError> {
+    match **form {
+        AtomType::Str(ref s) => Ok(s),
+        ref other => {
+            Err(AtomError::InvalidType(format!("string after #{}", tag), other.format(true)))
+        }
+    }
+}
+
+// #inst "2017-03-04T05:06:07.890+01:00" reads as milliseconds since the epoch like (now),
+// the time, its fraction and the offset may be left out
+fn inst(form: &AtomVal) -> AtomRet {
+    let s = tag_string("inst", form)?;
+    parse_timestamp(s)
+        .map(c_int)
+        .ok_or_else(|| AtomError::InvalidArgument(format!("invalid timestamp {:?}", s)))
+}
+
+fn is_digit(b: &u8) -> bool {
+    b'0' <= *b && *b <= b'9'
+}
+
+fn digits(s: &str, from: usize, len: usize) -> Option<i64> {
+    let bytes = s.as_bytes();
+    if bytes.len() < from + len || !bytes[from..from + len].iter().all(is_digit) {
+        return None;
+    }
+    s[from..from + len].parse().ok()
+}
+
+fn byte_at(s: &str, pos: usize) -> Option<u8> {
+    s.as_bytes().get(pos).cloned()
+}
+
+// days from 1970-01-01 to the given date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let year = digits(s, 0, 4)?;
+    let month = if byte_at(s, 4) == Some(b'-') { digits(s, 5, 2)? } else { return None };
+    let day = if byte_at(s, 7) == Some(b'-') { digits(s, 8, 2)? } else { return None };
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    let (mut hour, mut minute, mut second, mut millis) = (0, 0, 0, 0);
+    let mut pos = 10;
+    if byte_at(s, pos) == Some(b'T') {
+        hour = digits(s, 11, 2)?;
+        minute = if byte_at(s, 13) == Some(b':') { digits(s, 14, 2)? } else { return None };
+        pos = 16;
+        if byte_at(s, pos) == Some(b':') {
+            second = digits(s, 17, 2)?;
+            pos = 19;
+            if byte_at(s, pos) == Some(b'.') {
+                let fraction = s.as_bytes()[pos + 1..].iter().take_while(|b| is_digit(b)).count();
+                if fraction == 0 {
+                    return None;
+                }
+                let scale = [100, 10, 1];
+                for i in 0..fraction.min(3) {
+                    millis += digits(s, pos + 1 + i, 1)? * scale[i];
+                }
+                pos += 1 + fraction;
+            }
+        }
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+    }
+
+    let offset = match byte_at(s, pos) {
+        None => 0,
+        Some(b'Z') => {
+            pos += 1;
+            0
+        }
+        Some(sign) if sign == b'+' || sign == b'-' => {
+            let hours = digits(s, pos + 1, 2)?;
+            let minutes = if byte_at(s, pos + 3) == Some(b':') {
+                digits(s, pos + 4, 2)?
+            } else {
+                return None;
+            };
+            pos += 6;
+            let offset = hours * 60 + minutes;
+            if sign == b'+' { offset } else { -offset }
+        }
+        Some(_) => return None,
+    };
+    if pos != s.len() {
+        return None;
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 +
+                  second - offset * 60;
+    Some(seconds * 1000 + millis)
+}
+
+// The date of the proleptic Gregorian calendar the given days after 1970-01-01 are
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Milliseconds since the epoch as read by #inst, like "2017-07-14T02:40:00.000Z"
+pub fn format_timestamp(millis: i64) -> String {
+    let seconds = if millis >= 0 { millis / 1000 } else { (millis - 999) / 1000 };
+    let days = if seconds >= 0 { seconds / 86400 } else { (seconds - 86399) / 86400 };
+    let (year, month, day) = civil_from_days(days);
+    let second_of_day = seconds - days * 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            second_of_day / 3600,
+            second_of_day / 60 % 60,
+            second_of_day % 60,
+            millis - seconds * 1000)
+}
+
+// #uuid "..." reads as the lower case string after checking it's a UUID
+fn uuid(form: &AtomVal) -> AtomRet {
+    let s = tag_string("uuid", form)?;
+    let groups = s.split('-').map(|group| group.len()).collect::<Vec<_>>();
+    if groups != [8, 4, 4, 4, 12] || !s.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+        return Err(AtomError::InvalidArgument(format!("invalid UUID {:?}", s)));
+    }
+    Ok(c_string(&s.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_timestamp, format_timestamp};
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(parse_timestamp("2017-07-14T02:40:00Z"), Some(1500000000000));
+        assert_eq!(parse_timestamp("2017-07-14T04:40:00.5+02:00"), Some(1500000000500));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59.999"), Some(-1));
+        assert_eq!(parse_timestamp("2000-02-29T00:00"), Some(951782400000));
+
+        assert_eq!(parse_timestamp("2001-02-29"), None);
+        assert_eq!(parse_timestamp("2017-07-14T24:00"), None);
+        assert_eq!(parse_timestamp("2017-07-14 02:40"), None);
+        assert_eq!(parse_timestamp("2017-07-14T02:40:00."), None);
+        assert_eq!(parse_timestamp("2017-7-14"), None);
+        assert_eq!(parse_timestamp("2017-07-14T02:40:00+0200"), None);
+
+        assert_eq!(format_timestamp(1500000000500), "2017-07-14T02:40:00.500Z");
+        assert_eq!(format_timestamp(-1), "1969-12-31T23:59:59.999Z");
+        for &millis in &[0, 951782400000, -86400001, 4102444800123] {
+            assert_eq!(parse_timestamp(&format_timestamp(millis)), Some(millis));
+        }
+    }
+}
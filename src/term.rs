@@ -0,0 +1,298 @@
+// Terminal control for interactive scripts: styles and cursor movement are ANSI escape
+// sequences handed back as strings to print, the size and raw key reads go through stty, so
+// there's no terminal library to link.
+#[cfg(feature = "io")]
+use std::cell::RefCell;
+#[cfg(feature = "io")]
+use std::collections::VecDeque;
+#[cfg(feature = "io")]
+use std::io::{self, Read};
+#[cfg(feature = "io")]
+use std::process::{Command, Stdio};
+#[cfg(feature = "io")]
+use std::str;
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_string};
+#[cfg(feature = "io")]
+use data::{c_int, c_keyword, c_nil, c_vector};
+use env::Env;
+use core::set_builtin;
+
+const COLORS: &'static [&'static str] = &["black", "red", "green", "yellow", "blue", "magenta",
+                                          "cyan", "white"];
+
+// The SGR parameter of a style keyword
+fn style_code(name: &str) -> Option<usize> {
+    let color = |name: &str| COLORS.iter().position(|&color| color == name);
+    match name {
+        "bold" => Some(1),
+        "dim" => Some(2),
+        "italic" => Some(3),
+        "underline" => Some(4),
+        "blink" => Some(5),
+        "reverse" => Some(7),
+        _ if name.starts_with("bright-") => color(&name[7..]).map(|n| 90 + n),
+        _ if name.starts_with("bg-") => color(&name[3..]).map(|n| 40 + n),
+        _ => color(name).map(|n| 30 + n),
+    }
+}
+
+// (style s & styles) is s printed between the escape sequences turning styles on and off
+fn style(args: &[AtomVal]) -> AtomRet {
+    let text = args.get(0).map(|value| value.format(false)).unwrap_or_default();
+    if args.len() < 2 {
+        return Ok(c_string(&text));
+    }
+
+    let mut codes = vec![];
+    for style in &args[1..] {
+        let code = match **style {
+            AtomType::Keyword(ref name) => style_code(name),
+            _ => None,
+        };
+        match code {
+            Some(code) => codes.push(code.to_string()),
+            None => return Err(AtomError::InvalidArgument(format!("unknown style {}", style))),
+        }
+    }
+    Ok(c_string(&format!("\x1b[{}m{}\x1b[0m", codes.join(";"), text)))
+}
+
+fn int_arg(args: &[AtomVal], index: usize) -> Result<i64, AtomError> {
+    match args.get(index) {
+        Some(value) => value.get_int(),
+        None => Err(AtomError::InvalidArgument("missing count".to_string())),
+    }
+}
+
+// Rows and columns count from 1 at the top left
+fn cursor_to(args: &[AtomVal]) -> AtomRet {
+    Ok(c_string(&format!("\x1b[{};{}H", int_arg(args, 0)?, int_arg(args, 1)?)))
+}
+
+// Negative rows go up, negative columns left
+fn cursor_move(args: &[AtomVal]) -> AtomRet {
+    let mut out = String::new();
+    for &(count, back, forth) in &[(int_arg(args, 0)?, 'A', 'B'), (int_arg(args, 1)?, 'D', 'C')] {
+        if count != 0 {
+            out.push_str(&format!("\x1b[{}{}", count.abs(), if count < 0 { back } else { forth }));
+        }
+    }
+    Ok(c_string(&out))
+}
+
+fn clear_screen(_args: &[AtomVal]) -> AtomRet {
+    Ok(c_string("\x1b[2J\x1b[H"))
+}
+
+fn clear_line(_args: &[AtomVal]) -> AtomRet {
+    Ok(c_string("\r\x1b[2K"))
+}
+
+// stty run on the terminal stdin is attached to, what it printed if it succeeded
+#[cfg(feature = "io")]
+fn stty(args: &[&str]) -> Option<String> {
+    Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .and_then(|output| if output.status.success() {
+            String::from_utf8(output.stdout).ok()
+        } else {
+            None
+        })
+}
+
+// (terminal-size) is [columns rows], from the terminal or else COLUMNS and LINES, nil if
+// neither knows
+#[cfg(feature = "io")]
+fn terminal_size(_args: &[AtomVal]) -> AtomRet {
+    let from_stty = stty(&["size"]).and_then(|size| {
+        let numbers = size.split_whitespace().filter_map(|n| n.parse().ok()).collect::<Vec<i64>>();
+        // terminals which don't know their size say 0 0
+        if numbers.len() == 2 && numbers[0] > 0 && numbers[1] > 0 {
+            Some((numbers[1], numbers[0]))
+        } else {
+            None
+        }
+    });
+    let from_env = || {
+        let var = |name| ::std::env::var(name).ok().and_then(|value| value.parse::<i64>().ok());
+        var("COLUMNS").zip(var("LINES"))
+    };
+    match from_stty.or_else(from_env) {
+        Some((columns, rows)) => Ok(c_vector(vec![c_int(columns), c_int(rows)])),
+        None => Ok(c_nil()),
+    }
+}
+
+// Terminal settings to go back to once a key is read
+#[cfg(feature = "io")]
+struct RawMode(String);
+
+#[cfg(feature = "io")]
+impl RawMode {
+    // reads give up after a tenth of a second, so escape sequences can be told from Esc
+    fn enter() -> Result<RawMode, AtomError> {
+        let saved = stty(&["-g"]);
+        match saved {
+            Some(ref saved) if stty(&["raw", "-echo", "min", "0", "time", "1"]).is_some() => {
+                Ok(RawMode(saved.trim().to_string()))
+            }
+            _ => Err(AtomError::InvalidOperation("read-key needs a terminal and stty".to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "io")]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.0]);
+    }
+}
+
+#[cfg(feature = "io")]
+thread_local! {
+    // bytes read along with a key, the next keys typed
+    static PENDING: RefCell<VecDeque<u8>> = RefCell::new(VecDeque::new());
+}
+
+// Keys which aren't characters, escape sequences before the Esc they start with
+#[cfg(feature = "io")]
+const KEYS: &'static [(&'static [u8], &'static str)] = &[(b"\x1b[A", "up"),
+                                                         (b"\x1b[B", "down"),
+                                                         (b"\x1b[C", "right"),
+                                                         (b"\x1b[D", "left"),
+                                                         (b"\x1b[H", "home"),
+                                                         (b"\x1b[F", "end"),
+                                                         (b"\x1b[3~", "delete"),
+                                                         (b"\x1b", "escape"),
+                                                         (b"\r", "enter"),
+                                                         (b"\n", "enter"),
+                                                         (b"\t", "tab"),
+                                                         (b"\x7f", "backspace"),
+                                                         (b"\x08", "backspace")];
+
+// The key bytes starts with and how many bytes it took: characters as strings, other keys as
+// keywords like :enter, :up or :ctrl-c
+#[cfg(feature = "io")]
+fn decode(bytes: &[u8]) -> (AtomVal, usize) {
+    for &(sequence, name) in KEYS {
+        if bytes.starts_with(sequence) {
+            return (c_keyword(name), sequence.len());
+        }
+    }
+    if bytes[0] < b' ' {
+        let letter = ((bytes[0] + b'@') as char).to_ascii_lowercase();
+        return (c_keyword(&format!("ctrl-{}", letter)), 1);
+    }
+
+    // the longest prefix of up to four bytes which is one character
+    for len in (1..bytes.len().min(4) + 1).rev() {
+        if let Ok(s) = str::from_utf8(&bytes[..len]) {
+            if s.chars().count() == 1 {
+                return (c_string(s), len);
+            }
+        }
+    }
+    (c_string(&String::from_utf8_lossy(&bytes[..1])), 1)
+}
+
+// (read-key) waits for a key without echoing it, see decode for what it returns
+#[cfg(feature = "io")]
+fn read_key(_args: &[AtomVal]) -> AtomRet {
+    let mut bytes = PENDING.with(|pending| pending.borrow_mut().drain(..).collect::<Vec<_>>());
+    if bytes.is_empty() {
+        let _raw = RawMode::enter()?;
+        let mut buf = [0; 64];
+        while bytes.is_empty() {
+            let read = io::stdin()
+                .read(&mut buf)
+                .map_err(|err| AtomError::InvalidOperation(format!("read-key: {}", err)))?;
+            bytes.extend_from_slice(&buf[..read]);
+        }
+    }
+
+    let (key, len) = decode(&bytes);
+    PENDING.with(|pending| pending.borrow_mut().extend(&bytes[len..]));
+    Ok(key)
+}
+
+pub fn register(env: &Env) {
+    set_builtin(env,
+                "style",
+                style,
+                "(style s & styles) returns s to print in styles like :red, :bg-blue, \
+                 :bright-green, :bold, :dim, :italic, :underline, :blink or :reverse");
+    set_builtin(env,
+                "cursor-to",
+                cursor_to,
+                "(cursor-to row col) returns what to print to move the cursor to row and col, \
+                 counting from 1");
+    set_builtin(env,
+                "cursor-move",
+                cursor_move,
+                "(cursor-move rows cols) returns what to print to move the cursor down rows and \
+                 right cols, up and left for negative ones");
+    set_builtin(env,
+                "clear-screen",
+                clear_screen,
+                "(clear-screen) returns what to print to clear the terminal");
+    set_builtin(env,
+                "clear-line",
+                clear_line,
+                "(clear-line) returns what to print to clear the cursor's line and go to its \
+                 start");
+    #[cfg(feature = "io")]
+    set_builtin(env,
+                "terminal-size",
+                terminal_size,
+                "(terminal-size) returns [columns rows] of the terminal, nil if it's unknown");
+    #[cfg(feature = "io")]
+    set_builtin(env,
+                "read-key",
+                read_key,
+                "(read-key) waits for a key press without echoing it, returns characters as \
+                 strings and other keys as keywords like :enter, :up, :escape or :ctrl-c");
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{AtomError, c_int, c_keyword, c_string};
+    use super::{cursor_move, cursor_to, style};
+
+    #[test]
+    fn test_style() {
+        assert_eq!(style(&[c_string("hi"), c_keyword("red"), c_keyword("bold")]),
+                   Ok(c_string("\x1b[31;1mhi\x1b[0m")));
+        assert_eq!(style(&[c_int(1), c_keyword("bg-white"), c_keyword("bright-black")]),
+                   Ok(c_string("\x1b[47;90m1\x1b[0m")));
+        assert_eq!(style(&[c_string("plain")]), Ok(c_string("plain")));
+        assert_eq!(style(&[c_string("hi"), c_keyword("sparkly")]),
+                   Err(AtomError::InvalidArgument("unknown style :sparkly".to_string())));
+        assert!(style(&[c_string("hi"), c_string("red")]).is_err());
+    }
+
+    #[test]
+    fn test_cursor() {
+        assert_eq!(cursor_to(&[c_int(2), c_int(5)]), Ok(c_string("\x1b[2;5H")));
+        assert_eq!(cursor_move(&[c_int(-1), c_int(3)]), Ok(c_string("\x1b[1A\x1b[3C")));
+        assert_eq!(cursor_move(&[c_int(0), c_int(-2)]), Ok(c_string("\x1b[2D")));
+        assert!(cursor_to(&[c_int(1)]).is_err());
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_decode() {
+        use super::decode;
+
+        assert_eq!(decode(b"\x1b[Ax"), (c_keyword("up"), 3));
+        assert_eq!(decode(b"\x1b"), (c_keyword("escape"), 1));
+        assert_eq!(decode(b"\x03"), (c_keyword("ctrl-c"), 1));
+        assert_eq!(decode(b"\t"), (c_keyword("tab"), 1));
+        assert_eq!(decode(b"\r"), (c_keyword("enter"), 1));
+        assert_eq!(decode("éa".as_bytes()), (c_string("é"), 2));
+        assert_eq!(decode(b"ab"), (c_string("a"), 1));
+    }
+}
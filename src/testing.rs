@@ -0,0 +1,208 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use fnv::FnvHashMap;
+use data::{AtomVal, AtomType, AtomRet, AtomError, AtomKey, c_bool, c_int, c_keyword, c_map,
+           c_doc_func, c_string, c_symbol, c_vector};
+use env::{Env, env_set};
+use core::safe_get;
+use output;
+
+// Tests defined with deftest in order and the assertions counted since the last run-tests
+#[derive(Default)]
+struct Tests {
+    tests: Vec<(String, AtomVal)>,
+    current: Option<String>,
+    pass: i64,
+    fail: i64,
+    error: i64,
+}
+
+impl Tests {
+    fn failed(&mut self, form: &AtomVal, expected: Option<&AtomVal>, actual: &AtomVal) {
+        self.fail += 1;
+        match self.current {
+            Some(ref name) => output::write_out(&format!("FAIL in {}: {}\n", name, form)),
+            None => output::write_out(&format!("FAIL: {}\n", form)),
+        }
+        if let Some(expected) = expected {
+            output::write_out(&format!("  expected: {}\n", expected));
+        }
+        output::write_out(&format!("    actual: {}\n", actual));
+    }
+}
+
+// (register-test! name f), a test defined again replaces the old one
+fn register_test(tests: &RefCell<Tests>, args: &[AtomVal]) -> AtomRet {
+    let name = safe_get(args, 0).get_symbol()?.to_string();
+    let func = safe_get(args, 1);
+    let mut tests = tests.borrow_mut();
+    match tests.tests.iter().position(|test| test.0 == name) {
+        Some(index) => tests.tests[index].1 = func,
+        None => tests.tests.push((name.clone(), func)),
+    }
+    Ok(c_symbol(&name))
+}
+
+// (test-is 'form value)
+fn test_is(tests: &RefCell<Tests>, args: &[AtomVal]) -> AtomRet {
+    let value = safe_get(args, 1);
+    let mut tests = tests.borrow_mut();
+    if value.is_truthy() {
+        tests.pass += 1;
+    } else {
+        tests.failed(&safe_get(args, 0), None, &value);
+    }
+    Ok(c_bool(value.is_truthy()))
+}
+
+// (test-equal 'form expected actual)
+fn test_equal(tests: &RefCell<Tests>, args: &[AtomVal]) -> AtomRet {
+    let (expected, actual) = (safe_get(args, 1), safe_get(args, 2));
+    let mut tests = tests.borrow_mut();
+    if expected == actual {
+        tests.pass += 1;
+    } else {
+        tests.failed(&safe_get(args, 0), Some(&expected), &actual);
+    }
+    Ok(c_bool(expected == actual))
+}
+
+// {:type :assertion-error :message "..." :data {:form form :values [[arg value] ...]}}, the
+// message naming form and the values of its arguments that aren't literals
+fn assertion_error(form: &AtomVal, message: &AtomVal, values: &[AtomVal]) -> AtomError {
+    let args = form.get_list().map(|items| items.iter().skip(1).cloned().collect::<Vec<_>>());
+    let pairs = args.unwrap_or_default()
+        .into_iter()
+        .zip(values.iter().cloned())
+        .filter(|&(ref arg, ref value)| arg != value)
+        .collect::<Vec<_>>();
+
+    let mut text = match **message {
+        AtomType::Nil => format!("assert failed: {}", form),
+        _ => format!("{}: {}", message, form),
+    };
+    for &(ref arg, ref value) in &pairs {
+        text += &format!(", {} = {}", arg, value);
+    }
+
+    let field = |name| AtomKey::new(c_keyword(name)).unwrap();
+    let mut data = FnvHashMap::default();
+    data.insert(field("form"), form.clone());
+    let pairs = pairs.into_iter().map(|(arg, value)| c_vector(vec![arg, value])).collect();
+    data.insert(field("values"), c_vector(pairs));
+    let mut condition = FnvHashMap::default();
+    condition.insert(field("type"), c_keyword("assertion-error"));
+    condition.insert(field("message"), c_string(&text));
+    condition.insert(field("data"), c_map(data));
+    AtomError::Condition(c_map(condition))
+}
+
+// (assert-value 'form message value)
+fn assert_value(args: &[AtomVal]) -> AtomRet {
+    let value = safe_get(args, 2);
+    if !value.is_truthy() {
+        return Err(assertion_error(&safe_get(args, 0), &safe_get(args, 1), &[]));
+    }
+    Ok(value)
+}
+
+// (assert-call 'form message f & args)
+fn assert_call(args: &[AtomVal]) -> AtomRet {
+    let values = if args.len() > 3 { &args[3..] } else { &[] };
+    let value = safe_get(args, 2).apply(values)?;
+    if !value.is_truthy() {
+        return Err(assertion_error(&safe_get(args, 0), &safe_get(args, 1), values));
+    }
+    Ok(value)
+}
+
+// (run-tests) runs every test, prints failures and a summary and returns
+// {:test n :pass n :fail n :error n}
+fn run_tests(tests: &RefCell<Tests>) -> AtomRet {
+    let registered = {
+        let mut tests = tests.borrow_mut();
+        tests.pass = 0;
+        tests.fail = 0;
+        tests.error = 0;
+        tests.tests.clone()
+    };
+
+    for &(ref name, ref func) in &registered {
+        tests.borrow_mut().current = Some(name.clone());
+        // the borrow is released, the test body calls back into test-is and friends
+        if let Err(err) = func.apply(&[]) {
+            let mut tests = tests.borrow_mut();
+            tests.error += 1;
+            output::write_out(&format!("ERROR in {}: {}\n", name, err));
+        }
+    }
+
+    let mut tests = tests.borrow_mut();
+    tests.current = None;
+    output::write_out(&format!("Ran {} tests: {} passed, {} failed, {} errors\n",
+                           registered.len(),
+                           tests.pass,
+                           tests.fail,
+                           tests.error));
+
+    let mut counts = FnvHashMap::default();
+    for &(key, count) in &[("test", registered.len() as i64),
+                           ("pass", tests.pass),
+                           ("fail", tests.fail),
+                           ("error", tests.error)] {
+        counts.insert(AtomKey::new(c_keyword(key))?, c_int(count));
+    }
+    Ok(c_map(counts))
+}
+
+// Number of failed assertions and errors in the result of run-tests
+pub fn failures(result: &AtomVal) -> Result<i64, AtomError> {
+    let map = result.get_map()?;
+    let mut failures = 0;
+    for key in &["fail", "error"] {
+        if let Some(count) = map.get(&AtomKey::new(c_keyword(key))?) {
+            failures += count.get_int()?;
+        }
+    }
+    Ok(failures)
+}
+
+// The builtins behind deftest, is and assert= from core.clrs, assert, and run-tests
+pub fn register(env: &Env) {
+    let tests = Rc::new(RefCell::new(Tests::default()));
+
+    let registry = tests.clone();
+    env_set(env,
+            &c_symbol("register-test!"),
+            c_doc_func(move |args| register_test(&registry, args),
+                       "(register-test! name f) adds f to the tests run-tests runs, \
+                        deftest expands to it"));
+    let registry = tests.clone();
+    env_set(env,
+            &c_symbol("test-is"),
+            c_doc_func(move |args| test_is(&registry, args),
+                       "(test-is 'form value) counts an assertion passing if value is truthy, \
+                        is expands to it"));
+    let registry = tests.clone();
+    env_set(env,
+            &c_symbol("test-equal"),
+            c_doc_func(move |args| test_equal(&registry, args),
+                       "(test-equal 'form expected actual) counts an assertion passing if \
+                        expected equals actual, assert= expands to it"));
+    env_set(env,
+            &c_symbol("assert-value"),
+            c_doc_func(assert_value,
+                       "(assert-value 'form message value) returns value if it's truthy, else \
+                        fails with an :assertion-error, assert expands to it"));
+    env_set(env,
+            &c_symbol("assert-call"),
+            c_doc_func(assert_call,
+                       "(assert-call 'form message f & args) returns (apply f args) if it's \
+                        truthy, else fails with an :assertion-error showing args, assert \
+                        expands to it"));
+    env_set(env,
+            &c_symbol("run-tests"),
+            c_doc_func(move |_| run_tests(&tests),
+                       "(run-tests) runs the tests defined with deftest, prints failures and \
+                        returns {:test n :pass n :fail n :error n}"));
+}
@@ -0,0 +1,190 @@
+// Transient collections: a copy of a list, vector or map which conj! and assoc! change in
+// place, for building big collections without a new value per step. persistent! hands the
+// contents back as an ordinary value in O(1), after which the transient can't be used again.
+use std::cell::RefCell;
+use std::mem;
+use fnv::FnvHashMap;
+use data::{AtomError, AtomKey, AtomRet, AtomType, AtomVal, c_list, c_map, c_transient, c_vector};
+use env::Env;
+use core::{safe_get, set_builtin};
+
+enum Building {
+    List(Vec<AtomVal>),
+    Vector(Vec<AtomVal>),
+    Map(FnvHashMap<AtomKey, AtomVal>),
+    Done,
+}
+
+pub struct Transient(RefCell<Building>);
+
+impl Transient {
+    pub fn new(value: &AtomVal) -> Result<Transient, AtomError> {
+        let building = match **value {
+            AtomType::Nil => Building::List(vec![]),
+            AtomType::List(ref seq, _) => Building::List(seq.clone()),
+            AtomType::Vector(ref seq) => Building::Vector(seq.to_vec()),
+            AtomType::Map(ref map) => Building::Map(map.clone()),
+            _ => {
+                return Err(AtomError::InvalidType("List, Vector or Map".to_string(),
+                                                  value.format(true)))
+            }
+        };
+        Ok(Transient(RefCell::new(building)))
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match *self.0.borrow() {
+            Building::List(_) => "list",
+            Building::Vector(_) => "vector",
+            Building::Map(_) => "map",
+            Building::Done => "done",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self.0.borrow() {
+            Building::List(ref seq) |
+            Building::Vector(ref seq) => seq.len(),
+            Building::Map(ref map) => map.len(),
+            Building::Done => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Lists and vectors grow at the end, maps take [key value] vectors
+    pub fn conj(&self, value: AtomVal) -> Result<(), AtomError> {
+        match *self.0.borrow_mut() {
+            Building::List(ref mut seq) |
+            Building::Vector(ref mut seq) => seq.push(value),
+            Building::Map(ref mut map) => {
+                let entry = value.get_seq()?;
+                if entry.len() != 2 {
+                    return Err(AtomError::InvalidArgument("conj! on a map needs [key value]"
+                        .to_string()));
+                }
+                map.insert(AtomKey::new(entry[0].clone())?, entry[1].clone());
+            }
+            Building::Done => return Err(done()),
+        }
+        Ok(())
+    }
+
+    pub fn assoc(&self, key: AtomVal, value: AtomVal) -> Result<(), AtomError> {
+        match *self.0.borrow_mut() {
+            Building::Map(ref mut map) => {
+                map.insert(AtomKey::new(key)?, value);
+                Ok(())
+            }
+            Building::Done => Err(done()),
+            _ => Err(AtomError::InvalidOperation("assoc! needs a transient map".to_string())),
+        }
+    }
+
+    pub fn persistent(&self) -> AtomRet {
+        match mem::replace(&mut *self.0.borrow_mut(), Building::Done) {
+            Building::List(seq) => Ok(c_list(seq)),
+            Building::Vector(seq) => Ok(c_vector(seq)),
+            Building::Map(map) => Ok(c_map(map)),
+            Building::Done => Err(done()),
+        }
+    }
+}
+
+impl PartialEq for Transient {
+    fn eq(&self, other: &Transient) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+fn done() -> AtomError {
+    AtomError::InvalidOperation("transient used after persistent!".to_string())
+}
+
+fn get_transient(value: &AtomVal) -> Result<&Transient, AtomError> {
+    match **value {
+        AtomType::Transient(ref transient) => Ok(transient),
+        _ => Err(AtomError::InvalidType("Transient".to_string(), value.format(true))),
+    }
+}
+
+fn transient(args: &[AtomVal]) -> AtomRet {
+    Ok(c_transient(Transient::new(&safe_get(args, 0))?))
+}
+
+fn conj(args: &[AtomVal]) -> AtomRet {
+    let coll = safe_get(args, 0);
+    {
+        let transient = get_transient(&coll)?;
+        for value in args.iter().skip(1) {
+            transient.conj(value.clone())?;
+        }
+    }
+    Ok(coll)
+}
+
+fn assoc(args: &[AtomVal]) -> AtomRet {
+    if args.len() % 2 != 1 {
+        return Err(AtomError::InvalidArgument("assoc! needs keys and values in pairs"
+            .to_string()));
+    }
+    let coll = safe_get(args, 0);
+    {
+        let transient = get_transient(&coll)?;
+        for pair in args[1..].chunks(2) {
+            transient.assoc(pair[0].clone(), pair[1].clone())?;
+        }
+    }
+    Ok(coll)
+}
+
+fn persistent(args: &[AtomVal]) -> AtomRet {
+    get_transient(&safe_get(args, 0))?.persistent()
+}
+
+pub fn register(env: &Env) {
+    set_builtin(env,
+                "transient",
+                transient,
+                "(transient coll) returns a transient copy of the list, vector or map coll");
+    set_builtin(env,
+                "conj!",
+                conj,
+                "(conj! t & xs) adds xs at the end of the transient t, or [k v] entries to a map, \
+                 returns t");
+    set_builtin(env,
+                "assoc!",
+                assoc,
+                "(assoc! t & kvs) sets the keys in the transient map t to their values, returns t");
+    set_builtin(env,
+                "persistent!",
+                persistent,
+                "(persistent! t) returns the contents of the transient t as an ordinary value, t \
+                 can't be used afterwards");
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{c_int, c_list, c_nil, c_vector};
+    use super::Transient;
+
+    #[test]
+    fn test_transient() {
+        let transient = Transient::new(&c_vector(vec![c_int(0)])).unwrap();
+        for i in 1..3 {
+            transient.conj(c_int(i)).unwrap();
+        }
+        assert_eq!(transient.len(), 3);
+        assert_eq!(transient.persistent().unwrap(),
+                   c_vector(vec![c_int(0), c_int(1), c_int(2)]));
+        assert_eq!(transient.kind(), "done");
+        assert!(transient.conj(c_int(3)).is_err());
+        assert!(transient.persistent().is_err());
+
+        let list = Transient::new(&c_nil()).unwrap();
+        assert!(list.assoc(c_int(1), c_int(2)).is_err());
+        assert_eq!(list.persistent().unwrap(), c_list(vec![]));
+    }
+}
@@ -0,0 +1,362 @@
+// Persistent vector: a trie of 32-way branches over leaves of 32 values, with the last up to 32
+// values in a tail of their own. Versions share all nodes they don't differ in, so setting an
+// index copies one path of the trie and appending mostly copies the tail, both O(log32 n).
+// A subvec is a window into the trie it's taken from, it keeps all of that alive.
+use std::cell::OnceCell;
+use std::rc::Rc;
+use data::AtomVal;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+enum Node {
+    Branch(Vec<Rc<Node>>),
+    Leaf(Vec<AtomVal>),
+}
+
+impl Node {
+    fn children(&self) -> &Vec<Rc<Node>> {
+        match *self {
+            Node::Branch(ref children) => children,
+            Node::Leaf(_) => unreachable!(),
+        }
+    }
+}
+
+// node with the branches down to it from level, the level of branches over leaves being BITS
+fn path(level: usize, node: Rc<Node>) -> Rc<Node> {
+    if level == 0 { node } else { Rc::new(Node::Branch(vec![path(level - BITS, node)])) }
+}
+
+pub struct PersistentVector {
+    root: Rc<Node>,
+    // level of the root, the bits its index into the trie is shifted by
+    shift: usize,
+    tail: Rc<Vec<AtomVal>>,
+    // values in root and tail
+    size: usize,
+    // the window seen, all of it unless this is a subvec
+    start: usize,
+    len: usize,
+    // the values laid out in order, for what wants them as a slice
+    flat: OnceCell<Vec<AtomVal>>,
+}
+
+impl PersistentVector {
+    pub fn new(values: Vec<AtomVal>) -> PersistentVector {
+        let size = values.len();
+        let tail_offset = tail_offset(size);
+        let mut values = values;
+        let tail = values.split_off(tail_offset);
+
+        let mut nodes = Vec::with_capacity(tail_offset / WIDTH);
+        let mut values = values.into_iter();
+        for _ in 0..tail_offset / WIDTH {
+            nodes.push(Rc::new(Node::Leaf(values.by_ref().take(WIDTH).collect())));
+        }
+        let mut shift = BITS;
+        while nodes.len() > WIDTH {
+            let mut nodes_ = nodes.into_iter();
+            let mut branches = vec![];
+            loop {
+                let children = nodes_.by_ref().take(WIDTH).collect::<Vec<_>>();
+                if children.is_empty() {
+                    break;
+                }
+                branches.push(Rc::new(Node::Branch(children)));
+            }
+            nodes = branches;
+            shift += BITS;
+        }
+
+        PersistentVector {
+            root: Rc::new(Node::Branch(nodes)),
+            shift: shift,
+            tail: Rc::new(tail),
+            size: size,
+            start: 0,
+            len: size,
+            flat: OnceCell::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&AtomVal> {
+        if index >= self.len {
+            return None;
+        }
+        let index = self.start + index;
+        Some(&self.chunk(index)[index & MASK])
+    }
+
+    pub fn last(&self) -> Option<&AtomVal> {
+        if self.len == 0 { None } else { self.get(self.len - 1) }
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter {
+            vector: self,
+            index: 0,
+            chunk: &[],
+        }
+    }
+
+    // Walks the trie once, later calls hand out the same slice
+    pub fn as_slice(&self) -> &[AtomVal] {
+        self.flat.get_or_init(|| self.iter().cloned().collect())
+    }
+
+    pub fn to_vec(&self) -> Vec<AtomVal> {
+        match self.flat.get() {
+            Some(flat) => flat.clone(),
+            None => self.iter().cloned().collect(),
+        }
+    }
+
+    // A copy with value at index, None if index is out of range
+    pub fn set(&self, index: usize, value: AtomVal) -> Option<PersistentVector> {
+        if index >= self.len {
+            return None;
+        }
+        let index = self.start + index;
+        let tail_offset = tail_offset(self.size);
+        let (root, tail) = if index >= tail_offset {
+            let mut tail = (*self.tail).clone();
+            tail[index - tail_offset] = value;
+            (self.root.clone(), Rc::new(tail))
+        } else {
+            (set_in(&self.root, self.shift, index, value), self.tail.clone())
+        };
+        Some(PersistentVector {
+            root: root,
+            tail: tail,
+            flat: OnceCell::new(),
+            ..*self
+        })
+    }
+
+    // A copy with value appended; a subvec that doesn't reach the end of its trie is copied
+    pub fn push(&self, value: AtomVal) -> PersistentVector {
+        if self.start + self.len != self.size {
+            let mut values = self.to_vec();
+            values.push(value);
+            return PersistentVector::new(values);
+        }
+
+        let tail_offset = tail_offset(self.size);
+        if self.size - tail_offset < WIDTH {
+            let mut tail = Vec::with_capacity(self.tail.len() + 1);
+            tail.extend(self.tail.iter().cloned());
+            tail.push(value);
+            return PersistentVector {
+                root: self.root.clone(),
+                tail: Rc::new(tail),
+                size: self.size + 1,
+                len: self.len + 1,
+                flat: OnceCell::new(),
+                ..*self
+            };
+        }
+
+        // the full tail goes into the trie, which grows a level once the root is full
+        let leaf = Rc::new(Node::Leaf((*self.tail).clone()));
+        let (root, shift) = if (self.size >> BITS) > (1 << self.shift) {
+            let children = vec![self.root.clone(), path(self.shift, leaf)];
+            (Rc::new(Node::Branch(children)), self.shift + BITS)
+        } else {
+            (push_leaf(&self.root, self.shift, tail_offset, leaf), self.shift)
+        };
+        PersistentVector {
+            root: root,
+            shift: shift,
+            tail: Rc::new(vec![value]),
+            size: self.size + 1,
+            start: self.start,
+            len: self.len + 1,
+            flat: OnceCell::new(),
+        }
+    }
+
+    // The values from start up to end sharing this vector's nodes, None if the range is invalid
+    pub fn slice(&self, start: usize, end: usize) -> Option<PersistentVector> {
+        if start > end || end > self.len {
+            return None;
+        }
+        Some(PersistentVector {
+            root: self.root.clone(),
+            tail: self.tail.clone(),
+            start: self.start + start,
+            len: end - start,
+            flat: OnceCell::new(),
+            ..*self
+        })
+    }
+
+    // The leaf or tail holding the value at index into the whole trie
+    fn chunk(&self, index: usize) -> &[AtomVal] {
+        if index >= tail_offset(self.size) {
+            return &self.tail;
+        }
+        let mut node = &self.root;
+        let mut level = self.shift;
+        while level > 0 {
+            node = &node.children()[(index >> level) & MASK];
+            level -= BITS;
+        }
+        match **node {
+            Node::Leaf(ref values) => values,
+            Node::Branch(_) => unreachable!(),
+        }
+    }
+}
+
+// Index of the first value in the tail, the trie holding whole leaves only
+fn tail_offset(size: usize) -> usize {
+    if size < WIDTH { 0 } else { ((size - 1) >> BITS) << BITS }
+}
+
+fn set_in(node: &Rc<Node>, level: usize, index: usize, value: AtomVal) -> Rc<Node> {
+    match **node {
+        Node::Leaf(ref values) => {
+            let mut values = values.clone();
+            values[index & MASK] = value;
+            Rc::new(Node::Leaf(values))
+        }
+        Node::Branch(ref children) => {
+            let mut children = children.clone();
+            let slot = (index >> level) & MASK;
+            children[slot] = set_in(&children[slot], level - BITS, index, value);
+            Rc::new(Node::Branch(children))
+        }
+    }
+}
+
+// node with leaf added as the leaf holding index, the trie having room for it
+fn push_leaf(node: &Rc<Node>, level: usize, index: usize, leaf: Rc<Node>) -> Rc<Node> {
+    let mut children = node.children().clone();
+    let slot = (index >> level) & MASK;
+    if level == BITS {
+        children.push(leaf);
+    } else if slot < children.len() {
+        children[slot] = push_leaf(&children[slot], level - BITS, index, leaf);
+    } else {
+        children.push(path(level - BITS, leaf));
+    }
+    Rc::new(Node::Branch(children))
+}
+
+impl Clone for PersistentVector {
+    fn clone(&self) -> PersistentVector {
+        PersistentVector {
+            root: self.root.clone(),
+            tail: self.tail.clone(),
+            flat: OnceCell::new(),
+            ..*self
+        }
+    }
+}
+
+impl PartialEq for PersistentVector {
+    fn eq(&self, other: &PersistentVector) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+// Takes a leaf at a time, so iterating doesn't go down the trie for each value
+pub struct Iter<'a> {
+    vector: &'a PersistentVector,
+    index: usize,
+    chunk: &'a [AtomVal],
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a AtomVal;
+
+    fn next(&mut self) -> Option<&'a AtomVal> {
+        if self.chunk.is_empty() {
+            if self.index >= self.vector.len {
+                return None;
+            }
+            let index = self.vector.start + self.index;
+            let chunk = self.vector.chunk(index);
+            let end = (chunk.len()).min(self.vector.len - self.index + (index & MASK));
+            self.chunk = &chunk[index & MASK..end];
+        }
+        let (value, rest) = self.chunk.split_first().unwrap();
+        self.chunk = rest;
+        self.index += 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.vector.len - self.index;
+        (left, Some(left))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+impl<'a> IntoIterator for &'a PersistentVector {
+    type Item = &'a AtomVal;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::{c_int, AtomVal};
+    use super::PersistentVector;
+
+    fn ints(range: ::std::ops::Range<i64>) -> Vec<AtomVal> {
+        range.map(c_int).collect()
+    }
+
+    #[test]
+    fn test_push_and_get() {
+        // through a tail, one level of branches and a second
+        for &size in &[0, 1, 31, 32, 33, 64, 1056, 1057, 1088, 2000] {
+            let built = PersistentVector::new(ints(0..size));
+            let pushed = (0..size).fold(PersistentVector::new(vec![]), |v, i| v.push(c_int(i)));
+            for vector in &[&built, &pushed] {
+                assert_eq!(vector.len(), size as usize);
+                assert_eq!(vector.iter().cloned().collect::<Vec<_>>(), ints(0..size));
+                assert_eq!(vector.get(size as usize), None);
+            }
+            assert!(built == pushed);
+        }
+    }
+
+    #[test]
+    fn test_set_shares() {
+        let vector = PersistentVector::new(ints(0..100));
+        let set = vector.set(5, c_int(-1)).unwrap().set(99, c_int(-2)).unwrap();
+        assert_eq!(set.get(5), Some(&c_int(-1)));
+        assert_eq!(set.get(99), Some(&c_int(-2)));
+        assert_eq!(vector.get(5), Some(&c_int(5)));
+        assert_eq!(vector.as_slice(), &ints(0..100)[..]);
+        assert!(vector.set(100, c_int(0)).is_none());
+    }
+
+    #[test]
+    fn test_slice() {
+        let vector = PersistentVector::new(ints(0..100));
+        let slice = vector.slice(30, 70).unwrap();
+        assert_eq!(slice.to_vec(), ints(30..70));
+        assert_eq!(slice.slice(1, 3).unwrap().to_vec(), ints(31..33));
+        assert_eq!(slice.set(0, c_int(0)).unwrap().to_vec()[..2], [c_int(0), c_int(31)]);
+        assert_eq!(slice.push(c_int(0)).len(), 41);
+        assert_eq!(vector.slice(90, 100).unwrap().push(c_int(100)).to_vec(), ints(90..101));
+        assert!(vector.slice(5, 101).is_none());
+    }
+}
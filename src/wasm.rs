@@ -0,0 +1,62 @@
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+use interpreter::Interpreter;
+use output;
+
+// An interpreter for a page to run code in, what the code prints is kept for the page to show
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+pub struct Playground {
+    interpreter: Interpreter,
+}
+
+impl Default for Playground {
+    fn default() -> Playground {
+        Playground::new()
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+impl Playground {
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen(constructor))]
+    pub fn new() -> Playground {
+        output::capture_output(true);
+        Playground { interpreter: Interpreter::new() }
+    }
+
+    // Throws what render gives for errors
+    #[cfg(target_arch = "wasm32")]
+    pub fn eval_str(&self, source: &str) -> Result<String, JsValue> {
+        self.render(source).map_err(|report| JsValue::from_str(&report))
+    }
+
+    // What was printed since the last call
+    pub fn take_output(&self) -> String {
+        output::take_output()
+    }
+}
+
+impl Playground {
+    // The value of the last form as the REPL prints it, errors rendered as a report without
+    // colors
+    pub fn render(&self, source: &str) -> Result<String, String> {
+        self.interpreter
+            .run("<playground>", source)
+            .map(|value| value.to_string())
+            .map_err(|report| report.render(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Playground;
+
+    #[test]
+    fn test_render() {
+        let playground = Playground::new();
+        assert_eq!(playground.render("(+ 1 2)"), Ok("3".to_string()));
+        assert_eq!(playground.render("[1 :a \"b\"]"), Ok("[1 :a b]".to_string()));
+        assert_eq!(playground.render("(do (println 1) nil)"), Ok("nil".to_string()));
+        assert_eq!(playground.take_output(), "1\n");
+        assert!(playground.render("(undefined)").unwrap_err().contains("undefined"));
+    }
+}
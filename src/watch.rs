@@ -0,0 +1,89 @@
+// File change events for build watchers and hot reloading: (watch-path p handler) calls
+// (handler kind path) with kind :created, :modified or :removed for each change under p until
+// handler returns :stop. Changes are collected for a moment first, so an editor saving a file
+// in several writes shows up as one event.
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::time::Duration;
+use notify::{DebouncedEvent, RecursiveMode, Watcher, watcher};
+use data::{AtomError, AtomRet, AtomType, AtomVal, c_doc_func, c_keyword, c_nil, c_string,
+           c_symbol};
+use env::{Env, env_set};
+
+const DELAY_MS: u64 = 100;
+
+fn watch_error(path: &str, err: &dyn ToString) -> AtomError {
+    AtomError::InvalidOperation(format!("watch-path {}: {}", path, err.to_string()))
+}
+
+// The changes an event stands for, a rename removes one path and creates another; notices of
+// changes still being collected and rescans are left out
+fn changes(event: DebouncedEvent) -> Result<Vec<(&'static str, PathBuf)>, String> {
+    Ok(match event {
+        DebouncedEvent::Create(path) => vec![("created", path)],
+        DebouncedEvent::Write(path) |
+        DebouncedEvent::Chmod(path) => vec![("modified", path)],
+        DebouncedEvent::Remove(path) => vec![("removed", path)],
+        DebouncedEvent::Rename(from, to) => vec![("removed", from), ("created", to)],
+        DebouncedEvent::NoticeWrite(_) |
+        DebouncedEvent::NoticeRemove(_) |
+        DebouncedEvent::Rescan => vec![],
+        DebouncedEvent::Error(err, _) => return Err(err.to_string()),
+    })
+}
+
+fn watch_path(args: &[AtomVal]) -> AtomRet {
+    let path = match args.get(0).map(|path| &**path) {
+        Some(&AtomType::Str(ref path)) => path.clone(),
+        Some(other) => return Err(AtomError::InvalidType("String".to_string(), other.format(true))),
+        None => return Err(AtomError::InvalidArgument("missing path".to_string())),
+    };
+    let handler = match args.get(1) {
+        Some(handler) => handler.clone(),
+        None => return Err(AtomError::InvalidArgument("watch-path needs a handler".to_string())),
+    };
+
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, Duration::from_millis(DELAY_MS))
+        .map_err(|err| watch_error(&path, &err))?;
+    watcher.watch(&*path, RecursiveMode::Recursive).map_err(|err| watch_error(&path, &err))?;
+
+    // the watcher stops sending once it's dropped, which ends the loop
+    for event in rx {
+        for (kind, changed) in changes(event).map_err(|err| watch_error(&path, &err))? {
+            let result = handler.apply(&[c_keyword(kind), c_string(&changed.to_string_lossy())])?;
+            if result.is_keyword("stop") {
+                return Ok(c_nil());
+            }
+        }
+    }
+    Ok(c_nil())
+}
+
+pub fn register(env: &Env) {
+    env_set(env,
+            &c_symbol("watch-path"),
+            c_doc_func(watch_path,
+                       "(watch-path p handler) calls (handler kind path) with kind :created, \
+                        :modified or :removed for every change to the files under p, until \
+                        handler returns :stop"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use notify::DebouncedEvent;
+    use super::changes;
+
+    #[test]
+    fn test_changes() {
+        let path = |name: &str| PathBuf::from(name);
+        assert_eq!(changes(DebouncedEvent::Create(path("a"))).unwrap(),
+                   vec![("created", path("a"))]);
+        assert_eq!(changes(DebouncedEvent::Write(path("a"))).unwrap(),
+                   vec![("modified", path("a"))]);
+        assert_eq!(changes(DebouncedEvent::Rename(path("a"), path("b"))).unwrap(),
+                   vec![("removed", path("a")), ("created", path("b"))]);
+        assert_eq!(changes(DebouncedEvent::NoticeWrite(path("a"))).unwrap(), vec![]);
+    }
+}
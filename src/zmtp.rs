@@ -0,0 +1,144 @@
+// Just enough ZeroMQ (ZMTP 3 with the NULL mechanism) for the Jupyter kernel: one peer per
+// connection, messages as lists of frames
+use std::io::{self, Read, Write};
+
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+fn be_bytes(n: u64, len: usize) -> Vec<u8> {
+    (0..len).rev().map(|i| (n >> (i * 8)) as u8).collect()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Exchanges greetings and READY commands with the peer, socket_type is ours, like "ROUTER"
+pub fn handshake<S: Read + Write>(stream: &mut S, socket_type: &str) -> io::Result<()> {
+    let mut greeting = vec![0xff, 0, 0, 0, 0, 0, 0, 0, 1, 0x7f, 3, 0];
+    greeting.extend(b"NULL");
+    greeting.resize(64, 0);
+    stream.write_all(&greeting)?;
+
+    let mut ready = vec![5];
+    ready.extend(b"READY");
+    ready.push(11);
+    ready.extend(b"Socket-Type");
+    ready.extend(be_bytes(socket_type.len() as u64, 4));
+    ready.extend(socket_type.as_bytes());
+    write_frame(stream, COMMAND, &ready)?;
+    stream.flush()?;
+
+    let mut peer = [0; 64];
+    stream.read_exact(&mut peer)?;
+    if peer[0] != 0xff || peer[9] != 0x7f || peer[10] < 3 {
+        return Err(invalid("peer doesn't speak ZMTP 3"));
+    }
+    if &peer[12..16] != b"NULL" {
+        return Err(invalid("only the NULL security mechanism is supported"));
+    }
+    match read_frame(stream)? {
+        Some((flags, ref body)) if flags & COMMAND != 0 && body.starts_with(b"\x05READY") => Ok(()),
+        _ => Err(invalid("peer didn't send READY")),
+    }
+}
+
+fn write_frame<W: Write>(output: &mut W, flags: u8, body: &[u8]) -> io::Result<()> {
+    if body.len() > 255 {
+        output.write_all(&[flags | LONG])?;
+        output.write_all(&be_bytes(body.len() as u64, 8))?;
+    } else {
+        output.write_all(&[flags, body.len() as u8])?;
+    }
+    output.write_all(body)
+}
+
+// The flags and body of the next frame, None at the end of input
+fn read_frame<R: Read>(input: &mut R) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut flags = [0];
+    if input.read(&mut flags)? == 0 {
+        return Ok(None);
+    }
+    let flags = flags[0];
+    let len = if flags & LONG != 0 {
+        let mut len = [0; 8];
+        input.read_exact(&mut len)?;
+        len.iter().fold(0, |len, &byte| len << 8 | byte as usize)
+    } else {
+        let mut len = [0];
+        input.read_exact(&mut len)?;
+        len[0] as usize
+    };
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some((flags, body)))
+}
+
+pub fn send<W: Write>(output: &mut W, frames: &[Vec<u8>]) -> io::Result<()> {
+    for (i, frame) in frames.iter().enumerate() {
+        let flags = if i + 1 < frames.len() { MORE } else { 0 };
+        write_frame(output, flags, frame)?;
+    }
+    output.flush()
+}
+
+// The frames of the next message, commands like PING are skipped; None at the end of input
+pub fn recv<R: Read>(input: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut frames = vec![];
+    loop {
+        match read_frame(input)? {
+            None if frames.is_empty() => return Ok(None),
+            None => return Err(invalid("connection closed inside a message")),
+            Some((flags, _)) if flags & COMMAND != 0 => {}
+            Some((flags, body)) => {
+                frames.push(body);
+                if flags & MORE == 0 {
+                    return Ok(Some(frames));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use super::{handshake, send, recv};
+
+    #[test]
+    fn test_frames() {
+        let long = vec![7; 300];
+        let mut out = vec![];
+        send(&mut out, &[b"a".to_vec(), vec![], long.clone()]).unwrap();
+        assert_eq!(&out[..5], &[1, 1, b'a', 1, 0]);
+        assert_eq!(out[5], 2);
+        // a PING command between messages is skipped
+        out.extend(&[4, 5, 4, b'P', b'I', b'N', b'G']);
+        send(&mut out, &[b"b".to_vec()]).unwrap();
+
+        let mut input = Cursor::new(out);
+        assert_eq!(recv(&mut input).unwrap(), Some(vec![b"a".to_vec(), vec![], long]));
+        assert_eq!(recv(&mut input).unwrap(), Some(vec![b"b".to_vec()]));
+        assert_eq!(recv(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            handshake(&mut stream, "DEALER").unwrap();
+            send(&mut stream, &[b"hello".to_vec()]).unwrap();
+            recv(&mut stream).unwrap()
+        });
+        let mut stream = listener.accept().unwrap().0;
+        handshake(&mut stream, "ROUTER").unwrap();
+        let message = recv(&mut stream).unwrap().unwrap();
+        send(&mut stream, &message).unwrap();
+        assert_eq!(peer.join().unwrap(), Some(vec![b"hello".to_vec()]));
+    }
+}
@@ -0,0 +1,31 @@
+// Builds the plugin in tests/plugin, which links its own copy of rulsp, and loads it
+#![cfg(feature = "plugins")]
+extern crate rulsp;
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use rulsp::data::c_int;
+use rulsp::interpreter::Interpreter;
+
+#[test]
+fn test_load_built_plugin() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target = root.join("target").join("plugin");
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(root.join("tests").join("plugin").join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let library = target.join("debug")
+        .join(format!("{}hello{}", env::consts::DLL_PREFIX, env::consts::DLL_SUFFIX));
+    let interpreter = Interpreter::new();
+    interpreter.eval(&format!("(load-plugin {:?})", library.to_str().unwrap())).unwrap();
+    assert_eq!(interpreter.eval("(hello)").unwrap().to_string(), "hello from a plugin");
+    assert_eq!(interpreter.eval("(plugin-inc 2)").unwrap(), c_int(3));
+}
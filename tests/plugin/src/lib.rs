@@ -0,0 +1,25 @@
+#[macro_use]
+extern crate rulsp;
+
+use rulsp::data::{AtomError, c_doc_func, c_string, c_symbol};
+use rulsp::env::{Env, env_get, env_set};
+
+fn register(env: &Env) {
+    env_set(env,
+            &c_symbol("hello"),
+            c_doc_func(|_| Ok(c_string("hello from a plugin")), "(hello) greets"));
+
+    // looks up a builtin of the interpreter
+    let inc = env_get(env, &c_symbol("inc")).unwrap();
+    env_set(env,
+            &c_symbol("plugin-inc"),
+            c_doc_func(move |args| {
+                           match args.get(0) {
+                               Some(n) => inc.apply(&[n.clone()]),
+                               None => Err(AtomError::InvalidArgument("plugin-inc".to_string())),
+                           }
+                       },
+                       "(plugin-inc n) is (inc n)"));
+}
+
+rulsp_plugin!(register);